@@ -0,0 +1,43 @@
+//! Criterion suite for the crypto and protocol hot paths, to check performance motivated
+//! refactors against. `age-chat bench --profile <path>` runs one of them alone for a profiler.
+//!
+//! cargo bench
+
+use std::hint::black_box;
+use std::str::FromStr;
+
+use age::x25519::Identity;
+use age_chat::common::{ClientMsg, Note, ServerMsg};
+use criterion::{criterion_group, criterion_main, Criterion};
+
+const CONTENT: &str = "The quick brown fox jumps over the lazy dog";
+
+fn hot_paths(c: &mut Criterion) {
+    let sender = Identity::generate();
+    let recipient = Identity::generate();
+    let note = Note::encrypt_new(&sender, &recipient.to_public(), CONTENT.into()).unwrap();
+    let msg = ClientMsg::SendNote(note.clone());
+    let json = msg.to_string();
+
+    c.bench_function("encrypt_note", |b| {
+        b.iter(|| Note::encrypt_new(&sender, &recipient.to_public(), CONTENT.into()).unwrap())
+    });
+    c.bench_function("open_note", |b| b.iter(|| note.open(&recipient).unwrap()));
+    c.bench_function("serialize_msg", |b| b.iter(|| black_box(&msg).to_string()));
+    c.bench_function("parse_msg", |b| {
+        b.iter(|| ClientMsg::from_str(black_box(&json)).unwrap())
+    });
+    // What the server does to relay a note: parse, check the signature, reserialize
+    c.bench_function("relay", |b| {
+        b.iter(|| {
+            let ClientMsg::SendNote(note) = ClientMsg::from_str(black_box(&json)).unwrap() else {
+                panic!("Expected a note");
+            };
+            note.verify_signature().unwrap();
+            ServerMsg::RecNote(note).to_ws_msg()
+        })
+    });
+}
+
+criterion_group!(benches, hot_paths);
+criterion_main!(benches);
@@ -0,0 +1,83 @@
+//! A bot that replies to each note with its content echoed back, a starting point for writing
+//! bots of your own. The `bot echo` subcommand is a fuller version.
+//!
+//! cargo run --example bot_echo <address> <key file>
+
+use std::path::Path;
+
+use age_chat::common::load_key;
+use age_chat::{ChatClient, Outgoing};
+use anyhow::{bail, Result};
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let [address, key_file] = args.as_slice() else {
+        bail!("usage: bot_echo <address> <key file>");
+    };
+    let key = load_key(Path::new(key_file))?;
+    let pub_key = key.to_public().to_string();
+    let mut client = ChatClient::connect(address).await?;
+    client.authenticate(key, None).await?;
+    println!("Echoing notes sent to {pub_key}");
+    tokio::select! {
+        res = echo(&mut client, &pub_key) => res?,
+        _ = tokio::signal::ctrl_c() => {}
+    }
+    client.close().await
+}
+
+/// Reply to each note sent to us with its content, forever
+async fn echo(client: &mut ChatClient, pub_key: &str) -> Result<()> {
+    loop {
+        let note = client.next_note().await?;
+        // Our own notes sent from another device would echo back and forth forever
+        if note.from == pub_key {
+            continue;
+        }
+        client
+            .send(Outgoing {
+                recipient: note.from,
+                content: note.content,
+                sealed_sender: false,
+            })
+            .await?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use age::x25519::Identity;
+    use age_chat::embedded_server;
+
+    #[tokio::test]
+    async fn bot_echoes_notes() -> Result<()> {
+        let server = embedded_server("127.0.0.1:0").await?;
+        let address = server.addr().to_string();
+
+        let bot = Identity::generate();
+        let bot_pub_key = bot.to_public().to_string();
+        let mut bot_client = ChatClient::connect(&address).await?;
+        bot_client.authenticate(bot, None).await?;
+        let echo_pub_key = bot_pub_key.clone();
+        let bot_task = tokio::spawn(async move { echo(&mut bot_client, &echo_pub_key).await });
+
+        let mut client = ChatClient::connect(&address).await?;
+        client.authenticate(Identity::generate(), None).await?;
+        client
+            .send(Outgoing {
+                recipient: bot_pub_key.clone(),
+                content: "echo?".into(),
+                sealed_sender: false,
+            })
+            .await?;
+        let reply = client.next_note().await?;
+        assert_eq!(reply.from, bot_pub_key);
+        assert_eq!(reply.content, "echo?");
+
+        client.close().await?;
+        bot_task.abort();
+        server.shutdown().await
+    }
+}
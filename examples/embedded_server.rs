@@ -0,0 +1,61 @@
+//! Run a chat server inside another program, keeping everything in memory, and chat through it.
+//!
+//! cargo run --example embedded_server [listen address]
+
+use age::x25519::Identity;
+use age_chat::{embedded_server, ChatClient, Outgoing};
+use anyhow::Result;
+
+const GREETING: &str = "Hello through an embedded server";
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let listen = std::env::args().nth(1).unwrap_or("127.0.0.1:0".into());
+    let server = embedded_server(&listen).await?;
+    println!("Server listening on {}", server.addr());
+
+    let received = greet(&server.addr().to_string()).await?;
+    println!("Bob received: {received}");
+
+    // Signals are ours to handle, the server only stops when told to
+    println!("Ctrl-C to stop");
+    tokio::signal::ctrl_c().await?;
+    server.shutdown().await
+}
+
+/// Send a note between two new identities through the server, returning what arrived
+async fn greet(address: &str) -> Result<String> {
+    let alice = Identity::generate();
+    let bob = Identity::generate();
+    let bob_pub_key = bob.to_public().to_string();
+
+    let mut alice_client = ChatClient::connect(address).await?;
+    alice_client.authenticate(alice, None).await?;
+    let mut bob_client = ChatClient::connect(address).await?;
+    bob_client.authenticate(bob, None).await?;
+
+    alice_client
+        .send(Outgoing {
+            recipient: bob_pub_key,
+            content: GREETING.into(),
+            sealed_sender: false,
+        })
+        .await?;
+    let note = bob_client.next_note().await?;
+
+    alice_client.close().await?;
+    bob_client.close().await?;
+    Ok(note.content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn notes_go_through_embedded_server() -> Result<()> {
+        let server = embedded_server("127.0.0.1:0").await?;
+        assert_eq!(greet(&server.addr().to_string()).await?, GREETING);
+        server.shutdown().await
+    }
+}
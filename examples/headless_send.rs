@@ -0,0 +1,67 @@
+//! Send a single note from a script, without the TUI.
+//!
+//! cargo run --example headless_send <address> <key file> <recipient pubkey> <message>
+
+use std::path::Path;
+
+use age::x25519::Identity;
+use age_chat::common::load_key;
+use age_chat::{ChatClient, Outgoing};
+use anyhow::{bail, Result};
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let [address, key_file, recipient, message] = args.as_slice() else {
+        bail!("usage: headless_send <address> <key file> <recipient pubkey> <message>");
+    };
+    let key = load_key(Path::new(key_file))?;
+    let id = send(address, key, recipient, message).await?;
+    println!("Sent note {id}");
+    Ok(())
+}
+
+/// Send a note and wait for the server to accept it, returning its id
+async fn send(address: &str, key: Identity, recipient: &str, content: &str) -> Result<String> {
+    let mut client = ChatClient::connect(address).await?;
+    client.authenticate(key, None).await?;
+    let note = client
+        .send(Outgoing {
+            recipient: recipient.to_string(),
+            content: content.to_string(),
+            sealed_sender: false,
+        })
+        .await?;
+    client.close().await?;
+    Ok(note.id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use age_chat::embedded_server;
+
+    #[tokio::test]
+    async fn sent_note_arrives() -> Result<()> {
+        let server = embedded_server("127.0.0.1:0").await?;
+        let address = server.addr().to_string();
+        let recipient = Identity::generate();
+        let recipient_pub_key = recipient.to_public().to_string();
+        let mut recipient_client = ChatClient::connect(&address).await?;
+        recipient_client.authenticate(recipient, None).await?;
+
+        let id = send(
+            &address,
+            Identity::generate(),
+            &recipient_pub_key,
+            "headless",
+        )
+        .await?;
+
+        let note = recipient_client.next_note().await?;
+        assert_eq!(note.id, id);
+        assert_eq!(note.content, "headless");
+        recipient_client.close().await?;
+        server.shutdown().await
+    }
+}
@@ -0,0 +1,175 @@
+use age::x25519::Identity;
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use rand::Rng;
+use std::hint::black_box;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::common::{ClientMsg, Note, ServerMsg};
+use crate::server::{OverflowPolicy, Relay, UserConns, DEFAULT_SHARDS};
+
+const CONTENT: &str = "The quick brown fox jumps over the lazy dog";
+/// Messages each user's relay queue holds in the throughput benchmark, the oldest are dropped
+/// since nothing drains them
+const BENCH_RELAY_BUFFER: usize = 16;
+/// One in this many notes in the throughput benchmark is sent with a reconnect, taking the
+/// sender's lock for writing like authenticating does
+const RECONNECT_EVERY: u32 = 100;
+
+/// A hot path to measure
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum BenchPath {
+    /// Encrypting and signing a note
+    EncryptNote,
+    /// Decrypting a note and checking its signature
+    OpenNote,
+    /// Serializing a client message to JSON
+    SerializeMsg,
+    /// Parsing a client message from JSON
+    ParseMsg,
+    /// What the server does to relay a note: parse, check the signature, reserialize
+    Relay,
+}
+
+/// Inputs shared by the benchmarks
+struct Fixtures {
+    sender: Identity,
+    recipient: Identity,
+    note: Note,
+    msg: ClientMsg,
+    json: String,
+}
+
+impl Fixtures {
+    fn new() -> Result<Self> {
+        let sender = Identity::generate();
+        let recipient = Identity::generate();
+        let note = Note::encrypt_new(&sender, &recipient.to_public(), CONTENT.into())?;
+        let msg = ClientMsg::SendNote(note.clone());
+        let json = msg.to_string();
+        Ok(Self {
+            sender,
+            recipient,
+            note,
+            msg,
+            json,
+        })
+    }
+}
+
+/// Run one iteration of a hot path
+fn run_once(path: BenchPath, fixtures: &Fixtures) -> Result<()> {
+    match path {
+        BenchPath::EncryptNote => {
+            black_box(Note::encrypt_new(
+                &fixtures.sender,
+                &fixtures.recipient.to_public(),
+                CONTENT.into(),
+            )?);
+        }
+        BenchPath::OpenNote => {
+            black_box(fixtures.note.open(&fixtures.recipient)?);
+        }
+        BenchPath::SerializeMsg => {
+            black_box(fixtures.msg.to_string());
+        }
+        BenchPath::ParseMsg => {
+            black_box(ClientMsg::from_str(&fixtures.json)?);
+        }
+        BenchPath::Relay => {
+            let ClientMsg::SendNote(note) = ClientMsg::from_str(&fixtures.json)? else {
+                return Err(anyhow!("Expected a note"));
+            };
+            note.verify_signature()?;
+            black_box(ServerMsg::RecNote(note).to_ws_msg());
+        }
+    }
+    Ok(())
+}
+
+/// Time `iterations` runs of a hot path, after a warmup
+fn measure(path: BenchPath, fixtures: &Fixtures, iterations: u32) -> Result<Duration> {
+    for _ in 0..iterations.div_ceil(10) {
+        run_once(path, fixtures)?;
+    }
+    let start = Instant::now();
+    for _ in 0..iterations {
+        run_once(path, fixtures)?;
+    }
+    Ok(start.elapsed() / iterations.max(1))
+}
+
+/// Notes per second relayed between `users` concurrent users, each sending `iterations` notes to
+/// random others through the user connection map split into `shards`
+async fn relay_throughput(
+    note: &Note,
+    users: usize,
+    shards: usize,
+    iterations: u32,
+) -> Result<f64> {
+    let user_conns = Arc::new(UserConns::new(shards));
+    let pub_keys: Arc<Vec<String>> = Arc::new((0..users).map(|i| format!("user{i}")).collect());
+    for pub_key in pub_keys.iter() {
+        let relay = Relay::new(BENCH_RELAY_BUFFER, OverflowPolicy::DropOldest);
+        user_conns
+            .write(pub_key)
+            .await
+            .insert(pub_key.clone(), Arc::new(relay));
+    }
+
+    let start = Instant::now();
+    let mut senders = tokio::task::JoinSet::new();
+    for sender in 0..users {
+        let user_conns = Arc::clone(&user_conns);
+        let pub_keys = Arc::clone(&pub_keys);
+        let msg = ServerMsg::RecNote(note.clone());
+        senders.spawn(async move {
+            for i in 0..iterations {
+                if i % RECONNECT_EVERY == 0 {
+                    let pub_key = &pub_keys[sender];
+                    let mut user_conns_write = user_conns.write(pub_key).await;
+                    if let Some(relay) = user_conns_write.remove(pub_key) {
+                        user_conns_write.insert(pub_key.clone(), relay);
+                    }
+                }
+                let to = &pub_keys[rand::rng().random_range(0..pub_keys.len())];
+                if let Some(relay) = user_conns.read(to).await.get(to) {
+                    _ = relay.push(msg.clone());
+                }
+            }
+        });
+    }
+    while let Some(res) = senders.join_next().await {
+        res?;
+    }
+    let notes = users as f64 * f64::from(iterations);
+    Ok(notes / start.elapsed().as_secs_f64())
+}
+
+/// Entrance point to benchmarks from cli. With a profile path, only that path is run, so a
+/// profiler attached to the process sees nothing else.
+pub async fn run(iterations: u32, profile: Option<BenchPath>, users: usize) -> Result<()> {
+    let fixtures = Fixtures::new()?;
+    if let Some(path) = profile {
+        let start = Instant::now();
+        for _ in 0..iterations {
+            run_once(path, &fixtures)?;
+        }
+        println!("Ran {path:?} {iterations} times in {:?}", start.elapsed());
+        return Ok(());
+    }
+
+    for path in BenchPath::value_variants() {
+        let per_iter = measure(*path, &fixtures, iterations)?;
+        println!("{:<16} {per_iter:>12.2?}/iter", format!("{path:?}"));
+    }
+
+    // Contention on the user connection map, compared to a single lock
+    for shards in [1, DEFAULT_SHARDS] {
+        let rate = relay_throughput(&fixtures.note, users, shards, iterations).await?;
+        println!("Relay between {users} users, {shards} shards: {rate:>12.0} notes/s");
+    }
+    Ok(())
+}
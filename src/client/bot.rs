@@ -0,0 +1,178 @@
+use age::x25519::Identity;
+use anyhow::Result;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use crate::{ChatClient, Outgoing, Shutdown};
+
+/// Window each sender's replies are counted over
+const REPLY_WINDOW: Duration = Duration::from_secs(60);
+
+/// Who the echo bot replies to, and how often
+pub struct ReplyPolicy {
+    /// Pubkeys to reply to, anyone if None
+    allowed_senders: Option<HashSet<String>>,
+    /// Max replies to each sender per minute, 0 for no limit
+    replies_per_minute: u32,
+    /// Start of each sender's current window and the replies sent in it
+    windows: HashMap<String, (Instant, u32)>,
+}
+
+impl ReplyPolicy {
+    pub fn new(allowed_senders: Option<Vec<String>>, replies_per_minute: u32) -> Self {
+        Self {
+            allowed_senders: allowed_senders.map(|senders| senders.into_iter().collect()),
+            replies_per_minute,
+            windows: HashMap::new(),
+        }
+    }
+
+    /// Why we shouldn't reply to a note from a sender, counting the reply if we should
+    fn refuse(&mut self, sender: &str) -> Option<&'static str> {
+        if let Some(allowed) = &self.allowed_senders {
+            if !allowed.contains(sender) {
+                return Some("they're not an allowed sender");
+            }
+        }
+        if self.replies_per_minute == 0 {
+            return None;
+        }
+        let now = Instant::now();
+        self.windows
+            .retain(|_, (start, _)| now.duration_since(*start) < REPLY_WINDOW);
+        let (_, replies) = self.windows.entry(sender.to_string()).or_insert((now, 0));
+        if *replies >= self.replies_per_minute {
+            return Some("they're over the reply limit");
+        }
+        *replies += 1;
+        None
+    }
+}
+
+/// Reply to each note we receive with its content echoed back until interrupted. Only uses the
+/// library's public API, so it doubles as a template for writing bots.
+pub async fn echo(
+    address: &str,
+    key: Identity,
+    auth_token: Option<String>,
+    policy: ReplyPolicy,
+) -> Result<Shutdown> {
+    let mut client = match ChatClient::connect(address).await {
+        Ok(client) => client,
+        Err(e) => return e.downcast(),
+    };
+    let res = tokio::select! {
+        res = echo_notes(&mut client, key, auth_token, policy) => res,
+        _ = tokio::signal::ctrl_c() => Ok(Shutdown::Quit),
+    };
+    client.close().await?;
+    res.or_else(|e| e.downcast())
+}
+
+async fn echo_notes(
+    client: &mut ChatClient,
+    key: Identity,
+    auth_token: Option<String>,
+    mut policy: ReplyPolicy,
+) -> Result<Shutdown> {
+    let pub_key = key.to_public().to_string();
+    client.authenticate(key, auth_token).await?;
+    println!("Echoing notes sent to {pub_key}");
+    loop {
+        let note = client.next_note().await?;
+        // Our own notes sent from another device would echo back and forth forever
+        if note.from == pub_key {
+            continue;
+        }
+        if let Some(reason) = policy.refuse(&note.from) {
+            eprintln!("Not echoing a note from {}, {reason}", note.from);
+            continue;
+        }
+        let reply = Outgoing {
+            recipient: note.from.clone(),
+            content: note.content,
+            sealed_sender: false,
+        };
+        match client.send(reply).await {
+            Ok(_) => println!("Echoed a note from {}", note.from),
+            // One refused reply shouldn't stop the bot
+            Err(e) => match e.downcast::<Shutdown>()? {
+                Shutdown::NotSent(e) => eprintln!("Couldn't echo a note from {}: {e}", note.from),
+                shutdown => return Ok(shutdown),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server;
+
+    /// Start the bot as a new key on an in-memory server, returning the server's address and the
+    /// bot's pubkey
+    async fn start_bot(policy: ReplyPolicy) -> Result<(String, String)> {
+        let server = server::embedded("127.0.0.1:0").await?;
+        let address = server.addr().to_string();
+        let bot = Identity::generate();
+        let bot_pub_key = bot.to_public().to_string();
+        let bot_address = address.clone();
+        tokio::spawn(async move { echo(&bot_address, bot, None, policy).await });
+        Ok((address, bot_pub_key))
+    }
+
+    async fn send(client: &mut ChatClient, recipient: &str, content: &str) -> Result<()> {
+        let outgoing = Outgoing {
+            recipient: recipient.to_string(),
+            content: content.to_string(),
+            sealed_sender: false,
+        };
+        client.send(outgoing).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn echoes_notes_back() -> Result<()> {
+        let (address, bot_pub_key) = start_bot(ReplyPolicy::new(None, 0)).await?;
+        let mut client = ChatClient::connect(&address).await?;
+        client.authenticate(Identity::generate(), None).await?;
+
+        send(&mut client, &bot_pub_key, "hello bot").await?;
+
+        let reply = client.next_note().await?;
+        assert_eq!(reply.from, bot_pub_key);
+        assert_eq!(reply.content, "hello bot");
+        client.close().await
+    }
+
+    #[tokio::test]
+    async fn only_echoes_allowed_senders() -> Result<()> {
+        let allowed = Identity::generate();
+        let policy = ReplyPolicy::new(Some(vec![allowed.to_public().to_string()]), 0);
+        let (address, bot_pub_key) = start_bot(policy).await?;
+        let mut stranger = ChatClient::connect(&address).await?;
+        stranger.authenticate(Identity::generate(), None).await?;
+        let mut client = ChatClient::connect(&address).await?;
+        client.authenticate(allowed, None).await?;
+
+        send(&mut stranger, &bot_pub_key, "from a stranger").await?;
+        send(&mut client, &bot_pub_key, "from a friend").await?;
+
+        // The bot handles notes in order, so the stranger's would have been echoed first
+        assert_eq!(client.next_note().await?.content, "from a friend");
+        let stranger_reply =
+            tokio::time::timeout(Duration::from_millis(200), stranger.next_note()).await;
+        assert!(stranger_reply.is_err(), "Echoed a note from a stranger");
+        stranger.close().await?;
+        client.close().await
+    }
+
+    #[test]
+    fn limits_replies_per_sender() {
+        let mut policy = ReplyPolicy::new(None, 2);
+        assert_eq!(policy.refuse("a"), None);
+        assert_eq!(policy.refuse("a"), None);
+        assert!(policy.refuse("a").is_some());
+        assert_eq!(policy.refuse("b"), None);
+    }
+}
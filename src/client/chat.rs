@@ -0,0 +1,315 @@
+use age::x25519::{Identity, Recipient};
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, Utc};
+use std::collections::VecDeque;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+use super::comms::{Comms, ConnectionEvent, ConnectionSettings, Dialer, Received};
+use super::sync::ControlNote;
+use super::tls::{Tls, Trust};
+use super::Shutdown;
+use crate::common::{
+    signing_key, Auth, AuthChallenge, ClientMsg, Hello, Note, ServerMsg, SyncSince,
+    CHANNEL_BUFFER_SIZE, PROTOCOL_VERSION, SYNC_PROTOCOL_VERSION,
+};
+
+/// How often to ping the server, so a dead connection is noticed and reconnected
+const PING_INTERVAL: Duration = Duration::from_secs(15);
+/// How long the server has to answer a ping
+const PING_TIMEOUT: Duration = Duration::from_secs(10);
+/// Ids of the latest notes received, so ones synced again after a reconnect aren't seen twice
+const RECEIVED_IDS: usize = 1000;
+
+/// A note to send and who to
+pub struct Outgoing {
+    /// Pubkey of the recipient
+    pub recipient: String,
+    pub content: String,
+    /// Hide our pubkey from the server inside the encrypted payload
+    pub sealed_sender: bool,
+}
+
+/// A note sent to us, decrypted
+#[derive(Clone, Debug)]
+pub struct ReceivedNote {
+    pub id: String,
+    /// Pubkey of the sender, taken from inside the payload for sealed sender notes
+    pub from: String,
+    pub timestamp: DateTime<Utc>,
+    pub content: String,
+}
+
+/// Something that happened on the connection
+#[derive(Clone, Debug)]
+pub enum ChatEvent {
+    /// A note sent to us
+    Note(ReceivedNote),
+    /// A note sent to us that we couldn't decrypt
+    Unreadable {
+        id: String,
+        from: String,
+        error: String,
+    },
+    /// The connection dropped, we're reconnecting and will authenticate again once we are
+    Reconnecting { reason: String },
+    /// The server reported an error
+    ServerError(String),
+}
+
+/// A connection to a server for embedding age-chat in other tools. Authenticates as a key, sends
+/// notes and receives the ones sent to it, reconnecting and authenticating again by itself if the
+/// connection drops. Errors that end the connection are a [`Shutdown`] saying why.
+pub struct ChatClient {
+    comms: Comms,
+    shutdown_tx: broadcast::Sender<Shutdown>,
+    shutdown_rx: broadcast::Receiver<Shutdown>,
+    /// Key we authenticate as, once we have
+    key: Option<Identity>,
+    auth_token: Option<String>,
+    server_protocol_version: u32,
+    reconnected: bool,
+    last_note_id: Option<String>,
+    received_ids: VecDeque<String>,
+    /// Events that happened while waiting on something else
+    events: VecDeque<ChatEvent>,
+}
+
+impl ChatClient {
+    /// Connect to a server at a <host>:<port> address, a ws:// or wss:// url, or a domain whose
+    /// server is found in DNS
+    pub async fn connect(address: &str) -> Result<Self> {
+        let (shutdown_tx, shutdown_rx) = broadcast::channel::<Shutdown>(CHANNEL_BUFFER_SIZE);
+        let comms = Comms::run(
+            super::server_url(address).await?,
+            Dialer {
+                tls: Tls::new(Trust::Roots)?,
+                proxy: None,
+                daemon: None,
+            },
+            ConnectionSettings {
+                compression: true,
+                reconnect: true,
+                ping_interval: Some(PING_INTERVAL),
+                ping_timeout: PING_TIMEOUT,
+            },
+            None,
+            shutdown_tx.clone(),
+            shutdown_rx.resubscribe(),
+        )
+        .await
+        .map_err(|e| Shutdown::ConnectionFailed(format!("{e:#}")))?;
+        Ok(Self {
+            comms,
+            shutdown_tx,
+            shutdown_rx,
+            key: None,
+            auth_token: None,
+            server_protocol_version: 0,
+            reconnected: false,
+            last_note_id: None,
+            received_ids: VecDeque::new(),
+            events: VecDeque::new(),
+        })
+    }
+
+    /// Authenticate as a key and wait for the server to grant it, then fetch the notes queued for
+    /// us while we were away
+    pub async fn authenticate(&mut self, key: Identity, auth_token: Option<String>) -> Result<()> {
+        self.key = Some(key);
+        self.auth_token = auth_token;
+        self.send_auth()?;
+        loop {
+            match self.recv().await? {
+                Some(ServerMsg::AuthGranted(_)) => return Ok(()),
+                Some(msg) => self.queue(msg),
+                None => {}
+            }
+        }
+    }
+
+    /// Encrypt and send a note, waiting for the server to accept it. Returns the note as the
+    /// server stored it.
+    pub async fn send(&mut self, outgoing: Outgoing) -> Result<Note> {
+        let Some(key) = &self.key else {
+            bail!("Can't send before authenticating");
+        };
+        let recipient = Recipient::from_str(&outgoing.recipient).map_err(|e| anyhow!(e))?;
+        let note = if outgoing.sealed_sender {
+            Note::encrypt_new_sealed(key, &recipient, outgoing.content)?
+        } else {
+            Note::encrypt_new(key, &recipient, outgoing.content)?
+        };
+        let id = note.id.clone();
+        self.comms.try_send_msg(ClientMsg::SendNote(note))?;
+        // The server echoes the note back once it's accepted it
+        loop {
+            match self.recv().await? {
+                Some(ServerMsg::RecNote(echo)) if echo.id == id => {
+                    self.remember(&echo.id);
+                    return Ok(echo);
+                }
+                Some(ServerMsg::Error(e)) => bail!(Shutdown::NotSent(e.to_string())),
+                Some(msg) => self.queue(msg),
+                None => {}
+            }
+        }
+    }
+
+    /// Wait for the next thing to happen on the connection
+    pub async fn next_event(&mut self) -> Result<ChatEvent> {
+        loop {
+            if let Some(event) = self.events.pop_front() {
+                return Ok(event);
+            }
+            if let Some(msg) = self.recv().await? {
+                self.queue(msg);
+            }
+        }
+    }
+
+    /// Wait for the next note sent to us that we can read
+    pub async fn next_note(&mut self) -> Result<ReceivedNote> {
+        loop {
+            if let ChatEvent::Note(note) = self.next_event().await? {
+                return Ok(note);
+            }
+        }
+    }
+
+    /// Close the connection
+    pub async fn close(self) -> Result<()> {
+        _ = self.shutdown_tx.send(Shutdown::Quit);
+        self.comms.wait_shutdown().await
+    }
+
+    fn send_auth(&mut self) -> Result<()> {
+        let Some(key) = &self.key else {
+            return Ok(());
+        };
+        let signing_key = hex::encode(signing_key(key).verifying_key().as_bytes());
+        let auth = Auth::new(
+            key.to_public().to_string(),
+            signing_key,
+            self.auth_token.clone(),
+        );
+        self.comms.try_send_msg(ClientMsg::Hello(Hello {
+            protocol_version: PROTOCOL_VERSION,
+            presence_only: false,
+            compression: false,
+        }))?;
+        self.comms.try_send_msg(ClientMsg::AuthReq(auth))
+    }
+
+    /// Wait for the next message from the server, dealing with reconnects and the auth handshake
+    /// along the way. Returns None if there's nothing for the caller to look at.
+    async fn recv(&mut self) -> Result<Option<ServerMsg>> {
+        let received = match self.comms.recv().await {
+            Ok(received) => received,
+            // The connection ended for good, comms says why
+            Err(_) => bail!(self
+                .shutdown_rx
+                .try_recv()
+                .unwrap_or(Shutdown::ConnectionFailed(
+                    "Connection to server closed".into()
+                ))),
+        };
+        let msg = match received {
+            Received::Msg(msg) => msg,
+            Received::Event(ConnectionEvent::Reconnected) => {
+                self.reconnected = true;
+                self.send_auth()?;
+                return Ok(None);
+            }
+            Received::Event(ConnectionEvent::Reconnecting { reason, .. }) => {
+                self.events.push_back(ChatEvent::Reconnecting { reason });
+                return Ok(None);
+            }
+            // We only trust the system roots, so certificates aren't pinned, and authentication and
+            // failures show up as messages and errors
+            Received::Event(
+                ConnectionEvent::CertificateChanged(_)
+                | ConnectionEvent::Connecting { .. }
+                | ConnectionEvent::Authenticated
+                | ConnectionEvent::Failed { .. },
+            ) => return Ok(None),
+        };
+        match msg {
+            ServerMsg::HelloAck(ack) => self.server_protocol_version = ack.protocol_version,
+            ServerMsg::AuthSecret(auth) => {
+                let Some(key) = &self.key else {
+                    return Ok(None);
+                };
+                let challenge = AuthChallenge::decrypt(key, &auth.ciphertext)?;
+                self.comms.try_send_msg(ClientMsg::AuthPlaintext(Auth {
+                    plaintext: challenge.nonce,
+                    ..auth
+                }))?;
+            }
+            ServerMsg::AuthGranted(_) => {
+                // Get the notes that were in flight when our last connection dropped
+                if self.reconnected && self.server_protocol_version >= SYNC_PROTOCOL_VERSION {
+                    self.comms.try_send_msg(ClientMsg::SyncSince(SyncSince {
+                        after: self.last_note_id.clone(),
+                    }))?;
+                }
+                self.reconnected = false;
+                self.comms.try_send_msg(ClientMsg::FetchMailbox)?;
+                return Ok(Some(msg));
+            }
+            ServerMsg::AuthDenied(_) => bail!(Shutdown::AuthDenied),
+            msg => return Ok(Some(msg)),
+        }
+        Ok(None)
+    }
+
+    /// Keep a message nobody was waiting on as an event for later
+    fn queue(&mut self, msg: ServerMsg) {
+        match msg {
+            ServerMsg::RecNote(note) => {
+                if self.received_ids.contains(&note.id) {
+                    return;
+                }
+                self.remember(&note.id);
+                if let Some(event) = self.open(note) {
+                    self.events.push_back(event);
+                }
+            }
+            ServerMsg::Error(e) => self.events.push_back(ChatEvent::ServerError(e.to_string())),
+            _ => {}
+        }
+    }
+
+    fn remember(&mut self, id: &str) {
+        self.last_note_id = Some(id.to_string());
+        self.received_ids.push_back(id.to_string());
+        if self.received_ids.len() > RECEIVED_IDS {
+            self.received_ids.pop_front();
+        }
+    }
+
+    /// Decrypt a note into an event, skipping read positions synced from our other devices
+    fn open(&self, note: Note) -> Option<ChatEvent> {
+        let key = self.key.as_ref()?;
+        let opened = match note.open(key) {
+            Ok(opened) => opened,
+            Err(e) => {
+                return Some(ChatEvent::Unreadable {
+                    id: note.id,
+                    from: note.from,
+                    error: e.to_string(),
+                })
+            }
+        };
+        if ControlNote::parse(&opened.content).is_some() {
+            return None;
+        }
+        Some(ChatEvent::Note(ReceivedNote {
+            id: note.id,
+            from: opened.from,
+            timestamp: note.timestamp,
+            content: opened.content,
+        }))
+    }
+}
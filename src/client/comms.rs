@@ -1,69 +1,217 @@
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use futures_util::{SinkExt, StreamExt};
+use rand::Rng;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::time::Duration;
 use tokio::{
     io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
     sync::{
         broadcast,
         mpsc::{self, Receiver, Sender},
+        watch,
     },
     task::JoinHandle,
+    time::{self, Instant},
 };
-use tokio_tungstenite::{connect_async, tungstenite::Message, WebSocketStream};
-use tracing::{error, info};
+use tokio_tungstenite::{
+    client_async_tls_with_config,
+    tungstenite::{
+        client::IntoClientRequest, handshake::client::Request, protocol::frame::coding::CloseCode,
+        Message,
+    },
+    MaybeTlsStream, WebSocketStream,
+};
+use tracing::{error, info, warn};
+
+use super::proxy::Proxy;
+use super::recording::{Direction, Recorder};
+use super::tls::{CertChange, Tls};
+use super::Shutdown;
+use crate::common::{ClientMsg, Maintenance, ServerMsg, CHANNEL_BUFFER_SIZE};
+use crate::compression::{self, MIN_COMPRESS_BYTES};
+
+/// Min time between sending coalesced messages with the same key, e.g. typing indicators
+const COALESCE_INTERVAL: Duration = Duration::from_secs(2);
+/// Wait before the first reconnection attempt, doubling with each one after
+const RECONNECT_BASE_DELAY: Duration = Duration::from_millis(500);
+/// Longest wait between reconnection attempts
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(30);
+/// Url the websocket handshake with a daemon asks for, its socket is the only thing it serves
+const DAEMON_URL: &str = "ws://localhost/";
 
-use crate::common::{ClientMsg, ServerMsg, CHANNEL_BUFFER_SIZE};
+/// Stream a websocket connection runs over, TCP to the server or a unix socket to a daemon
+trait Transport: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Transport for T {}
+
+type Socket = WebSocketStream<MaybeTlsStream<Box<dyn Transport>>>;
 
 /// Manages communication with the server
 pub struct Comms {
     incoming_rx: Receiver<ServerMsg>,
     outgoing_tx: Sender<ClientMsg>,
+    events_rx: Receiver<ConnectionEvent>,
+    /// Round trip time of the latest ping, None while disconnected
+    latency_rx: watch::Receiver<Option<Duration>>,
     task_handle: JoinHandle<()>,
 }
 
+/// How the connection to the server behaves
+#[derive(Clone, Copy)]
+pub struct ConnectionSettings {
+    /// Offer the server to compress large messages
+    pub compression: bool,
+    /// Retry a dropped connection with exponential backoff rather than shutting down, unless we
+    /// were kicked
+    pub reconnect: bool,
+    /// How often to ping the server to measure latency and notice a dead connection, if at all
+    pub ping_interval: Option<Duration>,
+    /// How long the server has to answer a ping before the connection counts as dead
+    pub ping_timeout: Duration,
+}
+
+/// How to open connections to the server
+#[derive(Clone)]
+pub struct Dialer {
+    pub tls: Tls,
+    /// Proxy to connect through, if any
+    pub proxy: Option<Proxy>,
+    /// Unix socket of a daemon to attach to, rather than connecting to the server ourselves
+    pub daemon: Option<PathBuf>,
+}
+
+/// Changes to the connection's state, for the TUI to show
+#[derive(Clone, Debug)]
+pub enum ConnectionEvent {
+    /// The connection dropped, the next attempt to reconnect is after the delay
+    Reconnecting {
+        attempt: u32,
+        delay: Duration,
+        reason: String,
+    },
+    /// The delay is up and we're dialing the server again
+    Connecting { attempt: u32 },
+    /// Connected again. The new connection isn't authenticated yet.
+    Reconnected,
+    /// The server granted our authentication on this connection
+    Authenticated,
+    /// The connection ended for good, with why. Shutdown is broadcast right after.
+    Failed { reason: String },
+    /// The server presented a different key than the one we trusted on first use
+    CertificateChanged(CertChange),
+}
+
+/// Where the communication task reports on the connection, for the frontend to show
+struct Reports {
+    events_tx: Sender<ConnectionEvent>,
+    /// Feeds [`Comms::latency`]
+    latency_tx: watch::Sender<Option<Duration>>,
+    /// Downtime the server announced, to hold off reconnecting through it
+    maintenance_tx: watch::Sender<Option<Maintenance>>,
+}
+
+/// Whatever came from the connection next
+pub enum Received {
+    Msg(ServerMsg),
+    Event(ConnectionEvent),
+}
+
 impl Comms {
     /// Connect to the server and start the background server communication task. This will allow
     /// us to communicate with the server through channels. Will not finish awaiting until the server
     /// is connected.
     pub async fn run(
         addr: String,
-        shutdown_tx: broadcast::Sender<()>,
-        shutdown_rx: broadcast::Receiver<()>,
+        dialer: Dialer,
+        settings: ConnectionSettings,
+        recorder: Option<Recorder>,
+        shutdown_tx: broadcast::Sender<Shutdown>,
+        mut shutdown_rx: broadcast::Receiver<Shutdown>,
     ) -> Result<Self> {
         // Channel to send messages to server
         let (outgoing_tx, mut outgoing_rx) = mpsc::channel::<ClientMsg>(CHANNEL_BUFFER_SIZE);
         // Channel to receive messages from server
         let (incoming_tx, incoming_rx) = mpsc::channel::<ServerMsg>(CHANNEL_BUFFER_SIZE);
+        // Channel to receive connection state changes
+        let (events_tx, events_rx) = mpsc::channel::<ConnectionEvent>(CHANNEL_BUFFER_SIZE);
+        // Latest round trip time to the server
+        let (latency_tx, latency_rx) = watch::channel(None);
 
         // Open connection to server
-        let (mut socket, _) = connect_async(&addr)
-            .await
-            .context(format!("Cannot connect to {addr}"))?;
+        let mut socket = match dialer.connect(&addr).await {
+            Ok(socket) => socket,
+            Err(e) => match dialer.tls.take_change() {
+                Some(change) => bail!(change),
+                None => return Err(e).context(format!("Cannot connect to {addr}")),
+            },
+        };
         info!("🔗 Connected to server: {addr}");
 
         // Start the background server communication task
+        let reports = Reports {
+            events_tx,
+            latency_tx,
+            maintenance_tx: watch::Sender::new(None),
+        };
         let task_handle = tokio::spawn(async move {
-            // Talk to the server over the socket
-            let res = talk_server_socket(
-                &mut outgoing_rx,
-                incoming_tx,
-                shutdown_tx,
-                shutdown_rx,
-                &mut socket,
-            )
-            .await;
-            if let Err(e) = res {
-                error!("Error talking to the server {addr}: {e}");
-            }
+            loop {
+                // Talk to the server over the socket
+                let res = talk_server_socket(
+                    &mut outgoing_rx,
+                    &incoming_tx,
+                    &mut shutdown_rx,
+                    &mut socket,
+                    &settings,
+                    recorder.as_ref(),
+                    &reports,
+                )
+                .await;
+                _ = reports.latency_tx.send(None);
 
-            // Close connection to server. It's fine if it errors out.
-            _ = socket.close(None).await;
-            info!("⛓️‍💥 Disconnected from server: {addr}");
+                // Close connection to server. It's fine if it errors out.
+                _ = socket.close(None).await;
+                info!("⛓️‍💥 Disconnected from server: {addr}");
+
+                let shutdown = match res {
+                    Ok(None) => return,
+                    Ok(Some(shutdown)) => shutdown,
+                    Err(e) => {
+                        error!("Error talking to the server {addr}: {e}");
+                        Shutdown::ConnectionFailed(e.to_string())
+                    }
+                };
+                if !settings.reconnect || matches!(shutdown, Shutdown::Kicked(_)) {
+                    _ = reports.events_tx.try_send(ConnectionEvent::Failed {
+                        reason: shutdown.to_string(),
+                    });
+                    _ = shutdown_tx.send(shutdown);
+                    return;
+                }
+                let maintenance = reports.maintenance_tx.borrow().clone();
+                let reconnect_res = reconnect_with_backoff(
+                    &addr,
+                    &dialer,
+                    &reports.events_tx,
+                    &mut shutdown_rx,
+                    shutdown,
+                    maintenance.as_ref(),
+                )
+                .await;
+                match reconnect_res {
+                    Some(new_socket) => socket = new_socket,
+                    None => return,
+                }
+            }
         });
 
         Ok(Comms {
             incoming_rx,
             outgoing_tx,
+            events_rx,
+            latency_rx,
             task_handle,
         })
     }
@@ -78,6 +226,34 @@ impl Comms {
         Ok(self.incoming_rx.try_recv()?)
     }
 
+    /// Receive a change to the connection's state without blocking
+    pub fn try_recv_event(&mut self) -> Option<ConnectionEvent> {
+        self.events_rx.try_recv().ok()
+    }
+
+    /// Round trip time of the latest ping to the server, if we've measured it on this connection
+    pub fn latency(&self) -> Option<Duration> {
+        *self.latency_rx.borrow()
+    }
+
+    /// Wait for a message from the server
+    pub async fn recv_msg(&mut self) -> Result<ServerMsg> {
+        self.incoming_rx
+            .recv()
+            .await
+            .ok_or(anyhow!("Connection to server closed"))
+    }
+
+    /// Wait for a message from the server or a change to the connection's state
+    pub async fn recv(&mut self) -> Result<Received> {
+        tokio::select! {
+            msg_opt = self.incoming_rx.recv() => {
+                msg_opt.map(Received::Msg).ok_or(anyhow!("Connection to server closed"))
+            }
+            Some(event) = self.events_rx.recv() => Ok(Received::Event(event)),
+        }
+    }
+
     /// Wait for the communication task to end
     pub async fn wait_shutdown(self) -> Result<()> {
         self.task_handle.await?;
@@ -85,54 +261,364 @@ impl Comms {
     }
 }
 
+/// Reconnect to the server, waiting longer after each failed attempt, and until announced
+/// maintenance is over if we're in it. Returns None if we're told to shut down in the meantime.
+async fn reconnect_with_backoff(
+    addr: &str,
+    dialer: &Dialer,
+    events_tx: &Sender<ConnectionEvent>,
+    shutdown_rx: &mut broadcast::Receiver<Shutdown>,
+    disconnected: Shutdown,
+    maintenance: Option<&Maintenance>,
+) -> Option<Socket> {
+    let mut reason = disconnected.to_string();
+    for attempt in 1.. {
+        let delay = match maintenance.and_then(maintenance_delay) {
+            Some(delay) => {
+                reason = "Server is down for maintenance".into();
+                delay
+            }
+            None => reconnect_delay(attempt),
+        };
+        warn!(
+            "🔁 Lost connection to server ({reason}), reconnecting in {}ms, attempt {attempt}",
+            delay.as_millis()
+        );
+        _ = events_tx.try_send(ConnectionEvent::Reconnecting {
+            attempt,
+            delay,
+            reason: reason.clone(),
+        });
+        let connect = async {
+            time::sleep(delay).await;
+            _ = events_tx.try_send(ConnectionEvent::Connecting { attempt });
+            dialer.connect(addr).await
+        };
+        let connect_res = tokio::select! {
+            connect_res = connect => connect_res,
+            _ = shutdown_rx.recv() => return None,
+        };
+        match connect_res {
+            Ok(socket) => {
+                info!("🔗 Reconnected to server: {addr}");
+                _ = events_tx.try_send(ConnectionEvent::Reconnected);
+                return Some(socket);
+            }
+            Err(e) => match dialer.tls.take_change() {
+                Some(change) => {
+                    reason = "Server certificate changed".into();
+                    _ = events_tx.try_send(ConnectionEvent::CertificateChanged(change));
+                }
+                None => reason = format!("Cannot connect to {addr}: {e}"),
+            },
+        }
+    }
+    None
+}
+
+impl Dialer {
+    /// Open a websocket connection to the server, over TLS for wss:// urls, or to the daemon
+    async fn connect(&self, addr: &str) -> Result<Socket> {
+        let (request, stream) = match &self.daemon {
+            Some(path) => (
+                DAEMON_URL.into_client_request()?,
+                connect_daemon(path).await?,
+            ),
+            None => {
+                let request = addr.into_client_request()?;
+                let stream = self.connect_tcp(&request).await?;
+                (request, stream)
+            }
+        };
+        let connector = Some(self.tls.connector());
+        let (socket, _) = client_async_tls_with_config(request, stream, None, connector).await?;
+        Ok(socket)
+    }
+
+    /// Open a TCP connection to the server's host, through the proxy if there is one
+    async fn connect_tcp(&self, request: &Request) -> Result<Box<dyn Transport>> {
+        let uri = request.uri();
+        let host = uri
+            .host()
+            .context(format!("No host in {uri}"))?
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_string();
+        let default_port = if uri.scheme_str() == Some("wss") {
+            443
+        } else {
+            80
+        };
+        let port = uri.port_u16().unwrap_or(default_port);
+        match &self.proxy {
+            Some(proxy) => Ok(Box::new(proxy.connect(&host, port).await?)),
+            None => Ok(Box::new(TcpStream::connect((host.as_str(), port)).await?)),
+        }
+    }
+}
+
+/// Open a connection to a daemon's socket
+#[cfg(unix)]
+async fn connect_daemon(path: &Path) -> Result<Box<dyn Transport>> {
+    let stream = tokio::net::UnixStream::connect(path)
+        .await
+        .context(format!(
+            "Cannot connect to daemon socket {}",
+            path.display()
+        ))?;
+    Ok(Box::new(stream))
+}
+
+#[cfg(not(unix))]
+async fn connect_daemon(_path: &Path) -> Result<Box<dyn Transport>> {
+    bail!("Attaching to a daemon is only supported on unix")
+}
+
+/// How long to wait before a reconnection attempt: exponential backoff with jitter, so clients
+/// dropped together don't all come back at once
+fn reconnect_delay(attempt: u32) -> Duration {
+    let backoff = RECONNECT_BASE_DELAY
+        .saturating_mul(2u32.saturating_pow(attempt - 1))
+        .min(RECONNECT_MAX_DELAY);
+    backoff.mul_f64(rand::rng().random_range(0.5..=1.0))
+}
+
+/// How long to wait for maintenance we're in the middle of to be over, with jitter so clients
+/// don't all come back the moment it ends. None outside the window.
+fn maintenance_delay(maintenance: &Maintenance) -> Option<Duration> {
+    let now = chrono::Utc::now();
+    if now < maintenance.starts_at {
+        return None;
+    }
+    let remaining = (maintenance.ends_at() - now).to_std().ok()?;
+    Some(remaining + RECONNECT_MAX_DELAY.mul_f64(rand::rng().random_range(0.0..=1.0)))
+}
+
 /// Talk to the server over the websocket connection, simultaneously sending messages from the
-/// outgoing channel and putting received messages into the incoming channel.
+/// outgoing channel and putting received messages into the incoming channel. Offers compression
+/// in the hello if enabled, deflating large messages once the server agrees. Pings the server to
+/// measure latency, giving up on the connection if a ping goes unanswered.
+///
+/// Returns why the server closed the connection, or None if we were told to shut down.
 async fn talk_server_socket<T>(
     outgoing_rx: &mut Receiver<ClientMsg>,
-    incoming_tx: Sender<ServerMsg>,
-    shutdown_tx: broadcast::Sender<()>,
-    mut shutdown_rx: broadcast::Receiver<()>,
+    incoming_tx: &Sender<ServerMsg>,
+    shutdown_rx: &mut broadcast::Receiver<Shutdown>,
     socket: &mut WebSocketStream<T>,
-) -> Result<()>
+    settings: &ConnectionSettings,
+    recorder: Option<&Recorder>,
+    reports: &Reports,
+) -> Result<Option<Shutdown>>
 where
     T: AsyncRead + AsyncWrite + Unpin,
 {
     let (mut write, mut read) = socket.split();
+    let mut coalescer = Coalescer::default();
+    let offer_compression = settings.compression;
+    let mut compress = false;
+    // The first tick is immediate, measuring latency right away
+    let mut keepalive = settings.ping_interval.map(time::interval);
+    // Payload of the ping waiting for its pong and when it was sent, tagged so a late pong to an
+    // earlier one isn't mistaken for it
+    let mut ping_seq: u64 = 0;
+    let mut awaiting_pong: Option<(u64, Instant)> = None;
 
     loop {
         tokio::select! {
-            // Send outgoing messages from channel to server
+            // Send outgoing messages from channel to server, holding back rapid-fire ones
             client_msg_opt = outgoing_rx.recv() => {
-                let msg = client_msg_opt.ok_or(anyhow!("Outgoing message channel closed"))?;
-                info!("📤 Sending message: {msg:?}");
-                let ws_msg = msg.to_ws_msg();
-                write.send(ws_msg).await.context("Error sending WS message to the server")?
+                let mut msg = client_msg_opt.ok_or(anyhow!("Outgoing message channel closed"))?;
+                if let ClientMsg::Hello(hello) = &mut msg {
+                    hello.compression = offer_compression;
+                }
+                if let Some(msg) = coalescer.admit(msg) {
+                    info!("📤 Sending message: {msg:?}");
+                    let ws_msg = msg.to_ws_msg();
+                    record(recorder, Direction::Sent, &ws_msg);
+                    write.send(compress_ws_msg(ws_msg, compress)?).await.context("Error sending WS message to the server")?
+                }
+            }
+
+            // Send held back messages once their interval is up
+            _ = sleep_until(coalescer.next_due()) => {
+                for msg in coalescer.take_due() {
+                    info!("📤 Sending coalesced message: {msg:?}");
+                    let ws_msg = msg.to_ws_msg();
+                    record(recorder, Direction::Sent, &ws_msg);
+                    write.send(compress_ws_msg(ws_msg, compress)?).await.context("Error sending WS message to the server")?
+                }
             }
 
             // Receive incoming messages from server to channel
             ws_msg_res_opt = read.next() => {
-                let ws_msg = ws_msg_res_opt.ok_or(anyhow!("Connection to server closed"))??;
+                let mut ws_msg = ws_msg_res_opt.ok_or(anyhow!("Connection to server closed"))??;
+                // Compressed messages are recorded inflated, so recordings replay the same either way
+                if let Message::Binary(data) = &ws_msg {
+                    if offer_compression {
+                        ws_msg = Message::text(compression::inflate(data).context("Error inflating WS message")?);
+                    }
+                }
+                record(recorder, Direction::Received, &ws_msg);
                 match ws_msg {
                     Message::Text(payload) => {
                         let msg = ServerMsg::from_str(&payload).context("Error deserializing ServerMsg")?;
                         info!("📥 Received message: {msg:?}");
+                        match &msg {
+                            ServerMsg::HelloAck(ack) => compress = offer_compression && ack.compression,
+                            ServerMsg::AuthGranted(_) => {
+                                _ = reports.events_tx.try_send(ConnectionEvent::Authenticated);
+                            }
+                            ServerMsg::Maintenance(maintenance) => {
+                                reports.maintenance_tx.send_replace(Some(maintenance.clone()));
+                            }
+                            ServerMsg::MaintenanceCancelled => {
+                                reports.maintenance_tx.send_replace(None);
+                            }
+                            _ => {}
+                        }
                         incoming_tx.send(msg).await.context("Incoming message channel is closed")?;
                     }
-                    Message::Close(_frame) => {
-                        info!("👋 Received WS close message from server, disconnecting");
-                        shutdown_tx.send(())?;
-                        return Ok(());
+                    Message::Close(frame) => {
+                        let reason = frame.as_ref().map(|frame| frame.reason.to_string()).unwrap_or_default();
+                        info!("👋 Received WS close message from server, disconnecting: {reason}");
+                        // The server closes with a policy violation when an operator kicks us
+                        let shutdown = match frame.map(|frame| frame.code) {
+                            Some(CloseCode::Policy) => Shutdown::Kicked(reason),
+                            _ => Shutdown::ServerClosed(reason),
+                        };
+                        return Ok(Some(shutdown));
                     },
+                    Message::Pong(payload) => {
+                        if let Some((seq, sent_at)) = awaiting_pong {
+                            if *payload == seq.to_be_bytes() {
+                                awaiting_pong = None;
+                                _ = reports.latency_tx.send(Some(sent_at.elapsed()));
+                            }
+                        }
+                    }
+                    // tokio_tungstenite automatically answers pings
                     _ => {},
                 }
             }
 
+            // Ping the server, unless the last ping is still waiting for its pong
+            _ = tick(&mut keepalive) => {
+                if awaiting_pong.is_none() {
+                    ping_seq += 1;
+                    let ws_msg = Message::Ping(ping_seq.to_be_bytes().to_vec().into());
+                    record(recorder, Direction::Sent, &ws_msg);
+                    write.send(ws_msg).await.context("Error sending WS ping to the server")?;
+                    awaiting_pong = Some((ping_seq, Instant::now()));
+                }
+            }
+
+            // Give up on a connection that's stopped answering, so we can reconnect
+            _ = sleep_until(awaiting_pong.map(|(_, sent_at)| sent_at + settings.ping_timeout)) => {
+                warn!("💀 Server didn't answer a ping within {}s", settings.ping_timeout.as_secs());
+                return Err(anyhow!("Server stopped answering pings"));
+            }
+
             // Shutdown
             res = shutdown_rx.recv() => {
-                res.context("Error listening for shutdown signal")?;
-                info!("⛔ Received shutdown signal");
-                return Ok(());
+                let shutdown = res.context("Error listening for shutdown signal")?;
+                info!("⛔ Received shutdown signal: {shutdown}");
+                return Ok(None);
             }
         }
     }
 }
+
+/// Holds back rapid-fire control messages so the wire sees at most one per key per interval,
+/// letting the TUI send them on every keypress
+#[derive(Default)]
+struct Coalescer {
+    /// When a message with each key was last sent
+    sent_at: HashMap<String, Instant>,
+    /// Latest message held back for each key
+    pending: HashMap<String, ClientMsg>,
+}
+
+impl Coalescer {
+    /// Pass a message through to send now, or hold it back in place of any older one
+    fn admit(&mut self, msg: ClientMsg) -> Option<ClientMsg> {
+        let Some(key) = msg.coalesce_key() else {
+            return Some(msg);
+        };
+        let now = Instant::now();
+        if self
+            .sent_at
+            .get(&key)
+            .is_some_and(|sent_at| now.duration_since(*sent_at) < COALESCE_INTERVAL)
+        {
+            self.pending.insert(key, msg);
+            return None;
+        }
+        self.sent_at.insert(key, now);
+        Some(msg)
+    }
+
+    /// When the next held back message can be sent
+    fn next_due(&self) -> Option<Instant> {
+        self.pending
+            .keys()
+            .filter_map(|key| self.sent_at.get(key))
+            .min()
+            .map(|sent_at| *sent_at + COALESCE_INTERVAL)
+    }
+
+    /// Take the held back messages that can be sent now
+    fn take_due(&mut self) -> Vec<ClientMsg> {
+        let now = Instant::now();
+        let due: Vec<String> = self
+            .pending
+            .keys()
+            .filter(|key| {
+                self.sent_at
+                    .get(*key)
+                    .is_none_or(|sent_at| now.duration_since(*sent_at) >= COALESCE_INTERVAL)
+            })
+            .cloned()
+            .collect();
+        due.into_iter()
+            .filter_map(|key| {
+                self.sent_at.insert(key.clone(), now);
+                self.pending.remove(&key)
+            })
+            .collect()
+    }
+}
+
+/// Deflate a large text message into a binary frame if compression was negotiated
+fn compress_ws_msg(ws_msg: Message, compress: bool) -> Result<Message> {
+    match ws_msg {
+        Message::Text(text) if compress && text.len() >= MIN_COMPRESS_BYTES => {
+            Ok(Message::binary(compression::deflate(&text)?))
+        }
+        ws_msg => Ok(ws_msg),
+    }
+}
+
+/// Record a frame if the session is being recorded
+fn record(recorder: Option<&Recorder>, direction: Direction, ws_msg: &Message) {
+    if let Some(recorder) = recorder {
+        recorder.record(direction, ws_msg);
+    }
+}
+
+/// Wait for an optional interval's next tick, never completing if it's unset
+async fn tick(interval: &mut Option<time::Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Sleep until an optional deadline, never completing if it's unset
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
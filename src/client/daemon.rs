@@ -0,0 +1,554 @@
+use age::x25519::Identity;
+use anyhow::{bail, Context, Result};
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::sync::Arc;
+use tokio::sync::{broadcast, mpsc, watch};
+use tracing::{error, info, warn};
+
+use super::comms::{Comms, ConnectionEvent, Received};
+use super::rules::{self, Action, NoteKind, Rules};
+use super::sync::ControlNote;
+use super::webhook::Webhook;
+use super::{DaemonConfig, Shutdown};
+use crate::common::{
+    signing_key, Auth, AuthChallenge, ClientMsg, Hello, HelloAck, Note, Resume, ServerMsg,
+    SyncSince, CHANNEL_BUFFER_SIZE, PROTOCOL_VERSION, SYNC_PROTOCOL_VERSION,
+};
+
+const LOG_PATH: &str = "daemon.log";
+/// Messages kept for each identity while no frontend is attached, the oldest are dropped past it
+const MAX_HELD_MSGS: usize = 1000;
+
+/// What a frontend asks of the task holding its identity's connection to the server
+enum Request {
+    /// Attach a frontend that asked to authenticate as the identity, sending it the server's
+    /// messages once the daemon is authenticated
+    Attach {
+        id: u64,
+        msgs_tx: mpsc::Sender<ServerMsg>,
+    },
+    /// Pass a frontend's message on to the server
+    Forward { id: u64, msg: Box<ClientMsg> },
+}
+
+/// A frontend attached as an identity
+struct Frontend {
+    msgs_tx: mpsc::Sender<ServerMsg>,
+    /// Told it's authenticated, so it gets the server's messages
+    granted: bool,
+}
+
+/// Keep the connection to the server authenticated in the background, serving the TUI and
+/// scripts over a unix socket. They speak the same protocol to it as to the server, but the
+/// daemon does the auth and holds on to notes that come while nothing is attached.
+#[cfg(unix)]
+pub async fn run(config: DaemonConfig) -> Result<Shutdown> {
+    use std::os::unix::fs::PermissionsExt;
+    use tokio::net::{UnixListener, UnixStream};
+    use tokio::signal::unix::{signal, SignalKind};
+
+    // Logging
+    let file = File::create(LOG_PATH)?;
+    tracing_subscriber::fmt().with_writer(file).init();
+    info!("🏁 Daemon started");
+
+    let keys = match super::load_keys(&config.key_files, config.insecure_key_perms) {
+        Ok(keys) => keys,
+        Err(shutdown) => return Ok(shutdown),
+    };
+    info!("🔑 {} key files loaded", keys.len());
+    let rules = Arc::new(match &config.rules_file {
+        Some(path) => Rules::load(path)?,
+        None => Rules::default(),
+    });
+
+    // A socket left behind by a daemon that didn't shut down cleanly is safe to replace, one
+    // still being listened on isn't
+    let path = &config.socket;
+    if path.exists() {
+        if UnixStream::connect(path).await.is_ok() {
+            bail!("Daemon socket {} is already in use", path.display());
+        }
+        std::fs::remove_file(path).context("Error removing stale daemon socket")?;
+    }
+    let listener = UnixListener::bind(path)
+        .context(format!("Error binding daemon socket {}", path.display()))?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    info!("🔌 Daemon socket listening on {}", path.display());
+
+    // Create a channel for coordinated shutdown
+    let (shutdown_tx, mut shutdown_rx) = broadcast::channel::<Shutdown>(CHANNEL_BUFFER_SIZE);
+
+    // Connect as each identity, handing frontends the server's hello once we have it
+    let addr = match super::server_url(&config.address).await {
+        Ok(addr) => addr,
+        Err(shutdown) => return Ok(shutdown),
+    };
+    let dialer = super::dialer(&addr, &config.trust, &config.proxy, None)?;
+    let settings = super::connection_settings(
+        config.compression,
+        config.reconnect,
+        config.ping_interval,
+        config.ping_timeout,
+    );
+    let (hello_tx, hello_rx) = watch::channel(None);
+    let mut identities = HashMap::new();
+    let mut upstreams = vec![];
+    for key in keys {
+        let comms_res = Comms::run(
+            addr.clone(),
+            dialer.clone(),
+            settings,
+            None,
+            shutdown_tx.clone(),
+            shutdown_rx.resubscribe(),
+        )
+        .await;
+        let comms = match comms_res {
+            Ok(comms) => comms,
+            Err(e) => return Ok(Shutdown::ConnectionFailed(format!("{e:#}"))),
+        };
+        let (requests_tx, requests_rx) = mpsc::channel(CHANNEL_BUFFER_SIZE);
+        let upstream = Upstream::new(
+            key,
+            comms,
+            config.auth_token.clone(),
+            rules.clone(),
+            config.webhook.clone(),
+            hello_tx.clone(),
+            shutdown_tx.clone(),
+        );
+        identities.insert(upstream.pub_key.clone(), requests_tx);
+        upstreams.push(tokio::spawn(upstream.run(requests_rx)));
+    }
+    let identities = Arc::new(identities);
+
+    // Serve frontends until something shuts us down
+    let mut terminate = signal(SignalKind::terminate()).context("Error listening for SIGTERM")?;
+    let mut next_id = 0;
+    let shutdown = loop {
+        tokio::select! {
+            accept_res = listener.accept() => match accept_res {
+                Ok((stream, _)) => {
+                    next_id += 1;
+                    tokio::spawn(serve_frontend(next_id, stream, identities.clone(), hello_rx.clone()));
+                }
+                Err(e) => error!("🔌 Error accepting frontend: {e}"),
+            },
+            _ = tokio::signal::ctrl_c() => break Shutdown::Quit,
+            _ = terminate.recv() => break Shutdown::Quit,
+            res = shutdown_rx.recv() => break res.unwrap_or(Shutdown::Quit),
+        }
+    };
+
+    // Shutdown
+    _ = shutdown_tx.send(shutdown.clone());
+    for upstream in upstreams {
+        upstream.await?;
+    }
+    _ = std::fs::remove_file(path);
+    info!("🛑 Daemon stopped: {shutdown}");
+    Ok(shutdown)
+}
+
+#[cfg(not(unix))]
+pub async fn run(_config: DaemonConfig) -> Result<Shutdown> {
+    bail!("The daemon is only supported on unix")
+}
+
+/// Talk to a frontend attached to the socket, logging why it detached
+#[cfg(unix)]
+async fn serve_frontend(
+    id: u64,
+    stream: tokio::net::UnixStream,
+    identities: Arc<HashMap<String, mpsc::Sender<Request>>>,
+    hello_rx: watch::Receiver<Option<HelloAck>>,
+) {
+    info!("🔌 Frontend {id} attached");
+    match talk_frontend(id, stream, &identities, hello_rx).await {
+        Ok(()) => info!("🔌 Frontend {id} detached"),
+        Err(e) => warn!("🔌 Frontend {id} detached: {e:#}"),
+    }
+}
+
+/// Answer a frontend's hello and auth ourselves, then pass messages between it and the server.
+/// Ends when the frontend disconnects, or its identity's connection to the server drops and it
+/// should reconnect to us to authenticate again.
+#[cfg(unix)]
+async fn talk_frontend(
+    id: u64,
+    stream: tokio::net::UnixStream,
+    identities: &HashMap<String, mpsc::Sender<Request>>,
+    mut hello_rx: watch::Receiver<Option<HelloAck>>,
+) -> Result<()> {
+    use futures_util::{SinkExt, StreamExt};
+    use std::str::FromStr;
+    use tokio_tungstenite::tungstenite::Message;
+
+    let mut socket = tokio_tungstenite::accept_async(stream)
+        .await
+        .context("Error accepting websocket")?;
+    let (msgs_tx, mut msgs_rx) = mpsc::channel(CHANNEL_BUFFER_SIZE);
+    let mut msgs_tx = Some(msgs_tx);
+    let mut requests_tx = None;
+
+    let res = async {
+        loop {
+            tokio::select! {
+                ws_msg_opt = socket.next() => {
+                    let payload = match ws_msg_opt {
+                        Some(Ok(Message::Text(payload))) => payload,
+                        Some(Ok(Message::Close(_))) | None => return Ok(()),
+                        Some(Ok(_)) => continue,
+                        Some(Err(e)) => return Err(e.into()),
+                    };
+                    let msg = ClientMsg::from_str(&payload).context("Error deserializing ClientMsg")?;
+                    match msg {
+                        ClientMsg::Hello(_) => {
+                            // Frontends see the server's version, but nothing is worth
+                            // compressing over a local socket
+                            let ack = hello_rx.wait_for(Option::is_some).await?.clone();
+                            let ack = HelloAck {
+                                compression: false,
+                                ..ack.context("No hello from the server")?
+                            };
+                            socket.send(ServerMsg::HelloAck(ack).to_ws_msg()).await?;
+                        }
+                        // The socket is only open to our own user, so holding the key is proof
+                        // enough. A token from when the frontend last resumed is too.
+                        ClientMsg::AuthReq(Auth { pub_key, .. })
+                        | ClientMsg::Resume(Resume { pub_key, .. }) => {
+                            match (identities.get(&pub_key), msgs_tx.take()) {
+                                (Some(tx), Some(msgs_tx)) => {
+                                    info!("🔌 Frontend {id} authenticating as {pub_key}");
+                                    tx.send(Request::Attach { id, msgs_tx }).await?;
+                                    requests_tx = Some(tx);
+                                }
+                                _ => {
+                                    let denied = Auth::new(pub_key, "".into(), None);
+                                    socket.send(ServerMsg::AuthDenied(denied).to_ws_msg()).await?;
+                                    bail!("Frontend asked for an identity we don't hold");
+                                }
+                            }
+                        }
+                        ClientMsg::AuthPlaintext(_) => {}
+                        msg => match requests_tx {
+                            Some(tx) => tx.send(Request::Forward { id, msg: Box::new(msg) }).await?,
+                            None => bail!("Frontend sent {} before authenticating", msg.kind()),
+                        },
+                    }
+                }
+
+                msg_opt = msgs_rx.recv() => {
+                    let Some(msg) = msg_opt else {
+                        return Ok(());
+                    };
+                    socket.send(msg.to_ws_msg()).await?;
+                }
+            }
+        }
+    }
+    .await;
+    _ = socket.close(None).await;
+    res
+}
+
+/// One of the daemon's identities, keeping its connection to the server authenticated for the
+/// frontends attached as it
+struct Upstream {
+    key: Identity,
+    pub_key: String,
+    /// Hex encoded signing key, to authenticate with
+    signing_key: String,
+    auth_token: Option<String>,
+    comms: Comms,
+    rules: Arc<Rules>,
+    webhook: Option<Webhook>,
+    hello_tx: watch::Sender<Option<HelloAck>>,
+    shutdown_tx: broadcast::Sender<Shutdown>,
+    server_protocol_version: u32,
+    authenticated: bool,
+    /// The connection dropped and came back since we last authenticated
+    reconnected: bool,
+    /// Id of the last note sent to us, to sync from after reconnecting
+    last_note_id: Option<String>,
+    frontends: HashMap<u64, Frontend>,
+    /// Messages that came while no frontend was attached, replayed to the next one that fetches
+    /// its mailbox
+    held: VecDeque<ServerMsg>,
+}
+
+impl Upstream {
+    fn new(
+        key: Identity,
+        comms: Comms,
+        auth_token: Option<String>,
+        rules: Arc<Rules>,
+        webhook: Option<Webhook>,
+        hello_tx: watch::Sender<Option<HelloAck>>,
+        shutdown_tx: broadcast::Sender<Shutdown>,
+    ) -> Self {
+        Self {
+            pub_key: key.to_public().to_string(),
+            signing_key: hex::encode(signing_key(&key).verifying_key().as_bytes()),
+            key,
+            auth_token,
+            comms,
+            rules,
+            webhook,
+            hello_tx,
+            shutdown_tx,
+            server_protocol_version: 0,
+            authenticated: false,
+            reconnected: false,
+            last_note_id: None,
+            frontends: HashMap::new(),
+            held: VecDeque::new(),
+        }
+    }
+
+    /// Authenticate, then serve frontends until the connection to the server ends
+    async fn run(mut self, mut requests_rx: mpsc::Receiver<Request>) {
+        if let Err(e) = self.authenticate() {
+            error!("✍️ Error authenticating as {}: {e:#}", self.pub_key);
+        }
+        loop {
+            let res = tokio::select! {
+                received_res = self.comms.recv() => match received_res {
+                    Ok(Received::Msg(msg)) => self.handle_msg(msg),
+                    Ok(Received::Event(event)) => self.handle_event(event),
+                    Err(_) => break,
+                },
+                Some(request) = requests_rx.recv() => self.handle_request(request).await,
+            };
+            if let Err(e) = res {
+                error!("Error serving {}: {e:#}", self.pub_key);
+            }
+        }
+        if let Err(e) = self.comms.wait_shutdown().await {
+            error!("Error shutting down the connection: {e}");
+        }
+    }
+
+    /// Say hello and ask for a key challenge
+    fn authenticate(&mut self) -> Result<()> {
+        info!(
+            "✍️ Attempting to authenticate to server as {}",
+            self.pub_key
+        );
+        self.comms.try_send_msg(ClientMsg::Hello(Hello {
+            protocol_version: PROTOCOL_VERSION,
+            presence_only: false,
+            compression: false,
+        }))?;
+        self.comms.try_send_msg(ClientMsg::AuthReq(Auth::new(
+            self.pub_key.clone(),
+            self.signing_key.clone(),
+            self.auth_token.clone(),
+        )))
+    }
+
+    fn handle_msg(&mut self, msg: ServerMsg) -> Result<()> {
+        match msg {
+            ServerMsg::HelloAck(ack) => {
+                info!("👋 Server speaks protocol version {}", ack.protocol_version);
+                self.server_protocol_version = ack.protocol_version;
+                self.hello_tx.send_replace(Some(ack));
+            }
+            ServerMsg::AuthSecret(auth) => {
+                // Only ever hand back the nonce of a valid challenge for our own pubkey
+                let challenge = AuthChallenge::decrypt(&self.key, &auth.ciphertext)?;
+                self.comms.try_send_msg(ClientMsg::AuthPlaintext(Auth {
+                    plaintext: challenge.nonce,
+                    ..auth
+                }))?;
+            }
+            ServerMsg::AuthGranted(_) => {
+                info!(
+                    "✍️ Successfully authenticated to server as {}",
+                    self.pub_key
+                );
+                self.authenticated = true;
+                // Get the notes that were in flight when our last connection dropped
+                if self.reconnected && self.server_protocol_version >= SYNC_PROTOCOL_VERSION {
+                    self.comms.try_send_msg(ClientMsg::SyncSince(SyncSince {
+                        after: self.last_note_id.clone(),
+                    }))?;
+                }
+                self.reconnected = false;
+                self.comms.try_send_msg(ClientMsg::FetchMailbox)?;
+                // Let in the frontends that attached while we were authenticating
+                let ids: Vec<u64> = self.frontends.keys().copied().collect();
+                for id in ids {
+                    self.grant(id);
+                }
+            }
+            ServerMsg::AuthDenied(_) => {
+                error!("✍️ Failed authenticating to server as {}", self.pub_key);
+                self.shutdown_tx.send(Shutdown::AuthDenied)?;
+            }
+            // We authenticate with the key every time, frontends resume with us instead
+            ServerMsg::ResumeToken { .. } => {}
+            ServerMsg::RecNote(note) => {
+                if note.to == self.pub_key {
+                    self.last_note_id = Some(note.id.clone());
+                }
+                let attached = self.broadcast(&ServerMsg::RecNote(note.clone())) > 0;
+                self.notify(&note, attached);
+                if !attached {
+                    self.hold(ServerMsg::RecNote(note));
+                }
+            }
+            // Lost if nobody sees them, unlike the rest
+            msg @ (ServerMsg::SessionOffer(_)
+            | ServerMsg::SessionAccept(_)
+            | ServerMsg::Revoked(_)) => {
+                if self.broadcast(&msg) == 0 {
+                    self.hold(msg);
+                }
+            }
+            msg => {
+                self.broadcast(&msg);
+            }
+        }
+        Ok(())
+    }
+
+    /// Detach frontends while the connection is down, they reconnect and authenticate again once
+    /// it's back
+    fn handle_event(&mut self, event: ConnectionEvent) -> Result<()> {
+        match event {
+            ConnectionEvent::Reconnecting { .. } => {
+                self.authenticated = false;
+                self.frontends.clear();
+                Ok(())
+            }
+            ConnectionEvent::Reconnected => {
+                self.reconnected = true;
+                self.authenticate()
+            }
+            // Tls logs it, and reconnecting keeps failing until the user does something about it
+            ConnectionEvent::CertificateChanged(_) => Ok(()),
+            // We follow authentication ourselves, and shut down when the connection fails
+            ConnectionEvent::Connecting { .. }
+            | ConnectionEvent::Authenticated
+            | ConnectionEvent::Failed { .. } => Ok(()),
+        }
+    }
+
+    async fn handle_request(&mut self, request: Request) -> Result<()> {
+        match request {
+            Request::Attach { id, msgs_tx } => {
+                self.frontends.insert(
+                    id,
+                    Frontend {
+                        msgs_tx,
+                        granted: false,
+                    },
+                );
+                if self.authenticated {
+                    self.grant(id);
+                }
+            }
+            // The daemon fetched the mailbox when it authenticated, hand over what it held since
+            Request::Forward { id, msg } if matches!(*msg, ClientMsg::FetchMailbox) => {
+                let Some(frontend) = self.frontends.get(&id) else {
+                    return Ok(());
+                };
+                info!(
+                    "📬 Replaying {} held messages to frontend {id}",
+                    self.held.len()
+                );
+                for msg in self.held.drain(..) {
+                    frontend.msgs_tx.send(msg).await?;
+                }
+            }
+            Request::Forward { msg, .. } => self.comms.try_send_msg(*msg)?,
+        }
+        Ok(())
+    }
+
+    /// Tell a frontend it's authenticated, it gets the server's messages from now on
+    fn grant(&mut self, id: u64) {
+        let Some(frontend) = self.frontends.get_mut(&id) else {
+            return;
+        };
+        if frontend.granted {
+            return;
+        }
+        let auth = Auth::new(self.pub_key.clone(), self.signing_key.clone(), None);
+        frontend.granted = frontend
+            .msgs_tx
+            .try_send(ServerMsg::AuthGranted(auth))
+            .is_ok();
+    }
+
+    /// Send a message to every authenticated frontend, detaching the ones that went away or
+    /// aren't keeping up. Returns how many it was sent to.
+    fn broadcast(&mut self, msg: &ServerMsg) -> usize {
+        self.frontends.retain(|id, frontend| {
+            if !frontend.granted {
+                return true;
+            }
+            match frontend.msgs_tx.try_send(msg.clone()) {
+                Ok(()) => true,
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    warn!("🔌 Detaching frontend {id}, it isn't keeping up");
+                    false
+                }
+                Err(mpsc::error::TrySendError::Closed(_)) => false,
+            }
+        });
+        self.frontends
+            .values()
+            .filter(|frontend| frontend.granted)
+            .count()
+    }
+
+    /// Hold on to a message until a frontend attaches
+    fn hold(&mut self, msg: ServerMsg) {
+        self.held.push_back(msg);
+        if self.held.len() > MAX_HELD_MSGS {
+            warn!("📬 Dropping the oldest held message, no frontend attached for a while");
+            self.held.pop_front();
+        }
+    }
+
+    /// Forward a note to the webhook, and run the rules' commands for it if no frontend is
+    /// attached so notifications still show. Session notes can only be decrypted once, so they're
+    /// left to the frontend.
+    fn notify(&self, note: &Note, attached: bool) {
+        if attached && self.webhook.is_none() {
+            return;
+        }
+        let opened = match note.open(&self.key) {
+            Ok(opened) => opened,
+            Err(e) => {
+                info!("🔔 Leaving note {} to a frontend: {e}", note.id);
+                return;
+            }
+        };
+        // Our own notes echoed back and control notes aren't worth notifying about
+        if opened.from == self.pub_key || ControlNote::parse(&opened.content).is_some() {
+            return;
+        }
+        info!("🔔 New note {} from {}", note.id, opened.from);
+        if let Some(webhook) = &self.webhook {
+            webhook.forward(note, &opened.from, &opened.content);
+        }
+        if attached {
+            return;
+        }
+        let kind = if note.is_sealed() {
+            NoteKind::Sealed
+        } else {
+            NoteKind::Plain
+        };
+        for action in self.rules.evaluate(&opened.from, kind, &opened.content) {
+            if let Action::Run(command) = action {
+                rules::run_command(&command, &opened.from, &opened.content);
+            }
+        }
+    }
+}
@@ -0,0 +1,111 @@
+use age::x25519::Identity;
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tokio::sync::{
+    mpsc::{self, UnboundedReceiver, UnboundedSender},
+    Semaphore,
+};
+use tokio::task;
+
+use crate::common::{Note, OpenedNote};
+
+/// Batches at least this big show a progress bar
+const PROGRESS_MIN_NOTES: usize = 10;
+
+type Decrypted = (Note, Result<OpenedNote>);
+
+/// Decrypts received notes concurrently on the blocking thread pool, so a big batch doesn't hold
+/// up rendering. Notes are handed back in the order they were received.
+pub struct DecryptPool {
+    key: Identity,
+    /// Limits how many notes are decrypted at once
+    permits: Arc<Semaphore>,
+    results_tx: UnboundedSender<(u64, Decrypted)>,
+    results_rx: UnboundedReceiver<(u64, Decrypted)>,
+    /// Sequence number of the next note submitted
+    next_seq: u64,
+    /// Sequence number of the next note to hand back
+    next_out: u64,
+    /// Decrypted notes waiting on earlier ones to finish
+    ready: BTreeMap<u64, Decrypted>,
+    /// Notes submitted since the pool was last idle
+    batch_size: usize,
+}
+
+impl DecryptPool {
+    pub fn new(key: Identity) -> Self {
+        let workers = std::thread::available_parallelism().map_or(1, |n| n.get());
+        let (results_tx, results_rx) = mpsc::unbounded_channel();
+        Self {
+            key,
+            permits: Arc::new(Semaphore::new(workers)),
+            results_tx,
+            results_rx,
+            next_seq: 0,
+            next_out: 0,
+            ready: BTreeMap::new(),
+            batch_size: 0,
+        }
+    }
+
+    /// Start decrypting a note
+    pub fn submit(&mut self, note: Note) {
+        let seq = self.take_seq();
+        let key = self.key.clone();
+        let permits = Arc::clone(&self.permits);
+        let results_tx = self.results_tx.clone();
+        tokio::spawn(async move {
+            let Ok(_permit) = permits.acquire_owned().await else {
+                return;
+            };
+            let to_open = note.clone();
+            let opened = task::spawn_blocking(move || to_open.open(&key))
+                .await
+                .unwrap_or_else(|e| Err(e.into()));
+            _ = results_tx.send((seq, (note, opened)));
+        });
+    }
+
+    /// Queue a note that was already opened, e.g. with a session, keeping it in order
+    pub fn submit_opened(&mut self, note: Note, opened: Result<OpenedNote>) {
+        let seq = self.take_seq();
+        self.ready.insert(seq, (note, opened));
+    }
+
+    /// Wait for a note to finish decrypting, to then take with `try_recv`
+    pub async fn wait(&mut self) {
+        if let Some((seq, decrypted)) = self.results_rx.recv().await {
+            self.ready.insert(seq, decrypted);
+        }
+    }
+
+    /// Take the next decrypted note in receive order, if it's done
+    pub fn try_recv(&mut self) -> Option<Decrypted> {
+        while let Ok((seq, decrypted)) = self.results_rx.try_recv() {
+            self.ready.insert(seq, decrypted);
+        }
+        let decrypted = self.ready.remove(&self.next_out)?;
+        self.next_out += 1;
+        if self.next_out == self.next_seq {
+            self.batch_size = 0;
+        }
+        Some(decrypted)
+    }
+
+    /// Notes handed back and total notes in the current batch, if it's big enough to show
+    pub fn progress(&self) -> Option<(usize, usize)> {
+        if self.batch_size < PROGRESS_MIN_NOTES {
+            return None;
+        }
+        let pending = (self.next_seq - self.next_out) as usize;
+        Some((self.batch_size - pending, self.batch_size))
+    }
+
+    fn take_seq(&mut self) -> u64 {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.batch_size += 1;
+        seq
+    }
+}
@@ -0,0 +1,20 @@
+use notify_rust::Notification;
+use tokio::task;
+use tracing::warn;
+
+/// Raise a desktop notification for a note, leaving the text out if it's None. Shown from the
+/// blocking thread pool, since the notification service can be slow to answer.
+pub fn notify(from: &str, content: Option<&str>) {
+    let summary = format!("Note from {from}");
+    let body = content.unwrap_or("New note").to_string();
+    task::spawn_blocking(move || {
+        let show_res = Notification::new()
+            .appname("age-chat")
+            .summary(&summary)
+            .body(&body)
+            .show();
+        if let Err(e) = show_res {
+            warn!("🔔 Error showing desktop notification: {e}");
+        }
+    });
+}
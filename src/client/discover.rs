@@ -0,0 +1,106 @@
+use std::collections::BTreeMap;
+use std::io::{BufRead, IsTerminal, Write};
+use std::net::IpAddr;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use mdns_sd::{ServiceDaemon, ServiceEvent};
+use tokio::time::{self, Instant};
+use tracing::info;
+
+use crate::common::MDNS_SERVICE_TYPE;
+
+/// How long to wait for servers on the local network to answer
+const DISCOVER_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Browse the local network for servers advertising themselves, asking which to connect to if
+/// there are several. Returns the address of the one to connect to.
+pub async fn find_server() -> Result<String> {
+    let servers = browse().await?;
+    let mut servers: Vec<(String, String)> = servers.into_iter().collect();
+    match servers.len() {
+        0 => bail!("No age-chat servers found on the local network"),
+        1 => {
+            let (name, address) = servers.remove(0);
+            info!("📡 Found server {name} at {address}");
+            Ok(address)
+        }
+        _ => choose(servers),
+    }
+}
+
+/// Names and addresses of the servers that answered in time
+async fn browse() -> Result<BTreeMap<String, String>> {
+    let mdns = ServiceDaemon::new().context("Error starting mDNS")?;
+    let events = mdns
+        .browse(MDNS_SERVICE_TYPE)
+        .context("Error browsing for servers over mDNS")?;
+    let mut servers = BTreeMap::new();
+    let deadline = Instant::now() + DISCOVER_TIMEOUT;
+    while let Ok(Ok(event)) = time::timeout_at(deadline, events.recv_async()).await {
+        let ServiceEvent::ServiceResolved(service) = event else {
+            continue;
+        };
+        // Other hosts on the network are reached over IPv4 without needing a scope id
+        let addresses = service.get_addresses();
+        let Some(ip) = addresses
+            .iter()
+            .find(|ip| ip.is_ipv4())
+            .or_else(|| addresses.iter().next())
+        else {
+            continue;
+        };
+        let host = match ip {
+            IpAddr::V4(ip) => ip.to_string(),
+            IpAddr::V6(ip) => format!("[{ip}]"),
+        };
+        let address = match service.get_property_val_str("path") {
+            Some(path) => format!("ws://{host}:{}{path}", service.get_port()),
+            None => format!("{host}:{}", service.get_port()),
+        };
+        let name = service
+            .get_fullname()
+            .trim_end_matches(MDNS_SERVICE_TYPE)
+            .trim_end_matches('.')
+            .to_string();
+        servers.insert(name, address);
+    }
+    _ = mdns.shutdown();
+    Ok(servers)
+}
+
+/// Ask which of several servers to connect to
+fn choose(servers: Vec<(String, String)>) -> Result<String> {
+    let list: Vec<String> = servers
+        .iter()
+        .enumerate()
+        .map(|(i, (name, address))| format!("  {}) {name} at {address}", i + 1))
+        .collect();
+    if !std::io::stdin().is_terminal() {
+        bail!(
+            "Found several servers on the local network, give the address of one:\n{}",
+            list.join("\n")
+        );
+    }
+    println!("Found several servers on the local network:");
+    for line in &list {
+        println!("{line}");
+    }
+    print!("Connect to which? [1-{}] ", servers.len());
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().lock().read_line(&mut answer)?;
+    let choice = answer
+        .trim()
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_sub(1))
+        .and_then(|i| servers.get(i));
+    match choice {
+        Some((name, address)) => {
+            info!("📡 Chose server {name} at {address}");
+            Ok(address.clone())
+        }
+        None => bail!("No server chosen"),
+    }
+}
@@ -0,0 +1,66 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+
+use anyhow::{bail, Context, Result};
+use hickory_resolver::{ResolveError, TokioResolver};
+use tracing::info;
+
+/// Name under a domain its server's SRV and TXT records are kept at
+const SERVICE_PREFIX: &str = "_agechat._tcp";
+
+/// Websocket url of the server a bare domain points to. The `_agechat._tcp` SRV record gives the
+/// host and port, and a TXT record at the same name can add `tls=required` and `path=/chat`, or
+/// give a whole `url=wss://...` for domains without the SRV record.
+pub async fn lookup(domain: &str) -> Result<String> {
+    let resolver = TokioResolver::builder_tokio()
+        .context("Error reading the system DNS config")?
+        .build();
+    let name = format!("{SERVICE_PREFIX}.{domain}");
+    // Fully qualified, so search domains aren't tried
+    let fqdn = format!("{name}.");
+    let txt = match resolver.txt_lookup(fqdn.as_str()).await {
+        Ok(lookup) => lookup
+            .iter()
+            .flat_map(|txt| txt.txt_data().iter())
+            .filter_map(|data| {
+                let (key, value) = std::str::from_utf8(data).ok()?.split_once('=')?;
+                Some((key.trim().to_string(), value.trim().to_string()))
+            })
+            .collect(),
+        Err(e) if not_found(&e) => HashMap::new(),
+        Err(e) => return Err(e).context(format!("Error looking up TXT records of {name}")),
+    };
+    let srv = match resolver.srv_lookup(fqdn.as_str()).await {
+        // The most preferred record, the one weighted heaviest among them
+        Ok(lookup) => lookup
+            .iter()
+            .min_by_key(|srv| (srv.priority(), Reverse(srv.weight())))
+            .cloned(),
+        Err(e) if not_found(&e) => None,
+        Err(e) => return Err(e).context(format!("Error looking up SRV records of {name}")),
+    };
+    let url = match (srv, txt.get("url")) {
+        (Some(srv), _) => {
+            let host = srv.target().to_utf8();
+            let host = host.trim_end_matches('.');
+            if host.is_empty() {
+                bail!("{domain} says it doesn't run an age-chat server");
+            }
+            let scheme = match txt.get("tls").map(String::as_str) {
+                Some("required") => "wss",
+                _ => "ws",
+            };
+            let path = txt.get("path").map_or("", String::as_str);
+            format!("{scheme}://{host}:{}{path}", srv.port())
+        }
+        (None, Some(url)) => url.clone(),
+        (None, None) => bail!("No server found for {domain}, it has no {name} SRV or TXT record"),
+    };
+    info!("🧭 Found server for {domain} at {url}");
+    Ok(url)
+}
+
+/// Whether a lookup failed because the records don't exist, rather than DNS not working
+fn not_found(e: &ResolveError) -> bool {
+    e.is_no_records_found() || e.is_nx_domain()
+}
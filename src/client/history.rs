@@ -0,0 +1,244 @@
+use age::x25519::{Identity, Recipient};
+use anyhow::{bail, Context, Result};
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use tracing::info;
+
+use crate::common::Note;
+
+/// Schema migrations, applied in order when the history is opened. The database's
+/// `user_version` records how many have been applied. Only ever append to this list.
+const MIGRATIONS: &[&str] = &[
+    // 1: initial schema
+    "CREATE TABLE notes (
+        seq INTEGER PRIMARY KEY AUTOINCREMENT,
+        identity TEXT NOT NULL,
+        conversation TEXT NOT NULL,
+        id TEXT NOT NULL,
+        sender TEXT NOT NULL,
+        content TEXT NOT NULL,
+        note TEXT NOT NULL,
+        UNIQUE (identity, id)
+    );
+    CREATE INDEX notes_conversation ON notes (identity, conversation);",
+    // 2: sender and content sealed to the identity rather than in plaintext, existing rows are
+    // sealed the next time they're loaded
+    "ALTER TABLE notes ADD COLUMN sealed TEXT;",
+    // 3: when notes were sent, in milliseconds since the epoch, to page through them in order
+    "ALTER TABLE notes ADD COLUMN sent_at INTEGER NOT NULL DEFAULT 0;
+    UPDATE notes SET sent_at = CAST(
+        (julianday(json_extract(note, '$.timestamp')) - 2440587.5) * 86400000 AS INTEGER
+    );
+    CREATE INDEX notes_sent_at ON notes (identity, conversation, sent_at);",
+    // 4: notes and commands each identity submitted, sealed to it, to recall from the input box
+    "CREATE TABLE inputs (
+        seq INTEGER PRIMARY KEY AUTOINCREMENT,
+        identity TEXT NOT NULL,
+        sealed TEXT NOT NULL
+    );
+    CREATE INDEX inputs_identity ON inputs (identity, seq);",
+];
+
+/// Inputs kept for each identity, older ones are forgotten
+pub const INPUT_HISTORY_LEN: usize = 500;
+
+/// Notes shown in each conversation, kept in a SQLite database so they're there again after a
+/// restart. Session notes can't be decrypted twice, so the sender and content of every note are
+/// encrypted again to the identity that was shown it, and the disk never holds plaintext.
+pub struct History {
+    conn: Connection,
+}
+
+/// A note from the history with its sender and decrypted content
+pub struct HistoryNote {
+    pub note: Note,
+    pub from: String,
+    pub content: String,
+}
+
+/// What's sealed to the identity for each note
+#[derive(Serialize, Deserialize)]
+struct Sealed {
+    from: String,
+    content: String,
+}
+
+impl History {
+    /// Open or create the history database and bring its schema up to date
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut conn =
+            Connection::open(path).context(format!("Error opening history {}", path.display()))?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+        }
+        // Overwrite the plaintext of rows sealed after upgrading, rather than leave it in free pages
+        conn.pragma_update(None, "secure_delete", true)?;
+        migrate(&mut conn)?;
+        Ok(Self { conn })
+    }
+
+    /// Add a note an identity was shown in a conversation, unless it's already there
+    pub fn record(
+        &self,
+        identity: &Recipient,
+        conversation: &str,
+        note: &Note,
+        from: &str,
+        content: &str,
+    ) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR IGNORE INTO notes
+            (identity, conversation, id, sender, content, note, sealed, sent_at)
+            VALUES (?1, ?2, ?3, '', '', ?4, ?5, ?6)",
+            params![
+                identity.to_string(),
+                conversation,
+                note.id,
+                serde_json::to_string(note)?,
+                seal(identity, from, content)?,
+                note.timestamp.timestamp_millis()
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Up to `limit` notes an identity was shown in a conversation, oldest first by when they
+    /// were sent. The newest ones, or the ones sent before the note with `before_id`.
+    pub fn load_page(
+        &self,
+        identity: &Identity,
+        conversation: &str,
+        before_id: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<HistoryNote>> {
+        let pub_key = identity.to_public();
+        let mut stmt = self.conn.prepare(
+            "SELECT seq, sender, content, note, sealed FROM notes
+            WHERE identity = ?1 AND conversation = ?2 AND (?3 IS NULL OR (sent_at, seq) < (
+                SELECT sent_at, seq FROM notes WHERE identity = ?1 AND id = ?3
+            ))
+            ORDER BY sent_at DESC, seq DESC LIMIT ?4",
+        )?;
+        let params = params![pub_key.to_string(), conversation, before_id, limit];
+        let rows = stmt.query_map(params, |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, String>(3)?,
+                row.get::<_, Option<String>>(4)?,
+            ))
+        })?;
+        let mut notes = vec![];
+        for row in rows {
+            let (seq, from, content, note, sealed) = row?;
+            let note = serde_json::from_str(&note).context("Error parsing note in history")?;
+            let Some(sealed) = sealed else {
+                // Saved in plaintext before history was sealed
+                self.conn.execute(
+                    "UPDATE notes SET sender = '', content = '', sealed = ?1 WHERE seq = ?2",
+                    params![seal(&pub_key, &from, &content)?, seq],
+                )?;
+                notes.push(HistoryNote {
+                    note,
+                    from,
+                    content,
+                });
+                continue;
+            };
+            let plaintext = age::decrypt(identity, sealed.as_bytes())
+                .context("Error decrypting note in history")?;
+            let Sealed { from, content } = serde_json::from_slice(&plaintext)?;
+            notes.push(HistoryNote {
+                note,
+                from,
+                content,
+            });
+        }
+        notes.reverse();
+        Ok(notes)
+    }
+
+    /// Add something an identity submitted from the input box, forgetting the oldest once there
+    /// are too many
+    pub fn record_input(&self, identity: &Recipient, input: &str) -> Result<()> {
+        let sealed = seal_text(identity, input)?;
+        let identity = identity.to_string();
+        self.conn.execute(
+            "INSERT INTO inputs (identity, sealed) VALUES (?1, ?2)",
+            params![identity, sealed],
+        )?;
+        self.conn.execute(
+            "DELETE FROM inputs WHERE identity = ?1 AND seq <= (
+                SELECT seq FROM inputs WHERE identity = ?1 ORDER BY seq DESC LIMIT 1 OFFSET ?2
+            )",
+            params![identity, INPUT_HISTORY_LEN],
+        )?;
+        Ok(())
+    }
+
+    /// What an identity submitted from the input box, oldest first
+    pub fn load_inputs(&self, identity: &Identity) -> Result<Vec<String>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT sealed FROM inputs WHERE identity = ?1 ORDER BY seq DESC LIMIT ?2")?;
+        let params = params![identity.to_public().to_string(), INPUT_HISTORY_LEN];
+        let rows = stmt.query_map(params, |row| row.get::<_, String>(0))?;
+        let mut inputs = vec![];
+        for sealed in rows {
+            inputs
+                .push(open_text(identity, &sealed?).context("Error decrypting input in history")?);
+        }
+        inputs.reverse();
+        Ok(inputs)
+    }
+}
+
+/// Encrypt text to an identity, so it's kept on disk without being readable
+pub fn seal_text(identity: &Recipient, text: &str) -> Result<String> {
+    Ok(age::encrypt_and_armor(identity, text.as_bytes())?)
+}
+
+/// Decrypt text sealed to an identity
+pub fn open_text(identity: &Identity, sealed: &str) -> Result<String> {
+    Ok(String::from_utf8(age::decrypt(
+        identity,
+        sealed.as_bytes(),
+    )?)?)
+}
+
+/// Encrypt a note's sender and content to the identity it was shown to
+fn seal(identity: &Recipient, from: &str, content: &str) -> Result<String> {
+    let sealed = Sealed {
+        from: from.to_string(),
+        content: content.to_string(),
+    };
+    Ok(age::encrypt_and_armor(
+        identity,
+        &serde_json::to_vec(&sealed)?,
+    )?)
+}
+
+/// Apply the migrations the database hasn't had yet
+fn migrate(conn: &mut Connection) -> Result<()> {
+    let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    if version > MIGRATIONS.len() {
+        bail!(
+            "History schema version {version} is newer than this client supports ({})",
+            MIGRATIONS.len()
+        );
+    }
+
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)
+            .context(format!("Error applying history migration {}", i + 1))?;
+        tx.pragma_update(None, "user_version", i + 1)?;
+        tx.commit()?;
+        info!("💾 Applied history migration {}", i + 1);
+    }
+    Ok(())
+}
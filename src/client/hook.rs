@@ -0,0 +1,142 @@
+use anyhow::{anyhow, bail, Result};
+use std::collections::BTreeMap;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::process::Command;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::time;
+use tracing::warn;
+
+use crate::common::Note;
+
+/// Longest a hook can take on a note before it's killed and the note shown as usual
+const HOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What a hook decided to do with a note
+#[derive(Debug, Default)]
+pub struct Verdict {
+    /// Don't show the note
+    pub mute: bool,
+    /// Text to send back to the sender
+    pub reply: Option<String>,
+}
+
+/// A received note the hook is done with
+pub struct Hooked {
+    /// Index of the account that received it
+    pub account: usize,
+    pub note: Note,
+    pub from: String,
+    pub content: String,
+    pub verdict: Verdict,
+}
+
+/// Runs the user's hook command on received notes in the background, so a slow hook doesn't hold
+/// up rendering. Notes are handed back in the order they were received.
+pub struct Hook {
+    command: String,
+    results_tx: UnboundedSender<(u64, Hooked)>,
+    results_rx: UnboundedReceiver<(u64, Hooked)>,
+    /// Sequence number of the next note submitted
+    next_seq: u64,
+    /// Sequence number of the next note to hand back
+    next_out: u64,
+    /// Notes the hook is done with, waiting on earlier ones
+    ready: BTreeMap<u64, Hooked>,
+}
+
+impl Hook {
+    pub fn new(command: String) -> Self {
+        let (results_tx, results_rx) = mpsc::unbounded_channel();
+        Self {
+            command,
+            results_tx,
+            results_rx,
+            next_seq: 0,
+            next_out: 0,
+            ready: BTreeMap::new(),
+        }
+    }
+
+    /// Start running the hook on a decrypted note an account received
+    pub fn submit(&mut self, account: usize, note: Note, from: String, content: String) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        let command = self.command.clone();
+        let results_tx = self.results_tx.clone();
+        tokio::spawn(async move {
+            let verdict = match run(&command, &from, &content).await {
+                Ok(verdict) => verdict,
+                Err(e) => {
+                    warn!("🪝 Hook failed on note {}, showing it: {e:#}", note.id);
+                    Verdict::default()
+                }
+            };
+            let hooked = Hooked {
+                account,
+                note,
+                from,
+                content,
+                verdict,
+            };
+            _ = results_tx.send((seq, hooked));
+        });
+    }
+
+    /// Wait for the hook to finish with a note, to then take with `try_recv`
+    pub async fn wait(&mut self) {
+        if let Some((seq, hooked)) = self.results_rx.recv().await {
+            self.ready.insert(seq, hooked);
+        }
+    }
+
+    /// Take the next note the hook is done with in receive order, if it's done
+    pub fn try_recv(&mut self) -> Option<Hooked> {
+        while let Ok((seq, hooked)) = self.results_rx.try_recv() {
+            self.ready.insert(seq, hooked);
+        }
+        let hooked = self.ready.remove(&self.next_out)?;
+        self.next_out += 1;
+        Some(hooked)
+    }
+}
+
+/// Run the hook command on a note, with it in the `AGE_CHAT_FROM` and `AGE_CHAT_TEXT` env vars
+/// like rule commands, and parse what it printed
+async fn run(command: &str, from: &str, text: &str) -> Result<Verdict> {
+    let child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .env("AGE_CHAT_FROM", from)
+        .env("AGE_CHAT_TEXT", text)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()?;
+    let output = time::timeout(HOOK_TIMEOUT, child.wait_with_output())
+        .await
+        .map_err(|_| anyhow!("Timed out after {}s", HOOK_TIMEOUT.as_secs()))??;
+    if !output.status.success() {
+        bail!("Hook {}", output.status);
+    }
+    Ok(parse(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Parse a hook's output, where a `mute` line hides the note and `reply <text>` lines are sent
+/// back to the sender as one note. Anything else is ignored.
+fn parse(output: &str) -> Verdict {
+    let mut verdict = Verdict::default();
+    let mut reply = Vec::new();
+    for line in output.lines() {
+        match line.split_once(' ') {
+            _ if line.trim() == "mute" => verdict.mute = true,
+            Some(("reply", text)) => reply.push(text),
+            _ => warn!("🪝 Ignoring hook output line: {line}"),
+        }
+    }
+    if !reply.is_empty() {
+        verdict.reply = Some(reply.join("\n"));
+    }
+    verdict
+}
@@ -0,0 +1,67 @@
+use age::x25519::Identity;
+use anyhow::Result;
+
+use super::chat::{ChatClient, ChatEvent, ReceivedNote};
+use super::output::{Event, Output};
+use super::Shutdown;
+
+/// Authenticate as a key and print the notes we receive until interrupted, reconnecting if the
+/// connection drops. Meant for scripts and bots.
+pub async fn run(
+    address: &str,
+    key: Identity,
+    auth_token: Option<String>,
+    output: Output,
+) -> Result<Shutdown> {
+    let mut client = match ChatClient::connect(address).await {
+        Ok(client) => client,
+        Err(e) => return e.downcast(),
+    };
+    let res = tokio::select! {
+        res = listen(&mut client, key, auth_token, output) => res,
+        _ = tokio::signal::ctrl_c() => Ok(Shutdown::Quit),
+    };
+    client.close().await?;
+    res.or_else(|e| e.downcast())
+}
+
+/// Authenticate and print notes until the connection ends
+async fn listen(
+    client: &mut ChatClient,
+    key: Identity,
+    auth_token: Option<String>,
+    output: Output,
+) -> Result<Shutdown> {
+    client.authenticate(key, auth_token).await?;
+    loop {
+        match client.next_event().await? {
+            ChatEvent::Note(note) => print_note(&note, output)?,
+            ChatEvent::Unreadable { id, from, error } => {
+                eprintln!("Skipping note {id} from {from}: {error}")
+            }
+            ChatEvent::Reconnecting { reason } => match output {
+                Output::Text => eprintln!("Lost connection to server, reconnecting: {reason}"),
+                Output::Json => Event::Reconnecting { reason: &reason }.print()?,
+            },
+            ChatEvent::ServerError(e) => eprintln!("Server error: {e}"),
+        }
+    }
+}
+
+/// Print a note on a line of its own, as JSON or `<from>: <content>` with newlines escaped
+fn print_note(note: &ReceivedNote, output: Output) -> Result<()> {
+    match output {
+        Output::Text => {
+            let content = note.content.replace('\\', "\\\\").replace('\n', "\\n");
+            println!("{}: {content}", note.from);
+            Ok(())
+        }
+        Output::Json => Event::Note {
+            id: &note.id,
+            from: &note.from,
+            timestamp: note.timestamp,
+            content: &note.content,
+        }
+        .print(),
+    }
+}
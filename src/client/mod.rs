@@ -1,48 +1,881 @@
+mod bot;
+mod chat;
 mod comms;
+mod daemon;
+mod decrypt;
+mod desktop;
+mod discover;
+mod dns;
+mod history;
+mod hook;
+mod listen;
+mod outbox;
+mod output;
+mod presence;
+mod proxy;
+mod recording;
+mod resend;
+mod resume;
+mod revoke;
+mod rules;
+mod seen;
+mod send;
+mod session;
+mod signing_keys;
+mod sync;
+mod timestamps;
+mod tls;
 mod tui;
+mod webhook;
 
+use std::collections::HashMap;
+use std::fmt;
 use std::fs::File;
+use std::io::{IsTerminal, Read};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::time::Duration;
 
 use age::x25519::{Identity, Recipient};
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Context, Result};
+use clap::ValueEnum;
 use tokio::sync::broadcast;
 use tracing::info;
 
-use crate::client::comms::Comms;
-use crate::ClientArgs;
+use crate::client::comms::{Comms, ConnectionSettings, Dialer};
+use crate::client::proxy::Proxy;
+use crate::client::recording::Recorder;
+use crate::client::seen::SeenNotes;
+use crate::client::timestamps::{ConversationTimes, TimeDisplay, Timezone, DEFAULT_TIME_FORMAT};
+use crate::client::tls::{parse_fingerprint, Tls, Trust};
+use crate::client::webhook::Webhook;
+use crate::common::{check_key_perms, load_key, CHANNEL_BUFFER_SIZE};
+use crate::config::{Resolver, DEFAULT_ADDRESS};
+use crate::server;
+use crate::{ClientArgs, DaemonArgs};
 
+pub use chat::{ChatClient, ChatEvent, Outgoing, ReceivedNote};
+pub use output::{print_failure, Output};
+
+pub const DEFAULT_KEY_FILE: &str = "key.txt";
+pub const DEFAULT_BOT_REPLIES_PER_MINUTE: u32 = 10;
+const DEFAULT_DAEMON_SOCKET: &str = "daemon.sock";
 const LOG_PATH: &str = "client.log";
+const SEEN_NOTES_PATH: &str = "seen_notes.txt";
+const ARCHIVE_PATH: &str = "archive.txt";
+const RESUME_TOKENS_PATH: &str = "resume_tokens.txt";
+const OUTBOX_PATH: &str = "outbox.json";
+const HISTORY_PATH: &str = "history.db";
+const KNOWN_SERVERS_PATH: &str = "known_servers.txt";
+const KNOWN_SIGNING_KEYS_PATH: &str = "known_signing_keys.txt";
+/// Followed by the identity's pubkey, each identity's sessions are sealed to it in their own file
+const SESSIONS_PATH_PREFIX: &str = "sessions_";
+const DEFAULT_PING_INTERVAL: u64 = 15;
+const DEFAULT_PING_TIMEOUT: u64 = 10;
+/// Longest note read from stdin or a file, as much as servers accept by default. Encrypting it
+/// only makes it bigger.
+const MAX_NOTE_INPUT_BYTES: u64 = 1024 * 1024;
+
+/// Exit codes, so wrappers and monitoring can tell why the client exited. Other errors exit with 1.
+pub const EXIT_OK: i32 = 0;
+pub const EXIT_AUTH_DENIED: i32 = 2;
+pub const EXIT_CONNECTION_FAILED: i32 = 3;
+pub const EXIT_KEY_ERROR: i32 = 4;
+pub const EXIT_KICKED: i32 = 5;
+pub const EXIT_NOT_SENT: i32 = 6;
+
+/// Effective client configuration
+pub struct Config {
+    /// Addresses of the servers to connect to
+    pub addresses: Vec<String>,
+    /// Find a server on the local network to connect to instead
+    pub discover: bool,
+    /// Address to listen on for the recipients to connect to us directly, without a relay server
+    pub direct_listen: Option<String>,
+    /// Key files of the identities to chat as, each gets its own connection to every server
+    pub key_files: Vec<PathBuf>,
+    /// Recipient pubkeys to chat with, the first is shown on start
+    pub recipients: Vec<String>,
+    /// Open a conversation with anyone who sends us a note
+    pub allow_anyone: bool,
+    /// Token to present to the server's auth backend, or the invite code to join with
+    pub auth_token: Option<String>,
+    /// Hide our pubkey from the server inside the encrypted payload of notes
+    pub sealed_sender: bool,
+    /// Offer the recipient a forward secret session
+    pub forward_secrecy: bool,
+    /// Filter rules to evaluate received notes against
+    pub rules_file: Option<PathBuf>,
+    /// Shell command to run on each received note, which can mute it or reply to it
+    pub hook: Option<String>,
+    /// Webhook to forward received notes to
+    pub webhook: Option<Webhook>,
+    /// Raise a desktop notification for notes that aren't on screen
+    pub desktop_notifications: bool,
+    /// Leave the text out of desktop notifications
+    pub hide_notification_content: bool,
+    /// How to ring the bell when a note comes
+    pub bell: Bell,
+    /// Load key files even if other users can read them
+    pub insecure_key_perms: bool,
+    /// How to show note timestamps in each conversation
+    pub conversation_times: ConversationTimes,
+    /// Offer the server to compress large messages
+    pub compression: bool,
+    /// Reconnect with backoff when the connection drops, rather than quitting
+    pub reconnect: bool,
+    /// Keep a history of conversations to show again on the next start
+    pub history: bool,
+    /// Seconds between keepalive pings to the server, 0 to not ping
+    pub ping_interval: u64,
+    /// Seconds the server has to answer a ping before the connection counts as dead
+    pub ping_timeout: u64,
+    /// How to decide whether to trust the server's certificate on wss:// connections
+    pub trust: Trust,
+    /// SOCKS5 or HTTP proxy to connect through, e.g. Tor. Falls back to the standard proxy
+    /// environment variables.
+    pub proxy: Option<Proxy>,
+    /// What to do with a note piped to stdin, if anything
+    pub stdin_note: StdinNote,
+    /// Key file of a new identity to announce to the recipient
+    pub rotate_to: Option<PathBuf>,
+    /// File to record every frame sent and received to
+    pub record_session: Option<PathBuf>,
+    /// Blank out secrets and pubkeys in the recording
+    pub redact_recording: bool,
+    /// Unix socket of a daemon to attach to, rather than connecting to the server ourselves
+    pub daemon: Option<PathBuf>,
+}
+
+/// Effective daemon configuration
+pub struct DaemonConfig {
+    /// Address of the server to connect to
+    pub address: String,
+    /// Key files of the identities to stay authenticated as, each gets its own connection
+    pub key_files: Vec<PathBuf>,
+    /// Unix socket to serve the TUI and scripts on
+    pub socket: PathBuf,
+    /// Token to present to the server's auth backend
+    pub auth_token: Option<String>,
+    /// Filter rules whose commands run for notes that come while nothing is attached
+    pub rules_file: Option<PathBuf>,
+    /// Webhook to forward received notes to, whether or not anything is attached
+    pub webhook: Option<Webhook>,
+    /// Load key files even if other users can read them
+    pub insecure_key_perms: bool,
+    /// Offer the server to compress large messages
+    pub compression: bool,
+    /// Reconnect with backoff when the connection drops, rather than quitting
+    pub reconnect: bool,
+    /// Seconds between keepalive pings to the server, 0 to not ping
+    pub ping_interval: u64,
+    /// Seconds the server has to answer a ping before the connection counts as dead
+    pub ping_timeout: u64,
+    /// How to decide whether to trust the server's certificate on wss:// connections
+    pub trust: Trust,
+    /// SOCKS5 or HTTP proxy to connect through
+    pub proxy: Option<Proxy>,
+}
+
+/// What to do with a note piped to stdin
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum StdinNote {
+    /// Stdin isn't read
+    Ignore,
+    /// Pre-load it into the input box
+    Compose,
+    /// Send it once authenticated, then exit
+    Send,
+}
+
+/// How to ring the bell when a note comes
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Bell {
+    /// Don't
+    Off,
+    /// Ring the terminal's bell
+    Audible,
+    /// Flash the screen
+    Visual,
+}
+
+impl FromStr for Bell {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        <Self as ValueEnum>::from_str(s, true)
+    }
+}
+
+impl fmt::Display for Bell {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = self.to_possible_value().ok_or(fmt::Error)?;
+        write!(f, "{}", name.get_name())
+    }
+}
+
+/// Why the client shut down, broadcast to every part of it
+#[derive(Clone, Debug)]
+pub enum Shutdown {
+    /// The user quit, or the command finished
+    Quit,
+    /// The note piped to stdin was sent
+    Sent,
+    /// The server refused to authenticate us
+    AuthDenied,
+    /// A key file couldn't be loaded
+    KeyError(String),
+    /// The server couldn't be reached or the connection broke
+    ConnectionFailed(String),
+    /// The server closed the connection, with its reason if it gave one
+    ServerClosed(String),
+    /// An operator kicked or banned us, with their reason if they gave one
+    Kicked(String),
+    /// The server refused the note piped to stdin, with its error
+    NotSent(String),
+}
+
+impl Shutdown {
+    /// Process exit code to leave with
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Shutdown::Quit | Shutdown::Sent => EXIT_OK,
+            Shutdown::AuthDenied => EXIT_AUTH_DENIED,
+            Shutdown::KeyError(_) => EXIT_KEY_ERROR,
+            Shutdown::ConnectionFailed(_) | Shutdown::ServerClosed(_) => EXIT_CONNECTION_FAILED,
+            Shutdown::Kicked(_) => EXIT_KICKED,
+            Shutdown::NotSent(_) => EXIT_NOT_SENT,
+        }
+    }
+}
+
+impl fmt::Display for Shutdown {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Shutdown::Quit => write!(f, "Quit"),
+            Shutdown::Sent => write!(f, "Note sent"),
+            Shutdown::AuthDenied => write!(f, "Server denied authentication"),
+            Shutdown::ServerClosed(reason) if reason.is_empty() => {
+                write!(f, "Server closed the connection")
+            }
+            Shutdown::ServerClosed(reason) => write!(f, "Server closed the connection: {reason}"),
+            Shutdown::KeyError(e) => write!(f, "Error loading key: {e}"),
+            Shutdown::ConnectionFailed(e) => write!(f, "Connection to the server failed: {e}"),
+            Shutdown::Kicked(reason) if reason.is_empty() => write!(f, "Kicked by the server"),
+            Shutdown::Kicked(reason) => write!(f, "Kicked by the server: {reason}"),
+            Shutdown::NotSent(e) => write!(f, "Server refused the note: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for Shutdown {}
+
+impl Config {
+    /// Resolve the client config from cli args layered over env vars, config file and defaults
+    pub fn resolve(args: ClientArgs, resolver: &mut Resolver) -> Result<Self> {
+        // An invite code is just the token the invite auth backend checks
+        let auth_token = resolver.resolve_optional("auth-token", args.auth_token)?;
+        let invite = resolver.resolve_optional("invite", args.invite)?;
+        if auth_token.is_some() && invite.is_some() {
+            bail!("Only one of auth-token and invite can be set");
+        }
+        // Piping a note in is per invocation, so these are cli only
+        let stdin_note = match (&args.stdin_to, args.send) {
+            (None, _) => StdinNote::Ignore,
+            (Some(_), false) => StdinNote::Compose,
+            (Some(_), true) => StdinNote::Send,
+        };
+        let conversation_times = resolve_conversation_times(resolver, args.time_format, args.utc)?;
+        let trust = resolve_trust(resolver, args.pin_cert, args.tofu)?;
+        let recipients = match args.stdin_to {
+            Some(recipient) => vec![recipient],
+            None => split_list(&resolver.resolve_required("recipient", args.recipient)?),
+        };
+        let addresses = split_list(&resolver.resolve(
+            "address",
+            args.common.address,
+            DEFAULT_ADDRESS.into(),
+        )?);
+        if addresses.is_empty() {
+            bail!("No server address to connect to");
+        }
+        if addresses.len() > 1 {
+            if matches!(trust, Trust::Pin(_)) {
+                bail!("pin-cert only works with a single server, use tofu for several");
+            }
+            if args.daemon.is_some() {
+                bail!("A daemon connects to a single server, so can't be attached to with several");
+            }
+        }
+        Ok(Self {
+            addresses,
+            discover: resolver.resolve("discover", args.discover.then_some(true), false)?,
+            direct_listen: resolver.resolve_optional("direct-listen", args.direct_listen)?,
+            key_files: split_key_files(&resolver.resolve(
+                "key-file",
+                args.key_file,
+                DEFAULT_KEY_FILE.into(),
+            )?),
+            recipients,
+            allow_anyone: resolver.resolve(
+                "allow-anyone",
+                args.allow_anyone.then_some(true),
+                false,
+            )?,
+            auth_token: auth_token.or(invite),
+            sealed_sender: resolver.resolve(
+                "sealed-sender",
+                args.sealed_sender.then_some(true),
+                false,
+            )?,
+            forward_secrecy: resolver.resolve(
+                "forward-secrecy",
+                args.forward_secrecy.then_some(true),
+                false,
+            )?,
+            rules_file: resolver
+                .resolve_optional("rules-file", args.rules_file)?
+                .map(PathBuf::from),
+            hook: resolver.resolve_optional("hook", args.hook)?,
+            webhook: resolve_webhook(resolver, args.webhook, args.webhook_content)?,
+            desktop_notifications: resolver.resolve(
+                "desktop-notifications",
+                args.desktop_notifications.then_some(true),
+                false,
+            )?,
+            hide_notification_content: resolver.resolve(
+                "hide-notification-content",
+                args.hide_notification_content.then_some(true),
+                false,
+            )?,
+            bell: resolver.resolve("bell", args.bell, Bell::Off)?,
+            insecure_key_perms: resolver.insecure_key_perms(),
+            conversation_times,
+            compression: resolver.resolve(
+                "compression",
+                args.no_compression.then_some(false),
+                true,
+            )?,
+            reconnect: resolver.resolve("reconnect", args.no_reconnect.then_some(false), true)?,
+            history: resolver.resolve("history", args.no_history.then_some(false), true)?,
+            ping_interval: resolver.resolve(
+                "ping-interval",
+                args.ping_interval,
+                DEFAULT_PING_INTERVAL,
+            )?,
+            ping_timeout: resolver.resolve(
+                "ping-timeout",
+                args.ping_timeout,
+                DEFAULT_PING_TIMEOUT,
+            )?,
+            trust,
+            proxy: resolver
+                .resolve_optional("proxy", args.proxy)?
+                .map(|proxy| Proxy::from_str(&proxy))
+                .transpose()?,
+            stdin_note,
+            // Announcing a new key is a one off, so it's cli only
+            rotate_to: args.rotate_to.map(PathBuf::from),
+            // So is recording, which is for reproducing a bug
+            record_session: args.record_session.map(PathBuf::from),
+            redact_recording: args.redact_recording,
+            daemon: resolver
+                .resolve_optional("daemon-socket", args.daemon)?
+                .map(PathBuf::from),
+        })
+    }
+}
+
+impl DaemonConfig {
+    /// Resolve the daemon config from cli args layered over env vars, config file and defaults.
+    /// It shares the client's section, so `connect` attaches to the socket it listens on.
+    pub fn resolve(args: DaemonArgs, resolver: &mut Resolver) -> Result<Self> {
+        Ok(Self {
+            address: resolver.resolve("address", args.common.address, DEFAULT_ADDRESS.into())?,
+            key_files: split_key_files(&resolver.resolve(
+                "key-file",
+                args.key_file,
+                DEFAULT_KEY_FILE.into(),
+            )?),
+            socket: resolver
+                .resolve("daemon-socket", args.socket, DEFAULT_DAEMON_SOCKET.into())?
+                .into(),
+            auth_token: resolver.resolve_optional("auth-token", args.auth_token)?,
+            rules_file: resolver
+                .resolve_optional("rules-file", args.rules_file)?
+                .map(PathBuf::from),
+            webhook: resolve_webhook(resolver, args.webhook, args.webhook_content)?,
+            insecure_key_perms: resolver.insecure_key_perms(),
+            compression: resolver.resolve(
+                "compression",
+                args.no_compression.then_some(false),
+                true,
+            )?,
+            reconnect: resolver.resolve("reconnect", args.no_reconnect.then_some(false), true)?,
+            ping_interval: resolver.resolve(
+                "ping-interval",
+                args.ping_interval,
+                DEFAULT_PING_INTERVAL,
+            )?,
+            ping_timeout: resolver.resolve(
+                "ping-timeout",
+                args.ping_timeout,
+                DEFAULT_PING_TIMEOUT,
+            )?,
+            trust: resolve_trust(resolver, args.pin_cert, args.tofu)?,
+            proxy: resolver
+                .resolve_optional("proxy", args.proxy)?
+                .map(|proxy| Proxy::from_str(&proxy))
+                .transpose()?,
+        })
+    }
+}
+
+/// How to trust the server's certificate, from the pin-cert and tofu options
+fn resolve_trust(resolver: &mut Resolver, pin_cert: Option<String>, tofu: bool) -> Result<Trust> {
+    let pin_cert = resolver.resolve_optional("pin-cert", pin_cert)?;
+    let tofu = resolver.resolve("tofu", tofu.then_some(true), false)?;
+    Ok(match (pin_cert, tofu) {
+        (Some(_), true) => bail!("Only one of pin-cert and tofu can be set"),
+        (Some(fingerprint), false) => Trust::Pin(parse_fingerprint(&fingerprint)?),
+        (None, true) => Trust::FirstUse(KNOWN_SERVERS_PATH.into()),
+        (None, false) => Trust::Roots,
+    })
+}
+
+/// Webhook to forward received notes to, from the webhook and webhook-content options
+fn resolve_webhook(
+    resolver: &mut Resolver,
+    url: Option<String>,
+    content: bool,
+) -> Result<Option<Webhook>> {
+    let url = resolver.resolve_optional("webhook", url)?;
+    let content = resolver.resolve("webhook-content", content.then_some(true), false)?;
+    Ok(url.map(|url| Webhook::new(url, content)))
+}
+
+/// How to show timestamps by default and in the conversations with a `[conversations.<pubkey>]`
+/// table in the config file, which can set their own `time-format` or `locale`, and `timezone`,
+/// e.g. to see a peer's times as they do
+fn resolve_conversation_times(
+    resolver: &mut Resolver,
+    time_format: Option<String>,
+    utc: bool,
+) -> Result<ConversationTimes> {
+    let time_format = resolver.resolve("time-format", time_format, DEFAULT_TIME_FORMAT.into())?;
+    let timezone = match resolver.resolve("utc", utc.then_some(true), false)? {
+        true => Timezone::Utc,
+        false => Timezone::Local,
+    };
+    let default = TimeDisplay::new(time_format.clone(), timezone)?;
+    let mut by_peer = HashMap::new();
+    for peer in resolver.keyed_ids("conversations", &["time-format", "timezone", "locale"])? {
+        Recipient::from_str(&peer)
+            .map_err(|e| anyhow!("Invalid pubkey {peer} in [conversations] of config file: {e}"))?;
+        let peer_format: Option<String> =
+            resolver.resolve_keyed("conversations", &peer, "time-format")?;
+        let peer_timezone = resolver
+            .resolve_keyed("conversations", &peer, "timezone")?
+            .unwrap_or(timezone);
+        let peer_locale: Option<String> =
+            resolver.resolve_keyed("conversations", &peer, "locale")?;
+        let display = match (peer_locale, peer_format) {
+            (Some(_), Some(_)) => {
+                bail!("[conversations.{peer}] of config file sets both time-format and locale")
+            }
+            (Some(locale), None) => TimeDisplay::localized(&locale, peer_timezone)?,
+            (None, peer_format) => {
+                TimeDisplay::new(peer_format.unwrap_or(time_format.clone()), peer_timezone)?
+            }
+        };
+        by_peer.insert(peer, display);
+    }
+    Ok(ConversationTimes::new(default, by_peer))
+}
+
+/// Comma separated key files
+fn split_key_files(key_files: &str) -> Vec<PathBuf> {
+    split_list(key_files)
+        .into_iter()
+        .map(PathBuf::from)
+        .collect()
+}
+
+/// Comma separated values, e.g. recipients or server addresses
+fn split_list(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+        .map(String::from)
+        .collect()
+}
 
-/// Entrance point to client from cli
-pub async fn run(args: ClientArgs) -> Result<()> {
+/// Entrance point to client from cli, returning why it shut down
+pub async fn run(config: Config) -> Result<Shutdown> {
     // Logging
     let file = File::create(LOG_PATH)?;
     tracing_subscriber::fmt().with_writer(file).init();
     info!("🏁 Client started");
 
-    // Load the key file
-    let key_file = std::fs::read_to_string(args.key_file)?
-        .lines()
-        .filter(|line| !line.starts_with('#'))
-        .collect::<Vec<&str>>()
-        .join("\n");
-    let key = Identity::from_str(&key_file).map_err(|e| anyhow!(e))?;
-    let recipient = Recipient::from_str(&args.recipient).map_err(|e| anyhow!(e))?;
-    info!("🔑 Key file loaded");
+    // Load the key files
+    let keys = match load_keys(&config.key_files, config.insecure_key_perms) {
+        Ok(keys) => keys,
+        Err(shutdown) => return Ok(shutdown),
+    };
+    let recipients = config
+        .recipients
+        .iter()
+        .map(|recipient| Recipient::from_str(recipient).map_err(|e| anyhow!(e)))
+        .collect::<Result<Vec<_>>>()?;
+    if recipients.is_empty() {
+        bail!("No recipient to chat with");
+    }
+    info!("🔑 {} key files loaded", keys.len());
+
+    // Read a note piped in before the TUI takes over the terminal
+    let stdin_content = match config.stdin_note {
+        StdinNote::Ignore => None,
+        StdinNote::Compose | StdinNote::Send => Some(read_stdin_note()?),
+    };
+
+    // In direct mode we run the server the recipients connect to ourselves, only letting them and
+    // us in, and connect to it over loopback
+    let direct = match &config.direct_listen {
+        Some(listen) => {
+            let allow = keys
+                .iter()
+                .map(|key| key.to_public().to_string())
+                .chain(config.recipients.iter().cloned())
+                .collect();
+            let (stop, server) = server::direct(listen, allow).await?;
+            Some((stop, tokio::spawn(server)))
+        }
+        None => None,
+    };
+
+    // Find a server on the local network if asked to, asking which if there are several, before
+    // the TUI takes over the terminal
+    let addresses = if let Some(listen) = &config.direct_listen {
+        vec![loopback(listen)]
+    } else if config.discover {
+        vec![discover::find_server().await?]
+    } else {
+        config.addresses.clone()
+    };
+
+    // Load the ids of notes we've already received, to detect replays
+    let seen_notes = SeenNotes::load(Path::new(SEEN_NOTES_PATH))?;
 
     // Create a channel for coordinated shutdown
-    let (shutdown_tx, shutdown_rx) = broadcast::channel::<()>(1);
+    let (shutdown_tx, shutdown_rx) = broadcast::channel::<Shutdown>(CHANNEL_BUFFER_SIZE);
 
-    // Start communication with server
-    let addr = format!("ws://{}", args.common.address);
-    let mut comms = Comms::run(addr, shutdown_tx.clone(), shutdown_rx.resubscribe()).await?;
+    // Start communication with the servers, one connection per identity to each since each
+    // connection can only be authenticated as one user
+    let recorder = config
+        .record_session
+        .as_deref()
+        .map(|path| Recorder::create(path, config.redact_recording))
+        .transpose()?;
+    let settings = connection_settings(
+        config.compression,
+        config.reconnect,
+        config.ping_interval,
+        config.ping_timeout,
+    );
+    let mut connections = vec![];
+    for address in &addresses {
+        let addr = match server_url(address).await {
+            Ok(addr) => addr,
+            Err(shutdown) => return Ok(shutdown),
+        };
+        let dialer = dialer(&addr, &config.trust, &config.proxy, config.daemon.clone())?;
+        for key in &keys {
+            let recorder = recorder
+                .as_ref()
+                .map(|recorder| recorder.for_conn(connections.len()));
+            let comms_res = Comms::run(
+                addr.clone(),
+                dialer.clone(),
+                settings,
+                recorder,
+                shutdown_tx.clone(),
+                shutdown_rx.resubscribe(),
+            )
+            .await;
+            match comms_res {
+                Ok(comms) => connections.push((address.clone(), key.clone(), comms)),
+                Err(e) => return Ok(Shutdown::ConnectionFailed(format!("{e:#}"))),
+            }
+        }
+    }
 
     // Run the TUI
-    tui::run(&mut comms, key, recipient, shutdown_tx, shutdown_rx)?;
+    let shutdown = tui::run(
+        connections
+            .iter_mut()
+            .map(|(address, key, comms)| (address.clone(), key.clone(), comms))
+            .collect(),
+        &config,
+        recipients,
+        seen_notes,
+        stdin_content,
+        shutdown_tx,
+        shutdown_rx,
+    )
+    .await?;
 
     // Shutdown
-    comms.wait_shutdown().await?;
-    info!("🛑 Client stopped");
-    Ok(())
+    for (_, _, comms) in connections {
+        comms.wait_shutdown().await?;
+    }
+    // Our own connections are closed, let the peer know too before we go
+    if let Some((stop, server)) = direct {
+        stop.shutdown().await;
+        server.await??;
+    }
+    info!("🛑 Client stopped: {shutdown}");
+    Ok(shutdown)
+}
+
+/// Entrance point to the daemon from cli, returning why it stopped
+pub async fn daemon(config: DaemonConfig) -> Result<Shutdown> {
+    daemon::run(config).await
+}
+
+/// Entrance point to revoking a key from cli, returning why it stopped
+pub async fn revoke(
+    address: &str,
+    key_file: &Path,
+    auth_token: Option<String>,
+    reason: Option<String>,
+    insecure_key_perms: bool,
+) -> Result<Shutdown> {
+    let key_res = check_key_perms(key_file, insecure_key_perms).and_then(|_| load_key(key_file));
+    let key = match key_res {
+        Ok(key) => key,
+        Err(e) => return Ok(Shutdown::KeyError(format!("{e:#}"))),
+    };
+    revoke::run(address, key, auth_token, reason).await
+}
+
+/// Entrance point to watching presence from cli, returning why it stopped
+pub async fn presence(
+    address: &str,
+    key_file: &Path,
+    auth_token: Option<String>,
+    insecure_key_perms: bool,
+    output: Output,
+) -> Result<Shutdown> {
+    let key_res = check_key_perms(key_file, insecure_key_perms).and_then(|_| load_key(key_file));
+    let key = match key_res {
+        Ok(key) => key,
+        Err(e) => return Ok(Shutdown::KeyError(format!("{e:#}"))),
+    };
+    presence::run(address, key, auth_token, output).await
+}
+
+/// Entrance point to listening for notes from cli, returning why it stopped
+pub async fn listen(
+    address: &str,
+    key_file: &Path,
+    auth_token: Option<String>,
+    insecure_key_perms: bool,
+    output: Output,
+) -> Result<Shutdown> {
+    let key_res = check_key_perms(key_file, insecure_key_perms).and_then(|_| load_key(key_file));
+    let key = match key_res {
+        Ok(key) => key,
+        Err(e) => return Ok(Shutdown::KeyError(format!("{e:#}"))),
+    };
+    listen::run(address, key, auth_token, output).await
+}
+
+/// Entrance point to running the echo bot from cli, returning why it stopped. Only replies to the
+/// comma separated `allowed_senders` if given, at most `replies_per_minute` times each.
+pub async fn echo_bot(
+    address: &str,
+    key_file: &Path,
+    auth_token: Option<String>,
+    insecure_key_perms: bool,
+    allowed_senders: Option<&str>,
+    replies_per_minute: u32,
+) -> Result<Shutdown> {
+    let allowed_senders = allowed_senders.map(split_list);
+    for sender in allowed_senders.iter().flatten() {
+        Recipient::from_str(sender).map_err(|e| anyhow!("Invalid allowed sender {sender}: {e}"))?;
+    }
+    let key_res = check_key_perms(key_file, insecure_key_perms).and_then(|_| load_key(key_file));
+    let key = match key_res {
+        Ok(key) => key,
+        Err(e) => return Ok(Shutdown::KeyError(format!("{e:#}"))),
+    };
+    let policy = bot::ReplyPolicy::new(allowed_senders, replies_per_minute);
+    bot::echo(address, key, auth_token, policy).await
+}
+
+/// Entrance point to sending a single note from cli, returning why it stopped
+pub async fn send(
+    address: &str,
+    key_file: &Path,
+    auth_token: Option<String>,
+    insecure_key_perms: bool,
+    outgoing: Outgoing,
+    output: Output,
+) -> Result<Shutdown> {
+    let key_res = check_key_perms(key_file, insecure_key_perms).and_then(|_| load_key(key_file));
+    let key = match key_res {
+        Ok(key) => key,
+        Err(e) => return Ok(Shutdown::KeyError(format!("{e:#}"))),
+    };
+    send::run(address, key, auth_token, outgoing, output).await
+}
+
+/// Entrance point to replaying a session recording from cli
+pub fn replay(path: &Path) -> Result<()> {
+    recording::replay(path)
+}
+
+/// Websocket url to connect to, taking full ws:// or wss:// urls as is so a path behind a reverse
+/// proxy can be given, plain <host>:<port> addresses as ws://, and looking up the server of a bare
+/// domain in DNS
+async fn server_url(address: &str) -> Result<String, Shutdown> {
+    if address.contains("://") {
+        Ok(address.to_string())
+    } else if is_bare_domain(address) {
+        dns::lookup(address)
+            .await
+            .map_err(|e| Shutdown::ConnectionFailed(format!("{e:#}")))
+    } else {
+        Ok(format!("ws://{address}"))
+    }
+}
+
+/// Address to reach a server listening on an address from this machine, e.g. 127.0.0.1:42070 for
+/// 0.0.0.0:42070
+fn loopback(listen: &str) -> String {
+    match listen.parse::<SocketAddr>() {
+        Ok(addr) if addr.ip().is_unspecified() => {
+            let ip: IpAddr = match addr {
+                SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+            };
+            SocketAddr::new(ip, addr.port()).to_string()
+        }
+        _ => listen.to_string(),
+    }
+}
+
+/// Whether an address is a domain without a port, e.g. example.com
+fn is_bare_domain(address: &str) -> bool {
+    !address.contains(':') && address.contains('.') && address.parse::<IpAddr>().is_err()
+}
+
+/// Load the key files of the identities to connect as, or why we can't
+fn load_keys(key_files: &[PathBuf], insecure_key_perms: bool) -> Result<Vec<Identity>, Shutdown> {
+    let keys_res = key_files
+        .iter()
+        .map(|path| {
+            check_key_perms(path, insecure_key_perms)?;
+            load_key(path)
+        })
+        .collect::<Result<Vec<_>>>();
+    match keys_res {
+        Ok(keys) if keys.is_empty() => Err(Shutdown::KeyError("No key file given".into())),
+        Ok(keys) => Ok(keys),
+        Err(e) => Err(Shutdown::KeyError(format!("{e:#}"))),
+    }
+}
+
+/// How to open connections to the server, through the configured proxy or the one the
+/// environment says to use, or to a daemon instead
+fn dialer(
+    addr: &str,
+    trust: &Trust,
+    proxy: &Option<Proxy>,
+    daemon: Option<PathBuf>,
+) -> Result<Dialer> {
+    let dialer = Dialer {
+        tls: Tls::new(trust.clone())?,
+        proxy: match (proxy, &daemon) {
+            // The daemon connects through the proxy for us
+            (_, Some(_)) => None,
+            (Some(proxy), None) => Some(proxy.clone()),
+            (None, None) => Proxy::from_env(addr)?,
+        },
+        daemon,
+    };
+    if let Some(path) = &dialer.daemon {
+        info!("🔌 Attaching to daemon at {}", path.display());
+    }
+    if let Some(proxy) = &dialer.proxy {
+        info!("🧅 Connecting through proxy {proxy}");
+    }
+    Ok(dialer)
+}
+
+/// How the connection to the server behaves, from the configured seconds
+fn connection_settings(
+    compression: bool,
+    reconnect: bool,
+    ping_interval: u64,
+    ping_timeout: u64,
+) -> ConnectionSettings {
+    ConnectionSettings {
+        compression,
+        reconnect,
+        ping_interval: (ping_interval > 0).then(|| Duration::from_secs(ping_interval)),
+        ping_timeout: Duration::from_secs(ping_timeout),
+    }
+}
+
+/// Text of a note to send: the message given, or else read from a file or piped to stdin
+pub fn note_content(message: Option<String>, file: Option<&Path>) -> Result<String> {
+    if let Some(message) = message {
+        let message = message.trim_end();
+        if message.is_empty() {
+            bail!("The note is empty");
+        }
+        return Ok(message.to_string());
+    }
+    match file {
+        Some(path) => {
+            let file = File::open(path).context(format!("Error opening {}", path.display()))?;
+            read_note(file, &path.display().to_string())
+        }
+        None => {
+            let stdin = std::io::stdin();
+            if stdin.is_terminal() {
+                bail!("No note given, pass it as an argument, with --file or piped to stdin");
+            }
+            read_note(stdin, "stdin")
+        }
+    }
+}
+
+/// Read a note piped to stdin, refusing to wait on a terminal
+fn read_stdin_note() -> Result<String> {
+    let stdin = std::io::stdin();
+    if stdin.is_terminal() {
+        bail!("--stdin-to needs the note piped to stdin");
+    }
+    read_note(stdin, "stdin")
+}
+
+/// Read a note, which can span lines, refusing ones too big to send rather than reading
+/// without end
+fn read_note(reader: impl Read, source: &str) -> Result<String> {
+    let mut content = String::new();
+    reader
+        .take(MAX_NOTE_INPUT_BYTES + 1)
+        .read_to_string(&mut content)
+        .context(format!("Error reading note from {source}"))?;
+    if content.len() as u64 > MAX_NOTE_INPUT_BYTES {
+        bail!("Note from {source} is larger than the limit of {MAX_NOTE_INPUT_BYTES} bytes");
+    }
+    let content = content.trim_end();
+    if content.is_empty() {
+        bail!("No note in {source}");
+    }
+    Ok(content.to_string())
 }
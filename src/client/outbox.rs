@@ -0,0 +1,169 @@
+use age::x25519::{Identity, Recipient};
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use super::history::{open_text, seal_text};
+use crate::common::Note;
+
+/// Persistent queue of notes composed while disconnected, sent in order once the identity they're
+/// from authenticates again, even after a restart. They're kept unencrypted in memory until sent
+/// so they can go over whatever session is active by then, and sealed to their identity on disk.
+/// Sent notes stay until the server accepts them, so they're sent again if we quit before then.
+pub struct Outbox {
+    path: PathBuf,
+    notes: Vec<PendingNote>,
+    /// Notes from identities we aren't chatting as this run, kept as they were
+    others: Vec<SavedNote>,
+}
+
+/// A note waiting to be sent
+#[derive(Clone, Debug)]
+pub struct PendingNote {
+    pub from: String,
+    pub to: String,
+    pub content: String,
+    pub queued_at: DateTime<Utc>,
+    /// The note as sent, once it has been, waiting for the server to accept it
+    pub sent: Option<Note>,
+}
+
+/// A note waiting to be sent as saved to disk
+#[derive(Clone, Serialize, Deserialize)]
+struct SavedNote {
+    from: String,
+    to: String,
+    /// Content sealed to the identity it's from
+    #[serde(default)]
+    sealed: Option<String>,
+    /// Plaintext content, from before the outbox was sealed
+    #[serde(default, skip_serializing)]
+    content: Option<String>,
+    queued_at: DateTime<Utc>,
+    #[serde(default)]
+    sent: Option<Note>,
+}
+
+impl Outbox {
+    /// Load the queued notes from a json file, if it exists, opening the ones from our identities
+    pub fn load(path: &Path, identities: &[Identity]) -> Result<Self> {
+        let saved: Vec<SavedNote> = match fs::read_to_string(path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .context(format!("Error parsing outbox {}", path.display()))?,
+            Err(e) if e.kind() == ErrorKind::NotFound => vec![],
+            Err(e) => return Err(e.into()),
+        };
+        let mut outbox = Self {
+            path: path.to_path_buf(),
+            notes: vec![],
+            others: vec![],
+        };
+        let mut plaintext = false;
+        for mut note in saved {
+            let identity = identities
+                .iter()
+                .find(|identity| identity.to_public().to_string() == note.from);
+            let content = match (identity, note.sealed.as_deref(), note.content.take()) {
+                (Some(identity), Some(sealed), _) => open_text(identity, sealed)
+                    .context(format!("Error opening outbox {}", path.display()))?,
+                (Some(_), None, Some(content)) => {
+                    plaintext = true;
+                    content
+                }
+                // Seal plaintext left by older versions even if it's not ours to send this run
+                (None, None, Some(content)) => {
+                    plaintext = true;
+                    note.sealed = Some(seal_text(&recipient(&note.from)?, &content)?);
+                    outbox.others.push(note);
+                    continue;
+                }
+                (_, None, None) | (None, Some(_), _) => {
+                    outbox.others.push(note);
+                    continue;
+                }
+            };
+            outbox.notes.push(PendingNote {
+                from: note.from,
+                to: note.to,
+                content,
+                queued_at: note.queued_at,
+                sent: note.sent,
+            });
+        }
+        if plaintext {
+            outbox.save()?;
+        }
+        Ok(outbox)
+    }
+
+    /// Queue a note to send later, or keep one sent until it's accepted
+    pub fn push(&mut self, note: PendingNote) -> Result<()> {
+        self.notes.push(note);
+        self.save()
+    }
+
+    /// Notes waiting to be sent or accepted from an identity to a recipient, oldest first
+    pub fn pending<'a>(
+        &'a self,
+        from: &'a str,
+        to: &'a str,
+    ) -> impl Iterator<Item = &'a PendingNote> + 'a {
+        self.notes
+            .iter()
+            .filter(move |note| note.from == from && note.to == to)
+    }
+
+    /// Take every note waiting from an identity, oldest first, to send them. They're only gone
+    /// from disk once the outbox is next saved, so push them back once sent.
+    pub fn take_from(&mut self, from: &str) -> Vec<PendingNote> {
+        let (taken, kept) = self.notes.drain(..).partition(|note| note.from == from);
+        self.notes = kept;
+        taken
+    }
+
+    /// Forget a sent note the server echoed back
+    pub fn ack(&mut self, id: &str) -> Result<()> {
+        let before = self.notes.len();
+        self.notes
+            .retain(|note| note.sent.as_ref().is_none_or(|sent| sent.id != id));
+        if self.notes.len() != before {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let mut saved = Vec::with_capacity(self.notes.len() + self.others.len());
+        for note in &self.notes {
+            saved.push(SavedNote {
+                from: note.from.clone(),
+                to: note.to.clone(),
+                sealed: Some(seal_text(&recipient(&note.from)?, &note.content)?),
+                content: None,
+                queued_at: note.queued_at,
+                sent: note.sent.clone(),
+            });
+        }
+        saved.extend(self.others.iter().cloned());
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options
+            .open(&self.path)
+            .context(format!("Error saving outbox to {}", self.path.display()))?;
+        file.write_all(serde_json::to_string(&saved)?.as_bytes())?;
+        Ok(())
+    }
+}
+
+fn recipient(pub_key: &str) -> Result<Recipient> {
+    Recipient::from_str(pub_key).map_err(|e| anyhow!("Invalid pubkey {pub_key} in outbox: {e}"))
+}
@@ -0,0 +1,61 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use serde::Serialize;
+
+use super::Shutdown;
+
+/// How the headless subcommands print what happens
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Output {
+    /// Human readable lines
+    Text,
+    /// One JSON object per line, for scripts
+    Json,
+}
+
+/// Something that happened, printed as a JSON object tagged with its `event`
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    /// We received a note
+    Note {
+        id: &'a str,
+        from: &'a str,
+        timestamp: DateTime<Utc>,
+        content: &'a str,
+    },
+    /// The server accepted a note we sent
+    Sent {
+        id: &'a str,
+        to: &'a str,
+        timestamp: DateTime<Utc>,
+    },
+    /// Our chat session came online or went offline, or notes were queued for it
+    Presence { online: bool, queued_notes: usize },
+    /// The connection dropped and we're reconnecting
+    Reconnecting { reason: &'a str },
+    /// The subcommand failed, with the code it exits with
+    Failed { code: i32, error: String },
+}
+
+impl Event<'_> {
+    pub fn print(&self) -> Result<()> {
+        println!("{}", serde_json::to_string(self)?);
+        Ok(())
+    }
+}
+
+/// Print why a subcommand failed as an event, if it did and we're printing JSON. Errors still go
+/// to stderr too.
+pub fn print_failure(output: Output, shutdown: &Shutdown) -> Result<()> {
+    let code = shutdown.exit_code();
+    if output == Output::Json && code != super::EXIT_OK {
+        Event::Failed {
+            code,
+            error: shutdown.to_string(),
+        }
+        .print()?;
+    }
+    Ok(())
+}
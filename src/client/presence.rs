@@ -0,0 +1,117 @@
+use age::x25519::Identity;
+use anyhow::{bail, Result};
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+use super::comms::{Comms, ConnectionSettings, Dialer};
+use super::output::{Event, Output};
+use super::tls::{Tls, Trust};
+use super::Shutdown;
+use crate::common::{
+    signing_key, Auth, AuthChallenge, ClientMsg, Hello, Presence, ServerMsg, CHANNEL_BUFFER_SIZE,
+    PRESENCE_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
+
+/// Authenticate as a key without taking its chat session, printing a line each time the
+/// session comes online or goes offline, or notes are queued for it. Meant for status bars.
+pub async fn run(
+    address: &str,
+    key: Identity,
+    auth_token: Option<String>,
+    output: Output,
+) -> Result<Shutdown> {
+    let (shutdown_tx, mut shutdown_rx) = broadcast::channel::<Shutdown>(CHANNEL_BUFFER_SIZE);
+    let addr = match super::server_url(address).await {
+        Ok(addr) => addr,
+        Err(shutdown) => return Ok(shutdown),
+    };
+    let comms_res = Comms::run(
+        addr,
+        Dialer {
+            tls: Tls::new(Trust::Roots)?,
+            proxy: None,
+            daemon: None,
+        },
+        ConnectionSettings {
+            // Only small messages go over it, nothing worth compressing
+            compression: false,
+            // It's a one off, so a dropped connection just ends it
+            reconnect: false,
+            ping_interval: None,
+            ping_timeout: Duration::ZERO,
+        },
+        None,
+        shutdown_tx.clone(),
+        shutdown_rx.resubscribe(),
+    )
+    .await;
+    let mut comms = match comms_res {
+        Ok(comms) => comms,
+        Err(e) => return Ok(Shutdown::ConnectionFailed(format!("{e:#}"))),
+    };
+    let res = watch(&mut comms, &key, auth_token, output).await;
+    _ = shutdown_tx.send(Shutdown::Quit);
+    comms.wait_shutdown().await?;
+    match res? {
+        Some(shutdown) => Ok(shutdown),
+        // The connection ended, comms says why
+        None => Ok(shutdown_rx.try_recv().unwrap_or(Shutdown::ConnectionFailed(
+            "Connection to server closed".into(),
+        ))),
+    }
+}
+
+/// Do the auth handshake, then print presence until the connection ends. Returns why it ended if
+/// it wasn't the connection closing.
+async fn watch(
+    comms: &mut Comms,
+    key: &Identity,
+    auth_token: Option<String>,
+    output: Output,
+) -> Result<Option<Shutdown>> {
+    let pub_key = key.to_public().to_string();
+    let signing_key = hex::encode(signing_key(key).verifying_key().as_bytes());
+    comms.try_send_msg(ClientMsg::Hello(Hello {
+        protocol_version: PROTOCOL_VERSION,
+        presence_only: true,
+        compression: false,
+    }))?;
+    comms.try_send_msg(ClientMsg::AuthReq(Auth::new(
+        pub_key,
+        signing_key,
+        auth_token,
+    )))?;
+
+    while let Ok(msg) = comms.recv_msg().await {
+        match msg {
+            ServerMsg::HelloAck(ack) if ack.protocol_version < PRESENCE_PROTOCOL_VERSION => {
+                bail!("Server doesn't support presence-only connections");
+            }
+            ServerMsg::AuthSecret(auth) => {
+                let challenge = AuthChallenge::decrypt(key, &auth.ciphertext)?;
+                comms.try_send_msg(ClientMsg::AuthPlaintext(Auth {
+                    plaintext: challenge.nonce,
+                    ..auth
+                }))?;
+            }
+            ServerMsg::AuthDenied(_) => return Ok(Some(Shutdown::AuthDenied)),
+            ServerMsg::Presence(presence) => match output {
+                Output::Text => println!("{}", format_presence(&presence)),
+                Output::Json => Event::Presence {
+                    online: presence.online,
+                    queued_notes: presence.queued_notes,
+                }
+                .print()?,
+            },
+            ServerMsg::Error(e) => bail!("Server error: {e}"),
+            _ => {}
+        }
+    }
+    Ok(None)
+}
+
+/// One line for a status bar to show, e.g. `online 0` or `offline 3`
+fn format_presence(presence: &Presence) -> String {
+    let state = if presence.online { "online" } else { "offline" };
+    format!("{state} {}", presence.queued_notes)
+}
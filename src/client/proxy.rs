@@ -0,0 +1,207 @@
+use anyhow::{bail, Context, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use std::env;
+use std::fmt;
+use std::str::FromStr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_socks::tcp::Socks5Stream;
+
+/// Longest response to a CONNECT request we'll read before giving up on the proxy
+const MAX_CONNECT_RESPONSE_BYTES: usize = 8192;
+
+/// Proxy to reach the server through, e.g. Tor's at socks5://127.0.0.1:9050 or a corporate
+/// http://proxy:3128
+#[derive(Clone, Debug)]
+pub struct Proxy {
+    protocol: Protocol,
+    /// <host>:<port> of the proxy
+    address: String,
+    /// Username and password to authenticate with. Tor isolates streams by them.
+    credentials: Option<(String, String)>,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Protocol {
+    Socks5,
+    /// Tunnels with the CONNECT method
+    Http,
+}
+
+impl Proxy {
+    /// The proxy the standard environment variables say to reach a url through, if any:
+    /// `HTTPS_PROXY` for wss:// urls, `HTTP_PROXY` for ws:// ones and `ALL_PROXY` for either,
+    /// unless the host is in `NO_PROXY`
+    pub fn from_env(url: &str) -> Result<Option<Self>> {
+        let (scheme, rest) = url.split_once("://").unwrap_or(("ws", url));
+        let authority = rest.split('/').next().unwrap_or_default();
+        let host = split_host_port(authority).map_or(authority, |(host, _)| host);
+        if env_var("NO_PROXY").is_some_and(|no_proxy| no_proxy_matches(&no_proxy, host)) {
+            return Ok(None);
+        }
+        let specific = if scheme == "wss" {
+            "HTTPS_PROXY"
+        } else {
+            "HTTP_PROXY"
+        };
+        [specific, "ALL_PROXY"]
+            .into_iter()
+            .find_map(|name| env_var(name).map(|proxy| (name, proxy)))
+            .map(|(name, proxy)| Self::from_str(&proxy).context(format!("Invalid proxy in {name}")))
+            .transpose()
+    }
+
+    /// Open a TCP connection to a host through the proxy. The proxy resolves the host, so no DNS
+    /// lookups leak around it and onion services can be reached.
+    pub async fn connect(&self, host: &str, port: u16) -> Result<TcpStream> {
+        let res = match self.protocol {
+            Protocol::Socks5 => self.connect_socks5(host, port).await,
+            Protocol::Http => self.connect_http(host, port).await,
+        };
+        res.context(format!(
+            "Cannot connect to {host}:{port} through proxy {self}"
+        ))
+    }
+
+    async fn connect_socks5(&self, host: &str, port: u16) -> Result<TcpStream> {
+        let target = (host, port);
+        let stream = match &self.credentials {
+            Some((username, password)) => {
+                Socks5Stream::connect_with_password(
+                    self.address.as_str(),
+                    target,
+                    username,
+                    password,
+                )
+                .await?
+            }
+            None => Socks5Stream::connect(self.address.as_str(), target).await?,
+        };
+        Ok(stream.into_inner())
+    }
+
+    /// Ask the proxy to open a tunnel with CONNECT, then talk through it as if it were a plain
+    /// connection to the host
+    async fn connect_http(&self, host: &str, port: u16) -> Result<TcpStream> {
+        let mut stream = TcpStream::connect(&self.address).await?;
+        let target = if host.contains(':') {
+            format!("[{host}]:{port}")
+        } else {
+            format!("{host}:{port}")
+        };
+        let mut request = format!("CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n");
+        if let Some((username, password)) = &self.credentials {
+            let credentials = BASE64.encode(format!("{username}:{password}"));
+            request.push_str(&format!("Proxy-Authorization: Basic {credentials}\r\n"));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes()).await?;
+
+        // Read the response a byte at a time, so nothing the server sends after it is consumed
+        let mut response = vec![];
+        while !response.ends_with(b"\r\n\r\n") {
+            if response.len() >= MAX_CONNECT_RESPONSE_BYTES {
+                bail!("Proxy response is too long");
+            }
+            let byte = stream
+                .read_u8()
+                .await
+                .context("Proxy closed the connection")?;
+            response.push(byte);
+        }
+        let response = String::from_utf8_lossy(&response);
+        let status_line = response.lines().next().unwrap_or_default();
+        let status = status_line.split_whitespace().nth(1).unwrap_or_default();
+        if status != "200" {
+            bail!("Proxy refused to tunnel: {status_line}");
+        }
+        Ok(stream)
+    }
+}
+
+impl FromStr for Proxy {
+    type Err = anyhow::Error;
+
+    /// Parse a <scheme>://[<username>:<password>@]<host>[:<port>] url, where the scheme is
+    /// socks5 or http. socks5h:// is taken too, the proxy always resolves hosts either way.
+    fn from_str(url: &str) -> Result<Self> {
+        let (protocol, default_port, rest) = if let Some(rest) = url
+            .strip_prefix("socks5://")
+            .or_else(|| url.strip_prefix("socks5h://"))
+        {
+            (Protocol::Socks5, 1080, rest)
+        } else if let Some(rest) = url.strip_prefix("http://") {
+            (Protocol::Http, 80, rest)
+        } else {
+            bail!("Invalid proxy, expected socks5://<host>:<port> or http://<host>:<port>");
+        };
+        let (credentials, address) = match rest.rsplit_once('@') {
+            Some((credentials, address)) => {
+                let Some((username, password)) = credentials.split_once(':') else {
+                    bail!("Invalid proxy credentials, expected <username>:<password>");
+                };
+                (Some((username.to_string(), password.to_string())), address)
+            }
+            None => (None, rest),
+        };
+        let address = address.trim_end_matches('/');
+        let address = match split_host_port(address) {
+            Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {
+                address.to_string()
+            }
+            Some(_) => bail!("Invalid proxy address {address}, expected <host>:<port>"),
+            None if !address.is_empty() => format!("{address}:{default_port}"),
+            None => bail!("Invalid proxy, it has no host"),
+        };
+        Ok(Self {
+            protocol,
+            address,
+            credentials,
+        })
+    }
+}
+
+impl fmt::Display for Proxy {
+    /// The proxy's url, leaving out the password
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let scheme = match self.protocol {
+            Protocol::Socks5 => "socks5",
+            Protocol::Http => "http",
+        };
+        match &self.credentials {
+            Some((username, _)) => write!(f, "{scheme}://{username}@{}", self.address),
+            None => write!(f, "{scheme}://{}", self.address),
+        }
+    }
+}
+
+/// Read a proxy environment variable, which are conventionally either upper or lower case
+fn env_var(name: &str) -> Option<String> {
+    env::var(name)
+        .or_else(|_| env::var(name.to_lowercase()))
+        .ok()
+        .filter(|value| !value.is_empty())
+}
+
+/// Split <host>:<port>, leaving the brackets off IPv6 hosts. None if there's no port.
+fn split_host_port(address: &str) -> Option<(&str, &str)> {
+    if let Some(rest) = address.strip_prefix('[') {
+        let (host, port) = rest.split_once(']')?;
+        return Some((host, port.strip_prefix(':')?));
+    }
+    address.rsplit_once(':')
+}
+
+/// Whether a host is excluded from proxying by a `NO_PROXY` list, e.g. `localhost,.internal`
+/// matches localhost and any subdomain of internal
+fn no_proxy_matches(no_proxy: &str, host: &str) -> bool {
+    no_proxy.split(',').map(str::trim).any(|entry| {
+        let domain = entry.trim_start_matches("*.").trim_start_matches('.');
+        entry == "*"
+            || (!domain.is_empty()
+                && (host == domain
+                    || host
+                        .strip_suffix(domain)
+                        .is_some_and(|prefix| prefix.ends_with('.'))))
+    })
+}
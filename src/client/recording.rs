@@ -0,0 +1,205 @@
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs::{self, File};
+use std::io::{LineWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use tokio_tungstenite::tungstenite::Message;
+use tracing::error;
+
+use crate::common::{ClientMsg, ServerMsg};
+
+/// Fields blanked out of recorded frames when redacting: auth secrets and tokens, and anything
+/// identifying users. Notes are only ever recorded encrypted either way.
+const REDACTED_FIELDS: [&str; 7] = [
+    "plaintext",
+    "token",
+    "pub_key",
+    "signing_key",
+    "from",
+    "to",
+    "ip",
+];
+const REDACTED: &str = "[redacted]";
+
+/// Which way a frame went
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+/// A websocket frame as written to the recording, one json object per line
+#[derive(Debug, Serialize, Deserialize)]
+struct Frame {
+    at: DateTime<Utc>,
+    /// Which of the client's connections it went over, one per identity
+    conn: usize,
+    direction: Direction,
+    kind: FrameKind,
+    /// Text of text frames, reason of close frames and hex of binary frames
+    #[serde(default)]
+    payload: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum FrameKind {
+    Text,
+    Binary,
+    Ping,
+    Pong,
+    Close,
+}
+
+/// Writes every frame the client sends and receives to a file, so protocol bugs can be
+/// reproduced with `replay`
+#[derive(Clone)]
+pub struct Recorder {
+    file: Arc<Mutex<LineWriter<File>>>,
+    redact: bool,
+    conn: usize,
+}
+
+impl Recorder {
+    /// Start a recording, replacing any old one at the path. It can hold auth tokens, so only we
+    /// can read it.
+    pub fn create(path: &Path, redact: bool) -> Result<Self> {
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let file = options.open(path).context(format!(
+            "Error creating session recording {}",
+            path.display()
+        ))?;
+        Ok(Self {
+            file: Arc::new(Mutex::new(LineWriter::new(file))),
+            redact,
+            conn: 0,
+        })
+    }
+
+    /// The same recording, tagging frames as going over another connection
+    pub fn for_conn(&self, conn: usize) -> Self {
+        Self {
+            conn,
+            ..self.clone()
+        }
+    }
+
+    /// Record a frame, logging rather than failing if it can't be written
+    pub fn record(&self, direction: Direction, ws_msg: &Message) {
+        let (kind, payload) = match ws_msg {
+            Message::Text(text) if self.redact => (FrameKind::Text, redact(text)),
+            Message::Text(text) => (FrameKind::Text, text.to_string()),
+            Message::Binary(data) => (FrameKind::Binary, hex::encode(data)),
+            Message::Ping(_) => (FrameKind::Ping, String::new()),
+            Message::Pong(_) => (FrameKind::Pong, String::new()),
+            Message::Close(frame) => (
+                FrameKind::Close,
+                frame
+                    .as_ref()
+                    .map(|frame| frame.reason.to_string())
+                    .unwrap_or_default(),
+            ),
+            Message::Frame(_) => return,
+        };
+        let frame = Frame {
+            at: Utc::now(),
+            conn: self.conn,
+            direction,
+            kind,
+            payload,
+        };
+        let res = serde_json::to_string(&frame)
+            .map_err(Into::into)
+            .and_then(|line| {
+                let mut file = self.file.lock().expect("Recording lock poisoned");
+                writeln!(file, "{line}")
+            });
+        if let Err(e) = res {
+            error!("📼 Error recording frame: {e}");
+        }
+    }
+}
+
+/// Blank out the redacted fields of a json message, leaving it parseable
+fn redact(text: &str) -> String {
+    let Ok(mut value) = serde_json::from_str::<Value>(text) else {
+        return REDACTED.into();
+    };
+    redact_value(&mut value);
+    value.to_string()
+}
+
+fn redact_value(value: &mut Value) {
+    match value {
+        Value::Object(fields) => {
+            for (name, field) in fields.iter_mut() {
+                if REDACTED_FIELDS.contains(&name.as_str()) && field.is_string() {
+                    *field = Value::String(REDACTED.into());
+                } else {
+                    redact_value(field);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(redact_value),
+        _ => {}
+    }
+}
+
+/// Feed a recording back through the message parser, printing each message and failing if any
+/// didn't parse
+pub fn replay(path: &Path) -> Result<()> {
+    let recording = fs::read_to_string(path).context(format!(
+        "Error reading session recording {}",
+        path.display()
+    ))?;
+    let mut frames = 0;
+    let mut failures = 0;
+    for (i, line) in recording
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.is_empty())
+    {
+        frames += 1;
+        let frame: Frame = match serde_json::from_str(line) {
+            Ok(frame) => frame,
+            Err(e) => {
+                failures += 1;
+                println!("line {}: not a recorded frame: {e}", i + 1);
+                continue;
+            }
+        };
+        let prefix = format!("{} #{} {:?}", frame.at, frame.conn, frame.direction);
+        let FrameKind::Text = frame.kind else {
+            println!("{prefix} {:?} {}", frame.kind, frame.payload);
+            continue;
+        };
+        let parsed = match frame.direction {
+            Direction::Sent => ClientMsg::from_str(&frame.payload).map(|msg| msg.to_string()),
+            Direction::Received => ServerMsg::from_str(&frame.payload).map(|msg| msg.to_string()),
+        };
+        match parsed {
+            Ok(msg) => println!("{prefix} {msg}"),
+            Err(e) => {
+                failures += 1;
+                println!("line {}: {prefix} failed to parse: {e}", i + 1);
+                println!("  {}", frame.payload);
+            }
+        }
+    }
+    if failures > 0 {
+        bail!("{failures} of {frames} frames failed to parse");
+    }
+    println!("All {frames} frames parsed");
+    Ok(())
+}
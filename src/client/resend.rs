@@ -0,0 +1,101 @@
+use chrono::{DateTime, Utc};
+use std::time::{Duration, Instant};
+
+use crate::common::Note;
+
+/// Times a note is sent before giving up on the server accepting it
+const MAX_SEND_ATTEMPTS: u32 = 5;
+/// Wait for the server to accept a note before sending it again, doubled after each attempt
+const RESEND_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Notes sent that the server hasn't echoed back yet. They're sent again with backoff, including
+/// after reconnecting, until the server accepts them or they run out of attempts and are marked
+/// failed for the user to retry.
+#[derive(Default)]
+pub struct Unacked {
+    notes: Vec<SentNote>,
+}
+
+/// A note sent but not accepted yet
+pub struct SentNote {
+    pub note: Note,
+    /// Decrypted content, to show while it's waiting
+    pub content: String,
+    pub sent_at: DateTime<Utc>,
+    attempts: u32,
+    retry_at: Instant,
+    pub failed: bool,
+}
+
+impl Unacked {
+    /// Start waiting for the server to accept a note we just sent
+    pub fn track(&mut self, note: Note, content: String) {
+        self.notes.push(SentNote {
+            note,
+            content,
+            sent_at: Utc::now(),
+            attempts: 1,
+            retry_at: Instant::now() + RESEND_BACKOFF,
+            failed: false,
+        });
+    }
+
+    /// Stop waiting on a note the server echoed back. Returns false if it wasn't one we were
+    /// waiting on.
+    pub fn ack(&mut self, id: &str) -> bool {
+        let before = self.notes.len();
+        self.notes.retain(|sent| sent.note.id != id);
+        self.notes.len() != before
+    }
+
+    /// Whether we're waiting on the server to accept a note
+    pub fn contains(&self, id: &str) -> bool {
+        self.notes.iter().any(|sent| sent.note.id == id)
+    }
+
+    /// Notes due to be sent again, oldest first, counting the attempt. Ones out of attempts are
+    /// marked failed instead and returned separately.
+    pub fn due(&mut self) -> (Vec<Note>, Vec<Note>) {
+        let now = Instant::now();
+        let mut resend = Vec::new();
+        let mut failed = Vec::new();
+        for sent in self
+            .notes
+            .iter_mut()
+            .filter(|sent| !sent.failed && sent.retry_at <= now)
+        {
+            if sent.attempts >= MAX_SEND_ATTEMPTS {
+                sent.failed = true;
+                failed.push(sent.note.clone());
+                continue;
+            }
+            sent.retry_at = now + RESEND_BACKOFF * 2u32.pow(sent.attempts);
+            sent.attempts += 1;
+            resend.push(sent.note.clone());
+        }
+        (resend, failed)
+    }
+
+    /// Give the failed notes to a peer another round of attempts, starting now. Returns how many
+    /// there were.
+    pub fn retry_failed(&mut self, to: &str) -> usize {
+        let now = Instant::now();
+        let mut retried = 0;
+        for sent in self
+            .notes
+            .iter_mut()
+            .filter(|sent| sent.failed && sent.note.to == to)
+        {
+            sent.failed = false;
+            sent.attempts = 0;
+            sent.retry_at = now;
+            retried += 1;
+        }
+        retried
+    }
+
+    /// Notes to a peer still waiting to be accepted, oldest first
+    pub fn to<'a>(&'a self, to: &'a str) -> impl Iterator<Item = &'a SentNote> + 'a {
+        self.notes.iter().filter(move |sent| sent.note.to == to)
+    }
+}
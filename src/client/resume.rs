@@ -0,0 +1,65 @@
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+/// Persistent resumption tokens of each of our identities on each server, so a client restarted
+/// after its connection dropped can skip the key challenge. A token lets anyone holding it resume
+/// the session until it expires, so only we can read the file.
+pub struct ResumeTokens {
+    path: PathBuf,
+    tokens: HashMap<String, String>,
+}
+
+impl ResumeTokens {
+    /// Load the tokens from a file of `<pubkey>@<server> <token>` lines, if it exists
+    pub fn load(path: &Path) -> Result<Self> {
+        let tokens = match fs::read_to_string(path) {
+            Ok(contents) => contents
+                .lines()
+                .filter_map(|line| line.split_once(' '))
+                .map(|(pub_key, token)| (pub_key.to_string(), token.to_string()))
+                .collect(),
+            Err(e) if e.kind() == ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            tokens,
+        })
+    }
+
+    /// Take an identity's token to resume with. Tokens are single use, so it's forgotten.
+    pub fn take(&mut self, pub_key: &str) -> Result<Option<String>> {
+        let token = self.tokens.remove(pub_key);
+        if token.is_some() {
+            self.save()?;
+        }
+        Ok(token)
+    }
+
+    /// Remember the token the server gave an identity
+    pub fn set(&mut self, pub_key: &str, token: String) -> Result<()> {
+        self.tokens.insert(pub_key.to_string(), token);
+        self.save()
+    }
+
+    fn save(&self) -> Result<()> {
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options.open(&self.path).context(format!(
+            "Error saving resumption tokens to {}",
+            self.path.display()
+        ))?;
+        for (pub_key, token) in &self.tokens {
+            writeln!(file, "{pub_key} {token}")?;
+        }
+        Ok(())
+    }
+}
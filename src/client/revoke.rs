@@ -0,0 +1,109 @@
+use age::x25519::Identity;
+use anyhow::{anyhow, bail, Result};
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+use super::comms::{Comms, ConnectionSettings, Dialer};
+use super::tls::{Tls, Trust};
+use super::Shutdown;
+use crate::common::{
+    signing_key, Auth, AuthChallenge, ClientMsg, Hello, Revocation, ServerMsg, PROTOCOL_VERSION,
+    REVOCATION_PROTOCOL_VERSION,
+};
+
+/// Authenticate as a key and revoke it, so the server bans it and tells our contacts to stop
+/// trusting it
+pub async fn run(
+    address: &str,
+    key: Identity,
+    auth_token: Option<String>,
+    reason: Option<String>,
+) -> Result<Shutdown> {
+    let pub_key = key.to_public().to_string();
+    let (shutdown_tx, shutdown_rx) = broadcast::channel::<Shutdown>(1);
+    let addr = match super::server_url(address).await {
+        Ok(addr) => addr,
+        Err(shutdown) => return Ok(shutdown),
+    };
+    let comms_res = Comms::run(
+        addr,
+        Dialer {
+            tls: Tls::new(Trust::Roots)?,
+            proxy: None,
+            daemon: None,
+        },
+        ConnectionSettings {
+            // Only small messages go over it, nothing worth compressing
+            compression: false,
+            // It's a one off, so a dropped connection just ends it
+            reconnect: false,
+            ping_interval: None,
+            ping_timeout: Duration::ZERO,
+        },
+        None,
+        shutdown_tx.clone(),
+        shutdown_rx,
+    )
+    .await;
+    let mut comms = match comms_res {
+        Ok(comms) => comms,
+        Err(e) => return Ok(Shutdown::ConnectionFailed(format!("{e:#}"))),
+    };
+    let res = revoke(&mut comms, &key, &pub_key, auth_token, reason).await;
+    _ = shutdown_tx.send(Shutdown::Quit);
+    comms.wait_shutdown().await?;
+    let shutdown = res?;
+    if let Shutdown::Quit = shutdown {
+        println!("Revoked {pub_key}");
+    }
+    Ok(shutdown)
+}
+
+/// Do the auth handshake, then send the revocation and wait for the server to confirm it
+async fn revoke(
+    comms: &mut Comms,
+    key: &Identity,
+    pub_key: &str,
+    auth_token: Option<String>,
+    reason: Option<String>,
+) -> Result<Shutdown> {
+    let signing_key = hex::encode(signing_key(key).verifying_key().as_bytes());
+    comms.try_send_msg(ClientMsg::Hello(Hello {
+        protocol_version: PROTOCOL_VERSION,
+        presence_only: false,
+        compression: false,
+    }))?;
+    comms.try_send_msg(ClientMsg::AuthReq(Auth::new(
+        pub_key.to_string(),
+        signing_key,
+        auth_token,
+    )))?;
+
+    let mut reason = Some(reason);
+    loop {
+        match comms.recv_msg().await? {
+            ServerMsg::HelloAck(ack) if ack.protocol_version < REVOCATION_PROTOCOL_VERSION => {
+                bail!("Server doesn't support revoking keys");
+            }
+            ServerMsg::AuthSecret(auth) => {
+                let challenge = AuthChallenge::decrypt(key, &auth.ciphertext)?;
+                comms.try_send_msg(ClientMsg::AuthPlaintext(Auth {
+                    plaintext: challenge.nonce,
+                    ..auth
+                }))?;
+            }
+            ServerMsg::AuthGranted(_) => {
+                let reason = reason
+                    .take()
+                    .ok_or(anyhow!("Authenticated more than once"))?;
+                comms.try_send_msg(ClientMsg::Revoke(Revocation::new(key, reason)))?;
+            }
+            ServerMsg::AuthDenied(_) => return Ok(Shutdown::AuthDenied),
+            ServerMsg::Error(e) => bail!("Server error: {e}"),
+            ServerMsg::Revoked(revocation) if revocation.pub_key == pub_key => {
+                return Ok(Shutdown::Quit)
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,115 @@
+use anyhow::{Context, Result};
+use regex::Regex;
+use serde::{de::Error as _, Deserialize, Deserializer};
+use std::{path::Path, process::Stdio};
+use tokio::process::Command;
+use tracing::{error, info};
+
+/// Kind of note a rule can match on
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum NoteKind {
+    /// Signed note showing its sender to the server
+    Plain,
+    /// Note with the sender sealed inside the encrypted payload
+    Sealed,
+    /// Note encrypted with a forward secret session
+    Session,
+}
+
+/// What to do with a note matching a rule
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Action {
+    /// Show the note in the status line
+    Notify,
+    /// Don't show the note
+    Mute,
+    /// Show the note in a highlight color
+    Highlight,
+    /// Append the note to the archive file instead of showing it, sealed to the identity it was
+    /// sent to. Read a line with `base64 -d | age -d -i <key file>`.
+    Archive,
+    /// Run a shell command, with the note in the `AGE_CHAT_FROM` and `AGE_CHAT_TEXT` env vars
+    Run(String),
+}
+
+/// A filter rule, matching notes where all of the set conditions hold
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Rule {
+    /// Sender pubkey
+    sender: Option<String>,
+    /// Regex on the decrypted text
+    #[serde(default, deserialize_with = "deserialize_regex")]
+    text: Option<Regex>,
+    kind: Option<NoteKind>,
+    action: Action,
+}
+
+/// Rules file, e.g.
+///
+/// ```toml
+/// [[rule]]
+/// text = "(?i)urgent"
+/// action = "highlight"
+///
+/// [[rule]]
+/// sender = "age1..."
+/// action = { run = "notify-send \"$AGE_CHAT_TEXT\"" }
+/// ```
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rules {
+    #[serde(default)]
+    rule: Vec<Rule>,
+}
+
+impl Rules {
+    /// Load rules from a TOML file
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .context(format!("Error reading rules file {}", path.display()))?;
+        let rules: Self = toml::from_str(&contents)
+            .context(format!("Error parsing rules file {}", path.display()))?;
+        info!("📏 Loaded {} filter rules", rules.rule.len());
+        Ok(rules)
+    }
+
+    /// Actions of every rule matching a received note, in the order the rules are listed
+    pub fn evaluate(&self, from: &str, kind: NoteKind, text: &str) -> Vec<Action> {
+        self.rule
+            .iter()
+            .filter(|rule| rule.sender.as_ref().is_none_or(|sender| sender == from))
+            .filter(|rule| rule.kind.is_none_or(|k| k == kind))
+            .filter(|rule| rule.text.as_ref().is_none_or(|re| re.is_match(text)))
+            .map(|rule| rule.action.clone())
+            .collect()
+    }
+}
+
+/// Run a rule's command in the background, discarding its output so it can't mess up the TUI
+pub fn run_command(command: &str, from: &str, text: &str) {
+    info!("📏 Running rule command: {command}");
+    let spawn_res = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .env("AGE_CHAT_FROM", from)
+        .env("AGE_CHAT_TEXT", text)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn();
+    if let Err(e) = spawn_res {
+        error!("📏 Error running rule command {command}: {e}");
+    }
+}
+
+/// Compile an optional regex while deserializing, so bad patterns are caught on load
+fn deserialize_regex<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Regex>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|pattern| Regex::new(&pattern).map_err(D::Error::custom))
+        .transpose()
+}
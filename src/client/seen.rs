@@ -0,0 +1,33 @@
+use anyhow::Result;
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::Path;
+
+/// Persistent set of note ids we have already received, used to detect replayed notes
+pub struct SeenNotes {
+    ids: HashSet<String>,
+    file: File,
+}
+
+impl SeenNotes {
+    /// Load previously seen note ids from a file, creating it if it doesn't exist
+    pub fn load(path: &Path) -> Result<Self> {
+        let ids = match std::fs::read_to_string(path) {
+            Ok(contents) => contents.lines().map(String::from).collect(),
+            Err(e) if e.kind() == ErrorKind::NotFound => HashSet::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self { ids, file })
+    }
+
+    /// Record a note id as seen, persisting it to disk. Returns false if it was already seen.
+    pub fn insert(&mut self, id: &str) -> Result<bool> {
+        if !self.ids.insert(id.to_string()) {
+            return Ok(false);
+        }
+        writeln!(self.file, "{id}")?;
+        Ok(true)
+    }
+}
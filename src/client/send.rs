@@ -0,0 +1,59 @@
+use age::x25519::Identity;
+use anyhow::Result;
+use std::time::Duration;
+use tokio::time;
+
+use super::chat::{ChatClient, Outgoing};
+use super::output::{Event, Output};
+use super::Shutdown;
+
+/// Longest we wait for the server to authenticate us and accept the note, so a script or cron
+/// job never hangs on it
+const SEND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Authenticate as a key, send a single note and wait for the server to accept it
+pub async fn run(
+    address: &str,
+    key: Identity,
+    auth_token: Option<String>,
+    outgoing: Outgoing,
+    output: Output,
+) -> Result<Shutdown> {
+    let mut client = match ChatClient::connect(address).await {
+        Ok(client) => client,
+        Err(e) => return e.downcast(),
+    };
+    let res = time::timeout(
+        SEND_TIMEOUT,
+        send(&mut client, key, auth_token, outgoing, output),
+    )
+    .await;
+    client.close().await?;
+    match res {
+        Ok(res) => res.or_else(|e| e.downcast()),
+        Err(_) => Ok(Shutdown::ConnectionFailed(format!(
+            "Server didn't accept the note within {}s",
+            SEND_TIMEOUT.as_secs()
+        ))),
+    }
+}
+
+async fn send(
+    client: &mut ChatClient,
+    key: Identity,
+    auth_token: Option<String>,
+    outgoing: Outgoing,
+    output: Output,
+) -> Result<Shutdown> {
+    client.authenticate(key, auth_token).await?;
+    let note = client.send(outgoing).await?;
+    if output == Output::Json {
+        Event::Sent {
+            id: &note.id,
+            to: &note.to,
+            timestamp: note.timestamp,
+        }
+        .print()?;
+    }
+    Ok(Shutdown::Sent)
+}
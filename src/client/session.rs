@@ -0,0 +1,488 @@
+use age::x25519::{Identity, Recipient};
+use anyhow::{anyhow, Context, Result};
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use hkdf::Hkdf;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::client::history::{open_text, seal_text};
+use crate::common::{SessionHandshake, SessionHeader};
+
+const SESSION_INFO: &[u8] = b"age-chat-session-v1";
+const CHAIN_INFO: &[u8] = b"age-chat-chain-v1";
+/// Max message keys to skip over or keep for lost and late notes, so a bad counter can't make
+/// us spin
+const MAX_SKIP: u64 = 1000;
+/// Max sessions per peer kept after a newer one replaced them, for their notes still in flight
+const MAX_SUPERSEDED: usize = 4;
+
+/// Forward secret sessions with peers. Session keys come from the DH of ephemeral x25519 keys
+/// exchanged in handshakes signed and proven with our age keys, then each message key is
+/// ratcheted forward from a chain key and forgotten after use, so a later compromise of our age
+/// key can't decrypt earlier notes.
+///
+/// The ratchet state is saved sealed to our identity after every change, so notes sent while we
+/// were offline can still be decrypted after a restart.
+pub struct Sessions {
+    path: PathBuf,
+    identity: Recipient,
+    state: State,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct State {
+    /// Offers we've sent, by peer
+    pending: HashMap<String, Offer>,
+    /// Established sessions, by peer
+    active: HashMap<String, Session>,
+    /// Sessions replaced by a newer one, oldest first, by peer. Notes sent with them before the
+    /// peer switched can still arrive late from the mailbox.
+    superseded: HashMap<String, Vec<Session>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Offer {
+    session_id: String,
+    /// Our ephemeral x25519 secret
+    secret: [u8; 32],
+}
+
+/// Symmetric ratchet state for one peer
+#[derive(Clone, Serialize, Deserialize)]
+struct Session {
+    id: String,
+    send_chain: [u8; 32],
+    send_counter: u64,
+    recv_chain: [u8; 32],
+    recv_counter: u64,
+    /// Message keys skipped over for notes that haven't arrived yet, by counter
+    #[serde(default)]
+    skipped: BTreeMap<u64, [u8; 32]>,
+}
+
+impl Sessions {
+    /// Load an identity's sessions from a sealed file, if it exists
+    pub fn load(path: &Path, identity: &Identity) -> Result<Self> {
+        let state = match fs::read_to_string(path) {
+            Ok(sealed) => serde_json::from_str(&open_text(identity, &sealed)?)
+                .context(format!("Error parsing sessions in {}", path.display()))?,
+            Err(e) if e.kind() == ErrorKind::NotFound => State::default(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            identity: identity.to_public(),
+            state,
+        })
+    }
+
+    /// Whether we have a session with a peer
+    pub fn is_active(&self, peer: &str) -> bool {
+        self.state.active.contains_key(peer)
+    }
+
+    /// Start setting up a session with a peer, returning the session id and our ephemeral public
+    /// key to offer them. An offer still pending is made again, in case its acceptance is in
+    /// flight.
+    pub fn offer(&mut self, peer: &str) -> Result<(String, String)> {
+        if let Some(offer) = self.state.pending.get(peer) {
+            let public_key = PublicKey::from(&StaticSecret::from(offer.secret));
+            return Ok((offer.session_id.clone(), hex::encode(public_key.as_bytes())));
+        }
+        let mut id_bytes = [0u8; 16];
+        rand::rng().fill_bytes(&mut id_bytes);
+        let session_id = hex::encode(id_bytes);
+        let secret = random_secret();
+        let public_key = hex::encode(PublicKey::from(&secret).as_bytes());
+        let offer = Offer {
+            session_id: session_id.clone(),
+            secret: secret.to_bytes(),
+        };
+        self.state.pending.insert(peer.to_string(), offer);
+        self.save()?;
+        Ok((session_id, public_key))
+    }
+
+    /// Accept a peer's offer, returning our ephemeral public key to send back. If we both offered
+    /// at the same time only the offer from the lower pubkey is accepted, and None is returned.
+    pub fn accept(
+        &mut self,
+        our_pub_key: &str,
+        offer: &SessionHandshake,
+    ) -> Result<Option<String>> {
+        if self.state.pending.contains_key(&offer.from) && offer.from.as_str() > our_pub_key {
+            return Ok(None);
+        }
+        let secret = random_secret();
+        let public_key = hex::encode(PublicKey::from(&secret).as_bytes());
+        let session = Session::derive(&offer.session_id, secret, &offer.ephemeral_key, false)?;
+        self.state.pending.remove(&offer.from);
+        self.establish(&offer.from, session)?;
+        Ok(Some(public_key))
+    }
+
+    /// Finish setting up a session we offered, with the peer's acceptance
+    pub fn complete(&mut self, accept: &SessionHandshake) -> Result<()> {
+        let offer = self
+            .state
+            .pending
+            .get(&accept.from)
+            .ok_or(anyhow!("No pending session offer to {}", accept.from))?;
+        if offer.session_id != accept.session_id {
+            return Err(anyhow!("Session acceptance is for a different offer"));
+        }
+        let session = Session::derive(
+            &offer.session_id,
+            StaticSecret::from(offer.secret),
+            &accept.ephemeral_key,
+            true,
+        )?;
+        self.state.pending.remove(&accept.from);
+        self.establish(&accept.from, session)
+    }
+
+    /// Make a session the one we use with a peer, keeping the one it replaces for late notes
+    fn establish(&mut self, peer: &str, session: Session) -> Result<()> {
+        if let Some(old) = self.state.active.insert(peer.to_string(), session) {
+            let superseded = self.state.superseded.entry(peer.to_string()).or_default();
+            superseded.push(old);
+            if superseded.len() > MAX_SUPERSEDED {
+                superseded.remove(0);
+            }
+        }
+        self.save()
+    }
+
+    /// Encrypt content to a peer with the next message key, or None if there's no session
+    pub fn encrypt(
+        &mut self,
+        peer: &str,
+        content: &str,
+    ) -> Result<Option<(SessionHeader, String)>> {
+        let Some(session) = self.state.active.get_mut(peer) else {
+            return Ok(None);
+        };
+        let header = SessionHeader {
+            session_id: session.id.clone(),
+            counter: session.send_counter,
+        };
+        let message_key = ratchet(&mut session.send_chain);
+        session.send_counter += 1;
+        // Saved before the key is used, so a restart can never reuse it
+        self.save()?;
+        let ciphertext = cipher(&message_key)
+            .encrypt(
+                &Nonce::default(),
+                Payload {
+                    msg: content.as_bytes(),
+                    aad: &associated_data(&header),
+                },
+            )
+            .map_err(|_| anyhow!("Error encrypting with session"))?;
+        Ok(Some((header, hex::encode(ciphertext))))
+    }
+
+    /// Decrypt content from a peer. Each message key can only be used once, and the ratchet only
+    /// moves if the note decrypts.
+    pub fn decrypt(
+        &mut self,
+        peer: &str,
+        header: &SessionHeader,
+        ciphertext: &str,
+    ) -> Result<String> {
+        let session = self
+            .state
+            .active
+            .get_mut(peer)
+            .into_iter()
+            .chain(self.state.superseded.get_mut(peer).into_iter().flatten())
+            .find(|session| session.id == header.session_id)
+            .ok_or(anyhow!("Unknown session {}", header.session_id))?;
+        let mut next = session.clone();
+        let message_key = next.message_key(header.counter)?;
+        let plaintext = cipher(&message_key)
+            .decrypt(
+                &Nonce::default(),
+                Payload {
+                    msg: &hex::decode(ciphertext)?,
+                    aad: &associated_data(header),
+                },
+            )
+            .map_err(|_| anyhow!("Error decrypting with session"))?;
+        let content = String::from_utf8(plaintext)?;
+        *session = next;
+        self.save()?;
+        Ok(content)
+    }
+
+    /// Seal the state to our identity and replace the file with it
+    fn save(&self) -> Result<()> {
+        let sealed = seal_text(&self.identity, &serde_json::to_string(&self.state)?)?;
+        // Written aside then renamed, so a crash can't leave us with half the sessions
+        let tmp_path = self.path.with_extension("tmp");
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        options
+            .open(&tmp_path)
+            .and_then(|mut file| file.write_all(sealed.as_bytes()))
+            .and_then(|_| fs::rename(&tmp_path, &self.path))
+            .context(format!("Error saving sessions to {}", self.path.display()))
+    }
+}
+
+impl Session {
+    /// Derive the chain keys from our ephemeral secret and the peer's ephemeral public key
+    fn derive(
+        session_id: &str,
+        secret: StaticSecret,
+        their_key: &str,
+        initiator: bool,
+    ) -> Result<Self> {
+        let their_key: [u8; 32] = hex::decode(their_key)?
+            .try_into()
+            .map_err(|_| anyhow!("Invalid ephemeral key length"))?;
+        let shared = secret.diffie_hellman(&PublicKey::from(their_key));
+        if !shared.was_contributory() {
+            return Err(anyhow!("Ephemeral key is a low order point"));
+        }
+
+        let hkdf = Hkdf::<Sha256>::new(Some(session_id.as_bytes()), shared.as_bytes());
+        let mut okm = [0u8; 64];
+        hkdf.expand(SESSION_INFO, &mut okm)
+            .map_err(|_| anyhow!("Error deriving session keys"))?;
+        let (initiator_chain, responder_chain) = okm.split_at(32);
+        let (send_chain, recv_chain) = if initiator {
+            (initiator_chain, responder_chain)
+        } else {
+            (responder_chain, initiator_chain)
+        };
+
+        Ok(Self {
+            id: session_id.to_string(),
+            send_chain: send_chain.try_into()?,
+            send_counter: 0,
+            recv_chain: recv_chain.try_into()?,
+            recv_counter: 0,
+            skipped: BTreeMap::new(),
+        })
+    }
+
+    /// Take the message key for a received counter, keeping the keys skipped over for notes
+    /// that are late or lost
+    fn message_key(&mut self, counter: u64) -> Result<[u8; 32]> {
+        if counter < self.recv_counter {
+            return self
+                .skipped
+                .remove(&counter)
+                .ok_or(anyhow!("Message key {counter} was already used"));
+        }
+        if counter - self.recv_counter > MAX_SKIP {
+            return Err(anyhow!("Message key {counter} is too far ahead"));
+        }
+        while self.recv_counter < counter {
+            let skipped = ratchet(&mut self.recv_chain);
+            self.skipped.insert(self.recv_counter, skipped);
+            self.recv_counter += 1;
+        }
+        // Forget the oldest keys first, their notes are the likeliest to be lost for good
+        while self.skipped.len() as u64 > MAX_SKIP {
+            self.skipped.pop_first();
+        }
+        self.recv_counter += 1;
+        Ok(ratchet(&mut self.recv_chain))
+    }
+}
+
+/// Step a chain key forward, returning the message key for the current step
+fn ratchet(chain: &mut [u8; 32]) -> [u8; 32] {
+    let hkdf = Hkdf::<Sha256>::new(None, chain);
+    let mut okm = [0u8; 64];
+    hkdf.expand(CHAIN_INFO, &mut okm)
+        .expect("64 bytes is a valid hkdf output length");
+    chain.copy_from_slice(&okm[..32]);
+    okm[32..].try_into().expect("slice is 32 bytes")
+}
+
+fn cipher(message_key: &[u8; 32]) -> ChaCha20Poly1305 {
+    ChaCha20Poly1305::new(Key::from_slice(message_key))
+}
+
+/// Bind the ciphertext to its session and position in the chain
+fn associated_data(header: &SessionHeader) -> Vec<u8> {
+    [header.session_id.as_bytes(), &header.counter.to_be_bytes()].concat()
+}
+
+fn random_secret() -> StaticSecret {
+    let mut bytes = [0u8; 32];
+    rand::rng().fill_bytes(&mut bytes);
+    StaticSecret::from(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sessions of a new identity, saved to a fresh temp file
+    fn new_sessions() -> Result<(Identity, Sessions)> {
+        let identity = Identity::generate();
+        let path = std::env::temp_dir().join(format!(
+            "age-chat-sessions-{}-{:08x}.age",
+            std::process::id(),
+            rand::random::<u32>()
+        ));
+        let sessions = Sessions::load(&path, &identity)?;
+        Ok((identity, sessions))
+    }
+
+    /// Set up a session from alice to bob
+    fn handshake() -> Result<((Identity, Sessions), (Identity, Sessions))> {
+        let (alice, mut alice_sessions) = new_sessions()?;
+        let (bob, mut bob_sessions) = new_sessions()?;
+        let alice_pub_key = alice.to_public().to_string();
+        let bob_pub_key = bob.to_public().to_string();
+
+        let (session_id, ephemeral_key) = alice_sessions.offer(&bob_pub_key)?;
+        let offer = SessionHandshake::new(&alice, bob_pub_key.clone(), session_id, ephemeral_key)?;
+        let ephemeral_key = bob_sessions
+            .accept(&bob_pub_key, &offer)?
+            .ok_or(anyhow!("Offer wasn't accepted"))?;
+        let accept = SessionHandshake::new(&bob, alice_pub_key, offer.session_id, ephemeral_key)?;
+        alice_sessions.complete(&accept)?;
+        Ok(((alice, alice_sessions), (bob, bob_sessions)))
+    }
+
+    fn encrypt(sessions: &mut Sessions, peer: &Identity, content: &str) -> (SessionHeader, String) {
+        sessions
+            .encrypt(&peer.to_public().to_string(), content)
+            .unwrap()
+            .expect("session is set up")
+    }
+
+    fn decrypt(
+        sessions: &mut Sessions,
+        peer: &Identity,
+        (header, ciphertext): &(SessionHeader, String),
+    ) -> Result<String> {
+        sessions.decrypt(&peer.to_public().to_string(), header, ciphertext)
+    }
+
+    #[test]
+    fn decrypts_in_order() -> Result<()> {
+        let ((alice, mut alice_sessions), (bob, mut bob_sessions)) = handshake()?;
+        for content in ["one", "two", "three"] {
+            let sent = encrypt(&mut alice_sessions, &bob, content);
+            assert_eq!(decrypt(&mut bob_sessions, &alice, &sent)?, content);
+        }
+        let reply = encrypt(&mut bob_sessions, &alice, "back");
+        assert_eq!(decrypt(&mut alice_sessions, &bob, &reply)?, "back");
+        Ok(())
+    }
+
+    #[test]
+    fn message_keys_are_single_use() -> Result<()> {
+        let ((alice, mut alice_sessions), (bob, mut bob_sessions)) = handshake()?;
+        let sent = encrypt(&mut alice_sessions, &bob, "once");
+        decrypt(&mut bob_sessions, &alice, &sent)?;
+        assert!(decrypt(&mut bob_sessions, &alice, &sent).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn decrypts_after_skipped_notes() -> Result<()> {
+        let ((alice, mut alice_sessions), (bob, mut bob_sessions)) = handshake()?;
+        encrypt(&mut alice_sessions, &bob, "lost");
+        encrypt(&mut alice_sessions, &bob, "also lost");
+        let sent = encrypt(&mut alice_sessions, &bob, "arrived");
+        assert_eq!(decrypt(&mut bob_sessions, &alice, &sent)?, "arrived");
+        Ok(())
+    }
+
+    #[test]
+    fn decrypts_out_of_order() -> Result<()> {
+        let ((alice, mut alice_sessions), (bob, mut bob_sessions)) = handshake()?;
+        let first = encrypt(&mut alice_sessions, &bob, "first");
+        let second = encrypt(&mut alice_sessions, &bob, "second");
+        let third = encrypt(&mut alice_sessions, &bob, "third");
+        assert_eq!(decrypt(&mut bob_sessions, &alice, &third)?, "third");
+        assert_eq!(decrypt(&mut bob_sessions, &alice, &first)?, "first");
+        assert_eq!(decrypt(&mut bob_sessions, &alice, &second)?, "second");
+        assert!(decrypt(&mut bob_sessions, &alice, &first).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn failed_decryption_keeps_the_ratchet() -> Result<()> {
+        let ((alice, mut alice_sessions), (bob, mut bob_sessions)) = handshake()?;
+        let (header, _) = encrypt(&mut alice_sessions, &bob, "real");
+        let forged = (header.clone(), hex::encode(b"forged ciphertext"));
+        assert!(decrypt(&mut bob_sessions, &alice, &forged).is_err());
+        assert_eq!(
+            bob_sessions
+                .state
+                .active
+                .values()
+                .next()
+                .unwrap()
+                .recv_counter,
+            0
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn decrypts_after_restart() -> Result<()> {
+        let ((alice, mut alice_sessions), (bob, mut bob_sessions)) = handshake()?;
+        let first = encrypt(&mut alice_sessions, &bob, "before");
+        let second = encrypt(&mut alice_sessions, &bob, "while offline");
+        assert_eq!(
+            decrypt(&mut bob_sessions, &alice, &second)?,
+            "while offline"
+        );
+
+        let mut bob_sessions = Sessions::load(&bob_sessions.path, &bob)?;
+        assert_eq!(decrypt(&mut bob_sessions, &alice, &first)?, "before");
+        let mut alice_sessions = Sessions::load(&alice_sessions.path, &alice)?;
+        let third = encrypt(&mut alice_sessions, &bob, "after");
+        assert_eq!(third.0.counter, 2);
+        assert_eq!(decrypt(&mut bob_sessions, &alice, &third)?, "after");
+        Ok(())
+    }
+
+    #[test]
+    fn decrypts_with_superseded_session() -> Result<()> {
+        let ((alice, mut alice_sessions), (bob, mut bob_sessions)) = handshake()?;
+        let in_flight = encrypt(&mut alice_sessions, &bob, "old session");
+
+        // Bob offers a new session, replacing the first one on both sides
+        let alice_pub_key = alice.to_public().to_string();
+        let bob_pub_key = bob.to_public().to_string();
+        let (session_id, ephemeral_key) = bob_sessions.offer(&alice_pub_key)?;
+        let offer = SessionHandshake::new(&bob, alice_pub_key, session_id, ephemeral_key)?;
+        let ephemeral_key = alice_sessions.accept(&bob_pub_key, &offer)?.unwrap();
+        let accept = SessionHandshake::new(&alice, bob_pub_key, offer.session_id, ephemeral_key)?;
+        bob_sessions.complete(&accept)?;
+
+        let sent = encrypt(&mut alice_sessions, &bob, "new session");
+        assert_ne!(sent.0.session_id, in_flight.0.session_id);
+        assert_eq!(decrypt(&mut bob_sessions, &alice, &sent)?, "new session");
+        assert_eq!(
+            decrypt(&mut bob_sessions, &alice, &in_flight)?,
+            "old session"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,65 @@
+use anyhow::{bail, Context, Result};
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use tracing::{error, info};
+
+/// Signing keys pinned for each sender pubkey, persisted so a key forged by whoever relays a
+/// note can't be trusted afresh every run. A key the sender proved with their age identity
+/// replaces one trusted on first use.
+pub struct SigningKeys {
+    path: PathBuf,
+    keys: HashMap<String, String>,
+}
+
+impl SigningKeys {
+    /// Load the keys from a file of `<pubkey> <signing key>` lines, if it exists. Later lines
+    /// replace earlier ones for the same pubkey.
+    pub fn load(path: &Path) -> Result<Self> {
+        let keys = match fs::read_to_string(path) {
+            Ok(contents) => contents
+                .lines()
+                .filter_map(|line| line.split_once(' '))
+                .map(|(pub_key, signing_key)| (pub_key.to_string(), signing_key.to_string()))
+                .collect(),
+            Err(e) if e.kind() == ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            keys,
+        })
+    }
+
+    /// Pin the signing key for a pubkey. A key that differs from the pinned one is refused,
+    /// unless the sender proved it's theirs, in which case the pinned one was forged.
+    pub fn pin(&mut self, pub_key: &str, signing_key: &str, proven: bool) -> Result<()> {
+        match self.keys.get(pub_key) {
+            Some(known) if known == signing_key => return Ok(()),
+            Some(_) if proven => error!(
+                "🪪 The signing key pinned for {pub_key} was not theirs, replacing it with the one \
+                 they proved"
+            ),
+            Some(_) => bail!(
+                "Signing key does not match the one pinned for {pub_key}, the note may be forged"
+            ),
+            None if proven => info!("🪪 Pinning the signing key {pub_key} proved"),
+            None => info!("🪪 Trusting the signing key of {pub_key} on first use"),
+        }
+        self.append(pub_key, signing_key)?;
+        self.keys
+            .insert(pub_key.to_string(), signing_key.to_string());
+        Ok(())
+    }
+
+    fn append(&self, pub_key: &str, signing_key: &str) -> Result<()> {
+        let mut file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&self.path)
+            .context(format!("Error opening {}", self.path.display()))?;
+        writeln!(file, "{pub_key} {signing_key}")?;
+        Ok(())
+    }
+}
@@ -0,0 +1,90 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::common::KeyChange;
+
+/// Marks the content of notes to ourselves that sync state between our devices
+const CONTROL_PREFIX: &str = "age-chat-control-v1:";
+/// Min time between syncing read positions, so reading a busy conversation doesn't send a note
+/// for every note received
+const READ_SYNC_INTERVAL: Duration = Duration::from_secs(5);
+
+/// State synced between our devices, sent as notes to our own pubkey. Only one device can be
+/// connected at a time, so the server holds them in the mailbox for the next one. Key changes are
+/// the exception, sent to peers instead.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum ControlNote {
+    /// Last note read in the conversation with a peer
+    ReadPosition {
+        conversation: String,
+        note_id: String,
+    },
+    /// We moved to a new key, and peers should encrypt to it instead
+    KeyChange(KeyChange),
+}
+
+impl ControlNote {
+    pub fn to_content(&self) -> Result<String> {
+        Ok(format!("{CONTROL_PREFIX}{}", serde_json::to_string(self)?))
+    }
+
+    /// Parse note content as a control note, None if it's a regular chat note
+    pub fn parse(content: &str) -> Option<Result<Self>> {
+        let json = content.strip_prefix(CONTROL_PREFIX)?;
+        Some(serde_json::from_str(json).map_err(Into::into))
+    }
+}
+
+/// Last read note in each conversation, and which positions our other devices know about
+#[derive(Default)]
+pub struct ReadPositions {
+    read: HashMap<String, String>,
+    synced: HashMap<String, String>,
+    last_sync: Option<Instant>,
+}
+
+impl ReadPositions {
+    pub fn get(&self, conversation: &str) -> Option<&str> {
+        self.read.get(conversation).map(String::as_str)
+    }
+
+    /// Record that we've read up to a note
+    pub fn set(&mut self, conversation: &str, note_id: &str) {
+        self.read
+            .insert(conversation.to_string(), note_id.to_string());
+    }
+
+    /// Record a position one of our other devices synced, so we don't sync it back
+    pub fn set_synced(&mut self, conversation: &str, note_id: &str) {
+        self.set(conversation, note_id);
+        self.synced
+            .insert(conversation.to_string(), note_id.to_string());
+    }
+
+    /// Control notes for positions that changed since the last sync, if it's time to sync again
+    pub fn take_unsynced(&mut self) -> Vec<ControlNote> {
+        if self
+            .last_sync
+            .is_some_and(|last_sync| last_sync.elapsed() < READ_SYNC_INTERVAL)
+        {
+            return vec![];
+        }
+        let unsynced: Vec<ControlNote> = self
+            .read
+            .iter()
+            .filter(|(conversation, note_id)| self.synced.get(*conversation) != Some(note_id))
+            .map(|(conversation, note_id)| ControlNote::ReadPosition {
+                conversation: conversation.clone(),
+                note_id: note_id.clone(),
+            })
+            .collect();
+        if !unsynced.is_empty() {
+            self.last_sync = Some(Instant::now());
+            self.synced.clone_from(&self.read);
+        }
+        unsynced
+    }
+}
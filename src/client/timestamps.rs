@@ -0,0 +1,200 @@
+use anyhow::{anyhow, bail, Result};
+use chrono::format::StrftimeItems;
+use chrono::{DateTime, Datelike, FixedOffset, Local, NaiveDateTime, Timelike, Utc};
+use icu_calendar::{DateTime as IcuDateTime, Gregorian};
+use icu_datetime::{options::length, TypedDateTimeFormatter};
+use icu_locid::Locale;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::{fmt, str::FromStr};
+
+pub const DEFAULT_TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+thread_local! {
+    /// Date and time formatters by locale, loaded once since every note's timestamp uses one.
+    /// They can't be sent between threads.
+    static FORMATTERS: RefCell<HashMap<Locale, TypedDateTimeFormatter<Gregorian>>> =
+        RefCell::new(HashMap::new());
+}
+
+/// Timezone to show timestamps in
+#[derive(Clone, Copy, Debug)]
+pub enum Timezone {
+    Local,
+    Utc,
+    /// A fixed offset from UTC, e.g. to show a peer's times as they see them
+    Offset(FixedOffset),
+}
+
+impl FromStr for Timezone {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "local" => Ok(Self::Local),
+            "utc" | "UTC" => Ok(Self::Utc),
+            offset => FixedOffset::from_str(offset)
+                .map(Self::Offset)
+                .map_err(|_| anyhow!("expected local, utc or an offset like +09:00")),
+        }
+    }
+}
+
+impl fmt::Display for Timezone {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Local => write!(f, "local"),
+            Self::Utc => write!(f, "utc"),
+            Self::Offset(offset) => write!(f, "{offset}"),
+        }
+    }
+}
+
+/// How to show timestamps: a strftime format or a locale's way of writing them, and the timezone
+/// to show them in
+#[derive(Clone, Debug)]
+pub struct TimeDisplay {
+    style: Style,
+    timezone: Timezone,
+}
+
+#[derive(Clone, Debug)]
+enum Style {
+    Strftime(String),
+    /// A locale's medium date and time, e.g. `16.10.2026, 19:45:42` for de-DE
+    Locale(Locale),
+}
+
+impl TimeDisplay {
+    /// Checks the format is valid strftime, so it can't fail when showing a note
+    pub fn new(format: String, timezone: Timezone) -> Result<Self> {
+        if StrftimeItems::new(&format).parse().is_err() {
+            bail!("Invalid time format: {format}");
+        }
+        Ok(Self {
+            style: Style::Strftime(format),
+            timezone,
+        })
+    }
+
+    /// Show timestamps the way a locale like de-DE or ja writes dates and times, falling back to
+    /// the root locale's way for ones there's no data for
+    pub fn localized(locale: &str, timezone: Timezone) -> Result<Self> {
+        let locale =
+            Locale::from_str(locale).map_err(|e| anyhow!("Invalid locale {locale}: {e}"))?;
+        // Loaded now so a problem shows at startup rather than when showing a note
+        formatter(&locale)?;
+        Ok(Self {
+            style: Style::Locale(locale),
+            timezone,
+        })
+    }
+
+    pub fn format(&self, timestamp: DateTime<Utc>) -> String {
+        let format = match &self.style {
+            Style::Strftime(format) => format,
+            Style::Locale(locale) => return self.format_localized(locale, timestamp),
+        };
+        match self.timezone {
+            Timezone::Local => timestamp.with_timezone(&Local).format(format),
+            Timezone::Utc => timestamp.format(format),
+            Timezone::Offset(offset) => timestamp.with_timezone(&offset).format(format),
+        }
+        .to_string()
+    }
+
+    fn format_localized(&self, locale: &Locale, timestamp: DateTime<Utc>) -> String {
+        let local: NaiveDateTime = match self.timezone {
+            Timezone::Local => timestamp.with_timezone(&Local).naive_local(),
+            Timezone::Utc => timestamp.naive_utc(),
+            Timezone::Offset(offset) => timestamp.with_timezone(&offset).naive_local(),
+        };
+        let datetime = IcuDateTime::try_new_gregorian_datetime(
+            local.year(),
+            local.month() as u8,
+            local.day() as u8,
+            local.hour() as u8,
+            local.minute() as u8,
+            local.second() as u8,
+        );
+        let formatted = datetime.map_err(|e| anyhow!(e)).and_then(|datetime| {
+            FORMATTERS.with_borrow_mut(|formatters| {
+                if !formatters.contains_key(locale) {
+                    formatters.insert(locale.clone(), formatter(locale)?);
+                }
+                Ok(formatters[locale].format_to_string(&datetime))
+            })
+        });
+        // Neither can fail for a locale checked when configured and a real timestamp
+        formatted.unwrap_or_else(|_| local.format(DEFAULT_TIME_FORMAT).to_string())
+    }
+}
+
+/// Load a locale's formatter for its medium date and time
+fn formatter(locale: &Locale) -> Result<TypedDateTimeFormatter<Gregorian>> {
+    let options = length::Bag::from_date_time_style(length::Date::Medium, length::Time::Medium);
+    TypedDateTimeFormatter::try_new(&locale.into(), options.into())
+        .map_err(|e| anyhow!("Error loading date formats for locale {locale}: {e}"))
+}
+
+impl Default for TimeDisplay {
+    /// The default format in local time
+    fn default() -> Self {
+        Self {
+            style: Style::Strftime(DEFAULT_TIME_FORMAT.into()),
+            timezone: Timezone::Local,
+        }
+    }
+}
+
+/// How each conversation shows its timestamps, by peer, falling back to a default for the rest
+#[derive(Clone, Debug)]
+pub struct ConversationTimes {
+    default: TimeDisplay,
+    by_peer: HashMap<String, TimeDisplay>,
+}
+
+impl ConversationTimes {
+    pub fn new(default: TimeDisplay, by_peer: HashMap<String, TimeDisplay>) -> Self {
+        Self { default, by_peer }
+    }
+
+    pub fn get(&self, peer: &str) -> &TimeDisplay {
+        self.by_peer.get(peer).unwrap_or(&self.default)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timestamp() -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339("2026-10-16T19:45:42Z")
+            .unwrap()
+            .to_utc()
+    }
+
+    #[test]
+    fn formats_strftime_in_timezone() -> Result<()> {
+        let display = TimeDisplay::new("%H:%M %z".into(), Timezone::from_str("+09:00")?)?;
+        assert_eq!(display.format(timestamp()), "04:45 +0900");
+        Ok(())
+    }
+
+    #[test]
+    fn formats_like_locale() -> Result<()> {
+        let german = TimeDisplay::localized("de-DE", Timezone::Utc)?;
+        let american = TimeDisplay::localized("en-US", Timezone::Utc)?;
+        assert_eq!(german.format(timestamp()), "16.10.2026, 19:45:42");
+        assert_eq!(
+            american.format(timestamp()),
+            "Oct 16, 2026, 7:45:42\u{202f}PM"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_invalid_locale() {
+        assert!(TimeDisplay::localized("not a locale", Timezone::Utc).is_err());
+    }
+}
@@ -0,0 +1,275 @@
+use anyhow::{bail, Context, Result};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{self, CryptoProvider};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{
+    CertificateError, ClientConfig, DigitallySignedStruct, OtherError, RootCertStore,
+    SignatureScheme,
+};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio_tungstenite::Connector;
+use tracing::{error, warn};
+
+/// How to decide whether to trust the server's certificate on wss:// connections
+#[derive(Clone, Debug)]
+pub enum Trust {
+    /// Certificates a well known CA signed for the server's name
+    Roots,
+    /// Only the certificate, or public key, with this SHA-256 fingerprint, e.g. a self-signed one
+    Pin(String),
+    /// Whichever public key each server presented the first time, remembered in this file
+    FirstUse(PathBuf),
+}
+
+/// TLS settings shared by every connection to the server
+#[derive(Clone)]
+pub struct Tls {
+    config: Arc<ClientConfig>,
+    /// Set when a server presents a different key than the one we trusted on first use
+    changed: Arc<Mutex<Option<CertChange>>>,
+}
+
+/// A server's key changed since we first trusted it, so someone may be intercepting the
+/// connection
+#[derive(Clone, Debug)]
+pub struct CertChange {
+    pub host: String,
+    pub trusted: String,
+    pub presented: String,
+    /// File to remove the trusted key from if the change is expected
+    pub store: PathBuf,
+}
+
+impl Tls {
+    pub fn new(trust: Trust) -> Result<Self> {
+        let provider = Arc::new(crypto::ring::default_provider());
+        let builder = ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?;
+        let changed = Arc::new(Mutex::new(None));
+        let check = match trust {
+            Trust::Roots => {
+                let roots =
+                    RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+                let config = builder.with_root_certificates(roots).with_no_client_auth();
+                return Ok(Self {
+                    config: Arc::new(config),
+                    changed,
+                });
+            }
+            Trust::Pin(fingerprint) => Check::Pin(fingerprint),
+            Trust::FirstUse(path) => Check::FirstUse(KnownServers::load(&path)?),
+        };
+        let verifier = PinningVerifier {
+            provider,
+            check,
+            changed: changed.clone(),
+        };
+        let config = builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(verifier))
+            .with_no_client_auth();
+        Ok(Self {
+            config: Arc::new(config),
+            changed,
+        })
+    }
+
+    /// Connector to open wss:// connections with. Plain ws:// urls ignore it.
+    pub fn connector(&self) -> Connector {
+        Connector::Rustls(self.config.clone())
+    }
+
+    /// Take the key change that made the last connection attempt fail, if that's why it did
+    pub fn take_change(&self) -> Option<CertChange> {
+        self.changed
+            .lock()
+            .expect("Cert change lock poisoned")
+            .take()
+    }
+}
+
+/// Normalize a SHA-256 fingerprint given as hex, with or without colons like openssl prints them
+pub fn parse_fingerprint(fingerprint: &str) -> Result<String> {
+    let fingerprint = fingerprint.replace(':', "").to_lowercase();
+    match hex::decode(&fingerprint) {
+        Ok(bytes) if bytes.len() == Sha256::output_size() => Ok(fingerprint),
+        _ => bail!("Invalid certificate fingerprint, expected the hex of a SHA-256 hash"),
+    }
+}
+
+impl fmt::Display for CertChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "THE CERTIFICATE OF {} HAS CHANGED, SOMEONE MAY BE INTERCEPTING THE CONNECTION! \
+             We trusted key {} but it presented {}. If the server really changed its key, remove \
+             its line from {} to trust the new one.",
+            self.host,
+            self.trusted,
+            self.presented,
+            self.store.display()
+        )
+    }
+}
+
+impl std::error::Error for CertChange {}
+
+/// What the server's certificate is checked against instead of the CA roots
+#[derive(Debug)]
+enum Check {
+    Pin(String),
+    FirstUse(KnownServers),
+}
+
+/// Checks the server's certificate against a pinned or remembered fingerprint. Any certificate
+/// that matches is trusted whoever signed it, so the handshake signatures are the only other
+/// check.
+#[derive(Debug)]
+struct PinningVerifier {
+    provider: Arc<CryptoProvider>,
+    check: Check,
+    changed: Arc<Mutex<Option<CertChange>>>,
+}
+
+impl ServerCertVerifier for PinningVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let key_fingerprint = key_fingerprint(end_entity)?;
+        match &self.check {
+            Check::Pin(pin) => {
+                let cert_fingerprint = hex::encode(Sha256::digest(end_entity));
+                if *pin != cert_fingerprint && *pin != key_fingerprint {
+                    return Err(rustls::Error::General(format!(
+                        "Server certificate doesn't match the pinned fingerprint, its \
+                         certificate is {cert_fingerprint} and its key {key_fingerprint}"
+                    )));
+                }
+            }
+            Check::FirstUse(known_servers) => {
+                let host = server_name.to_str();
+                if let Some(trusted) = known_servers.trust(&host, &key_fingerprint) {
+                    let change = CertChange {
+                        host: host.to_string(),
+                        trusted,
+                        presented: key_fingerprint,
+                        store: known_servers.path.clone(),
+                    };
+                    error!("🔏 {change}");
+                    *self.changed.lock().expect("Cert change lock poisoned") = Some(change.clone());
+                    return Err(rustls::Error::InvalidCertificate(CertificateError::Other(
+                        OtherError(Arc::new(change)),
+                    )));
+                }
+            }
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+/// SHA-256 fingerprint of a certificate's public key, which stays the same when it's renewed
+/// with the same key
+fn key_fingerprint(cert: &CertificateDer<'_>) -> Result<String, rustls::Error> {
+    let (_, cert) = x509_parser::parse_x509_certificate(cert)
+        .map_err(|_| rustls::Error::InvalidCertificate(CertificateError::BadEncoding))?;
+    Ok(hex::encode(Sha256::digest(cert.public_key().raw)))
+}
+
+/// Keys of the servers we've trusted on first use, like ssh's known_hosts
+#[derive(Debug)]
+struct KnownServers {
+    path: PathBuf,
+    keys: Mutex<HashMap<String, String>>,
+}
+
+impl KnownServers {
+    /// Load the keys from a file of `<host> <fingerprint>` lines, if it exists
+    fn load(path: &Path) -> Result<Self> {
+        let keys = match fs::read_to_string(path) {
+            Ok(contents) => contents
+                .lines()
+                .filter_map(|line| line.split_once(' '))
+                .map(|(host, fingerprint)| (host.to_string(), fingerprint.to_string()))
+                .collect(),
+            Err(e) if e.kind() == ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            keys: Mutex::new(keys),
+        })
+    }
+
+    /// Check a host presented the key we trust for it, trusting it if it's the first time we've
+    /// seen the host. Returns the key we trust if it's a different one.
+    fn trust(&self, host: &str, fingerprint: &str) -> Option<String> {
+        let mut keys = self.keys.lock().expect("Known servers lock poisoned");
+        match keys.get(host) {
+            Some(trusted) if trusted == fingerprint => None,
+            Some(trusted) => Some(trusted.clone()),
+            None => {
+                warn!("🔏 Trusting {host} on first use, its key is {fingerprint}");
+                if let Err(e) = self.append(host, fingerprint) {
+                    error!("🔏 Error remembering the key of {host}: {e:#}");
+                }
+                keys.insert(host.to_string(), fingerprint.to_string());
+                None
+            }
+        }
+    }
+
+    fn append(&self, host: &str, fingerprint: &str) -> Result<()> {
+        let mut file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&self.path)
+            .context(format!("Error opening {}", self.path.display()))?;
+        writeln!(file, "{host} {fingerprint}")?;
+        Ok(())
+    }
+}
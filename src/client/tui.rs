@@ -1,137 +1,588 @@
 use age::x25519::{Identity, Recipient};
-use anyhow::Result;
-use chrono::Local;
-use crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use anyhow::{anyhow, bail, Context, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use chrono::{Local, Utc};
+use crossterm::event::{
+    DisableBracketedPaste, DisableFocusChange, EnableBracketedPaste, EnableFocusChange, Event,
+    EventStream, KeyCode, KeyEventKind, KeyModifiers,
+};
+use crossterm::terminal::{self, EnterAlternateScreen};
+use futures_util::future::{self, select_all};
+use futures_util::StreamExt;
 use ratatui::{
     layout::{Constraint, Layout, Position},
-    style::{Color, Style},
-    text::{Line, Span},
-    widgets::{Block, List, ListItem, Paragraph},
+    style::{Color, Modifier, Style},
+    text::{Line, Text},
+    widgets::{Block, LineGauge, List, ListItem, Paragraph, Wrap},
     DefaultTerminal, Frame,
 };
-use std::time::Duration;
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
 use tokio::sync::broadcast::{Receiver, Sender};
-use tracing::info;
+use tokio::time;
+use tracing::{error, info, warn};
 
-use super::comms::Comms;
-use crate::common::{Auth, ClientMsg, Note, ServerMsg};
+use super::comms::{Comms, ConnectionEvent, Received};
+use super::decrypt::DecryptPool;
+use super::desktop;
+use super::history::{History, INPUT_HISTORY_LEN};
+use super::hook::{Hook, Hooked};
+use super::outbox::{Outbox, PendingNote};
+use super::resend::Unacked;
+use super::resume::ResumeTokens;
+use super::rules::{self, Action, NoteKind, Rules};
+use super::seen::SeenNotes;
+use super::session::Sessions;
+use super::signing_keys::SigningKeys;
+use super::sync::{ControlNote, ReadPositions};
+use super::timestamps::{ConversationTimes, TimeDisplay};
+use super::tls::CertChange;
+use super::webhook::Webhook;
+use super::{
+    check_key_perms, Bell, Config, Shutdown, StdinNote, ARCHIVE_PATH, HISTORY_PATH,
+    KNOWN_SIGNING_KEYS_PATH, OUTBOX_PATH, RESUME_TOKENS_PATH, SESSIONS_PATH_PREFIX,
+};
+use crate::common::{
+    load_key, signing_key, Auth, AuthChallenge, ClientMsg, Deprecation, ErrorKind, FetchHistory,
+    Hello, HistoryPage, KeyChange, Maintenance, Note, OpenedNote, Resume, Revocation, ServerMsg,
+    SessionHandshake, SyncSince, Typing, HISTORY_PROTOCOL_VERSION, PROTOCOL_VERSION,
+    RESUME_PROTOCOL_VERSION, SYNC_PROTOCOL_VERSION, TYPING_PROTOCOL_VERSION,
+};
 
-pub fn run(
-    comms: &mut Comms,
-    key: Identity,
-    recipient: Recipient,
-    shutdown_tx: Sender<()>,
-    shutdown_rx: Receiver<()>,
-) -> Result<()> {
+pub async fn run(
+    connections: Vec<(String, Identity, &mut Comms)>,
+    config: &Config,
+    conversations: Vec<Recipient>,
+    seen_notes: SeenNotes,
+    stdin_content: Option<String>,
+    shutdown_tx: Sender<Shutdown>,
+    shutdown_rx: Receiver<Shutdown>,
+) -> Result<Shutdown> {
+    let mut app = App::new(
+        connections,
+        config,
+        conversations,
+        seen_notes,
+        stdin_content,
+        shutdown_tx,
+        shutdown_rx,
+    )?;
     info!("🖥️ Started TUI");
     let terminal = ratatui::init();
-    let app = App::new(comms, key, recipient, shutdown_tx, shutdown_rx);
-    let app_res = app.run(terminal);
+    enable_modes(config.desktop_notifications);
+    let app_res = app.run(terminal).await;
+    disable_modes(config.desktop_notifications);
     ratatui::restore();
     info!("🖥️ Stopped TUI");
+
+    // The server may have gone away for downtime it warned us about
+    if let Some(maintenance) = app.maintenance().filter(|m| m.starts_at <= Utc::now()) {
+        println!(
+            "Server is down for maintenance ({}), expected back at {}",
+            maintenance.message,
+            maintenance.ends_at().with_timezone(&Local).format("%H:%M")
+        );
+    }
     app_res
 }
 
-const POLL_DURATION_MILLIS: u64 = 10;
+/// How often to redraw while nothing happens, for countdowns and typing indicators to move on
+const REDRAW_INTERVAL: Duration = Duration::from_millis(250);
+const SIDEBAR_WIDTH: u16 = 28;
+/// Characters of pubkeys to show in the sidebar
+const SHORT_KEY_LEN: usize = 16;
+/// How long to show a peer as typing after their last typing indicator
+const TYPING_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long the visual bell flashes the screen for
+const FLASH_DURATION: Duration = Duration::from_millis(150);
+/// Lines the warning about a changed server certificate takes up
+const CERT_WARNING_HEIGHT: u16 = 4;
+/// Notes loaded at once from local history or the server's archive. A shorter page from the
+/// server means it has no older ones, so this shouldn't be more than it sends per fetch.
+const HISTORY_PAGE_SIZE: usize = 100;
 
-/// App holds the state of the application
-struct App<'a> {
+/// Where an account's connection is at, to show next to it
+enum ConnectionState {
+    /// Connected and proving who we are
+    Authenticating,
+    Authenticated,
+    /// Lost the connection, trying again at the instant
+    Reconnecting {
+        attempt: u32,
+        at: Instant,
+    },
+    /// Dialing the server again
+    Connecting {
+        attempt: u32,
+    },
+    /// Gave up on the connection
+    Failed,
+}
+
+/// What woke the app loop up
+enum Wake {
+    Terminal(Event),
+    /// A message or connection event on an account's connection
+    Received(usize, Received),
+    Shutdown(Shutdown),
+    /// Something else changed, or it's time to redraw anyway
+    Redraw,
+}
+
+/// An identity we are chatting as, with its own connection to a server
+struct Account<'a> {
+    /// Address of the server this connection is to
+    server: String,
     /// Communication with server
     comms: &'a mut Comms,
-    /// Current private key
+    /// Private key of this identity
     priv_key: Identity,
-    /// Current public key
+    /// Public key of this identity
     pub_key: Recipient,
-    /// Whether or not we've succesfully authenticated
-    authenticated: bool,
-    /// Current recipient pubkey we are chatting with
-    recipient: Recipient,
+    /// Note signing key, hex encoded
+    signing_key: String,
+    /// Signing keys pinned for each sender pubkey, to reject spoofed notes
+    known_signing_keys: SigningKeys,
+    /// Forward secret sessions with peers
+    sessions: Sessions,
+    /// Sender and decrypted content of notes by note id, so each is only decrypted once. Session
+    /// notes can't be decrypted again anyway since their message keys are discarded.
+    contents: HashMap<String, (String, String)>,
+    /// Decrypts received notes in the background
+    decrypt: DecryptPool,
+    /// Message types the server has deprecated that we haven't warned about using yet
+    deprecations: HashMap<String, Deprecation>,
+    /// Where our connection is at, e.g. authenticated
+    connection: ConnectionState,
+    /// Token to resume our last session with, once the server says it understands them
+    resume_token: Option<String>,
+    /// Protocol version the server speaks
+    server_protocol_version: u32,
+    /// Id of the last note the server handed us, to fetch the ones after it after reconnecting
+    last_note_id: Option<String>,
+    /// Whether we reconnected and haven't fetched the notes lost in flight yet
+    reconnected: bool,
+    /// Notes and commands submitted from the input box, oldest first, to recall with Up and Down
+    inputs: Vec<String>,
+    /// Notes scrolled up from the newest one
+    scroll: usize,
+    /// Whether local history may hold notes older than the ones loaded
+    older_in_history: bool,
+    /// Whether the server's archive may hold notes older than the ones loaded
+    older_on_server: bool,
+    /// Whether we're waiting on a page of the server's archive
+    fetching_history: bool,
+    /// When each peer last told us they're typing
+    typing: HashMap<String, Instant>,
+    /// Keys their owners revoked, which we won't encrypt to
+    revoked: HashSet<String>,
+    /// New key of each peer that moved on from an old one, so notes still in flight from the old
+    /// key stay in the conversation
+    former_keys: HashMap<String, String>,
     /// History of recorded notes (chat messages)
     notes: Vec<Note>,
+    /// Ids of notes a rule highlighted
+    highlighted: HashSet<String>,
+    /// Notes sent that the server hasn't accepted yet
+    unacked: Unacked,
+    /// Last note read in each conversation, synced with our other devices
+    read_positions: ReadPositions,
+    /// Latest status to show the user, e.g. errors from the server
+    status: String,
+    /// Downtime the server has announced
+    maintenance: Option<Maintenance>,
+}
+
+/// App holds the state of the application
+struct App<'a> {
+    /// Identities we are chatting as
+    accounts: Vec<Account<'a>>,
+    /// Index of the account shown
+    active: usize,
+    /// Token to present to the server's auth backend
+    auth_token: Option<String>,
+    /// Whether to hide our pubkey from the server inside sent notes
+    sealed_sender: bool,
+    /// Whether to offer the recipient a forward secret session
+    forward_secrecy: bool,
+    /// How to show note timestamps in each conversation
+    conversation_times: ConversationTimes,
+    /// Peer of the conversation shown
+    recipient: Recipient,
+    /// Peers we're chatting with, in the order their conversations were opened
+    conversations: Vec<Recipient>,
+    /// Open a conversation with anyone who sends us a note
+    allow_anyone: bool,
+    /// Ids of notes already received, to drop replays
+    seen_notes: SeenNotes,
+    /// Tokens to resume each identity's session with after a restart
+    resume_tokens: ResumeTokens,
+    /// Notes submitted while disconnected, sent once reconnected
+    outbox: Outbox,
+    /// Notes shown in past runs and this one, unless history is turned off
+    history: Option<History>,
+    /// Filter rules evaluated on received notes
+    rules: Rules,
+    /// User's hook command, run on received notes the rules let through
+    hook: Option<Hook>,
+    /// Webhook to forward received notes to
+    webhook: Option<Webhook>,
+    /// Whether to raise a desktop notification for notes that aren't on screen
+    desktop_notifications: bool,
+    /// Leave the text out of desktop notifications
+    hide_notification_content: bool,
+    /// Whether the terminal has focus, as far as it tells us
+    focused: bool,
+    /// How to ring the bell when a note comes
+    bell: Bell,
+    /// When to stop flashing the screen for the visual bell
+    flash_until: Option<Instant>,
     /// Current value of the input box
     input: String,
     /// Position of cursor in the editor area.
     character_index: usize,
+    /// Index of the active account's input recalled into the input box, while going through them
+    recalled: Option<usize>,
+    /// Whether to send the input as soon as we're authenticated, then exit
+    send_input: bool,
+    /// Whether to suspend the TUI to compose a note in the user's editor
+    compose: bool,
+    /// Id of the note sent on startup, to exit once the server echoes it back
+    sent_note_id: Option<String>,
+    /// New identity to announce to the recipient once authenticated
+    rotate_to: Option<Identity>,
+    /// New key the recipient announced, waiting for the user to confirm switching to it
+    pending_key_change: Option<KeyChange>,
+    /// The server's key changed since we trusted it on first use, warned about until a
+    /// connection succeeds again
+    cert_change: Option<CertChange>,
+    /// Lines of notes that fit on screen as of the last draw, to scroll by pages
+    notes_height: Cell<usize>,
     /// Channels to coordinate shutdowns with the rest of the program
-    shutdown_tx: Sender<()>,
-    shutdown_rx: Receiver<()>,
+    shutdown_tx: Sender<Shutdown>,
+    shutdown_rx: Receiver<Shutdown>,
 }
 
 impl<'a> App<'a> {
     fn new(
-        comms: &'a mut Comms,
-        key: Identity,
-        recipient: Recipient,
-        shutdown_tx: Sender<()>,
-        shutdown_rx: Receiver<()>,
-    ) -> Self {
-        Self {
-            comms,
-            pub_key: key.to_public(),
-            priv_key: key,
-            authenticated: false,
-            recipient,
-            notes: Vec::new(),
-            input: String::new(),
-            character_index: 0,
+        connections: Vec<(String, Identity, &'a mut Comms)>,
+        config: &Config,
+        conversations: Vec<Recipient>,
+        seen_notes: SeenNotes,
+        stdin_content: Option<String>,
+        shutdown_tx: Sender<Shutdown>,
+        shutdown_rx: Receiver<Shutdown>,
+    ) -> Result<Self> {
+        let rules = match &config.rules_file {
+            Some(path) => Rules::load(path)?,
+            None => Rules::default(),
+        };
+        let rotate_to = match &config.rotate_to {
+            Some(path) => {
+                check_key_perms(path, config.insecure_key_perms)?;
+                Some(load_key(path)?)
+            }
+            None => None,
+        };
+        let mut accounts: Vec<Account> = connections
+            .into_iter()
+            .map(|(server, key, comms)| Account::new(server, key, comms))
+            .collect::<Result<_>>()?;
+        let identities: Vec<Identity> = accounts
+            .iter()
+            .map(|account| account.priv_key.clone())
+            .collect();
+        let history = if config.history {
+            let history = History::open(Path::new(HISTORY_PATH))?;
+            for account in &mut accounts {
+                account.inputs = history.load_inputs(&account.priv_key)?;
+                for peer in &conversations {
+                    let conversation = peer.to_string();
+                    account.load_history_page(&history, &conversation)?;
+                    // They were read in the runs that showed them, and synced to our other
+                    // devices
+                    if let Some(note_id) = account.newest_note_id(&conversation) {
+                        account.read_positions.set_synced(&conversation, &note_id);
+                    }
+                }
+            }
+            Some(history)
+        } else {
+            None
+        };
+        Ok(Self {
+            accounts,
+            active: 0,
+            auth_token: config.auth_token.clone(),
+            sealed_sender: config.sealed_sender,
+            forward_secrecy: config.forward_secrecy,
+            conversation_times: config.conversation_times.clone(),
+            recipient: conversations[0].clone(),
+            conversations,
+            allow_anyone: config.allow_anyone,
+            seen_notes,
+            resume_tokens: ResumeTokens::load(Path::new(RESUME_TOKENS_PATH))?,
+            outbox: Outbox::load(Path::new(OUTBOX_PATH), &identities)?,
+            history,
+            rules,
+            hook: config.hook.clone().map(Hook::new),
+            // A daemon we're attached to forwards notes itself
+            webhook: config.webhook.clone().filter(|_| config.daemon.is_none()),
+            desktop_notifications: config.desktop_notifications,
+            hide_notification_content: config.hide_notification_content,
+            focused: true,
+            bell: config.bell,
+            flash_until: None,
+            character_index: stdin_content.as_ref().map_or(0, |c| c.chars().count()),
+            recalled: None,
+            input: stdin_content.unwrap_or_default(),
+            send_input: config.stdin_note == StdinNote::Send,
+            compose: false,
+            sent_note_id: None,
+            rotate_to,
+            pending_key_change: None,
+            cert_change: None,
+            notes_height: Cell::new(0),
             shutdown_tx,
             shutdown_rx,
-        }
+        })
     }
 
-    /// Run the main app loop
-    fn run(mut self, mut terminal: DefaultTerminal) -> Result<()> {
-        // Authenticate
-        info!(
-            "✍️ Attempting to authenticate to server as {}",
-            self.pub_key
-        );
-        self.comms
-            .try_send_msg(ClientMsg::AuthReq(Auth::new(self.pub_key.to_string())))?;
+    /// Run the main app loop, returning why it stopped. Sleeps until there's a keypress, a
+    /// message, a note done decrypting or a redraw due.
+    async fn run(&mut self, mut terminal: DefaultTerminal) -> Result<Shutdown> {
+        let mut terminal_events = EventStream::new();
+        let mut redraw = time::interval(REDRAW_INTERVAL);
+        redraw.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+
+        // Authenticate each identity on its own connection
+        for i in 0..self.accounts.len() {
+            self.authenticate(i)?;
+        }
 
         loop {
             // Shutdown
-            if self.shutdown_rx.try_recv().is_ok() {
-                info!("⛔ Received shutdown signal");
-                return Ok(());
+            if let Ok(shutdown) = self.shutdown_rx.try_recv() {
+                info!("⛔ Received shutdown signal: {shutdown}");
+                return Ok(shutdown);
             };
 
-            // Handle new messages
-            while let Ok(msg) = self.comms.try_recv_msg() {
-                self.handle_msg(msg)?;
+            // Handle connections dropping and coming back, new messages, and notes that have
+            // finished decrypting
+            for i in 0..self.accounts.len() {
+                while let Some(event) = self.accounts[i].comms.try_recv_event() {
+                    self.handle_connection_event(i, event)?;
+                }
+                while let Ok(msg) = self.accounts[i].comms.try_recv_msg() {
+                    self.handle_msg(i, msg)?;
+                }
+                while let Some((note, opened)) = self.accounts[i].decrypt.try_recv() {
+                    self.receive_note(i, note, opened)?;
+                }
+                self.accounts[i].sync_read_positions()?;
+                self.accounts[i].resend_unacked()?;
+            }
+            while let Some(hooked) = self.hook.as_mut().and_then(Hook::try_recv) {
+                self.finish_hook(hooked)?;
             }
 
-            // Don't do anything else unless authenticated
-            if !self.authenticated {
-                continue;
+            // Send the note piped to stdin, without waiting for the user to press enter
+            if self.send_input && self.accounts[self.active].authenticated() {
+                self.send_input = false;
+                let content = std::mem::take(&mut self.input);
+                self.reset_cursor();
+                self.sent_note_id = Some(self.send_note(content)?);
+            }
+
+            // Compose a note in the user's editor, with nothing else reading the terminal meanwhile
+            if self.compose {
+                self.compose = false;
+                drop(terminal_events);
+                let composed = self.compose_in_editor(&mut terminal);
+                terminal_events = EventStream::new();
+                composed?;
+            }
+
+            // Tell the recipient about our new key
+            if self.accounts[self.active].authenticated() {
+                if let Some(new_identity) = self.rotate_to.take() {
+                    self.announce_key_change(&new_identity)?;
+                }
             }
 
             // Draw the TUI
             terminal.draw(|frame| self.draw(frame))?;
 
-            // Handle keypresses
-            self.handle_keypresses()?;
+            // Wait for something to happen
+            let flash_until = self.flash_until.filter(|until| Instant::now() < *until);
+            let wakes = self
+                .accounts
+                .iter_mut()
+                .map(|account| Box::pin(account.wake()));
+            let wake = tokio::select! {
+                Some(event) = terminal_events.next() => Wake::Terminal(event?),
+                (received, i, _) = select_all(wakes) => match received {
+                    Some(received) => Wake::Received(i, received),
+                    None => Wake::Redraw,
+                },
+                () = wait_hook(self.hook.as_mut()) => Wake::Redraw,
+                Ok(shutdown) = self.shutdown_rx.recv() => Wake::Shutdown(shutdown),
+                _ = redraw.tick() => Wake::Redraw,
+                // End the visual bell's flash on time
+                () = time::sleep_until(flash_until.unwrap_or_else(Instant::now).into()),
+                    if flash_until.is_some() => Wake::Redraw,
+            };
+            match wake {
+                Wake::Terminal(event) => self.handle_terminal_event(event)?,
+                Wake::Received(i, Received::Msg(msg)) => self.handle_msg(i, msg)?,
+                Wake::Received(i, Received::Event(event)) => {
+                    self.handle_connection_event(i, event)?
+                }
+                Wake::Shutdown(shutdown) => {
+                    info!("⛔ Received shutdown signal: {shutdown}");
+                    return Ok(shutdown);
+                }
+                Wake::Redraw => {}
+            }
+        }
+    }
+
+    /// Say hello on an account's connection and authenticate it. With a token from our last
+    /// session we wait to hear the server can resume it, skipping the key challenge.
+    fn authenticate(&mut self, i: usize) -> Result<()> {
+        let account = &mut self.accounts[i];
+        info!(
+            "✍️ Attempting to authenticate to server as {}",
+            account.pub_key
+        );
+        account.send_msg(ClientMsg::Hello(Hello {
+            protocol_version: PROTOCOL_VERSION,
+            presence_only: false,
+            compression: false,
+        }))?;
+        account.resume_token = self.resume_tokens.take(&account.resume_key())?;
+        if account.resume_token.is_none() {
+            self.send_auth_req(i)?;
+        }
+        Ok(())
+    }
+
+    /// Show where an account's connection is at, authenticating again once it's back
+    fn handle_connection_event(&mut self, i: usize, event: ConnectionEvent) -> Result<()> {
+        let account = &mut self.accounts[i];
+        match event {
+            ConnectionEvent::Reconnecting {
+                attempt,
+                delay,
+                reason,
+            } => {
+                account.connection = ConnectionState::Reconnecting {
+                    attempt,
+                    at: Instant::now() + delay,
+                };
+                account.status = format!("Lost connection to server: {reason}");
+                Ok(())
+            }
+            ConnectionEvent::Connecting { attempt } => {
+                account.connection = ConnectionState::Connecting { attempt };
+                Ok(())
+            }
+            ConnectionEvent::Reconnected => {
+                account.connection = ConnectionState::Authenticating;
+                account.status = "Reconnected".into();
+                account.reconnected = true;
+                self.cert_change = None;
+                self.authenticate(i)
+            }
+            ConnectionEvent::Authenticated => {
+                account.connection = ConnectionState::Authenticated;
+                Ok(())
+            }
+            ConnectionEvent::Failed { reason } => {
+                account.connection = ConnectionState::Failed;
+                account.status = format!("Connection failed: {reason}");
+                Ok(())
+            }
+            ConnectionEvent::CertificateChanged(change) => {
+                self.cert_change = Some(change);
+                Ok(())
+            }
         }
     }
 
-    /// Handle incoming message from the server
-    fn handle_msg(&mut self, msg: ServerMsg) -> Result<()> {
+    /// Ask the server for a key challenge to authenticate an account with
+    fn send_auth_req(&mut self, i: usize) -> Result<()> {
+        let account = &mut self.accounts[i];
+        account.send_msg(ClientMsg::AuthReq(Auth::new(
+            account.pub_key.to_string(),
+            account.signing_key.clone(),
+            self.auth_token.clone(),
+        )))
+    }
+
+    /// Handle incoming message from the server on an account's connection
+    fn handle_msg(&mut self, i: usize, msg: ServerMsg) -> Result<()> {
+        let account = &mut self.accounts[i];
         match msg {
+            ServerMsg::HelloAck(ack) => {
+                info!("👋 Server speaks protocol version {}", ack.protocol_version);
+                account.server_protocol_version = ack.protocol_version;
+                for deprecation in ack.deprecations {
+                    warn!(
+                        "⚠️ Server has deprecated {}: {}",
+                        deprecation.feature, deprecation.message
+                    );
+                    account
+                        .deprecations
+                        .insert(deprecation.feature.clone(), deprecation);
+                }
+                let Some(token) = account.resume_token.take() else {
+                    return Ok(());
+                };
+                if ack.protocol_version < RESUME_PROTOCOL_VERSION {
+                    return self.send_auth_req(i);
+                }
+                info!("✍️ Resuming our last session as {}", account.pub_key);
+                account.send_msg(ClientMsg::Resume(Resume {
+                    pub_key: account.pub_key.to_string(),
+                    token,
+                }))
+            }
+            ServerMsg::ResumeToken { token } => {
+                self.resume_tokens.set(&account.resume_key(), token)
+            }
+            ServerMsg::History(page) => self.merge_history(i, page),
             ServerMsg::AuthSecret(auth) => {
                 info!(
                     "✍️ Decrypting secret {} for pubkey {} to authenticate to the server",
                     auth.ciphertext, auth.pub_key
                 );
-                // TODO: what if pub_key in auth is different than self.pub_key?
-                // TODO: this is not secure, as the server can have the client decrypt arbitrary secrets
-                let plaintext =
-                    String::from_utf8(age::decrypt(&self.priv_key, auth.ciphertext.as_bytes())?)?;
+                // Only ever hand back the nonce of a valid challenge for our own pubkey, so the
+                // server can't use us to decrypt arbitrary ciphertexts
+                let plaintext = match AuthChallenge::decrypt(&account.priv_key, &auth.ciphertext) {
+                    Ok(challenge) => challenge.nonce,
+                    Err(e) => {
+                        error!("✍️ Rejecting auth secret from server, shutting down: {e}");
+                        self.shutdown_tx.send(Shutdown::ConnectionFailed(format!(
+                            "Invalid auth secret: {e}"
+                        )))?;
+                        return Ok(());
+                    }
+                };
                 let auth_plaintext = Auth {
                     pub_key: auth.pub_key,
+                    signing_key: auth.signing_key,
+                    token: auth.token,
                     plaintext,
                     ciphertext: auth.ciphertext,
                 };
-                self.comms
+                account
+                    .comms
                     .try_send_msg(ClientMsg::AuthPlaintext(auth_plaintext))?;
                 Ok(())
             }
@@ -140,106 +591,1129 @@ impl<'a> App<'a> {
                     "✍️ Successfully authenticated to server as {}",
                     auth.pub_key
                 );
-                self.authenticated = true;
-                Ok(())
+
+                // Get the notes that were in flight when our last connection dropped
+                if account.reconnected && account.server_protocol_version >= SYNC_PROTOCOL_VERSION {
+                    account.send_msg(ClientMsg::SyncSince(SyncSince {
+                        after: account.last_note_id.clone(),
+                    }))?;
+                }
+                account.reconnected = false;
+
+                // Get the notes that arrived while we were offline
+                account.send_msg(ClientMsg::FetchMailbox)?;
+
+                // Offer a forward secret session, falling back to plain notes until accepted.
+                // Session notes and handshakes show the server who we talk to, so sealed sender
+                // goes without. Sessions from past runs are kept, replacing them would strand
+                // notes still in flight.
+                if self.forward_secrecy && !self.sealed_sender {
+                    for peer in &self.conversations {
+                        let recipient = peer.to_string();
+                        if account.sessions.is_active(&recipient) {
+                            continue;
+                        }
+                        let (session_id, ephemeral_key) = account.sessions.offer(&recipient)?;
+                        info!("🤝 Offering forward secret session to {recipient}");
+                        let offer = SessionHandshake::new(
+                            &account.priv_key,
+                            recipient,
+                            session_id,
+                            ephemeral_key,
+                        )?;
+                        account.send_msg(ClientMsg::SessionOffer(offer))?;
+                    }
+                }
+
+                // A new device has no history of its own, fill it in from the server's archive
+                if self.accounts[i]
+                    .oldest_note_id(&self.recipient.to_string())
+                    .is_none()
+                {
+                    self.load_older(i)?;
+                }
+
+                // Send the notes submitted while we were disconnected
+                self.flush_outbox(i)
             }
             ServerMsg::AuthDenied(auth) => {
                 info!(
                     "✍️ Failed authenticating to server as {}, shutting down",
                     auth.pub_key
                 );
-                self.shutdown_tx.send(())?;
+                self.shutdown_tx.send(Shutdown::AuthDenied)?;
                 Ok(())
             }
             ServerMsg::RecNote(note) => {
-                info!("✉️ Received new note");
-                self.notes.push(note);
+                // The server echoes notes back once it's accepted them
+                if account.unacked.ack(&note.id) {
+                    self.outbox.ack(&note.id)?;
+                }
+                if self.sent_note_id.as_ref() == Some(&note.id) {
+                    info!("✉️ Sent note {} from stdin, shutting down", note.id);
+                    self.shutdown_tx.send(Shutdown::Sent)?;
+                    return Ok(());
+                }
+                // Scope seen ids to the account, since our other identities get the same note
+                let seen_id = format!("{}/{}", account.pub_key, note.id);
+                if !self.seen_notes.insert(&seen_id)? {
+                    warn!("✉️ Dropping replayed note {} from {}", note.id, note.from);
+                    return Ok(());
+                }
+                if note.to == account.pub_key.to_string() {
+                    account.last_note_id = Some(note.id.clone());
+                }
+                // Session notes have to be decrypted in order as the ratchet advances, the rest
+                // are decrypted in the background
+                if note.session.is_some() {
+                    let opened = account.open_session_note(&note);
+                    account.decrypt.submit_opened(note, opened);
+                } else {
+                    account.decrypt.submit(note);
+                }
+                Ok(())
+            }
+            ServerMsg::QuotaUsage(usage) => {
+                info!("📦 Received quota usage: {usage:?}");
+                account.status = format!(
+                    "Storage used: {} / {} bytes",
+                    usage.used_bytes, usage.quota_bytes
+                );
+                Ok(())
+            }
+            ServerMsg::Error(e) if matches!(e.kind, ErrorKind::ResumeRejected) => {
+                info!("✍️ Server couldn't resume our last session, authenticating in full: {e}");
+                self.send_auth_req(i)
+            }
+            // Until it's echoed back, an error is about the note piped to stdin
+            ServerMsg::Error(e) if self.sent_note_id.is_some() => {
+                warn!("❗ Server refused note from stdin, shutting down: {e}");
+                self.shutdown_tx.send(Shutdown::NotSent(e.to_string()))?;
+                Ok(())
+            }
+            ServerMsg::Error(e) => {
+                warn!("❗ Received error from server: {e}");
+                account.status = format!("Error: {e}");
+                Ok(())
+            }
+            ServerMsg::Maintenance(maintenance) => {
+                info!(
+                    "🚧 Server announced maintenance from {} for {}s: {}",
+                    maintenance.starts_at, maintenance.duration_secs, maintenance.message
+                );
+                account.maintenance = Some(maintenance);
+                Ok(())
+            }
+            ServerMsg::MaintenanceCancelled => {
+                info!("🚧 Server cancelled maintenance");
+                account.maintenance = None;
+                Ok(())
+            }
+            ServerMsg::Typing(typing) => {
+                account.typing.insert(typing.from, Instant::now());
+                Ok(())
+            }
+            ServerMsg::Revoked(revocation) => {
+                let pub_key = revocation.pub_key.clone();
+                if let Err(e) = account.revoke(&revocation) {
+                    warn!("🪪 Rejecting revocation of {pub_key}: {e}");
+                } else if pub_key == self.recipient.to_string() {
+                    account.status = "Recipient revoked their key, it can't be sent to".into();
+                }
+                Ok(())
+            }
+            ServerMsg::DuplicateLogin(duplicate) => {
+                warn!(
+                    "🕵️ Another device at {} proved it holds our key at {}",
+                    duplicate.ip, duplicate.at
+                );
+                account.status = format!(
+                    "Warning: another device at {} tried to sign in with this key",
+                    duplicate.ip
+                );
+                Ok(())
+            }
+            ServerMsg::Announcement { message } => {
+                info!("📣 Server announced: {message}");
+                account.status = format!("Announcement: {message}");
+                Ok(())
+            }
+            // Only sent to presence-only connections
+            ServerMsg::Presence(_) => Ok(()),
+            ServerMsg::SessionOffer(offer) => {
+                if self.sealed_sender {
+                    info!(
+                        "🤝 Ignoring session offer from {}, sealed sender notes can't use sessions",
+                        offer.from
+                    );
+                } else if let Err(e) = account.accept_session(&offer) {
+                    warn!("🤝 Rejecting session offer from {}: {e}", offer.from);
+                }
+                Ok(())
+            }
+            ServerMsg::SessionAccept(accept) => {
+                let complete_res = accept
+                    .verify_signature()
+                    .and_then(|_| accept.check_key_proof(&account.priv_key))
+                    .and_then(|_| account.pin_signing_key(&accept.from, &accept.signing_key, true))
+                    .and_then(|_| account.sessions.complete(&accept));
+                match complete_res {
+                    Ok(()) => {
+                        info!("🤝 Forward secret session with {} set up", accept.from);
+                        account.status = "Forward secrecy enabled".into();
+                    }
+                    Err(e) => warn!("🤝 Rejecting session acceptance from {}: {e}", accept.from),
+                }
                 Ok(())
             }
         }
     }
 
-    /// Handle keypresses, using poll so we don't block forever waiting
-    fn handle_keypresses(&mut self) -> Result<()> {
-        if event::poll(Duration::from_millis(POLL_DURATION_MILLIS))? {
-            let Event::Key(key) = event::read()? else {
+    /// Handle a note an account received once it's decrypted
+    fn receive_note(&mut self, i: usize, note: Note, opened: Result<OpenedNote>) -> Result<()> {
+        let account = &mut self.accounts[i];
+        let check_res = opened.and_then(|opened| {
+            // Check the note is signed by the same key as previous notes from the sender
+            account.pin_signing_key(&opened.from, &opened.signing_key, opened.key_proven)?;
+            Ok(opened)
+        });
+        let opened = match check_res {
+            Ok(opened) => opened,
+            Err(e) => {
+                warn!("✉️ Dropping note {} from {}: {e}", note.id, note.from);
+                account.status = format!("Dropped a note: {e}");
                 return Ok(());
-            };
-            if key.kind != KeyEventKind::Press {
+            }
+        };
+
+        // Notes to ourselves from ourselves can be state synced from our other devices
+        let own_pub_key = account.pub_key.to_string();
+        if opened.from == own_pub_key && note.to == own_pub_key {
+            if let Some(control) = ControlNote::parse(&opened.content) {
+                match control {
+                    Ok(control) => account.apply_control_note(control),
+                    Err(e) => warn!("🔄 Dropping invalid control note {}: {e}", note.id),
+                }
+                return Ok(());
+            }
+        }
+
+        // Peers announce new keys with control notes too
+        if let Some(control) = ControlNote::parse(&opened.content) {
+            match control {
+                Ok(ControlNote::KeyChange(key_change)) => {
+                    self.receive_key_change(i, &opened.from, key_change)
+                }
+                Ok(_) => warn!("🔄 Dropping control note {} from {}", note.id, opened.from),
+                Err(e) => warn!("🔄 Dropping invalid control note {}: {e}", note.id),
+            }
+            return Ok(());
+        }
+
+        info!("✉️ Received new note");
+        account.typing.remove(&opened.from);
+        account.contents.insert(
+            note.id.clone(),
+            (opened.from.clone(), opened.content.clone()),
+        );
+        let conversation = account.conversation(&note)?;
+        if !self.has_conversation(&conversation) {
+            if !self.allow_anyone {
+                info!("💬 Ignoring note {} with {conversation}", note.id);
+                self.accounts[i].status = format!(
+                    "Ignored a note from {}, /open their pubkey to chat with them",
+                    short_key(&conversation)
+                );
                 return Ok(());
+            }
+            let peer = Recipient::from_str(&conversation).map_err(|e| anyhow!(e))?;
+            self.open_conversation(peer)?;
+        }
+        if let Some(webhook) = self.webhook.as_ref().filter(|_| !self.is_own(&opened.from)) {
+            webhook.forward(&note, &opened.from, &opened.content);
+        }
+        if !self.apply_rules(i, &note)? {
+            return Ok(());
+        }
+        // The hook decides whether to show it once it's done
+        let own = self.is_own(&opened.from);
+        match &mut self.hook {
+            Some(hook) if !own => hook.submit(i, note, opened.from, opened.content),
+            _ => self.show_note(i, note, &opened.from, &opened.content)?,
+        }
+        Ok(())
+    }
+
+    /// Show a received note in an account's conversation and save it to history
+    fn show_note(&mut self, i: usize, note: Note, from: &str, content: &str) -> Result<()> {
+        let account = &mut self.accounts[i];
+        let conversation = account.conversation(&note)?;
+        if let Some(history) = &self.history {
+            if let Err(e) = history.record(&account.pub_key, &conversation, &note, from, content) {
+                error!("💾 Error saving note {} to history: {e:#}", note.id);
+            }
+        }
+        account.notes.push(note);
+        // Keep the view still if the user scrolled up to read older notes
+        if account.scroll > 0 {
+            account.scroll += 1;
+        }
+        let on_screen =
+            self.focused && i == self.active && conversation == self.recipient.to_string();
+        if i == self.active && conversation == self.recipient.to_string() {
+            account.mark_read(&conversation);
+        }
+        if !self.is_own(from) {
+            self.ring_bell();
+        }
+        // Notify about notes that aren't on screen
+        if self.desktop_notifications && !on_screen && !self.is_own(from) {
+            let content = (!self.hide_notification_content).then_some(content);
+            desktop::notify(&short_key(from), content);
+        }
+        Ok(())
+    }
+
+    /// Show a note the hook let through, and send its reply to the sender
+    fn finish_hook(&mut self, hooked: Hooked) -> Result<()> {
+        let Hooked {
+            account: i,
+            note,
+            from,
+            content,
+            verdict,
+        } = hooked;
+        if verdict.mute {
+            info!("🪝 Hook muted note {}", note.id);
+        } else {
+            self.show_note(i, note, &from, &content)?;
+        }
+        if let Some(reply) = verdict.reply {
+            info!("🪝 Sending hook reply to {from}");
+            let send_res = Recipient::from_str(&from)
+                .map_err(|e| anyhow!(e))
+                .and_then(|to| self.send_note_as(i, &to, reply));
+            if let Err(e) = send_res {
+                warn!("🪝 Error sending hook reply to {from}: {e}");
+                self.accounts[i].status = format!("Hook reply not sent: {e}");
+            }
+        }
+        Ok(())
+    }
+
+    /// Ring the bell for a note that came
+    fn ring_bell(&mut self) {
+        match self.bell {
+            Bell::Off => {}
+            Bell::Audible => {
+                let mut stdout = std::io::stdout();
+                if let Err(e) = stdout.write_all(b"\x07").and_then(|_| stdout.flush()) {
+                    warn!("🔔 Error ringing the bell: {e}");
+                }
+            }
+            Bell::Visual => self.flash_until = Some(Instant::now() + FLASH_DURATION),
+        }
+    }
+
+    /// Whether a pubkey is one of the identities we're chatting as
+    fn is_own(&self, pub_key: &str) -> bool {
+        self.accounts
+            .iter()
+            .any(|account| account.pub_key.to_string() == pub_key)
+    }
+
+    /// Merge archived notes the server sent into an account's conversation, skipping the ones we
+    /// already have
+    fn merge_history(&mut self, i: usize, page: HistoryPage) -> Result<()> {
+        let account = &mut self.accounts[i];
+        account.fetching_history = false;
+        account.older_on_server = page.notes.len() >= HISTORY_PAGE_SIZE;
+        let mut merged = 0;
+        for note in page.notes {
+            if account.contents.contains_key(&note.id) {
+                continue;
+            }
+            // Session notes can't be decrypted again once their message keys are discarded
+            if note.session.is_some() {
+                continue;
+            }
+            let opened = note.open(&account.priv_key).and_then(|opened| {
+                account.pin_signing_key(&opened.from, &opened.signing_key, opened.key_proven)?;
+                Ok(opened)
+            });
+            let opened = match opened {
+                Ok(opened) => opened,
+                Err(e) => {
+                    warn!(
+                        "📜 Dropping archived note {} from {}: {e}",
+                        note.id, note.from
+                    );
+                    continue;
+                }
             };
+            if ControlNote::parse(&opened.content).is_some() {
+                continue;
+            }
+            self.seen_notes
+                .insert(&format!("{}/{}", account.pub_key, note.id))?;
+            account.contents.insert(
+                note.id.clone(),
+                (opened.from.clone(), opened.content.clone()),
+            );
+            if let Some(history) = &self.history {
+                let conversation = account.conversation(&note)?;
+                if let Err(e) = history.record(
+                    &account.pub_key,
+                    &conversation,
+                    &note,
+                    &opened.from,
+                    &opened.content,
+                ) {
+                    error!("💾 Error saving note {} to history: {e:#}", note.id);
+                }
+            }
+            account.notes.push(note);
+            merged += 1;
+        }
+        info!("📜 Merged {merged} archived notes with {}", page.peer);
+        if merged == 0 {
+            return Ok(());
+        }
+        account.notes.sort_by_key(|note| note.timestamp);
+        // They were read on the devices that received them
+        if account.read_positions.get(&page.peer).is_none() {
+            let last = account
+                .notes
+                .iter()
+                .rev()
+                .find(|note| account.conversation(note).is_ok_and(|c| c == page.peer));
+            if let Some(last) = last {
+                let note_id = last.id.clone();
+                account.read_positions.set_synced(&page.peer, &note_id);
+            }
+        }
+        account.status = format!("Fetched {merged} older notes from the server");
+        Ok(())
+    }
 
-            match key.code {
-                KeyCode::Char('c') if key.modifiers == KeyModifiers::CONTROL => {
-                    self.shutdown_tx.send(())?;
-                    return Ok(());
+    /// Hold on to a key change a peer announced, until the user confirms switching to it
+    fn receive_key_change(&mut self, i: usize, from: &str, key_change: KeyChange) {
+        let account = &mut self.accounts[i];
+        // The server echoes our own announcements back
+        if from == account.pub_key.to_string() {
+            return;
+        }
+        if key_change.old_key != from {
+            warn!(
+                "🔁 Dropping key change for {} sent by {from}",
+                key_change.old_key
+            );
+            return;
+        }
+        if let Err(e) = key_change
+            .verify_signature()
+            .and_then(|_| Recipient::from_str(&key_change.new_key).map_err(|e| anyhow!(e)))
+        {
+            warn!("🔁 Dropping invalid key change from {from}: {e}");
+            return;
+        }
+        if !self.has_conversation(from) {
+            info!("🔁 Ignoring key change from {from}, who we aren't chatting with");
+            return;
+        }
+        info!("🔁 {from} announced a new key {}", key_change.new_key);
+        let account = &mut self.accounts[i];
+        account.status = format!(
+            "{} moved to new key {}, press Ctrl+K to switch to it",
+            short_key(from),
+            short_key(&key_change.new_key)
+        );
+        self.pending_key_change = Some(key_change);
+    }
+
+    /// Start encrypting to the key a peer announced, once the user confirms it
+    fn switch_recipient_key(&mut self) -> Result<()> {
+        let Some(key_change) = self.pending_key_change.take() else {
+            return Ok(());
+        };
+        let new_recipient = Recipient::from_str(&key_change.new_key).map_err(|e| anyhow!(e))?;
+        for account in &mut self.accounts {
+            if let Err(e) =
+                account.pin_signing_key(&key_change.new_key, &key_change.new_signing_key, false)
+            {
+                warn!("🔁 Not switching to new key {}: {e}", key_change.new_key);
+                account.status = format!("Not switching to new key: {e}");
+                return Ok(());
+            }
+        }
+        info!(
+            "🔁 Switched peer from {} to {}",
+            key_change.old_key, key_change.new_key
+        );
+        for account in &mut self.accounts {
+            account.change_key(&key_change.old_key, &key_change.new_key);
+            account.status = format!(
+                "Switched to peer's new key {}",
+                short_key(&key_change.new_key)
+            );
+        }
+        for peer in &mut self.conversations {
+            if peer.to_string() == key_change.old_key {
+                *peer = new_recipient.clone();
+            }
+        }
+        if self.recipient.to_string() == key_change.old_key {
+            self.recipient = new_recipient;
+        }
+        Ok(())
+    }
+
+    /// Announce a new identity to the recipient from the active account
+    fn announce_key_change(&mut self, new_identity: &Identity) -> Result<()> {
+        let old_key = self.accounts[self.active].pub_key.to_string();
+        let key_change = KeyChange::new(old_key, new_identity);
+        info!(
+            "🔁 Announcing new key {} to {}",
+            key_change.new_key, self.recipient
+        );
+        let new_key = short_key(&key_change.new_key);
+        self.send_note(ControlNote::KeyChange(key_change).to_content()?)?;
+        self.accounts[self.active].status = format!("Announced new key {new_key} to recipient");
+        Ok(())
+    }
+
+    /// Evaluate the filter rules on a note an account received, returning whether to show it
+    fn apply_rules(&mut self, i: usize, note: &Note) -> Result<bool> {
+        let account = &mut self.accounts[i];
+        let (from, content) = account.open_note(note)?;
+        // Notes from any of our own identities aren't filtered
+        if self.is_own(&from) {
+            return Ok(true);
+        }
+        let account = &mut self.accounts[i];
+        let kind = if note.session.is_some() {
+            NoteKind::Session
+        } else if note.is_sealed() {
+            NoteKind::Sealed
+        } else {
+            NoteKind::Plain
+        };
+
+        let mut show = true;
+        for action in self.rules.evaluate(&from, kind, &content) {
+            info!("📏 Applying rule action {action:?} to note {}", note.id);
+            match action {
+                Action::Notify => account.status = format!("New note from {from}"),
+                Action::Mute => show = false,
+                Action::Highlight => {
+                    account.highlighted.insert(note.id.clone());
+                }
+                Action::Archive => {
+                    account.archive_note(note)?;
+                    show = false;
                 }
-                KeyCode::Enter => self.submit_note()?,
-                KeyCode::Char(to_insert) => self.enter_char(to_insert),
-                KeyCode::Backspace => self.delete_char(),
-                KeyCode::Left => self.move_cursor_left(),
-                KeyCode::Right => self.move_cursor_right(),
-                _ => {}
+                Action::Run(command) => rules::run_command(&command, &from, &content),
             }
         }
+        Ok(show)
+    }
+
+    /// Handle a keypress or focus change from the terminal
+    fn handle_terminal_event(&mut self, event: Event) -> Result<()> {
+        let key = match event {
+            Event::Key(key) => key,
+            Event::FocusGained => {
+                self.focused = true;
+                return Ok(());
+            }
+            Event::FocusLost => {
+                self.focused = false;
+                return Ok(());
+            }
+            Event::Paste(text) => {
+                self.paste(&text);
+                return self.send_typing();
+            }
+            _ => return Ok(()),
+        };
+        if key.kind != KeyEventKind::Press {
+            return Ok(());
+        };
+
+        match key.code {
+            KeyCode::Char('c') if key.modifiers == KeyModifiers::CONTROL => {
+                self.shutdown_tx.send(Shutdown::Quit)?;
+                return Ok(());
+            }
+            KeyCode::Char('k') if key.modifiers == KeyModifiers::CONTROL => {
+                self.switch_recipient_key()?
+            }
+            KeyCode::Char('n') if key.modifiers == KeyModifiers::CONTROL => {
+                self.switch_conversation(1)
+            }
+            KeyCode::Char('p') if key.modifiers == KeyModifiers::CONTROL => {
+                self.switch_conversation(self.conversations.len() - 1)
+            }
+            KeyCode::Char('r') if key.modifiers == KeyModifiers::CONTROL => self.retry_failed(),
+            KeyCode::Char('e') if key.modifiers == KeyModifiers::CONTROL => {
+                let account = &mut self.accounts[self.active];
+                if account.revoked.contains(&self.recipient.to_string()) {
+                    account.status = "Recipient revoked their key, it can't be sent to".into();
+                } else {
+                    self.compose = true;
+                }
+            }
+            KeyCode::PageUp => self.scroll_up(self.notes_height.get().max(2) - 1)?,
+            KeyCode::PageDown => self.scroll_down(self.notes_height.get().max(2) - 1),
+            // Go through earlier inputs from an empty input box, like a shell, scrolling when
+            // there are none
+            KeyCode::Up
+                if self.recalled.is_some()
+                    || (self.input.is_empty() && !self.accounts[self.active].inputs.is_empty()) =>
+            {
+                self.recall_input(true)
+            }
+            KeyCode::Down if self.recalled.is_some() => self.recall_input(false),
+            KeyCode::Up => self.scroll_up(1)?,
+            KeyCode::Down => self.scroll_down(1),
+            KeyCode::Tab => self.switch_account(1),
+            KeyCode::BackTab => self.switch_account(self.accounts.len() - 1),
+            KeyCode::Enter => self.submit_note()?,
+            KeyCode::Char(to_insert) => {
+                self.enter_char(to_insert);
+                self.send_typing()?;
+            }
+            KeyCode::Backspace => self.delete_char(),
+            KeyCode::Left => self.move_cursor_left(),
+            KeyCode::Right => self.move_cursor_right(),
+            _ => {}
+        }
 
         Ok(())
     }
 
-    /// Send a note when the user presses enter
+    /// Downtime announced by the server, all our connections are to the same one
+    fn maintenance(&self) -> Option<&Maintenance> {
+        self.accounts
+            .iter()
+            .find_map(|account| account.maintenance.as_ref())
+    }
+
+    /// Load older notes in an account's conversation, from local history until it runs out and
+    /// then from the server's archive
+    fn load_older(&mut self, i: usize) -> Result<()> {
+        let conversation = self.recipient.to_string();
+        let account = &mut self.accounts[i];
+        if let Some(history) = self.history.as_ref().filter(|_| account.older_in_history) {
+            if account.load_history_page(history, &conversation)? > 0 {
+                return Ok(());
+            }
+        }
+        if !account.older_on_server
+            || account.fetching_history
+            || !account.authenticated()
+            || account.server_protocol_version < HISTORY_PROTOCOL_VERSION
+        {
+            return Ok(());
+        }
+        account.fetching_history = true;
+        let before_id = account.oldest_note_id(&conversation);
+        account.send_msg(ClientMsg::FetchHistory(FetchHistory {
+            peer: conversation,
+            before_id,
+            limit: HISTORY_PAGE_SIZE as u32,
+        }))
+    }
+
+    /// Scroll the active account's notes up towards older ones, loading more at the top
+    fn scroll_up(&mut self, lines: usize) -> Result<()> {
+        let recipient = self.recipient.to_string();
+        let account = &mut self.accounts[self.active];
+        let pub_key = account.pub_key.to_string();
+        let total = account.conversation_notes(&recipient).count()
+            + account.unacked.to(&recipient).count()
+            + self
+                .outbox
+                .pending(&pub_key, &recipient)
+                .filter(|note| !account.tracks(note))
+                .count();
+        let max_scroll = total.saturating_sub(self.notes_height.get());
+        account.scroll = (account.scroll + lines).min(max_scroll);
+        if account.scroll == max_scroll {
+            self.load_older(self.active)?;
+        }
+        Ok(())
+    }
+
+    /// Scroll the active account's notes down towards the newest one
+    fn scroll_down(&mut self, lines: usize) {
+        let account = &mut self.accounts[self.active];
+        account.scroll = account.scroll.saturating_sub(lines);
+    }
+
+    /// Whether we're connected to more than one server, so conversations need tagging with theirs
+    fn several_servers(&self) -> bool {
+        self.accounts
+            .iter()
+            .any(|account| account.server != self.accounts[0].server)
+    }
+
+    /// Show the account `offset` places after the active one, wrapping around
+    fn switch_account(&mut self, offset: usize) {
+        self.active = (self.active + offset) % self.accounts.len();
+        self.recalled = None;
+        let recipient = self.recipient.to_string();
+        self.accounts[self.active].mark_read(&recipient);
+    }
+
+    /// Whether we're chatting with a peer
+    fn has_conversation(&self, peer: &str) -> bool {
+        self.conversations
+            .iter()
+            .any(|recipient| recipient.to_string() == peer)
+    }
+
+    /// Start chatting with a peer, loading what local history we have with them
+    fn open_conversation(&mut self, peer: Recipient) -> Result<()> {
+        let conversation = peer.to_string();
+        if self.has_conversation(&conversation) {
+            return Ok(());
+        }
+        info!("💬 Opened conversation with {conversation}");
+        if let Some(history) = &self.history {
+            for account in &mut self.accounts {
+                account.load_history_page(history, &conversation)?;
+            }
+        }
+        self.conversations.push(peer);
+        Ok(())
+    }
+
+    /// Show the conversation `offset` places after the shown one, wrapping around
+    fn switch_conversation(&mut self, offset: usize) {
+        let current = self
+            .conversations
+            .iter()
+            .position(|peer| peer.to_string() == self.recipient.to_string())
+            .unwrap_or(0);
+        let next = (current + offset) % self.conversations.len();
+        self.show_conversation(self.conversations[next].clone());
+    }
+
+    /// Show the conversation with a peer, from its newest note
+    fn show_conversation(&mut self, peer: Recipient) {
+        self.recipient = peer;
+        let recipient = self.recipient.to_string();
+        let account = &mut self.accounts[self.active];
+        account.scroll = 0;
+        account.mark_read(&recipient);
+    }
+
+    /// Send a note from the active account, or run a slash command, when the user presses enter
     fn submit_note(&mut self) -> Result<()> {
-        let note = Note::encrypt_new(&self.pub_key, &self.recipient, self.input.clone())?;
-        self.comms.try_send_msg(ClientMsg::SendNote(note))?;
+        self.remember_input();
+        if let Some(pub_key) = self.input.strip_prefix("/open ") {
+            match Recipient::from_str(pub_key.trim()) {
+                Ok(peer) => {
+                    self.open_conversation(peer.clone())?;
+                    self.accounts[self.active].status =
+                        format!("Chatting with {}", short_key(&peer.to_string()));
+                    self.show_conversation(peer);
+                }
+                Err(e) => self.accounts[self.active].status = format!("Invalid pubkey: {e}"),
+            }
+            self.input.clear();
+            self.reset_cursor();
+            return Ok(());
+        }
+        let account = &mut self.accounts[self.active];
+        if account.revoked.contains(&self.recipient.to_string()) {
+            account.status = "Recipient revoked their key, it can't be sent to".into();
+            return Ok(());
+        }
+        match self.input.as_str() {
+            "/quota" if !account.authenticated() => {
+                account.status = "Not authenticated yet".into();
+                return Ok(());
+            }
+            "/quota" => account.send_msg(ClientMsg::QuotaQuery)?,
+            _ => self.send_or_queue(self.input.clone())?,
+        }
 
         self.input.clear();
         self.reset_cursor();
         Ok(())
     }
 
+    /// Keep what's submitted from the input box to recall later, skipping repeats
+    fn remember_input(&mut self) {
+        self.recalled = None;
+        let account = &mut self.accounts[self.active];
+        if self.input.trim().is_empty() || account.inputs.last() == Some(&self.input) {
+            return;
+        }
+        account.inputs.push(self.input.clone());
+        if account.inputs.len() > INPUT_HISTORY_LEN {
+            account.inputs.remove(0);
+        }
+        if let Some(history) = &self.history {
+            if let Err(e) = history.record_input(&account.pub_key, &self.input) {
+                error!("💾 Error saving input to history: {e:#}");
+            }
+        }
+    }
+
+    /// Put an older input in the input box, or a newer one, ending on an empty box going past
+    /// the newest
+    fn recall_input(&mut self, older: bool) {
+        let inputs = &self.accounts[self.active].inputs;
+        let recalled = match (self.recalled, older) {
+            (None, true) => inputs.len().checked_sub(1),
+            (None, false) => return,
+            (Some(i), true) => Some(i.saturating_sub(1)),
+            (Some(i), false) => Some(i + 1).filter(|&i| i < inputs.len()),
+        };
+        self.input = recalled.map(|i| inputs[i].clone()).unwrap_or_default();
+        self.recalled = recalled;
+        self.character_index = self.input.chars().count();
+    }
+
+    /// Send a note to the recipient from the active account, or keep it to send once we're back
+    fn send_or_queue(&mut self, content: String) -> Result<()> {
+        if self.accounts[self.active].authenticated() {
+            self.send_note(content)?;
+            return Ok(());
+        }
+        let account = &mut self.accounts[self.active];
+        self.outbox.push(PendingNote {
+            from: account.pub_key.to_string(),
+            to: self.recipient.to_string(),
+            content,
+            queued_at: Utc::now(),
+            sent: None,
+        })?;
+        account.status = "Not connected, note will be sent once reconnected".into();
+        Ok(())
+    }
+
+    /// Suspend the TUI to write a note in the user's editor, starting from the input, and send it
+    fn compose_in_editor(&mut self, terminal: &mut DefaultTerminal) -> Result<()> {
+        disable_modes(self.desktop_notifications);
+        ratatui::restore();
+        let composed = edit_in_editor(&self.input);
+        terminal::enable_raw_mode()?;
+        crossterm::execute!(std::io::stdout(), EnterAlternateScreen)?;
+        enable_modes(self.desktop_notifications);
+        terminal.clear()?;
+        match composed {
+            Ok(content) if content.trim().is_empty() => {
+                self.accounts[self.active].status = "Empty note, not sent".into();
+                Ok(())
+            }
+            Ok(content) => {
+                self.input.clear();
+                self.reset_cursor();
+                self.recalled = None;
+                self.send_or_queue(content)
+            }
+            Err(e) => {
+                warn!("📝 Error composing note in editor: {e:#}");
+                self.accounts[self.active].status = format!("Editor failed: {e}");
+                Ok(())
+            }
+        }
+    }
+
+    /// Tell the recipient we're typing. Sent on every keypress, comms coalesces them.
+    fn send_typing(&mut self) -> Result<()> {
+        let account = &mut self.accounts[self.active];
+        // Sealed sender hides who we talk to from the server, so don't give it away
+        if !account.authenticated()
+            || self.sealed_sender
+            || account.server_protocol_version < TYPING_PROTOCOL_VERSION
+        {
+            return Ok(());
+        }
+        account.send_msg(ClientMsg::Typing(Typing {
+            from: account.pub_key.to_string(),
+            to: self.recipient.to_string(),
+        }))
+    }
+
+    /// Send a note to the recipient from the active account, returning its id
+    fn send_note(&mut self, content: String) -> Result<String> {
+        let recipient = self.recipient.clone();
+        self.send_note_as(self.active, &recipient, content)
+    }
+
+    /// Send the notes queued in the outbox while an account was disconnected, in order, along
+    /// with ones sent in an earlier run the server never accepted. Ones that can no longer be
+    /// sent are dropped.
+    fn flush_outbox(&mut self, i: usize) -> Result<()> {
+        let pending = self.outbox.take_from(&self.accounts[i].pub_key.to_string());
+        if pending.is_empty() {
+            return Ok(());
+        }
+        info!("📤 Sending {} notes from the outbox", pending.len());
+        for note in pending {
+            if let Some(sent) = &note.sent {
+                // The same note again, so the recipient drops it if the first did get through
+                let account = &mut self.accounts[i];
+                if !account.unacked.contains(&sent.id) {
+                    account.send_msg(ClientMsg::SendNote(sent.clone()))?;
+                    account.unacked.track(sent.clone(), note.content.clone());
+                }
+                self.outbox.push(note)?;
+                continue;
+            }
+            let send_res = Recipient::from_str(&note.to)
+                .map_err(|e| anyhow!(e))
+                .and_then(|recipient| self.send_note_as(i, &recipient, note.content));
+            if let Err(e) = send_res {
+                warn!("📤 Dropping note to {} from the outbox: {e}", note.to);
+                self.accounts[i].status = format!("Queued note not sent: {e}");
+            }
+        }
+        self.outbox.save()
+    }
+
+    /// Give the failed notes in the conversation shown another round of attempts
+    fn retry_failed(&mut self) {
+        let account = &mut self.accounts[self.active];
+        let retried = account.unacked.retry_failed(&self.recipient.to_string());
+        account.status = match retried {
+            0 => "No failed notes to retry".into(),
+            1 => "Retrying failed note".into(),
+            n => format!("Retrying {n} failed notes"),
+        };
+    }
+
+    /// Send a note to a recipient from an account, returning its id
+    fn send_note_as(&mut self, i: usize, to: &Recipient, content: String) -> Result<String> {
+        let account = &mut self.accounts[i];
+        let recipient = to.to_string();
+        if account.revoked.contains(&recipient) {
+            return Err(anyhow!("Recipient {recipient} revoked their key"));
+        }
+        // Session notes carry `from` in the clear, so sealed sender never uses them
+        let note = if self.sealed_sender {
+            Note::encrypt_new_sealed(&account.priv_key, to, content.clone())?
+        } else if let Some((header, ciphertext)) = account.sessions.encrypt(&recipient, &content)? {
+            let note = Note::new_session(&account.priv_key, to, header, ciphertext)?;
+            account.contents.insert(
+                note.id.clone(),
+                (account.pub_key.to_string(), content.clone()),
+            );
+            note
+        } else {
+            Note::encrypt_new(&account.priv_key, to, content.clone())?
+        };
+        let id = note.id.clone();
+        account.send_msg(ClientMsg::SendNote(note.clone()))?;
+        // Kept on disk too until the server accepts it, in case we quit before then
+        self.outbox.push(PendingNote {
+            from: account.pub_key.to_string(),
+            to: recipient,
+            content: content.clone(),
+            queued_at: Utc::now(),
+            sent: Some(note.clone()),
+        })?;
+        account.unacked.track(note, content);
+        Ok(id)
+    }
+
     /// Draw the TUI
     fn draw(&self, frame: &mut Frame) {
         let true_black = Color::Rgb(0, 0, 0);
         let true_white = Color::Rgb(255, 255, 255);
 
-        let vertical = Layout::vertical([Constraint::Min(1), Constraint::Length(3)]);
-        let [notes_area, input_area] = vertical.areas(frame.area());
+        let horizontal =
+            Layout::horizontal([Constraint::Length(SIDEBAR_WIDTH), Constraint::Min(1)]);
+        let [sidebar_area, main_area] = horizontal.areas(frame.area());
+        let account = &self.accounts[self.active];
+        let progress = account.decrypt.progress();
+        let banner = self.maintenance().and_then(maintenance_banner);
+        let vertical = Layout::vertical([
+            Constraint::Length(CERT_WARNING_HEIGHT * self.cert_change.is_some() as u16),
+            Constraint::Length(banner.is_some() as u16),
+            Constraint::Min(1),
+            Constraint::Length(progress.is_some() as u16),
+            Constraint::Length(3),
+        ]);
+        let [cert_warning_area, banner_area, notes_area, progress_area, input_area] =
+            vertical.areas(main_area);
+
+        if let Some(change) = &self.cert_change {
+            let warning = Paragraph::new(format!(" {change}"))
+                .wrap(Wrap { trim: true })
+                .style(
+                    Style::default()
+                        .fg(true_white)
+                        .bg(Color::Red)
+                        .add_modifier(Modifier::BOLD),
+                );
+            frame.render_widget(warning, cert_warning_area);
+        }
+
+        if let Some(banner) = banner {
+            let banner =
+                Paragraph::new(banner).style(Style::default().fg(true_black).bg(Color::Yellow));
+            frame.render_widget(banner, banner_area);
+        }
 
-        let notes: Vec<ListItem> = self
-            .notes
+        // Conversations grouped by the identity they're held as, and the server when there are
+        // several
+        let recipient = self.recipient.to_string();
+        let short_recipient = short_key(&recipient);
+        let several_servers = self.several_servers();
+        let accounts: Vec<ListItem> = self
+            .accounts
             .iter()
+            .enumerate()
+            .map(|(i, account)| {
+                let identity = short_key(&account.pub_key.to_string());
+                let mut lines = vec![Line::from(identity)];
+                if let Some(state) = account.connection_label() {
+                    lines.push(Line::from(format!("  ({state})")).style(Color::Yellow));
+                }
+                if several_servers {
+                    lines.push(Line::from(format!(
+                        "  on {}",
+                        short_server(&account.server)
+                    )));
+                }
+                for peer in &self.conversations {
+                    let peer = peer.to_string();
+                    let marker = if peer == recipient { "→" } else { " " };
+                    let mut conversation = format!("  {marker} {}", short_key(&peer));
+                    let unread = account.unread(&peer);
+                    if unread > 0 {
+                        conversation.push_str(&format!(" ({unread})"));
+                    }
+                    lines.push(Line::from(conversation));
+                }
+                let item = ListItem::new(lines);
+                if i == self.active {
+                    item.style(Style::default().add_modifier(Modifier::REVERSED))
+                } else {
+                    item
+                }
+            })
+            .collect();
+        let accounts = List::new(accounts)
+            .style(Style::default().fg(true_white).bg(true_black))
+            .block(Block::bordered().title("Identities"));
+        frame.render_widget(accounts, sidebar_area);
+
+        let times = self.conversation_times.get(&recipient);
+        let mut notes: Vec<ListItem> = account
+            .conversation_notes(&recipient)
             .map(|n| {
-                let content = Line::from(Span::raw(
-                    self.render_note(n)
+                let content = Text::raw(
+                    account
+                        .render_note(n, times)
                         .unwrap_or("<error rendering note>".to_string()),
-                ));
-                ListItem::new(content)
+                );
+                let item = ListItem::new(content);
+                if account.highlighted.contains(&n.id) {
+                    item.style(Style::default().fg(Color::Yellow))
+                } else {
+                    item
+                }
             })
             .collect();
+        // Notes the server hasn't accepted yet go after the ones it has, then the ones waiting in
+        // the outbox
+        let pub_key = account.pub_key.to_string();
+        notes.extend(
+            account
+                .unacked
+                .to(&recipient)
+                .filter(|sent| ControlNote::parse(&sent.content).is_none())
+                .map(|sent| {
+                    let timestamp = times.format(sent.sent_at);
+                    let state = if sent.failed {
+                        "failed, Ctrl+R to retry"
+                    } else {
+                        "sending"
+                    };
+                    let content = format!("[{timestamp}] {pub_key} ({state}): {}", sent.content);
+                    let color = if sent.failed {
+                        Color::Red
+                    } else {
+                        Color::DarkGray
+                    };
+                    ListItem::new(content).style(Style::default().fg(color))
+                }),
+        );
+        let untracked = self
+            .outbox
+            .pending(&pub_key, &recipient)
+            .filter(|note| !account.tracks(note) && ControlNote::parse(&note.content).is_none());
+        notes.extend(untracked.map(|note| {
+            let timestamp = times.format(note.queued_at);
+            let content = format!("[{timestamp}] {} (pending): {}", note.from, note.content);
+            ListItem::new(content).style(Style::default().fg(Color::DarkGray))
+        }));
+        // Show the newest notes that fit, or older ones if scrolled up
+        let height = notes_area.height.saturating_sub(2) as usize;
+        self.notes_height.set(height);
+        let end = notes.len().saturating_sub(account.scroll);
+        notes.truncate(end);
+        // Notes composed in an editor can take several lines
+        let mut lines = 0;
+        let start = notes
+            .iter()
+            .rposition(|item| {
+                lines += item.height();
+                lines > height
+            })
+            .map_or(0, |i| (i + 1).min(end.saturating_sub(1)));
+        notes.drain(..start);
+        let mut notes_title = "Messages".to_string();
+        if several_servers {
+            notes_title.push_str(&format!(" on {}", short_server(&account.server)));
+        }
+        if account.is_typing(&recipient) {
+            notes_title.push_str(&format!(" - {short_recipient} is typing..."));
+        }
+        if account.scroll > 0 {
+            notes_title.push_str(" (scrolled up, PgDn for newer)");
+        }
         let notes = List::new(notes)
             .style(Style::default().fg(true_white).bg(true_black))
-            .block(Block::bordered().title("Messages"));
+            .block(Block::bordered().title(notes_title));
         frame.render_widget(notes, notes_area);
 
+        if let Some((done, total)) = progress {
+            let progress = LineGauge::default()
+                .style(Style::default().fg(true_white).bg(true_black))
+                .filled_style(Style::default().fg(Color::Green))
+                .label(format!("Decrypting {done}/{total}"))
+                .ratio(done as f64 / total as f64);
+            frame.render_widget(progress, progress_area);
+        }
+
         let input = Paragraph::new(self.input.as_str())
             .style(Style::default().fg(true_white).bg(true_black))
-            .block(Block::bordered().title("Input"));
+            .block(
+                Block::bordered()
+                    .title(account.input_title())
+                    .title(Line::from(account.latency_title()).right_aligned()),
+            );
         frame.render_widget(input, input_area);
 
         frame.set_cursor_position(Position::new(
             input_area.x + self.character_index as u16 + 1,
             input_area.y + 1,
         ));
-    }
 
-    /// Render a note as a String for display in the TUI
-    fn render_note(&self, note: &Note) -> Result<String> {
-        let local_time = note.timestamp.with_timezone(&Local);
-        let timestamp_str = local_time.format("%Y-%m-%d %H:%M:%S").to_string();
-        Ok(format!(
-            "[{timestamp_str}] {}: {}",
-            note.from,
-            note.decrypt_content(&self.priv_key)?
-        ))
+        if self.flash_until.is_some_and(|until| Instant::now() < until) {
+            let area = frame.area();
+            frame
+                .buffer_mut()
+                .set_style(area, Style::default().add_modifier(Modifier::REVERSED));
+        }
     }
 
     fn move_cursor_left(&mut self) {
@@ -253,11 +1727,31 @@ impl<'a> App<'a> {
     }
 
     fn enter_char(&mut self, new_char: char) {
+        // Editing a recalled input makes it a new one
+        self.recalled = None;
         let index = self.byte_index();
         self.input.insert(index, new_char);
         self.move_cursor_right();
     }
 
+    /// Insert pasted text at the cursor in one go. The input is a single line, so line breaks
+    /// and tabs become spaces and other control characters are dropped.
+    fn paste(&mut self, text: &str) {
+        let text: String = text
+            .replace("\r\n", "\n")
+            .chars()
+            .filter_map(|c| match c {
+                '\n' | '\r' | '\t' => Some(' '),
+                c if c.is_control() => None,
+                c => Some(c),
+            })
+            .collect();
+        self.recalled = None;
+        let index = self.byte_index();
+        self.input.insert_str(index, &text);
+        self.character_index = self.clamp_cursor(self.character_index + text.chars().count());
+    }
+
     /// Returns the byte index based on the character position.
     /// Since each character in a string can be contain multiple bytes, it's necessary to calculate
     /// the byte index based on the index of the character.
@@ -270,6 +1764,7 @@ impl<'a> App<'a> {
     }
 
     fn delete_char(&mut self) {
+        self.recalled = None;
         let is_not_cursor_leftmost = self.character_index != 0;
         if is_not_cursor_leftmost {
             // Method "remove" is not used on the saved text for deleting the selected char.
@@ -299,3 +1794,495 @@ impl<'a> App<'a> {
         self.character_index = 0;
     }
 }
+
+impl<'a> Account<'a> {
+    fn new(server: String, key: Identity, comms: &'a mut Comms) -> Result<Self> {
+        let pub_key = key.to_public();
+        let signing_key = hex::encode(signing_key(&key).verifying_key().as_bytes());
+        let mut known_signing_keys = SigningKeys::load(Path::new(KNOWN_SIGNING_KEYS_PATH))?;
+        known_signing_keys.pin(&pub_key.to_string(), &signing_key, true)?;
+        let sessions_path = format!("{SESSIONS_PATH_PREFIX}{pub_key}.age");
+        let sessions = Sessions::load(Path::new(&sessions_path), &key)?;
+        Ok(Self {
+            server,
+            comms,
+            pub_key,
+            decrypt: DecryptPool::new(key.clone()),
+            priv_key: key,
+            signing_key,
+            known_signing_keys,
+            sessions,
+            contents: HashMap::new(),
+            deprecations: HashMap::new(),
+            connection: ConnectionState::Authenticating,
+            resume_token: None,
+            server_protocol_version: 1,
+            last_note_id: None,
+            reconnected: false,
+            inputs: Vec::new(),
+            scroll: 0,
+            older_in_history: false,
+            older_on_server: true,
+            fetching_history: false,
+            typing: HashMap::new(),
+            revoked: HashSet::new(),
+            former_keys: HashMap::new(),
+            notes: Vec::new(),
+            highlighted: HashSet::new(),
+            unacked: Unacked::default(),
+            read_positions: ReadPositions::default(),
+            maintenance: None,
+            status: String::new(),
+        })
+    }
+
+    /// What our resumption token is saved under, since a token is only good on the server that
+    /// gave it
+    fn resume_key(&self) -> String {
+        format!("{}@{}", self.pub_key, self.server)
+    }
+
+    /// Send a message to the server, warning once if the server has deprecated it
+    fn send_msg(&mut self, msg: ClientMsg) -> Result<()> {
+        if let Some(deprecation) = self.deprecations.remove(msg.kind()) {
+            warn!("⚠️ Server deprecation: {}", deprecation.message);
+            self.status = format!("Deprecated: {}", deprecation.message);
+        }
+        self.comms.try_send_msg(msg)
+    }
+
+    /// Accept a peer's forward secret session offer
+    fn accept_session(&mut self, offer: &SessionHandshake) -> Result<()> {
+        offer.verify_signature()?;
+        offer.check_key_proof(&self.priv_key)?;
+        self.pin_signing_key(&offer.from, &offer.signing_key, true)?;
+        let Some(ephemeral_key) = self.sessions.accept(&self.pub_key.to_string(), offer)? else {
+            info!(
+                "🤝 Ignoring session offer from {}, ours takes precedence",
+                offer.from
+            );
+            return Ok(());
+        };
+        info!("🤝 Accepting forward secret session from {}", offer.from);
+        let accept = SessionHandshake::new(
+            &self.priv_key,
+            offer.from.clone(),
+            offer.session_id.clone(),
+            ephemeral_key,
+        )?;
+        self.send_msg(ClientMsg::SessionAccept(accept))?;
+        Ok(())
+    }
+
+    /// Check and decrypt a note encrypted with a forward secret session
+    fn open_session_note(&mut self, note: &Note) -> Result<OpenedNote> {
+        note.verify_signature()?;
+        let key_proven = note.check_key_proof(&self.priv_key)?;
+        self.pin_signing_key(&note.from, &note.signing_key, key_proven)?;
+        let opened = |content| OpenedNote {
+            from: note.from.clone(),
+            signing_key: note.signing_key.clone(),
+            content,
+            key_proven,
+        };
+        // We can't decrypt our own echoed notes, their content was saved when sending
+        if note.from == self.pub_key.to_string() {
+            let (_, content) = self
+                .contents
+                .get(&note.id)
+                .ok_or(anyhow!("Content of our own session note was not saved"))?;
+            return Ok(opened(content.clone()));
+        }
+        let header = note
+            .session
+            .as_ref()
+            .ok_or(anyhow!("Note has no session"))?;
+        let content = self
+            .sessions
+            .decrypt(&note.from, header, &note.encrypted_content)?;
+        Ok(opened(content))
+    }
+
+    /// Load a page of notes from past runs in a conversation, older than the ones loaded,
+    /// returning how many there were
+    fn load_history_page(&mut self, history: &History, conversation: &str) -> Result<usize> {
+        let before_id = self.oldest_note_id(conversation);
+        let loaded = history.load_page(
+            &self.priv_key,
+            conversation,
+            before_id.as_deref(),
+            HISTORY_PAGE_SIZE,
+        )?;
+        info!(
+            "💾 Loaded {} notes with {conversation} from history",
+            loaded.len()
+        );
+        self.older_in_history = loaded.len() == HISTORY_PAGE_SIZE;
+        let count = loaded.len();
+        for loaded in loaded {
+            if self.contents.contains_key(&loaded.note.id) {
+                continue;
+            }
+            self.contents
+                .insert(loaded.note.id.clone(), (loaded.from, loaded.content));
+            self.notes.push(loaded.note);
+        }
+        self.notes.sort_by_key(|note| note.timestamp);
+        Ok(count)
+    }
+
+    /// Id of the oldest note loaded in a conversation
+    fn oldest_note_id(&self, conversation: &str) -> Option<String> {
+        self.conversation_notes(conversation)
+            .next()
+            .map(|note| note.id.clone())
+    }
+
+    /// Append a note to the archive file, sealed to this identity as a line of base64 age
+    /// ciphertext so the file never holds plaintext
+    fn archive_note(&self, note: &Note) -> Result<()> {
+        // Archived in a fixed format so it reads the same whatever the display settings
+        let line = self.render_note(note, &TimeDisplay::default())?;
+        let sealed = age::encrypt(&self.pub_key, line.as_bytes())?;
+        let mut options = OpenOptions::new();
+        options.create(true).append(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options.open(ARCHIVE_PATH)?;
+        writeln!(file, "{}", BASE64.encode(sealed))?;
+        Ok(())
+    }
+
+    /// Pin the signing key for a pubkey, rejecting keys that differ from ones seen before unless
+    /// the sender proved it's theirs
+    fn pin_signing_key(&mut self, pub_key: &str, signing_key: &str, proven: bool) -> Result<()> {
+        self.known_signing_keys.pin(pub_key, signing_key, proven)
+    }
+
+    /// Stop trusting a key its owner revoked, checking it was signed by the key we know them by
+    fn revoke(&mut self, revocation: &Revocation) -> Result<()> {
+        revocation.verify_signature()?;
+        self.pin_signing_key(&revocation.pub_key, &revocation.signing_key, false)?;
+        if self.revoked.insert(revocation.pub_key.clone()) {
+            warn!(
+                "🪪 Key {} was revoked by its owner: {}",
+                revocation.pub_key,
+                revocation.reason.as_deref().unwrap_or("no reason given")
+            );
+        }
+        Ok(())
+    }
+
+    /// Peer a note's conversation is with
+    fn conversation(&self, note: &Note) -> Result<String> {
+        let (from, _) = self.open_note(note)?;
+        if from == self.pub_key.to_string() {
+            Ok(self.current_key(&note.to))
+        } else {
+            Ok(self.current_key(&from))
+        }
+    }
+
+    /// Key a peer goes by now, following any key changes we switched to
+    fn current_key(&self, pub_key: &str) -> String {
+        self.former_keys
+            .get(pub_key)
+            .cloned()
+            .unwrap_or(pub_key.to_string())
+    }
+
+    /// Follow a peer's key change, including from keys they had before the old one
+    fn change_key(&mut self, old_key: &str, new_key: &str) {
+        for current in self.former_keys.values_mut() {
+            if current == old_key {
+                *current = new_key.to_string();
+            }
+        }
+        self.former_keys
+            .insert(old_key.to_string(), new_key.to_string());
+        self.former_keys.remove(new_key);
+    }
+
+    /// Position of a note in the history
+    fn note_index(&self, note_id: &str) -> Option<usize> {
+        self.notes.iter().position(|note| note.id == note_id)
+    }
+
+    /// Notes from the peer after the last one read in the conversation
+    fn unread(&self, conversation: &str) -> usize {
+        let start = self
+            .read_positions
+            .get(conversation)
+            .and_then(|note_id| self.note_index(note_id))
+            .map_or(0, |i| i + 1);
+        self.notes[start..]
+            .iter()
+            .filter(|note| {
+                self.open_note(note)
+                    .is_ok_and(|(from, _)| self.current_key(&from) == conversation)
+            })
+            .count()
+    }
+
+    /// Notes loaded in a conversation, oldest first
+    fn conversation_notes<'n>(&'n self, conversation: &'n str) -> impl Iterator<Item = &'n Note> {
+        self.notes
+            .iter()
+            .filter(move |note| self.conversation(note).is_ok_and(|c| c == conversation))
+    }
+
+    /// Id of the newest note loaded in a conversation
+    fn newest_note_id(&self, conversation: &str) -> Option<String> {
+        self.conversation_notes(conversation)
+            .last()
+            .map(|note| note.id.clone())
+    }
+
+    /// Mark every note in a conversation as read
+    fn mark_read(&mut self, conversation: &str) {
+        if let Some(note_id) = self.newest_note_id(conversation) {
+            self.read_positions.set(conversation, &note_id);
+        }
+    }
+
+    /// Apply state synced from one of our other devices
+    fn apply_control_note(&mut self, control: ControlNote) {
+        match control {
+            ControlNote::ReadPosition {
+                conversation,
+                note_id,
+            } => {
+                // Only move forward, in case we've read further since
+                let current = self
+                    .read_positions
+                    .get(&conversation)
+                    .and_then(|current| self.note_index(current));
+                if let (Some(current), Some(synced)) = (current, self.note_index(&note_id)) {
+                    if synced < current {
+                        return;
+                    }
+                }
+                info!("🔄 Synced read position in conversation with {conversation}");
+                self.read_positions.set_synced(&conversation, &note_id);
+            }
+            // Key changes are for peers, our own devices already know our keys
+            ControlNote::KeyChange(_) => warn!("🔄 Ignoring key change sent to ourselves"),
+        }
+    }
+
+    /// Tell our other devices where we've read up to, once they're out of date
+    fn sync_read_positions(&mut self) -> Result<()> {
+        if !self.authenticated() {
+            return Ok(());
+        }
+        for control in self.read_positions.take_unsynced() {
+            let note = Note::encrypt_new(&self.priv_key, &self.pub_key, control.to_content()?)?;
+            self.send_msg(ClientMsg::SendNote(note))?;
+        }
+        Ok(())
+    }
+
+    /// Send the notes the server hasn't accepted again once they're due, marking ones out of
+    /// attempts failed. Waits while disconnected, so they go out again after reconnecting.
+    fn resend_unacked(&mut self) -> Result<()> {
+        if !self.authenticated() {
+            return Ok(());
+        }
+        let (resend, failed) = self.unacked.due();
+        for note in resend {
+            info!("🔁 Sending note {} to {} again", note.id, note.to);
+            self.send_msg(ClientMsg::SendNote(note))?;
+        }
+        if !failed.is_empty() {
+            for note in &failed {
+                warn!("🔁 Gave up sending note {} to {}", note.id, note.to);
+            }
+            self.status = match failed.len() {
+                1 => "Note not accepted by the server, press Ctrl+R to retry".into(),
+                n => format!("{n} notes not accepted by the server, press Ctrl+R to retry"),
+            };
+        }
+        Ok(())
+    }
+
+    /// Wait for a message or connection event, or for a note to finish decrypting. Returns None
+    /// for the latter, since decrypted notes are taken in order with `try_recv`.
+    async fn wake(&mut self) -> Option<Received> {
+        tokio::select! {
+            // Once comms has ended we only wait for the shutdown it sent
+            Ok(received) = self.comms.recv() => Some(received),
+            () = self.decrypt.wait() => None,
+        }
+    }
+
+    /// Whether a note from the outbox is one we're waiting on the server to accept, so it's
+    /// shown as sending rather than pending
+    fn tracks(&self, note: &PendingNote) -> bool {
+        note.sent
+            .as_ref()
+            .is_some_and(|sent| self.unacked.contains(&sent.id))
+    }
+
+    fn authenticated(&self) -> bool {
+        matches!(self.connection, ConnectionState::Authenticated)
+    }
+
+    /// Where the connection is at, unless it's authenticated and there's nothing to say. Counts
+    /// down to reconnecting so the wait doesn't look like a hang.
+    fn connection_label(&self) -> Option<String> {
+        match &self.connection {
+            ConnectionState::Authenticated => None,
+            ConnectionState::Authenticating => Some("authenticating".into()),
+            ConnectionState::Reconnecting { attempt, at } => {
+                let secs = at.saturating_duration_since(Instant::now()).as_secs_f64();
+                Some(format!("retry {attempt} in {}s", secs.ceil()))
+            }
+            ConnectionState::Connecting { attempt } => Some(format!("connecting, try {attempt}")),
+            ConnectionState::Failed => Some("failed".into()),
+        }
+    }
+
+    /// Whether a peer told us they're typing recently
+    fn is_typing(&self, peer: &str) -> bool {
+        self.typing
+            .get(peer)
+            .is_some_and(|at| at.elapsed() < TYPING_TIMEOUT)
+    }
+
+    /// Round trip time to the server, for the corner of the input box
+    fn latency_title(&self) -> String {
+        match self.comms.latency() {
+            Some(latency) => format!(" {}ms ", latency.as_millis()),
+            None => String::new(),
+        }
+    }
+
+    /// Title of the input box, showing the latest status if there is one
+    fn input_title(&self) -> String {
+        if self.status.is_empty() {
+            "Input".to_string()
+        } else {
+            format!("Input - {}", self.status)
+        }
+    }
+
+    /// Render a note as a String for display in the TUI
+    fn render_note(&self, note: &Note, times: &TimeDisplay) -> Result<String> {
+        let timestamp_str = times.format(note.timestamp);
+        let (from, content) = self.open_note(note)?;
+        if self.revoked.contains(&from) {
+            return Ok(format!("[{timestamp_str}] {from} (revoked key): {content}"));
+        }
+        Ok(format!("[{timestamp_str}] {from}: {content}"))
+    }
+
+    /// Sender and decrypted content of a received note
+    fn open_note(&self, note: &Note) -> Result<(String, String)> {
+        self.contents
+            .get(&note.id)
+            .cloned()
+            .ok_or(anyhow!("Note {} has not been decrypted", note.id))
+    }
+}
+
+/// Countdown to announced downtime, None once it's over
+fn maintenance_banner(maintenance: &Maintenance) -> Option<String> {
+    let now = Utc::now();
+    if maintenance.ends_at() <= now {
+        return None;
+    }
+    let back_at = maintenance.ends_at().with_timezone(&Local).format("%H:%M");
+    let until_start = (maintenance.starts_at - now).num_seconds();
+    if until_start > 0 {
+        Some(format!(
+            " Maintenance in {}m{:02}s, back around {back_at}: {}",
+            until_start / 60,
+            until_start % 60,
+            maintenance.message
+        ))
+    } else {
+        Some(format!(
+            " Maintenance in progress, back around {back_at}: {}",
+            maintenance.message
+        ))
+    }
+}
+
+/// Shorten a pubkey to fit in the sidebar
+fn short_key(pub_key: &str) -> String {
+    match pub_key.get(..SHORT_KEY_LEN) {
+        Some(prefix) => format!("{prefix}…"),
+        None => pub_key.to_string(),
+    }
+}
+
+/// Server address without the scheme, to tag conversations with
+fn short_server(address: &str) -> &str {
+    address.split_once("://").map_or(address, |(_, rest)| rest)
+}
+
+/// Turn on the terminal modes the TUI uses on top of ratatui's
+fn enable_modes(focus_change: bool) {
+    // Pastes come as one event rather than a keypress per character, so newlines don't send
+    _ = crossterm::execute!(std::io::stdout(), EnableBracketedPaste);
+    // Terminals that support it tell us when they lose focus, to raise desktop notifications
+    if focus_change {
+        _ = crossterm::execute!(std::io::stdout(), EnableFocusChange);
+    }
+}
+
+fn disable_modes(focus_change: bool) {
+    if focus_change {
+        _ = crossterm::execute!(std::io::stdout(), DisableFocusChange);
+    }
+    _ = crossterm::execute!(std::io::stdout(), DisableBracketedPaste);
+}
+
+/// Open `$VISUAL` or `$EDITOR` on a temp file holding some text, returning what was saved
+fn edit_in_editor(text: &str) -> Result<String> {
+    let editor = std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_string());
+    let path = std::env::temp_dir().join(format!(
+        "age-chat-{}-{:08x}.txt",
+        std::process::id(),
+        rand::random::<u32>()
+    ));
+    // The note is plaintext until sent, so only we can read it
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options
+        .open(&path)
+        .and_then(|mut file| file.write_all(text.as_bytes()))
+        .context(format!("Error creating {}", path.display()))?;
+    // Through the shell, since editors like `code --wait` come with arguments
+    let status = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(format!("{editor} \"$1\""))
+        .arg("sh")
+        .arg(&path)
+        .status();
+    let content = fs::read_to_string(&path);
+    _ = fs::remove_file(&path);
+    let status = status.context(format!("Error running {editor}"))?;
+    if !status.success() {
+        bail!("{editor} exited with {status}");
+    }
+    Ok(content?.trim_end_matches(['\n', '\r']).to_string())
+}
+
+/// Wait for the hook to finish with a note, forever if there's no hook
+async fn wait_hook(hook: Option<&mut Hook>) {
+    match hook {
+        Some(hook) => hook.wait().await,
+        None => future::pending().await,
+    }
+}
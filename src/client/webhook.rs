@@ -0,0 +1,56 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::common::Note;
+
+/// Body POSTed to the webhook for each note received
+#[derive(Serialize)]
+struct WebhookNote<'a> {
+    id: &'a str,
+    from: &'a str,
+    to: &'a str,
+    timestamp: DateTime<Utc>,
+    /// Decrypted text, only sent if asked for
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content: Option<&'a str>,
+}
+
+/// Forwards notes we receive to a webhook, e.g. to bridge them into Slack, Discord or ntfy
+#[derive(Clone)]
+pub struct Webhook {
+    url: String,
+    /// Send the decrypted text too, rather than just who it's from and when
+    content: bool,
+    client: reqwest::Client,
+}
+
+impl Webhook {
+    pub fn new(url: String, content: bool) -> Self {
+        Self {
+            url,
+            content,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// POST a decrypted note in the background, so a slow webhook doesn't hold anything up.
+    /// Failures are only logged.
+    pub fn forward(&self, note: &Note, from: &str, content: &str) {
+        let body = WebhookNote {
+            id: &note.id,
+            from,
+            to: &note.to,
+            timestamp: note.timestamp,
+            content: self.content.then_some(content),
+        };
+        let req = self.client.post(&self.url).json(&body);
+        let id = note.id.clone();
+        tokio::spawn(async move {
+            match req.send().await.and_then(|res| res.error_for_status()) {
+                Ok(_) => info!("🪃 Forwarded note {id} to webhook"),
+                Err(e) => warn!("🪃 Error forwarding note {id} to webhook: {e}"),
+            }
+        });
+    }
+}
@@ -1,20 +1,59 @@
 use age::{
-    armor::{ArmoredWriter, Format},
+    armor::{ArmoredReader, ArmoredWriter, Format},
+    secrecy::ExposeSecret,
     x25519::{Identity, Recipient},
     Encryptor,
 };
-use anyhow::Result;
-use chrono::{DateTime, Utc};
+use anyhow::{anyhow, bail, Context, Result};
+use bech32::FromBase32;
+use chrono::{DateTime, TimeDelta, Utc};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use hkdf::Hkdf;
+use rand::RngCore;
 use serde::{Deserialize, Serialize};
-use std::{fmt, io::Write, str::FromStr};
+use sha2::{Digest, Sha256};
+use std::{fmt, io::Write, path::Path, str::FromStr};
 use tokio_tungstenite::tungstenite::Message;
 
 pub const CHANNEL_BUFFER_SIZE: usize = 1000;
+/// mDNS service type servers advertise themselves on the local network as
+pub const MDNS_SERVICE_TYPE: &str = "_age-chat._tcp.local.";
+/// Last line of age armor, the first line is checked when parsing the header
+const ARMOR_END: &str = "-----END AGE ENCRYPTED FILE-----";
+/// Bytes of the poly1305 tag every session ciphertext ends with
+const SESSION_TAG_BYTES: usize = 16;
+pub const PROTOCOL_VERSION: u32 = 10;
+/// First protocol version where clients fetch their mailbox, older clients have it pushed on auth
+pub const MAILBOX_PROTOCOL_VERSION: u32 = 2;
+/// First protocol version with typing indicators
+pub const TYPING_PROTOCOL_VERSION: u32 = 3;
+/// First protocol version with key revocations
+pub const REVOCATION_PROTOCOL_VERSION: u32 = 4;
+/// First protocol version with duplicate login warnings
+pub const DUPLICATE_LOGIN_PROTOCOL_VERSION: u32 = 5;
+/// First protocol version with operator announcements
+pub const ANNOUNCEMENT_PROTOCOL_VERSION: u32 = 6;
+/// First protocol version with presence-only connections
+pub const PRESENCE_PROTOCOL_VERSION: u32 = 7;
+/// First protocol version with session resumption tokens
+pub const RESUME_PROTOCOL_VERSION: u32 = 8;
+/// First protocol version where clients fetch the notes lost in flight after reconnecting
+pub const SYNC_PROTOCOL_VERSION: u32 = 9;
+/// First protocol version with fetching history the server archived
+pub const HISTORY_PROTOCOL_VERSION: u32 = 10;
+const SIGNING_KEY_CONTEXT: &[u8] = b"age-chat-signing-key-v1";
+const AUTH_CHALLENGE_CONTEXT: &str = "age-chat-auth-v1";
+const REVOCATION_CONTEXT: &[u8] = b"age-chat-revocation-v1";
+const KEY_CHANGE_CONTEXT: &[u8] = b"age-chat-key-change-v1";
+const KEY_PROOF_CONTEXT: &[u8] = b"age-chat-key-proof-v1";
+const HANDSHAKE_PROOF_CONTEXT: &[u8] = b"age-chat-handshake-proof-v1";
 
 /// WS Messages that the server sends
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum ServerMsg {
+    /// Acknowledge the client's hello with our protocol version and deprecations
+    HelloAck(HelloAck),
     /// Send the client a secret to decrypt to authenticate
     AuthSecret(Auth),
     /// Signal the client that they have successfully authenticated
@@ -23,34 +62,377 @@ pub enum ServerMsg {
     AuthDenied(Auth),
     /// Signal the client they have received a new chat message
     RecNote(Note),
+    /// Tell the client how much of its storage quota it is using
+    QuotaUsage(QuotaUsage),
+    /// Signal the client that a request failed
+    Error(ServerError),
+    /// Relay a peer's offer to set up a forward secret session
+    SessionOffer(SessionHandshake),
+    /// Relay a peer's acceptance of our forward secret session offer
+    SessionAccept(SessionHandshake),
+    /// Warn the client of downtime the operator has scheduled
+    Maintenance(Maintenance),
+    /// Tell the client scheduled downtime was called off
+    MaintenanceCancelled,
+    /// Relay that a peer is typing a note to the client
+    Typing(Typing),
+    /// Tell the client a key was revoked by its owner and must no longer be trusted
+    Revoked(Revocation),
+    /// Warn the client that another device proved it holds the same key
+    DuplicateLogin(DuplicateLogin),
+    /// Show the user a message from the server's operator
+    Announcement { message: String },
+    /// Tell a presence-only connection about its user's chat session and mailbox
+    Presence(Presence),
+    /// Give the client a single use token to resume its session with if the connection drops
+    ResumeToken { token: String },
+    /// Send the client archived notes with a peer it asked for
+    History(HistoryPage),
 }
 
 /// WS Messages that the client sends
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum ClientMsg {
+    /// Tell the server which protocol version we speak
+    Hello(Hello),
     /// Request the server to authenticate as a user
     AuthReq(Auth),
     /// Return the decrypted secret to authenticate
     AuthPlaintext(Auth),
     /// Signal the server to send a new chat message
     SendNote(Note),
+    /// Ask the server how much of our storage quota we are using
+    QuotaQuery,
+    /// Ask the server for the notes that arrived while we were offline
+    FetchMailbox,
+    /// Offer a peer a forward secret session
+    SessionOffer(SessionHandshake),
+    /// Accept a peer's forward secret session offer
+    SessionAccept(SessionHandshake),
+    /// Tell a peer we're typing a note to them
+    Typing(Typing),
+    /// Revoke the key we're authenticated as, e.g. because it was compromised
+    Revoke(Revocation),
+    /// Authenticate with a resumption token from an earlier connection instead of a challenge
+    Resume(Resume),
+    /// Ask for the notes delivered after the last one we received, which may have been lost when
+    /// our previous connection dropped
+    SyncSince(SyncSince),
+    /// Ask for older notes with a peer the server archived, e.g. to fill a new device's history
+    FetchHistory(FetchHistory),
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Auth {
     pub pub_key: String,
+    /// Hex encoded ed25519 key that notes from this user will be signed with
+    pub signing_key: String,
+    /// Token for the server's auth backend, e.g. an invite token
+    #[serde(default)]
+    pub token: Option<String>,
     pub ciphertext: String,
     pub plaintext: String,
 }
 
+/// Resumes an earlier authenticated session
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Resume {
+    pub pub_key: String,
+    pub token: String,
+}
+
+/// Where to resume receiving notes from after reconnecting
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SyncSince {
+    /// Id of the last note we received, None if we haven't received any
+    pub after: Option<String>,
+}
+
+/// Which archived notes with a peer to fetch
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FetchHistory {
+    pub peer: String,
+    /// Fetch the notes before this one, or the newest if None
+    pub before_id: Option<String>,
+    /// Most notes to fetch, the server may send fewer
+    pub limit: u32,
+}
+
+/// Archived notes with a peer, oldest first
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HistoryPage {
+    pub peer: String,
+    pub notes: Vec<Note>,
+}
+
+/// First message the client sends after connecting
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Hello {
+    pub protocol_version: u32,
+    /// Only watch presence once authenticated, leaving the user free to chat from another
+    /// connection
+    #[serde(default)]
+    pub presence_only: bool,
+    /// Accept large messages as deflated binary frames, and send them that way if the server
+    /// agrees. Filled in by the comms task, which does the compressing.
+    #[serde(default)]
+    pub compression: bool,
+}
+
+/// Whether a user has a chat session connected, and how many notes wait in their mailbox
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Presence {
+    pub online: bool,
+    pub queued_notes: usize,
+}
+
+/// The server's reply to a hello
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HelloAck {
+    pub protocol_version: u32,
+    /// Message types or encodings that will be removed in a future version
+    pub deprecations: Vec<Deprecation>,
+    /// The server agreed to compress large messages both ways
+    #[serde(default)]
+    pub compression: bool,
+}
+
+/// A message type or encoding the server will stop supporting
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Deprecation {
+    /// What is deprecated, e.g. a `ClientMsg` type like `QuotaQuery`
+    pub feature: String,
+    /// What to do instead
+    pub message: String,
+}
+
+/// Bytes of server storage a user is using, e.g. for notes queued to offline users
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct QuotaUsage {
+    pub used_bytes: usize,
+    pub quota_bytes: usize,
+}
+
+/// Downtime the operator has scheduled
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Maintenance {
+    pub starts_at: DateTime<Utc>,
+    /// Expected length of the downtime in seconds
+    pub duration_secs: u64,
+    pub message: String,
+}
+
+/// An error the server reports back to the client
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ServerError {
+    pub kind: ErrorKind,
+    pub message: String,
+}
+
+/// Kinds of errors the server reports back to the client
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum ErrorKind {
+    /// Storing the note would put the sender over their storage quota
+    QuotaExceeded,
+    /// The peer has to be online for the request
+    PeerOffline,
+    /// The client is sending notes faster than the server allows
+    RateLimited,
+    /// Too many failed auth attempts, the client has to wait before trying again
+    AuthThrottled,
+    /// The peer's relay queue is full because it isn't keeping up
+    PeerBackedUp,
+    /// The resumption token is unknown or expired, the client has to authenticate in full
+    ResumeRejected,
+    /// The note is larger than the server relays
+    NoteTooLarge,
+    /// The note's content isn't age or session ciphertext
+    InvalidNote,
+}
+
+/// Plaintext of the auth secret. It is labeled and bound to the client's pubkey so clients only
+/// ever return decryptions of auth challenges, and the server can't use them as decryption oracles.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AuthChallenge {
+    pub context: String,
+    pub nonce: String,
+    pub pub_key: String,
+}
+
 /// A chat message
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Note {
+    /// Random unique id, used by clients to detect replayed notes
+    pub id: String,
+    /// Sender pubkey, empty for sealed sender notes
     pub from: String,
     pub to: String,
     pub encrypted_content: String,
     pub timestamp: DateTime<Utc>,
+    /// Hex encoded ed25519 key of the sender, empty for sealed sender notes
+    pub signing_key: String,
+    /// Hex encoded signature over the rest of the note, empty for sealed sender notes
+    pub signature: String,
+    /// Set if the content is encrypted with a forward secret session rather than age
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session: Option<SessionHeader>,
+    /// Hex encoded proof the signing key belongs to the sender's age identity, see
+    /// [`prove_signing_key`]. Empty for sealed sender notes and from older clients.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub key_proof: String,
+}
+
+/// Which forward secret session and message key a note is encrypted with
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SessionHeader {
+    pub session_id: String,
+    pub counter: u64,
+}
+
+/// One side of setting up a forward secret session: a signed ephemeral x25519 key. Both sides
+/// derive the session keys from the DH of their ephemeral keys, which are never stored.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SessionHandshake {
+    pub session_id: String,
+    pub from: String,
+    pub to: String,
+    /// Hex encoded ephemeral x25519 public key
+    pub ephemeral_key: String,
+    /// Hex encoded ed25519 key of the sender
+    pub signing_key: String,
+    /// Hex encoded signature over the rest of the handshake
+    pub signature: String,
+    /// Proof the handshake comes from the age identity `from`, see [`prove_signing_key`]
+    #[serde(default)]
+    pub key_proof: String,
+}
+
+/// A user is typing a note to a peer. Unsigned, it's only a hint and never stored.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Typing {
+    pub from: String,
+    pub to: String,
+}
+
+/// Another device authenticating with a key that already has a session, which may mean the key
+/// was stolen
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DuplicateLogin {
+    /// IP address the other device connected from
+    pub ip: String,
+    /// Protocol version the other device's client speaks
+    pub protocol_version: u32,
+    pub at: DateTime<Utc>,
+}
+
+/// A user's signed statement that their key is compromised and must no longer be used
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Revocation {
+    pub pub_key: String,
+    /// Hex encoded ed25519 key of the revoked identity
+    pub signing_key: String,
+    pub revoked_at: DateTime<Utc>,
+    pub reason: Option<String>,
+    /// Hex encoded signature over the rest of the revocation
+    pub signature: String,
+}
+
+/// Encrypted payload of a sealed sender note, holding what would otherwise be on the outer note
+#[derive(Serialize, Deserialize)]
+struct SealedPayload {
+    from: String,
+    signing_key: String,
+    signature: String,
+    content: String,
+    #[serde(default)]
+    key_proof: String,
+}
+
+/// A note's sender and content after decryption and signature verification
+pub struct OpenedNote {
+    pub from: String,
+    pub signing_key: String,
+    pub content: String,
+    /// Whether the sender proved the signing key is theirs, rather than it only being trusted on
+    /// first use
+    pub key_proven: bool,
+}
+
+/// Derive the ed25519 key used to sign notes from an age identity
+pub fn signing_key(identity: &Identity) -> SigningKey {
+    let mut hasher = Sha256::new();
+    hasher.update(SIGNING_KEY_CONTEXT);
+    hasher.update(identity.to_string().expose_secret().as_bytes());
+    SigningKey::from_bytes(&hasher.finalize().into())
+}
+
+/// Prove a signing key belongs to the age identity `from` to `peer`, the other side of the note.
+/// Anyone can encrypt to a recipient and sign with a key of their own, so the signing key alone
+/// can't show who sent a note. This is keyed with the x25519 agreement between the sender's and
+/// recipient's age keys, which only the two of them can compute.
+pub fn prove_signing_key(
+    identity: &Identity,
+    peer: &str,
+    from: &str,
+    signing_key: &str,
+) -> Result<String> {
+    agreement_proof(
+        identity,
+        peer,
+        KEY_PROOF_CONTEXT,
+        &[from.as_bytes(), signing_key.as_bytes()],
+    )
+}
+
+/// Keyed hash of `parts` that only the holders of `identity` and `peer` can compute
+fn agreement_proof(
+    identity: &Identity,
+    peer: &str,
+    context: &[u8],
+    parts: &[&[u8]],
+) -> Result<String> {
+    let secret: [u8; 32] = bech32_bytes(identity.to_string().expose_secret())?
+        .try_into()
+        .map_err(|_| anyhow!("Invalid age identity length"))?;
+    let peer_key: [u8; 32] = bech32_bytes(peer)?
+        .try_into()
+        .map_err(|_| anyhow!("Invalid age pubkey length {peer}"))?;
+    let shared = x25519_dalek::StaticSecret::from(secret)
+        .diffie_hellman(&x25519_dalek::PublicKey::from(peer_key));
+    let mut proof = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(context), shared.as_bytes())
+        .expand(&parts.join(&0u8), &mut proof)
+        .map_err(|_| anyhow!("Invalid key proof length"))?;
+    Ok(hex::encode(proof))
+}
+
+/// Check the proof of a signing key on a note we can decrypt, returning whether there was one
+fn check_key_proof(
+    identity: &Identity,
+    from: &str,
+    to: &str,
+    signing_key: &str,
+    proof: &str,
+) -> Result<bool> {
+    if proof.is_empty() {
+        return Ok(false);
+    }
+    let peer = if from == identity.to_public().to_string() {
+        to
+    } else {
+        from
+    };
+    if prove_signing_key(identity, peer, from, signing_key)? != proof {
+        bail!("Signing key is not proven to belong to {from}, the note may be forged");
+    }
+    Ok(true)
+}
+
+/// Data part of a bech32 encoded age key
+fn bech32_bytes(encoded: &str) -> Result<Vec<u8>> {
+    let (_, data, _) = bech32::decode(encoded).context("Invalid bech32 age key")?;
+    Ok(Vec::<u8>::from_base32(&data)?)
 }
 
 impl FromStr for ServerMsg {
@@ -72,6 +454,19 @@ impl ServerMsg {
     pub fn to_ws_msg(&self) -> Message {
         Message::text(self.to_string())
     }
+
+    /// Oldest protocol version that understands the message, older clients would fail to parse it
+    pub fn min_protocol_version(&self) -> u32 {
+        match self {
+            ServerMsg::Typing(_) => TYPING_PROTOCOL_VERSION,
+            ServerMsg::Revoked(_) => REVOCATION_PROTOCOL_VERSION,
+            ServerMsg::DuplicateLogin(_) => DUPLICATE_LOGIN_PROTOCOL_VERSION,
+            ServerMsg::Announcement { .. } => ANNOUNCEMENT_PROTOCOL_VERSION,
+            ServerMsg::Presence(_) => PRESENCE_PROTOCOL_VERSION,
+            ServerMsg::ResumeToken { .. } => RESUME_PROTOCOL_VERSION,
+            _ => 1,
+        }
+    }
 }
 
 impl FromStr for ClientMsg {
@@ -93,44 +488,507 @@ impl ClientMsg {
     pub fn to_ws_msg(&self) -> Message {
         Message::text(self.to_string())
     }
+
+    /// Name of the message type, as used for its serde tag
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ClientMsg::Hello(_) => "Hello",
+            ClientMsg::AuthReq(_) => "AuthReq",
+            ClientMsg::AuthPlaintext(_) => "AuthPlaintext",
+            ClientMsg::SendNote(_) => "SendNote",
+            ClientMsg::QuotaQuery => "QuotaQuery",
+            ClientMsg::FetchMailbox => "FetchMailbox",
+            ClientMsg::SessionOffer(_) => "SessionOffer",
+            ClientMsg::SessionAccept(_) => "SessionAccept",
+            ClientMsg::Typing(_) => "Typing",
+            ClientMsg::Revoke(_) => "Revoke",
+            ClientMsg::Resume(_) => "Resume",
+            ClientMsg::SyncSince(_) => "SyncSince",
+            ClientMsg::FetchHistory(_) => "FetchHistory",
+        }
+    }
+
+    /// Key that rapid-fire messages are coalesced by before being sent, None to always send
+    pub fn coalesce_key(&self) -> Option<String> {
+        match self {
+            ClientMsg::Typing(typing) => Some(format!("Typing/{}", typing.to)),
+            _ => None,
+        }
+    }
 }
 
 impl Auth {
-    pub fn new(pub_key: String) -> Self {
+    pub fn new(pub_key: String, signing_key: String, token: Option<String>) -> Self {
         Self {
             pub_key,
+            signing_key,
+            token,
             ciphertext: "".into(),
             plaintext: "".into(),
         }
     }
 }
 
+impl Maintenance {
+    /// When the server is expected back
+    pub fn ends_at(&self) -> DateTime<Utc> {
+        self.starts_at + TimeDelta::seconds(self.duration_secs.try_into().unwrap_or(i64::MAX))
+    }
+}
+
+impl ServerError {
+    pub fn new(kind: ErrorKind, message: String) -> Self {
+        Self { kind, message }
+    }
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}: {}", self.kind, self.message)
+    }
+}
+
+impl SessionHandshake {
+    /// Create a signed handshake from us to a peer, proven with our age identity
+    pub fn new(
+        from: &Identity,
+        to: String,
+        session_id: String,
+        ephemeral_key: String,
+    ) -> Result<Self> {
+        let signing_key = signing_key(from);
+        let mut handshake = Self {
+            session_id,
+            from: from.to_public().to_string(),
+            to,
+            ephemeral_key,
+            signing_key: hex::encode(signing_key.verifying_key().as_bytes()),
+            signature: "".into(),
+            key_proof: "".into(),
+        };
+        handshake.signature = sign(&signing_key, &handshake.signed_bytes());
+        handshake.key_proof = handshake.prove(from)?;
+        Ok(handshake)
+    }
+
+    /// Check that the handshake was signed by its signing key
+    pub fn verify_signature(&self) -> Result<()> {
+        verify(&self.signing_key, &self.signature, &self.signed_bytes())
+    }
+
+    /// Check that the handshake to us comes from the age identity it claims. The signature alone
+    /// doesn't show that, as anyone relaying it could swap in their own ephemeral and signing keys.
+    pub fn check_key_proof(&self, identity: &Identity) -> Result<()> {
+        if self.key_proof.is_empty() || self.prove(identity)? != self.key_proof {
+            bail!("Handshake is not proven to come from {}", self.from);
+        }
+        Ok(())
+    }
+
+    /// Proof over the whole handshake, keyed with the agreement between `from` and `to`
+    fn prove(&self, identity: &Identity) -> Result<String> {
+        let peer = if self.from == identity.to_public().to_string() {
+            &self.to
+        } else {
+            &self.from
+        };
+        agreement_proof(
+            identity,
+            peer,
+            HANDSHAKE_PROOF_CONTEXT,
+            &[&self.signed_bytes(), self.signing_key.as_bytes()],
+        )
+    }
+
+    fn signed_bytes(&self) -> Vec<u8> {
+        [
+            self.session_id.as_bytes(),
+            self.from.as_bytes(),
+            self.to.as_bytes(),
+            self.ephemeral_key.as_bytes(),
+        ]
+        .join(&0u8)
+    }
+}
+
+impl Revocation {
+    /// Create a signed revocation of our own key
+    pub fn new(identity: &Identity, reason: Option<String>) -> Self {
+        let signing_key = signing_key(identity);
+        let mut revocation = Self {
+            pub_key: identity.to_public().to_string(),
+            signing_key: hex::encode(signing_key.verifying_key().as_bytes()),
+            revoked_at: Utc::now(),
+            reason,
+            signature: "".into(),
+        };
+        revocation.signature = sign(&signing_key, &revocation.signed_bytes());
+        revocation
+    }
+
+    /// Check that the revocation was signed by its signing key
+    pub fn verify_signature(&self) -> Result<()> {
+        verify(&self.signing_key, &self.signature, &self.signed_bytes())
+    }
+
+    fn signed_bytes(&self) -> Vec<u8> {
+        [
+            REVOCATION_CONTEXT,
+            self.pub_key.as_bytes(),
+            self.revoked_at.to_rfc3339().as_bytes(),
+            self.reason.as_deref().unwrap_or_default().as_bytes(),
+        ]
+        .join(&0u8)
+    }
+}
+
+/// A user's announcement to a contact that they've moved to a new key. Sent in a note from the
+/// old key and signed by the new one, so it can't point contacts at a key the sender doesn't hold.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct KeyChange {
+    pub old_key: String,
+    pub new_key: String,
+    /// Hex encoded signing key of the new key
+    pub new_signing_key: String,
+    pub signature: String,
+}
+
+impl KeyChange {
+    /// Create a key change from an old pubkey to a new identity, signed by the new identity
+    pub fn new(old_key: String, new_identity: &Identity) -> Self {
+        let signing_key = signing_key(new_identity);
+        let mut key_change = Self {
+            old_key,
+            new_key: new_identity.to_public().to_string(),
+            new_signing_key: hex::encode(signing_key.verifying_key().as_bytes()),
+            signature: "".into(),
+        };
+        key_change.signature = sign(&signing_key, &key_change.signed_bytes());
+        key_change
+    }
+
+    /// Check that the key change was signed by the new key's signing key
+    pub fn verify_signature(&self) -> Result<()> {
+        verify(&self.new_signing_key, &self.signature, &self.signed_bytes())
+    }
+
+    fn signed_bytes(&self) -> Vec<u8> {
+        [
+            KEY_CHANGE_CONTEXT,
+            self.old_key.as_bytes(),
+            self.new_key.as_bytes(),
+        ]
+        .join(&0u8)
+    }
+}
+
+impl AuthChallenge {
+    /// Create a new challenge with a random nonce for a pubkey
+    pub fn new(pub_key: String) -> Self {
+        let mut bytes = [0u8; 64];
+        rand::rng().fill_bytes(&mut bytes);
+        Self {
+            context: AUTH_CHALLENGE_CONTEXT.into(),
+            nonce: hex::encode(bytes),
+            pub_key,
+        }
+    }
+
+    /// Encrypt the challenge to the pubkey it is for
+    pub fn encrypt(&self) -> Result<String> {
+        let recipient = Recipient::from_str(&self.pub_key).map_err(|e| anyhow!(e))?;
+        let plaintext = serde_json::to_vec(self)?;
+        Ok(age::encrypt_and_armor(&recipient, &plaintext)?)
+    }
+
+    /// Decrypt a challenge, rejecting anything that isn't a challenge for our own pubkey
+    pub fn decrypt(priv_key: &Identity, ciphertext: &str) -> Result<Self> {
+        let plaintext = age::decrypt(priv_key, ciphertext.as_bytes())?;
+        let challenge: Self = serde_json::from_slice(&plaintext)
+            .map_err(|_| anyhow!("Auth secret is not an auth challenge"))?;
+        if challenge.context != AUTH_CHALLENGE_CONTEXT {
+            return Err(anyhow!(
+                "Auth challenge has unexpected context {}",
+                challenge.context
+            ));
+        }
+        if challenge.pub_key != priv_key.to_public().to_string() {
+            return Err(anyhow!(
+                "Auth challenge is for a different pubkey {}",
+                challenge.pub_key
+            ));
+        }
+        Ok(challenge)
+    }
+}
+
 impl Note {
-    pub fn encrypt_new(from: &Recipient, to: &Recipient, content: String) -> Result<Self> {
-        // Encrypt to from and to pubkeys
-        let recipients: Vec<&dyn age::Recipient> = vec![from, to];
-        let encryptor = Encryptor::with_recipients(recipients.into_iter())?;
-        let mut encrypted_content = vec![];
-        let mut writer = encryptor.wrap_output(ArmoredWriter::wrap_output(
-            &mut encrypted_content,
-            Format::AsciiArmor,
-        )?)?;
-        writer.write_all(&content.into_bytes())?;
-        writer.finish()?.finish()?;
-        let encrypted_content = String::from_utf8(encrypted_content)?;
-
-        Ok(Self {
-            from: from.to_string(),
+    /// Create a note to a recipient, signed so that nobody else can send notes as us
+    pub fn encrypt_new(from: &Identity, to: &Recipient, content: String) -> Result<Self> {
+        let encrypted_content = encrypt_to_both(from, to, content.as_bytes())?;
+        Self::signed(from, to, encrypted_content, None)
+    }
+
+    /// Create a note with content already encrypted with a forward secret session
+    pub fn new_session(
+        from: &Identity,
+        to: &Recipient,
+        session: SessionHeader,
+        encrypted_content: String,
+    ) -> Result<Self> {
+        Self::signed(from, to, encrypted_content, Some(session))
+    }
+
+    fn signed(
+        from: &Identity,
+        to: &Recipient,
+        encrypted_content: String,
+        session: Option<SessionHeader>,
+    ) -> Result<Self> {
+        let signing_key = signing_key(from);
+        let from_pub_key = from.to_public().to_string();
+        let signing_key_hex = hex::encode(signing_key.verifying_key().as_bytes());
+        let mut note = Self {
+            id: new_note_id(),
+            key_proof: prove_signing_key(from, &to.to_string(), &from_pub_key, &signing_key_hex)?,
+            from: from_pub_key,
             to: to.to_string(),
             encrypted_content,
             timestamp: Utc::now(),
+            signing_key: signing_key_hex,
+            signature: "".into(),
+            session,
+        };
+        note.signature = sign(
+            &signing_key,
+            &note.signed_bytes(&note.from, &note.encrypted_content)?,
+        );
+        Ok(note)
+    }
+
+    /// Create a sealed sender note. The sender and signature are only inside the encrypted
+    /// payload, so the server only learns who the note is to.
+    pub fn encrypt_new_sealed(from: &Identity, to: &Recipient, content: String) -> Result<Self> {
+        let signing_key = signing_key(from);
+        let mut note = Self {
+            id: new_note_id(),
+            from: "".into(),
+            to: to.to_string(),
+            encrypted_content: "".into(),
+            timestamp: Utc::now(),
+            signing_key: "".into(),
+            signature: "".into(),
+            session: None,
+            key_proof: "".into(),
+        };
+        let from_pub_key = from.to_public().to_string();
+        let signing_key_hex = hex::encode(signing_key.verifying_key().as_bytes());
+        let payload = SealedPayload {
+            signature: sign(&signing_key, &note.signed_bytes(&from_pub_key, &content)?),
+            key_proof: prove_signing_key(from, &to.to_string(), &from_pub_key, &signing_key_hex)?,
+            from: from_pub_key,
+            signing_key: signing_key_hex,
+            content,
+        };
+        note.encrypted_content = encrypt_to_both(from, to, &serde_json::to_vec(&payload)?)?;
+        Ok(note)
+    }
+
+    /// Whether the sender is hidden inside the encrypted payload
+    pub fn is_sealed(&self) -> bool {
+        self.from.is_empty()
+    }
+
+    /// Check the signature of an unsealed note, which the server can do without decrypting
+    pub fn verify_signature(&self) -> Result<()> {
+        verify(
+            &self.signing_key,
+            &self.signature,
+            &self.signed_bytes(&self.from, &self.encrypted_content)?,
+        )
+    }
+
+    /// Check the proof of an unsealed note's signing key, returning whether it had one. Sealed
+    /// notes' proofs are checked when they're opened.
+    pub fn check_key_proof(&self, identity: &Identity) -> Result<bool> {
+        check_key_proof(
+            identity,
+            &self.from,
+            &self.to,
+            &self.signing_key,
+            &self.key_proof,
+        )
+    }
+
+    /// Check the content is plausibly ciphertext without decrypting it: age armor with a valid
+    /// header, or a session's hex with room for its tag. Cheap enough to do on every note.
+    pub fn check_ciphertext(&self) -> Result<()> {
+        if self.session.is_some() {
+            let ciphertext =
+                hex::decode(&self.encrypted_content).context("Session ciphertext is not hex")?;
+            if ciphertext.len() < SESSION_TAG_BYTES {
+                return Err(anyhow!("Session ciphertext is too short"));
+            }
+            return Ok(());
+        }
+        if !self.encrypted_content.trim_end().ends_with(ARMOR_END) {
+            return Err(anyhow!("Content is not complete age armor"));
+        }
+        age::Decryptor::new(ArmoredReader::new(self.encrypted_content.as_bytes()))
+            .context("Content is not age armor")?;
+        Ok(())
+    }
+
+    /// Decrypt the note and verify the sender's signature. Notes encrypted with a forward secret
+    /// session can only be decrypted by the session.
+    pub fn open(&self, priv_key: &Identity) -> Result<OpenedNote> {
+        if self.session.is_some() {
+            return Err(anyhow!("Note is encrypted with a forward secret session"));
+        }
+        let plaintext =
+            String::from_utf8(age::decrypt(priv_key, self.encrypted_content.as_bytes())?)?;
+        if !self.is_sealed() {
+            self.verify_signature()?;
+            return Ok(OpenedNote {
+                key_proven: self.check_key_proof(priv_key)?,
+                from: self.from.clone(),
+                signing_key: self.signing_key.clone(),
+                content: plaintext,
+            });
+        }
+
+        let payload: SealedPayload = serde_json::from_str(&plaintext)?;
+        verify(
+            &payload.signing_key,
+            &payload.signature,
+            &self.signed_bytes(&payload.from, &payload.content)?,
+        )?;
+        Ok(OpenedNote {
+            key_proven: check_key_proof(
+                priv_key,
+                &payload.from,
+                &self.to,
+                &payload.signing_key,
+                &payload.key_proof,
+            )?,
+            from: payload.from,
+            signing_key: payload.signing_key,
+            content: payload.content,
         })
     }
 
-    pub fn decrypt_content(&self, priv_key: &Identity) -> Result<String> {
-        Ok(String::from_utf8(age::decrypt(
-            priv_key,
-            self.encrypted_content.as_bytes(),
-        )?)?)
+    /// The fields covered by the signature. The body is the ciphertext for regular notes, and
+    /// the plaintext for sealed notes since their signature is inside the ciphertext.
+    fn signed_bytes(&self, from: &str, body: &str) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec(&(
+            from,
+            &self.to,
+            &self.id,
+            &self.timestamp,
+            body,
+        ))?)
+    }
+}
+
+/// Random unique note id, so that recipients can detect replays
+fn new_note_id() -> String {
+    let mut id_bytes = [0u8; 16];
+    rand::rng().fill_bytes(&mut id_bytes);
+    hex::encode(id_bytes)
+}
+
+/// Encrypt to both the sender and recipient, so the sender can read their own notes
+fn encrypt_to_both(from: &Identity, to: &Recipient, plaintext: &[u8]) -> Result<String> {
+    let from_pub_key = from.to_public();
+    let recipients: Vec<&dyn age::Recipient> = vec![&from_pub_key, to];
+    let encryptor = Encryptor::with_recipients(recipients.into_iter())?;
+    let mut encrypted = vec![];
+    let mut writer = encryptor.wrap_output(ArmoredWriter::wrap_output(
+        &mut encrypted,
+        Format::AsciiArmor,
+    )?)?;
+    writer.write_all(plaintext)?;
+    writer.finish()?.finish()?;
+    Ok(String::from_utf8(encrypted)?)
+}
+
+/// Load an identity from a key file, skipping comment lines
+pub fn load_key(path: &Path) -> Result<Identity> {
+    let key_file = std::fs::read_to_string(path)
+        .context(format!("Error reading key file {}", path.display()))?
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .collect::<Vec<&str>>()
+        .join("\n");
+    Identity::from_str(&key_file).map_err(|e| anyhow!(e))
+}
+
+/// Make sure other users can't read a key file, offering to fix it if run interactively
+#[cfg(unix)]
+pub fn check_key_perms(path: &Path, insecure_ok: bool) -> Result<()> {
+    use std::io::{BufRead, IsTerminal, Write};
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = std::fs::metadata(path)
+        .context(format!("Error reading key file {}", path.display()))?
+        .permissions()
+        .mode();
+    if mode & 0o077 == 0 {
+        return Ok(());
+    }
+    if insecure_ok {
+        tracing::warn!(
+            "🔑 Key file {} is accessible by other users (mode {:o})",
+            path.display(),
+            mode & 0o777
+        );
+        return Ok(());
+    }
+
+    if std::io::stdin().is_terminal() {
+        print!(
+            "Key file {} is accessible by other users (mode {:o}), restrict it to 600? [y/N] ",
+            path.display(),
+            mode & 0o777
+        );
+        std::io::stdout().flush()?;
+        let mut answer = String::new();
+        std::io::stdin().lock().read_line(&mut answer)?;
+        if answer.trim().eq_ignore_ascii_case("y") {
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))
+                .context(format!("Error fixing permissions of {}", path.display()))?;
+            tracing::info!("🔑 Restricted key file {} to mode 600", path.display());
+            return Ok(());
+        }
     }
+    bail!(
+        "Key file {} is accessible by other users (mode {:o}), run `chmod 600` on it or pass \
+         --insecure-key-perms",
+        path.display(),
+        mode & 0o777
+    )
+}
+
+/// Other platforms don't have unix permission bits to check
+#[cfg(not(unix))]
+pub fn check_key_perms(_path: &Path, _insecure_ok: bool) -> Result<()> {
+    Ok(())
+}
+
+/// Sign bytes, returning the hex encoded signature
+fn sign(signing_key: &SigningKey, bytes: &[u8]) -> String {
+    hex::encode(signing_key.sign(bytes).to_bytes())
+}
+
+/// Verify a hex encoded signature with a hex encoded key
+fn verify(signing_key: &str, signature: &str, bytes: &[u8]) -> Result<()> {
+    let key_bytes: [u8; 32] = hex::decode(signing_key)?
+        .try_into()
+        .map_err(|_| anyhow!("Invalid signing key length"))?;
+    let signature_bytes: [u8; 64] = hex::decode(signature)?
+        .try_into()
+        .map_err(|_| anyhow!("Invalid signature length"))?;
+    let key = VerifyingKey::from_bytes(&key_bytes)?;
+    key.verify(bytes, &Signature::from_bytes(&signature_bytes))?;
+    Ok(())
 }
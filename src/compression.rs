@@ -0,0 +1,59 @@
+use anyhow::{bail, Result};
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Messages shorter than this are sent as text even once compression is negotiated, deflating
+/// them doesn't pay off. Armored notes are well over it.
+pub const MIN_COMPRESS_BYTES: usize = 256;
+/// Largest message a compressed frame may inflate to, so a small frame can't exhaust memory
+const MAX_INFLATED_BYTES: u64 = 16 * 1024 * 1024;
+
+/// Deflate a message's json, to send as a binary frame
+pub fn deflate(text: &str) -> Result<Vec<u8>> {
+    let mut encoder = DeflateEncoder::new(vec![], Compression::default());
+    encoder.write_all(text.as_bytes())?;
+    Ok(encoder.finish()?)
+}
+
+/// Inflate a binary frame back into a message's json
+pub fn inflate(data: &[u8]) -> Result<String> {
+    let mut text = String::new();
+    DeflateDecoder::new(data)
+        .take(MAX_INFLATED_BYTES + 1)
+        .read_to_string(&mut text)?;
+    if text.len() as u64 > MAX_INFLATED_BYTES {
+        bail!("Compressed message inflates to over {MAX_INFLATED_BYTES} bytes");
+    }
+    Ok(text)
+}
+
+/// Bytes of messages before and after compression, for reporting how much it saves
+#[derive(Default)]
+pub struct CompressionStats {
+    raw_bytes: AtomicU64,
+    wire_bytes: AtomicU64,
+}
+
+impl CompressionStats {
+    pub fn record(&self, raw_bytes: usize, wire_bytes: usize) {
+        self.raw_bytes
+            .fetch_add(raw_bytes as u64, Ordering::Relaxed);
+        self.wire_bytes
+            .fetch_add(wire_bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn raw_bytes(&self) -> u64 {
+        self.raw_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn wire_bytes(&self) -> u64 {
+        self.wire_bytes.load(Ordering::Relaxed)
+    }
+
+    /// How many times smaller compressed messages were, if any were compressed
+    pub fn ratio(&self) -> Option<f64> {
+        let wire_bytes = self.wire_bytes();
+        (wire_bytes > 0).then(|| self.raw_bytes() as f64 / wire_bytes as f64)
+    }
+}
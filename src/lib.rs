@@ -0,0 +1,1079 @@
+mod bench;
+mod client;
+pub mod common;
+mod compression;
+mod config;
+mod server;
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use chrono::NaiveTime;
+use clap::{Parser, Subcommand};
+
+use crate::bench::BenchPath;
+use crate::config::{Resolver, DEFAULT_ADDRESS};
+use crate::server::{AuthBackendKind, LogFormat, OverflowPolicy, QuotaPolicy};
+
+pub use crate::client::{ChatClient, ChatEvent, Outgoing, ReceivedNote, Shutdown};
+pub use crate::server::{embedded as embedded_server, EmbeddedServer};
+
+/// The age-chat command line, which the binary parses and runs
+#[derive(Parser)]
+pub struct Cli {
+    /// Config file to load [default: age-chat.toml]
+    #[clap(long, global = true)]
+    config: Option<PathBuf>,
+
+    /// Print the effective config values and their sources, then exit
+    #[clap(long, global = true)]
+    print_config: bool,
+
+    /// Key file to decrypt age encrypted config file values with, e.g. made with `age -a -r`
+    #[clap(long, global = true)]
+    secrets_key: Option<String>,
+
+    #[command(subcommand)]
+    command: Subcommands,
+}
+
+#[derive(Subcommand)]
+enum Subcommands {
+    /// Run the chat server
+    ///
+    /// SIGHUP reloads the access lists, and the rates, limits and timeouts from the config file,
+    /// env and flags. Other settings need a restart to change.
+    Serve(Box<ServerArgs>),
+    /// Run the chat server
+    Connect(Box<ClientArgs>),
+    /// Stay connected and authenticated in the background, for the TUI and scripts to attach to
+    /// with `connect --daemon`, so notifications keep working with no TUI open
+    Daemon(Box<DaemonArgs>),
+    /// Measure the crypto and protocol hot paths
+    Bench(BenchArgs),
+    /// Generate invite codes for new users of a server using the invite auth backend
+    Invite(InviteArgs),
+    /// Schedule or cancel downtime, which the server announces to clients
+    Maintenance(MaintenanceArgs),
+    /// Manage a running server through its admin socket
+    Admin(AdminArgs),
+    /// Revoke a compromised key, banning it and telling contacts to stop trusting it
+    Revoke(RevokeArgs),
+    /// Print a line whenever our chat session comes online or goes offline or notes are queued
+    /// for it, e.g. for a status bar. Doesn't stop the chat client connecting.
+    Presence(PresenceArgs),
+    /// Feed a session recorded with --record-session back through the message parser
+    Replay(ReplayArgs),
+    /// Send a single note and exit once the server accepts it, e.g. from scripts or cron jobs
+    Send(SendArgs),
+    /// Print the notes we receive, one line each, until interrupted, e.g. for bots
+    Listen(ListenArgs),
+    /// Run an example bot built on the library's ChatClient
+    Bot(BotArgs),
+}
+
+#[derive(Clone, Parser)]
+struct CommonArgs {
+    /// Address to connect to formatted as <host>:<port>, or a full ws:// or wss:// url to connect
+    /// through a reverse proxy, e.g. wss://example.com/chat. Clients look up the server of a bare
+    /// domain in its _agechat._tcp SRV or TXT record. The client takes several comma separated to
+    /// chat on each at once. [default: 0.0.0.0:42069]
+    address: Option<String>,
+}
+
+#[derive(Clone, Parser)]
+struct ServerArgs {
+    /// Address to listen on, repeat for more, e.g. `--listen [::]:42069 --listen 0.0.0.0:42069` for
+    /// dual-stack. IPv6 addresses only accept IPv6. Replaces the positional address.
+    #[clap(long)]
+    listen: Vec<String>,
+
+    /// Allow a new server process to bind the same address, then send this one SIGUSR2 to stop
+    /// accepting and drain its connections for a zero-downtime upgrade
+    #[clap(long)]
+    reuse_port: bool,
+
+    /// Redis to relay through to users connected to other server instances, so several can run
+    /// behind a load balancer, e.g. redis://127.0.0.1/. Needs --db pointing every instance at
+    /// the same database, which holds the mailboxes.
+    #[clap(long)]
+    redis_url: Option<String>,
+
+    /// Max notes per second delivered to each client, 0 to disable pacing [default: 100]
+    #[clap(long)]
+    send_rate: Option<u32>,
+
+    /// Max messages queued for relaying to each client before the overflow policy kicks in
+    /// [default: 1000]
+    #[clap(long)]
+    relay_buffer: Option<usize>,
+
+    /// What to do when a client isn't keeping up with the messages relayed to it [default: drop-new]
+    #[clap(long)]
+    relay_overflow: Option<OverflowPolicy>,
+
+    /// Max bytes of a note's encrypted content, larger notes are refused [default: 1048576]
+    #[clap(long)]
+    max_note_bytes: Option<usize>,
+
+    /// Notes per second each user can send, 0 to disable rate limiting [default: 10]
+    #[clap(long)]
+    note_rate: Option<u32>,
+
+    /// Max notes each user can send in a burst before being rate limited [default: 50]
+    #[clap(long)]
+    note_burst: Option<u32>,
+
+    /// Rate limited notes before a client is disconnected, 0 to never disconnect [default: 20]
+    #[clap(long)]
+    rate_limit_strikes: Option<u32>,
+
+    /// Max open connections from each IP, 0 for unlimited [default: 32]
+    #[clap(long)]
+    ip_max_conns: Option<u32>,
+
+    /// Connection attempts per second allowed from each IP, 0 for unlimited [default: 5]
+    #[clap(long)]
+    ip_conn_rate: Option<u32>,
+
+    /// Max connection attempts from each IP in a burst [default: 20]
+    #[clap(long)]
+    ip_conn_burst: Option<u32>,
+
+    /// Seconds a client can go without responding to pings before it's disconnected, 0 to
+    /// never disconnect [default: 300]
+    #[clap(long)]
+    idle_timeout: Option<u64>,
+
+    /// Seconds a write to a client can stall before it's disconnected, 0 to wait forever
+    /// [default: 10]
+    #[clap(long)]
+    write_timeout: Option<u64>,
+
+    /// Seconds a client's relay queue can stay over 75% full before it's disconnected as too slow,
+    /// 0 to never disconnect [default: 30]
+    #[clap(long)]
+    slow_client_timeout: Option<u64>,
+
+    /// Unix socket to listen for commands from `age-chat admin` on
+    #[clap(long)]
+    admin_socket: Option<String>,
+
+    /// Seconds to wait for connections to close on ctrl-c or SIGTERM before force closing them,
+    /// 0 to wait forever [default: 30]
+    #[clap(long)]
+    shutdown_timeout: Option<u64>,
+
+    /// Seconds between keepalive pings to each client, 0 to not ping [default: 30]
+    #[clap(long)]
+    ping_interval: Option<u64>,
+
+    /// Pings in a row a client can leave unanswered before it's disconnected [default: 3]
+    #[clap(long)]
+    max_missed_pongs: Option<u32>,
+
+    /// How to authorize users after the key challenge [default: challenge]
+    #[clap(long)]
+    auth_backend: Option<AuthBackendKind>,
+
+    /// File of allowed pubkeys or invite codes for the allowlist and invite auth backends
+    #[clap(long)]
+    auth_file: Option<String>,
+
+    /// Url to POST pubkeys and tokens to for the webhook auth backend
+    #[clap(long)]
+    auth_webhook: Option<String>,
+
+    /// Failed auth attempts before an IP is temporarily banned, 0 to never ban [default: 10]
+    #[clap(long)]
+    auth_max_failures: Option<u32>,
+
+    /// Seconds temporary auth bans last [default: 900]
+    #[clap(long)]
+    auth_ban_secs: Option<u64>,
+
+    /// File of the only pubkeys allowed to authenticate, reloaded on SIGHUP
+    #[clap(long)]
+    allow_file: Option<String>,
+
+    /// File of pubkeys banned from authenticating, reloaded on SIGHUP
+    #[clap(long)]
+    deny_file: Option<String>,
+
+    /// Max bytes of notes each user can have stored for offline users [default: 10485760]
+    #[clap(long)]
+    quota_bytes: Option<usize>,
+
+    /// What to do when a user would go over their storage quota [default: reject]
+    #[clap(long)]
+    quota_policy: Option<QuotaPolicy>,
+
+    /// Max bytes of notes in each user's mailbox, dropping the oldest when full [default: 10485760]
+    #[clap(long)]
+    mailbox_bytes: Option<usize>,
+
+    /// Seconds a client can resume its session for after its connection drops, skipping the key
+    /// challenge, 0 to not allow resuming [default: 300]
+    #[clap(long)]
+    resume_ttl: Option<u64>,
+
+    /// Notes delivered to each user to keep in memory, so a client that reconnects can fetch the
+    /// ones lost in flight, 0 to keep none [default: 100]
+    #[clap(long)]
+    sync_window: Option<usize>,
+
+    /// Seconds to keep delivered notes for reconnecting clients to fetch [default: 300]
+    #[clap(long)]
+    sync_window_ttl: Option<u64>,
+
+    /// Don't compress large messages like notes for clients that offer it
+    #[clap(long)]
+    no_compression: bool,
+
+    /// Comma separated client message types to warn clients are deprecated, e.g. QuotaQuery
+    #[clap(long)]
+    deprecated: Option<String>,
+
+    /// Path clients have to connect to, e.g. /chat when a reverse proxy forwards it as is
+    /// [default: any path]
+    #[clap(long)]
+    ws_path: Option<String>,
+
+    /// Comma separated Host headers to accept connections for, e.g. chat.example.com [default:
+    /// any host]
+    #[clap(long)]
+    allowed_hosts: Option<String>,
+
+    /// Comma separated Origin headers to accept connections from, for browser clients. Clients
+    /// without an Origin are always accepted. [default: any origin]
+    #[clap(long)]
+    allowed_origins: Option<String>,
+
+    /// SQLite database file to persist users, queued notes, bans and invites in
+    #[clap(long)]
+    db: Option<String>,
+
+    /// Seconds to keep notes queued for offline users before dropping them [default: forever]
+    #[clap(long)]
+    retention_max_age: Option<u64>,
+
+    /// Max bytes of notes queued for each offline user, dropping the oldest [default: unlimited]
+    #[clap(long)]
+    retention_max_bytes: Option<usize>,
+
+    /// Seconds between sweeps for notes past retention [default: 60]
+    #[clap(long)]
+    retention_sweep_interval: Option<u64>,
+
+    /// Seconds to archive relayed notes so clients can fetch them as history, e.g. on a new
+    /// device. Only ciphertexts are kept. [default: don't archive]
+    #[clap(long)]
+    history_max_age: Option<u64>,
+
+    /// Time of day in UTC to compact storage every day, e.g. 04:00 off-peak [default: never]
+    #[clap(long)]
+    compact_at: Option<NaiveTime>,
+
+    /// File to append an audit log of who authenticated, relay counts and errors to, one json
+    /// object per line
+    #[clap(long)]
+    audit_log: Option<String>,
+
+    /// Secret salt unique to this deployment, to log users as salted hashes of their pubkeys and
+    /// leave out addresses. Better set in the env or encrypted in the config file than on the
+    /// command line.
+    #[clap(long)]
+    audit_salt: Option<String>,
+
+    /// How to format logs [default: pretty]
+    #[clap(long)]
+    log_format: Option<LogFormat>,
+
+    /// Level to log at, or filter directives like age_chat=debug,tungstenite=warn [default: info]
+    #[clap(long)]
+    log_level: Option<String>,
+
+    /// File to append logs to instead of stdout
+    #[clap(long)]
+    log_file: Option<String>,
+
+    /// Advertise the server on the local network under this name, so clients can find it with
+    /// `connect --discover`
+    #[clap(long)]
+    advertise: Option<String>,
+
+    /// Load the secrets key even if other users can read it
+    #[clap(long)]
+    insecure_key_perms: bool,
+
+    #[command(flatten)]
+    common: CommonArgs,
+}
+
+#[derive(Parser)]
+#[clap(
+    after_help = "Exit codes: 0 quit or note sent, 1 error, 2 auth denied, 3 connection failed, \
+                  4 key error, 5 kicked, 6 note refused"
+)]
+struct ClientArgs {
+    /// Comma separated key files of the identities to chat as [default: key.txt]
+    #[clap(long, short = 'u')]
+    key_file: Option<String>,
+
+    /// Recipient pubkeys to chat with, comma separated, each in a conversation of its own. The
+    /// first is shown on start.
+    #[clap(long, short = 'r')]
+    recipient: Option<String>,
+
+    /// Open a conversation with anyone who sends us a note, not just the recipients
+    #[clap(long)]
+    allow_anyone: bool,
+
+    /// Pre-load the input box with a note to this recipient pubkey read from stdin
+    #[clap(long, conflicts_with = "recipient")]
+    stdin_to: Option<String>,
+
+    /// Send the note read from stdin as soon as we're authenticated, then exit
+    #[clap(long, requires = "stdin_to")]
+    send: bool,
+
+    /// Token to present to the server's auth backend, e.g. for the webhook backend
+    #[clap(long)]
+    auth_token: Option<String>,
+
+    /// Invite code to join a server using the invite auth backend, only needed the first time
+    #[clap(long)]
+    invite: Option<String>,
+
+    /// Hide our pubkey from the server by sealing it inside the encrypted payload of notes
+    #[clap(long)]
+    sealed_sender: bool,
+
+    /// Offer the recipient a forward secret session, using plain notes until they accept. Ignored
+    /// with --sealed-sender, as session notes show the server who sent them.
+    #[clap(long)]
+    forward_secrecy: bool,
+
+    /// TOML file of filter rules to evaluate received notes against
+    #[clap(long)]
+    rules_file: Option<String>,
+
+    /// Shell command run on each note received once it's decrypted, with the note in the
+    /// `AGE_CHAT_FROM` and `AGE_CHAT_TEXT` env vars. Printing `mute` hides the note and `reply
+    /// <text>` sends the text back to the sender.
+    #[clap(long)]
+    hook: Option<String>,
+
+    /// Url to POST a JSON summary of each note received to, e.g. to bridge notes into Slack,
+    /// Discord or ntfy
+    #[clap(long)]
+    webhook: Option<String>,
+
+    /// Include the decrypted text in what's POSTed to the webhook, not just who it's from and when
+    #[clap(long)]
+    webhook_content: bool,
+
+    /// Raise a desktop notification when a note comes while the terminal is unfocused or
+    /// another conversation is shown
+    #[clap(long)]
+    desktop_notifications: bool,
+
+    /// Leave the text out of desktop notifications, only saying who a note is from
+    #[clap(long)]
+    hide_notification_content: bool,
+
+    /// Ring the bell when a note comes, from any peer and for any identity [default: off]
+    #[clap(long)]
+    bell: Option<client::Bell>,
+
+    /// Load key files even if other users can read them
+    #[clap(long)]
+    insecure_key_perms: bool,
+
+    /// Key file of a new identity to announce to the recipient, so they can switch to it
+    #[clap(long)]
+    rotate_to: Option<String>,
+
+    /// Record every frame sent and received to this file, to reproduce protocol bugs with `replay`
+    #[clap(long)]
+    record_session: Option<String>,
+
+    /// Blank out auth secrets, tokens, pubkeys and IPs in the session recording
+    #[clap(long, requires = "record_session")]
+    redact_recording: bool,
+
+    /// strftime format to show note timestamps in [default: %Y-%m-%d %H:%M:%S]. A
+    /// `[conversations.<pubkey>]` table in the config file can set a `time-format` and `timezone`
+    /// (local, utc or an offset like +09:00) for the conversation with that peer, or a `locale`
+    /// like de-DE to show its times the way that locale writes dates and times.
+    #[clap(long)]
+    time_format: Option<String>,
+
+    /// Show note timestamps in UTC rather than local time, e.g. to agree on times across timezones
+    #[clap(long)]
+    utc: bool,
+
+    /// Don't offer the server to compress large messages like notes
+    #[clap(long)]
+    no_compression: bool,
+
+    /// Quit when the connection to the server drops, rather than reconnecting with backoff
+    #[clap(long)]
+    no_reconnect: bool,
+
+    /// Don't keep a history of conversations to show again on the next start
+    #[clap(long)]
+    no_history: bool,
+
+    /// Seconds between pings to the server, to show latency and notice a dead connection, 0 to
+    /// not ping [default: 15]
+    #[clap(long)]
+    ping_interval: Option<u64>,
+
+    /// Seconds the server has to answer a ping before the connection counts as dead [default: 10]
+    #[clap(long)]
+    ping_timeout: Option<u64>,
+
+    /// Only trust a wss:// server presenting the certificate or public key with this SHA-256
+    /// fingerprint, e.g. a self-signed one, rather than one a well known CA signed
+    #[clap(long, conflicts_with = "tofu")]
+    pin_cert: Option<String>,
+
+    /// Trust the key each wss:// server presents the first time, refusing to connect if it
+    /// changes after, rather than requiring a certificate a well known CA signed
+    #[clap(long)]
+    tofu: bool,
+
+    /// Proxy to connect through, e.g. socks5://127.0.0.1:9050 for Tor to reach onion services or
+    /// hide our IP from the server, or http://proxy:3128 to tunnel through an HTTP proxy with
+    /// CONNECT. It resolves the server's host too. [default: HTTPS_PROXY, HTTP_PROXY or ALL_PROXY
+    /// unless the host is in NO_PROXY]
+    #[clap(long)]
+    proxy: Option<String>,
+
+    /// Attach to the daemon listening on this unix socket rather than connecting to the server,
+    /// it authenticates for us
+    #[clap(long)]
+    daemon: Option<String>,
+
+    /// Find a server advertising itself on the local network rather than connecting to the
+    /// address, asking which if there are several
+    #[clap(long, conflicts_with = "daemon")]
+    discover: bool,
+
+    /// Experimental: chat without a relay server by listening on this address for the recipients
+    /// to connect to us directly, e.g. 0.0.0.0:42070. They connect with our address as theirs.
+    #[clap(long, conflicts_with_all = ["daemon", "discover"])]
+    direct_listen: Option<String>,
+
+    #[command(flatten)]
+    common: CommonArgs,
+}
+
+#[derive(Parser)]
+#[clap(
+    after_help = "Frontends speak the same protocol over the socket as to the server. Exits with \
+                  the same codes as connect."
+)]
+struct DaemonArgs {
+    /// Unix socket to serve the TUI and scripts on, only our user can connect to it
+    /// [default: daemon.sock]
+    #[clap(long)]
+    socket: Option<String>,
+
+    /// Comma separated key files of the identities to stay authenticated as [default: key.txt]
+    #[clap(long, short = 'u')]
+    key_file: Option<String>,
+
+    /// Token to present to the server's auth backend, e.g. for the webhook backend
+    #[clap(long)]
+    auth_token: Option<String>,
+
+    /// TOML file of filter rules, whose commands run for notes that come while nothing is
+    /// attached
+    #[clap(long)]
+    rules_file: Option<String>,
+
+    /// Url to POST a JSON summary of each note received to, e.g. to bridge notes into Slack,
+    /// Discord or ntfy
+    #[clap(long)]
+    webhook: Option<String>,
+
+    /// Include the decrypted text in what's POSTed to the webhook, not just who it's from and when
+    #[clap(long)]
+    webhook_content: bool,
+
+    /// Load key files even if other users can read them
+    #[clap(long)]
+    insecure_key_perms: bool,
+
+    /// Don't offer the server to compress large messages like notes
+    #[clap(long)]
+    no_compression: bool,
+
+    /// Quit when the connection to the server drops, rather than reconnecting with backoff
+    #[clap(long)]
+    no_reconnect: bool,
+
+    /// Seconds between pings to the server, to notice a dead connection, 0 to not ping
+    /// [default: 15]
+    #[clap(long)]
+    ping_interval: Option<u64>,
+
+    /// Seconds the server has to answer a ping before the connection counts as dead [default: 10]
+    #[clap(long)]
+    ping_timeout: Option<u64>,
+
+    /// Only trust a wss:// server presenting the certificate or public key with this SHA-256
+    /// fingerprint
+    #[clap(long, conflicts_with = "tofu")]
+    pin_cert: Option<String>,
+
+    /// Trust the key each wss:// server presents the first time, refusing to connect if it
+    /// changes after
+    #[clap(long)]
+    tofu: bool,
+
+    /// Proxy to connect through, e.g. socks5://127.0.0.1:9050 [default: HTTPS_PROXY, HTTP_PROXY
+    /// or ALL_PROXY unless the host is in NO_PROXY]
+    #[clap(long)]
+    proxy: Option<String>,
+
+    #[command(flatten)]
+    common: CommonArgs,
+}
+
+#[derive(Parser)]
+struct BenchArgs {
+    /// Iterations of each path to run
+    #[clap(long, default_value_t = 1000)]
+    iterations: u32,
+
+    /// Only run this path, e.g. under a profiler
+    #[clap(long)]
+    profile: Option<BenchPath>,
+
+    /// Concurrent users to measure relay throughput with
+    #[clap(long, default_value_t = 2000)]
+    users: usize,
+}
+
+#[derive(Parser)]
+struct InviteArgs {
+    /// SQLite database file of the server to add the codes to
+    #[clap(long)]
+    db: Option<String>,
+
+    /// Number of codes to generate
+    #[clap(long, default_value_t = 1)]
+    count: u32,
+
+    /// Seconds until the codes stop working [default: never]
+    #[clap(long)]
+    expires_in: Option<u64>,
+
+    /// Let each code be used any number of times until it expires
+    #[clap(long)]
+    reusable: bool,
+}
+
+#[derive(Parser)]
+struct MaintenanceArgs {
+    /// SQLite database file of the server to schedule the maintenance in
+    #[clap(long)]
+    db: Option<String>,
+
+    /// Seconds from now until the downtime starts
+    #[clap(long, required_unless_present = "cancel")]
+    starts_in: Option<u64>,
+
+    /// Expected length of the downtime in seconds
+    #[clap(long, default_value_t = 600)]
+    duration: u64,
+
+    /// Message to show users
+    #[clap(long, default_value = "Scheduled maintenance")]
+    message: String,
+
+    /// Cancel the scheduled downtime instead
+    #[clap(long, conflicts_with = "starts_in")]
+    cancel: bool,
+}
+
+#[derive(Parser)]
+struct AdminArgs {
+    /// Admin socket of the server to manage
+    #[clap(long)]
+    admin_socket: Option<String>,
+
+    /// Command to run: users, stats, kick <pubkey> [reason], ban <pubkey> [reason], reload,
+    /// announce <message> or compact
+    #[clap(required = true, num_args = 1..)]
+    command: Vec<String>,
+}
+
+#[derive(Parser)]
+struct RevokeArgs {
+    /// Key file of the identity to revoke
+    #[clap(long, short = 'u')]
+    key_file: PathBuf,
+
+    /// Why the key is being revoked, shown to contacts
+    #[clap(long)]
+    reason: Option<String>,
+
+    /// Token to present to the server's auth backend, e.g. for the webhook backend
+    #[clap(long)]
+    auth_token: Option<String>,
+
+    /// Load the key file even if other users can read it
+    #[clap(long)]
+    insecure_key_perms: bool,
+
+    #[command(flatten)]
+    common: CommonArgs,
+}
+
+#[derive(Parser)]
+#[clap(
+    after_help = "Prints lines of `online <queued notes>` or `offline <queued notes>`. Exits with \
+                  the same codes as connect."
+)]
+struct PresenceArgs {
+    /// Key file of the identity to watch [default: key.txt]
+    #[clap(long, short = 'u')]
+    key_file: Option<String>,
+
+    /// How to print what happens, json prints objects tagged with their `event`
+    #[clap(long, value_enum, default_value = "text")]
+    output: client::Output,
+
+    /// Token to present to the server's auth backend, e.g. for the webhook backend
+    #[clap(long)]
+    auth_token: Option<String>,
+
+    /// Load the key file even if other users can read it
+    #[clap(long)]
+    insecure_key_perms: bool,
+
+    #[command(flatten)]
+    common: CommonArgs,
+}
+
+#[derive(Parser)]
+#[clap(
+    after_help = "Prints nothing once the server accepted the note, or a `sent` event with --output \
+                  json. Exits with the same codes as connect."
+)]
+struct SendArgs {
+    /// Text of the note [default: read from stdin]
+    message: Option<String>,
+
+    /// Read the text of the note from this file instead
+    #[clap(long, conflicts_with = "message")]
+    file: Option<PathBuf>,
+
+    /// Recipient pubkey to send the note to
+    #[clap(long, short = 'r')]
+    recipient: Option<String>,
+
+    /// Key file of the identity to send as [default: key.txt]
+    #[clap(long, short = 'u')]
+    key_file: Option<String>,
+
+    /// Token to present to the server's auth backend, e.g. for the webhook backend
+    #[clap(long)]
+    auth_token: Option<String>,
+
+    /// Hide our pubkey from the server by sealing it inside the encrypted payload of the note
+    #[clap(long)]
+    sealed_sender: bool,
+
+    /// Load the key file even if other users can read it
+    #[clap(long)]
+    insecure_key_perms: bool,
+
+    /// How to print what happens, json prints objects tagged with their `event`
+    #[clap(long, value_enum, default_value = "text")]
+    output: client::Output,
+
+    /// Address to connect to formatted as <host>:<port>, or a full ws:// or wss:// url. A flag
+    /// here since the note is positional. [default: 0.0.0.0:42069]
+    #[clap(long)]
+    address: Option<String>,
+}
+
+#[derive(Parser)]
+#[clap(
+    after_help = "Prints lines of `<from>: <text>` with newlines in the text escaped, or JSON \
+                  objects with --output json. Exits with the same codes as connect."
+)]
+struct ListenArgs {
+    /// Key file of the identity to receive as [default: key.txt]
+    #[clap(long, short = 'u')]
+    key_file: Option<String>,
+
+    /// Token to present to the server's auth backend, e.g. for the webhook backend
+    #[clap(long)]
+    auth_token: Option<String>,
+
+    /// How to print what happens, json prints objects tagged with their `event`
+    #[clap(long, value_enum, default_value = "text")]
+    output: client::Output,
+
+    /// Load the key file even if other users can read it
+    #[clap(long)]
+    insecure_key_perms: bool,
+
+    #[command(flatten)]
+    common: CommonArgs,
+}
+
+#[derive(Parser)]
+struct BotArgs {
+    #[command(subcommand)]
+    bot: Bots,
+}
+
+#[derive(Subcommand)]
+enum Bots {
+    /// Reply to each note received with its content echoed back, until interrupted
+    Echo(EchoBotArgs),
+}
+
+#[derive(Parser)]
+#[command(after_help = "Exits with the same codes as connect.")]
+struct EchoBotArgs {
+    /// Key file of the identity to run the bot as [default: key.txt]
+    #[clap(long, short = 'u')]
+    key_file: Option<String>,
+
+    /// Token to present to the server's auth backend, e.g. for the webhook backend
+    #[clap(long)]
+    auth_token: Option<String>,
+
+    /// Load the key file even if other users can read it
+    #[clap(long)]
+    insecure_key_perms: bool,
+
+    /// Comma separated pubkeys to reply to, ignoring notes from anyone else [default: anyone]
+    #[clap(long)]
+    allowed_senders: Option<String>,
+
+    /// Max replies to each sender per minute, 0 for no limit [default: 10]
+    #[clap(long)]
+    replies_per_minute: Option<u32>,
+
+    #[command(flatten)]
+    common: CommonArgs,
+}
+
+#[derive(Parser)]
+struct ReplayArgs {
+    /// Session recording to replay
+    file: PathBuf,
+}
+
+impl Cli {
+    /// Run the parsed subcommand
+    pub async fn run(self) -> Result<()> {
+        match self.command {
+            Subcommands::Serve(args) => {
+                let mut resolver = Resolver::new(
+                    "server",
+                    self.config.as_deref(),
+                    self.secrets_key.clone(),
+                    args.insecure_key_perms,
+                )?;
+                let mut config = server::Config::resolve((*args).clone(), &mut resolver)?;
+                resolver.check_unknown_keys()?;
+                if self.print_config {
+                    resolver.print();
+                    return Ok(());
+                }
+                // Reloads read the config file and env again, over the same cli args. A typo fails
+                // the reload like it fails startup, keeping the old limits.
+                config.reload = Some(Arc::new(move || {
+                    let mut resolver = Resolver::new(
+                        "server",
+                        self.config.as_deref(),
+                        self.secrets_key.clone(),
+                        args.insecure_key_perms,
+                    )?;
+                    let config = server::Config::resolve((*args).clone(), &mut resolver)?;
+                    resolver.check_unknown_keys()?;
+                    Ok(config)
+                }));
+                server::run(config).await?
+            }
+            Subcommands::Connect(args) => {
+                let mut resolver = Resolver::new(
+                    "client",
+                    self.config.as_deref(),
+                    self.secrets_key,
+                    args.insecure_key_perms,
+                )?;
+                let config = client::Config::resolve(*args, &mut resolver)?;
+                resolver.check_unknown_keys()?;
+                if self.print_config {
+                    resolver.print();
+                    return Ok(());
+                }
+                exit_with(client::run(config).await?)
+            }
+            Subcommands::Daemon(args) => {
+                let mut resolver = Resolver::new(
+                    "client",
+                    self.config.as_deref(),
+                    self.secrets_key,
+                    args.insecure_key_perms,
+                )?;
+                let config = client::DaemonConfig::resolve(*args, &mut resolver)?;
+                if self.print_config {
+                    resolver.print();
+                    return Ok(());
+                }
+                exit_with(client::daemon(config).await?)
+            }
+            Subcommands::Bench(args) => {
+                bench::run(args.iterations, args.profile, args.users).await?
+            }
+            Subcommands::Replay(args) => client::replay(&args.file)?,
+            Subcommands::Invite(args) => {
+                // Shares the server's section so the db path only needs setting once
+                let mut resolver =
+                    Resolver::new("server", self.config.as_deref(), self.secrets_key, false)?;
+                let db = PathBuf::from(resolver.resolve_required("db", args.db)?);
+                if self.print_config {
+                    resolver.print();
+                    return Ok(());
+                }
+                let codes =
+                    server::create_invites(&db, args.count, args.expires_in, args.reusable).await?;
+                for code in codes {
+                    println!("{code}");
+                }
+            }
+            Subcommands::Maintenance(args) => {
+                let mut resolver =
+                    Resolver::new("server", self.config.as_deref(), self.secrets_key, false)?;
+                let db = PathBuf::from(resolver.resolve_required("db", args.db)?);
+                if self.print_config {
+                    resolver.print();
+                    return Ok(());
+                }
+                // Clap makes sure there's a start time unless cancelling
+                match args.starts_in {
+                    Some(starts_in) => {
+                        server::maintenance::schedule(
+                            &db,
+                            Duration::from_secs(starts_in),
+                            Duration::from_secs(args.duration),
+                            args.message,
+                        )
+                        .await?
+                    }
+                    None => server::maintenance::cancel(&db).await?,
+                }
+            }
+            Subcommands::Admin(args) => {
+                let mut resolver =
+                    Resolver::new("server", self.config.as_deref(), self.secrets_key, false)?;
+                let socket =
+                    PathBuf::from(resolver.resolve_required("admin-socket", args.admin_socket)?);
+                if self.print_config {
+                    resolver.print();
+                    return Ok(());
+                }
+                let reply = server::admin::send(&socket, &args.command.join(" ")).await?;
+                println!("{reply}");
+            }
+            Subcommands::Revoke(args) => {
+                // Shares the client's section so the address only needs setting once. The key
+                // file is never taken from config, so the wrong key can't be revoked by accident.
+                let mut resolver = Resolver::new(
+                    "client",
+                    self.config.as_deref(),
+                    self.secrets_key,
+                    args.insecure_key_perms,
+                )?;
+                let address =
+                    resolver.resolve("address", args.common.address, DEFAULT_ADDRESS.into())?;
+                let auth_token = resolver.resolve_optional("auth-token", args.auth_token)?;
+                let insecure_key_perms = resolver.insecure_key_perms();
+                if self.print_config {
+                    resolver.print();
+                    return Ok(());
+                }
+                let shutdown = client::revoke(
+                    &address,
+                    &args.key_file,
+                    auth_token,
+                    args.reason,
+                    insecure_key_perms,
+                )
+                .await?;
+                exit_with(shutdown)
+            }
+            Subcommands::Presence(args) => {
+                // Shares the client's section, so it watches the same identity by default
+                let mut resolver = Resolver::new(
+                    "client",
+                    self.config.as_deref(),
+                    self.secrets_key,
+                    args.insecure_key_perms,
+                )?;
+                let address =
+                    resolver.resolve("address", args.common.address, DEFAULT_ADDRESS.into())?;
+                let key_file =
+                    resolver.resolve("key-file", args.key_file, client::DEFAULT_KEY_FILE.into())?;
+                if key_file.contains(',') {
+                    bail!("presence watches a single key file, got {key_file}");
+                }
+                let auth_token = resolver.resolve_optional("auth-token", args.auth_token)?;
+                let insecure_key_perms = resolver.insecure_key_perms();
+                if self.print_config {
+                    resolver.print();
+                    return Ok(());
+                }
+                let shutdown = client::presence(
+                    &address,
+                    Path::new(&key_file),
+                    auth_token,
+                    insecure_key_perms,
+                    args.output,
+                )
+                .await?;
+                client::print_failure(args.output, &shutdown)?;
+                exit_with(shutdown)
+            }
+            Subcommands::Listen(args) => {
+                // Shares the client's section, so it receives as the same identity by default
+                let mut resolver = Resolver::new(
+                    "client",
+                    self.config.as_deref(),
+                    self.secrets_key,
+                    args.insecure_key_perms,
+                )?;
+                let address =
+                    resolver.resolve("address", args.common.address, DEFAULT_ADDRESS.into())?;
+                let key_file =
+                    resolver.resolve("key-file", args.key_file, client::DEFAULT_KEY_FILE.into())?;
+                if key_file.contains(',') {
+                    bail!("listen receives as a single key file, got {key_file}");
+                }
+                let auth_token = resolver.resolve_optional("auth-token", args.auth_token)?;
+                let insecure_key_perms = resolver.insecure_key_perms();
+                if self.print_config {
+                    resolver.print();
+                    return Ok(());
+                }
+                let shutdown = client::listen(
+                    &address,
+                    Path::new(&key_file),
+                    auth_token,
+                    insecure_key_perms,
+                    args.output,
+                )
+                .await?;
+                client::print_failure(args.output, &shutdown)?;
+                exit_with(shutdown)
+            }
+            Subcommands::Send(args) => {
+                // Shares the client's section, so alerts go to the usual recipient by default
+                let mut resolver = Resolver::new(
+                    "client",
+                    self.config.as_deref(),
+                    self.secrets_key,
+                    args.insecure_key_perms,
+                )?;
+                let address = resolver.resolve("address", args.address, DEFAULT_ADDRESS.into())?;
+                let key_file =
+                    resolver.resolve("key-file", args.key_file, client::DEFAULT_KEY_FILE.into())?;
+                if key_file.contains(',') {
+                    bail!("send sends as a single key file, got {key_file}");
+                }
+                let recipient = resolver.resolve_required("recipient", args.recipient)?;
+                let auth_token = resolver.resolve_optional("auth-token", args.auth_token)?;
+                let sealed_sender =
+                    resolver.resolve("sealed-sender", args.sealed_sender.then_some(true), false)?;
+                let insecure_key_perms = resolver.insecure_key_perms();
+                if self.print_config {
+                    resolver.print();
+                    return Ok(());
+                }
+                let content = client::note_content(args.message, args.file.as_deref())?;
+                let shutdown = client::send(
+                    &address,
+                    Path::new(&key_file),
+                    auth_token,
+                    insecure_key_perms,
+                    client::Outgoing {
+                        recipient,
+                        content,
+                        sealed_sender,
+                    },
+                    args.output,
+                )
+                .await?;
+                client::print_failure(args.output, &shutdown)?;
+                exit_with(shutdown)
+            }
+            Subcommands::Bot(args) => match args.bot {
+                Bots::Echo(args) => {
+                    // Shares the client's section, so it runs as the same identity by default
+                    let mut resolver = Resolver::new(
+                        "client",
+                        self.config.as_deref(),
+                        self.secrets_key,
+                        args.insecure_key_perms,
+                    )?;
+                    let address =
+                        resolver.resolve("address", args.common.address, DEFAULT_ADDRESS.into())?;
+                    let key_file = resolver.resolve(
+                        "key-file",
+                        args.key_file,
+                        client::DEFAULT_KEY_FILE.into(),
+                    )?;
+                    if key_file.contains(',') {
+                        bail!("bot runs as a single key file, got {key_file}");
+                    }
+                    let auth_token = resolver.resolve_optional("auth-token", args.auth_token)?;
+                    let insecure_key_perms = resolver.insecure_key_perms();
+                    let allowed_senders = resolver
+                        .resolve_optional::<String>("allowed-senders", args.allowed_senders)?;
+                    let replies_per_minute = resolver.resolve(
+                        "replies-per-minute",
+                        args.replies_per_minute,
+                        client::DEFAULT_BOT_REPLIES_PER_MINUTE,
+                    )?;
+                    if self.print_config {
+                        resolver.print();
+                        return Ok(());
+                    }
+                    let shutdown = client::echo_bot(
+                        &address,
+                        Path::new(&key_file),
+                        auth_token,
+                        insecure_key_perms,
+                        allowed_senders.as_deref(),
+                        replies_per_minute,
+                    )
+                    .await?;
+                    exit_with(shutdown)
+                }
+            },
+        }
+        Ok(())
+    }
+}
+
+/// Exit with the client's exit code, saying why unless it was a normal quit
+fn exit_with(shutdown: client::Shutdown) {
+    if shutdown.exit_code() != client::EXIT_OK {
+        eprintln!("{shutdown}");
+        std::process::exit(shutdown.exit_code());
+    }
+}
@@ -0,0 +1,91 @@
+use anyhow::Result;
+use std::{
+    collections::HashSet,
+    fmt,
+    path::{Path, PathBuf},
+};
+use tracing::info;
+
+use super::auth::read_entries;
+
+/// Pubkeys allowed to or banned from authenticating, read from files so they can be reloaded
+/// while the server runs. Bans in storage apply too.
+pub struct AccessLists {
+    allow_file: Option<PathBuf>,
+    deny_file: Option<PathBuf>,
+    /// Only these pubkeys may authenticate, if set
+    allow: Option<HashSet<String>>,
+    deny: HashSet<String>,
+}
+
+/// Why a pubkey isn't allowed to authenticate
+pub enum Denial {
+    NotAllowed,
+    Denied,
+}
+
+impl fmt::Display for Denial {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Denial::NotAllowed => write!(f, "pubkey is not on the allowlist"),
+            Denial::Denied => write!(f, "pubkey is on the denylist"),
+        }
+    }
+}
+
+impl AccessLists {
+    pub fn load(allow_file: Option<&Path>, deny_file: Option<&Path>) -> Result<Self> {
+        let mut lists = Self {
+            allow_file: allow_file.map(Path::to_path_buf),
+            deny_file: deny_file.map(Path::to_path_buf),
+            allow: None,
+            deny: HashSet::new(),
+        };
+        lists.reload()?;
+        Ok(lists)
+    }
+
+    /// Only let these pubkeys authenticate, without files to reload
+    pub fn only(allow: HashSet<String>) -> Self {
+        Self {
+            allow_file: None,
+            deny_file: None,
+            allow: Some(allow),
+            deny: HashSet::new(),
+        }
+    }
+
+    /// Read the files again, keeping the current lists if either can't be read. An allowlist that
+    /// wasn't read from a file is kept as is.
+    pub fn reload(&mut self) -> Result<()> {
+        let allow = match self.allow_file.as_deref() {
+            Some(path) => Some(read_entries(path)?),
+            None => self.allow.clone(),
+        };
+        let deny = self
+            .deny_file
+            .as_deref()
+            .map(read_entries)
+            .transpose()?
+            .unwrap_or_default();
+        info!(
+            "🚧 Loaded access lists, {} allowed pubkeys and {} denied",
+            allow.as_ref().map_or("any".into(), |a| a.len().to_string()),
+            deny.len()
+        );
+        self.allow = allow;
+        self.deny = deny;
+        Ok(())
+    }
+
+    /// Check whether a pubkey may authenticate
+    pub fn check(&self, pub_key: &str) -> Result<(), Denial> {
+        if self.deny.contains(pub_key) {
+            return Err(Denial::Denied);
+        }
+        match &self.allow {
+            Some(allow) if !allow.contains(pub_key) => Err(Denial::NotAllowed),
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,178 @@
+use anyhow::{anyhow, bail, Context, Result};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tokio::sync::{mpsc, oneshot};
+
+/// Commands operators can send over the admin socket, one per connection
+#[derive(Debug)]
+pub enum Command {
+    /// List the pubkeys of authenticated users
+    Users,
+    /// Show stats for each open connection
+    Stats,
+    /// Disconnect a user, telling them why
+    Kick {
+        pub_key: String,
+        reason: Option<String>,
+    },
+    /// Ban a user, disconnecting them if they're connected
+    Ban {
+        pub_key: String,
+        reason: Option<String>,
+    },
+    /// Reload the access lists, rates, limits and timeouts
+    Reload,
+    /// Show a message to every connected user
+    Announce { message: String },
+    /// Compact storage now
+    Compact,
+}
+
+impl FromStr for Command {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut words = s.split_whitespace();
+        let command = words.next().ok_or(anyhow!("Empty command"))?;
+        let arg = words.next().map(String::from);
+        // Everything after the pubkey is the reason
+        let rest = words.collect::<Vec<_>>().join(" ");
+        let reason = (!rest.is_empty()).then_some(rest);
+        match (command, arg) {
+            ("users", None) => Ok(Command::Users),
+            ("stats", None) => Ok(Command::Stats),
+            ("kick", Some(pub_key)) => Ok(Command::Kick { pub_key, reason }),
+            ("ban", Some(pub_key)) => Ok(Command::Ban { pub_key, reason }),
+            ("reload", None) => Ok(Command::Reload),
+            ("compact", None) => Ok(Command::Compact),
+            ("announce", Some(first)) => Ok(Command::Announce {
+                message: match reason {
+                    Some(rest) => format!("{first} {rest}"),
+                    None => first,
+                },
+            }),
+            ("announce", None) => bail!("No message to announce"),
+            ("users" | "stats" | "reload" | "compact", Some(_)) => bail!("Too many arguments: {s}"),
+            _ => bail!("Unknown command: {s}"),
+        }
+    }
+}
+
+/// A command from the admin socket, and where to send the reply
+pub struct Request {
+    pub command: Command,
+    pub reply_tx: oneshot::Sender<String>,
+}
+
+/// The listening admin socket, removed when dropped
+pub struct AdminSocket {
+    path: PathBuf,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl AdminSocket {
+    /// Listen on a unix socket only the server's user can connect to, sending commands to the
+    /// server through the channel
+    #[cfg(unix)]
+    pub async fn bind(path: &Path, requests_tx: mpsc::Sender<Request>) -> Result<Self> {
+        use std::os::unix::fs::PermissionsExt;
+        use tokio::net::{UnixListener, UnixStream};
+
+        use super::tasks;
+
+        // A socket file left behind by a server that didn't shut down cleanly is safe to replace,
+        // one still being listened on isn't
+        if path.exists() {
+            if UnixStream::connect(path).await.is_ok() {
+                bail!("Admin socket {} is already in use", path.display());
+            }
+            std::fs::remove_file(path).context("Error removing stale admin socket")?;
+        }
+        let listener = UnixListener::bind(path)
+            .context(format!("Error binding admin socket {}", path.display()))?;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+        tracing::info!("🛠️ Admin socket listening on {}", path.display());
+
+        let task = tasks::spawn("admin socket", async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => {
+                        tasks::spawn("admin command", serve(stream, requests_tx.clone()));
+                    }
+                    Err(e) => tracing::error!("🛠️ Error accepting admin connection: {e}"),
+                }
+            }
+        });
+        Ok(Self {
+            path: path.to_path_buf(),
+            task,
+        })
+    }
+
+    #[cfg(not(unix))]
+    pub async fn bind(_path: &Path, _requests_tx: mpsc::Sender<Request>) -> Result<Self> {
+        bail!("--admin-socket is only supported on unix")
+    }
+}
+
+impl Drop for AdminSocket {
+    fn drop(&mut self) {
+        self.task.abort();
+        _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Read a command from an admin connection, and write back the server's reply
+#[cfg(unix)]
+async fn serve(stream: tokio::net::UnixStream, requests_tx: mpsc::Sender<Request>) {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    let (read, mut write) = stream.into_split();
+    let mut line = String::new();
+    let reply = match BufReader::new(read).read_line(&mut line).await {
+        Ok(_) => match line.parse::<Command>() {
+            Ok(command) => {
+                tracing::info!("🛠️ Received admin command: {command:?}");
+                let (reply_tx, reply_rx) = oneshot::channel();
+                let request = Request { command, reply_tx };
+                match requests_tx.send(request).await {
+                    Ok(()) => reply_rx
+                        .await
+                        .unwrap_or("Error: server is shutting down".into()),
+                    Err(_) => "Error: server is shutting down".into(),
+                }
+            }
+            Err(e) => format!("Error: {e}"),
+        },
+        Err(e) => format!("Error: {e}"),
+    };
+    if let Err(e) = write.write_all(format!("{reply}\n").as_bytes()).await {
+        tracing::error!("🛠️ Error replying to admin command: {e}");
+    }
+}
+
+/// Send a command to a running server's admin socket, returning its reply
+#[cfg(unix)]
+pub async fn send(path: &Path, command: &str) -> Result<String> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::UnixStream;
+
+    // Check it locally first for a better error than the server's
+    command.parse::<Command>()?;
+    let mut stream = UnixStream::connect(path)
+        .await
+        .context(format!("Cannot connect to admin socket {}", path.display()))?;
+    stream.write_all(format!("{command}\n").as_bytes()).await?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply).await?;
+    let reply = reply.trim_end();
+    if let Some(e) = reply.strip_prefix("Error: ") {
+        bail!("{e}");
+    }
+    Ok(reply.to_string())
+}
+
+#[cfg(not(unix))]
+pub async fn send(_path: &Path, _command: &str) -> Result<String> {
+    bail!("The admin socket is only supported on unix")
+}
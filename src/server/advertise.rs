@@ -0,0 +1,76 @@
+use std::net::SocketAddr;
+
+use anyhow::{Context, Result};
+use mdns_sd::{ServiceDaemon, ServiceInfo};
+use tracing::{info, warn};
+
+use super::UpgradeRules;
+use crate::common::MDNS_SERVICE_TYPE;
+
+/// Advertises the server on the local network over mDNS until stopped
+pub struct Advertiser {
+    mdns: ServiceDaemon,
+    fullname: String,
+}
+
+impl Advertiser {
+    /// Start answering mDNS queries for the server under a name, on the port of the first address
+    /// it listens on. Clients are told the path to connect to if there's one they have to use.
+    pub fn start(name: &str, listen: &[String], upgrade_rules: &UpgradeRules) -> Result<Self> {
+        let port = listen
+            .iter()
+            .find_map(|address| address.parse::<SocketAddr>().ok())
+            .context("No listen address to advertise the port of")?
+            .port();
+        let properties: Vec<(&str, &str)> = upgrade_rules
+            .path
+            .as_deref()
+            .map(|path| vec![("path", path)])
+            .unwrap_or_default();
+        // Addresses are filled in from the network interfaces, and kept up to date as they change
+        let service = ServiceInfo::new(
+            MDNS_SERVICE_TYPE,
+            name,
+            &format!("{}.local.", host_label(name)),
+            (),
+            port,
+            &properties[..],
+        )
+        .context("Error describing the server to advertise")?
+        .enable_addr_auto();
+        let fullname = service.get_fullname().to_string();
+        let mdns = ServiceDaemon::new().context("Error starting mDNS")?;
+        mdns.register(service)
+            .context("Error advertising the server over mDNS")?;
+        info!("📣 Advertising server on the local network as {name} on port {port}");
+        Ok(Self { mdns, fullname })
+    }
+
+    /// Withdraw the advertisement, so clients stop finding the server
+    pub fn stop(self) {
+        if let Err(e) = self.mdns.unregister(&self.fullname) {
+            warn!("📣 Error withdrawing mDNS advertisement: {e}");
+        }
+        _ = self.mdns.shutdown();
+    }
+}
+
+/// Name to answer address queries for, the advertised name cut down to what hostnames allow
+fn host_label(name: &str) -> String {
+    let label: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    let label = label.trim_matches('-');
+    if label.is_empty() {
+        "age-chat".to_string()
+    } else {
+        label.to_string()
+    }
+}
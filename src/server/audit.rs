@@ -0,0 +1,183 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Mutex;
+use tracing::error;
+
+use crate::common::ErrorKind;
+
+/// Bytes of the salted hash kept to identify a user in privacy mode
+const USER_HASH_BYTES: usize = 16;
+
+/// Append-only log of the server's metadata, one json object per line, so operators can account
+/// for how it's used. It never holds note contents.
+///
+/// In privacy mode pubkeys are replaced by a hash salted per deployment and peer addresses are
+/// left out, so entries for the same user can be tied together but not back to who they are.
+pub struct AuditLog {
+    file: Mutex<std::fs::File>,
+    /// Set in privacy mode
+    salt: Option<String>,
+}
+
+/// Something worth accounting for
+pub enum Event<'a> {
+    AuthGranted {
+        pub_key: &'a str,
+        peer_addr: SocketAddr,
+        resumed: bool,
+    },
+    AuthFailed {
+        pub_key: &'a str,
+        peer_addr: SocketAddr,
+    },
+    /// A user's connection closed, with how many notes were relayed over it
+    Disconnected {
+        pub_key: &'a str,
+        peer_addr: SocketAddr,
+        notes_sent: u64,
+        notes_delivered: u64,
+    },
+    /// An error reported back to a client
+    Error {
+        pub_key: Option<&'a str>,
+        peer_addr: SocketAddr,
+        kind: ErrorKind,
+    },
+    Kicked {
+        pub_key: &'a str,
+        banned: bool,
+    },
+}
+
+/// A line of the log
+#[derive(Serialize)]
+struct Record {
+    time: DateTime<Utc>,
+    event: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    peer: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    resumed: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    notes_sent: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    notes_delivered: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<ErrorKind>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    banned: Option<bool>,
+}
+
+impl AuditLog {
+    /// Open the log for appending, creating it readable only by us. A salt turns on privacy
+    /// mode.
+    pub fn open(path: &Path, salt: Option<String>) -> Result<Self> {
+        let mut options = OpenOptions::new();
+        options.append(true).create(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let file = options
+            .open(path)
+            .context(format!("Error opening audit log {}", path.display()))?;
+        Ok(Self {
+            file: Mutex::new(file),
+            salt,
+        })
+    }
+
+    /// Append an event. Failing to is logged rather than disrupting the server.
+    pub fn record(&self, event: Event<'_>) {
+        let record = self.to_record(event);
+        let res = serde_json::to_string(&record)
+            .map_err(anyhow::Error::from)
+            .and_then(|line| {
+                let mut file = self.file.lock().expect("Audit log lock poisoned");
+                writeln!(file, "{line}")?;
+                Ok(())
+            });
+        if let Err(e) = res {
+            error!("📒 Error writing to audit log: {e}");
+        }
+    }
+
+    fn to_record(&self, event: Event<'_>) -> Record {
+        let mut record = Record {
+            time: Utc::now(),
+            event: "",
+            user: None,
+            peer: None,
+            resumed: None,
+            notes_sent: None,
+            notes_delivered: None,
+            error: None,
+            banned: None,
+        };
+        let (pub_key, peer_addr) = match event {
+            Event::AuthGranted {
+                pub_key,
+                peer_addr,
+                resumed,
+            } => {
+                record.event = "auth_granted";
+                record.resumed = Some(resumed);
+                (Some(pub_key), Some(peer_addr))
+            }
+            Event::AuthFailed { pub_key, peer_addr } => {
+                record.event = "auth_failed";
+                (Some(pub_key), Some(peer_addr))
+            }
+            Event::Disconnected {
+                pub_key,
+                peer_addr,
+                notes_sent,
+                notes_delivered,
+            } => {
+                record.event = "disconnected";
+                record.notes_sent = Some(notes_sent);
+                record.notes_delivered = Some(notes_delivered);
+                (Some(pub_key), Some(peer_addr))
+            }
+            Event::Error {
+                pub_key,
+                peer_addr,
+                kind,
+            } => {
+                record.event = "error";
+                record.error = Some(kind);
+                (pub_key, Some(peer_addr))
+            }
+            Event::Kicked { pub_key, banned } => {
+                record.event = "kicked";
+                record.banned = Some(banned);
+                (Some(pub_key), None)
+            }
+        };
+        record.user = pub_key.map(|pub_key| self.user(pub_key));
+        if self.salt.is_none() {
+            record.peer = peer_addr.map(|addr| addr.to_string());
+        }
+        record
+    }
+
+    /// How a user appears in the log, their pubkey or its salted hash in privacy mode
+    fn user(&self, pub_key: &str) -> String {
+        let Some(salt) = &self.salt else {
+            return pub_key.to_string();
+        };
+        let mut hasher = Sha256::new();
+        hasher.update(salt.as_bytes());
+        hasher.update(pub_key.as_bytes());
+        hex::encode(&hasher.finalize()[..USER_HASH_BYTES])
+    }
+}
@@ -0,0 +1,214 @@
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use chrono::{TimeDelta, Utc};
+use clap::ValueEnum;
+use rand::RngCore;
+use serde::Serialize;
+use std::{collections::HashSet, fmt, path::Path, str::FromStr, sync::Arc, time::Duration};
+use tracing::info;
+
+use super::storage::{self, Invite, Storage};
+
+/// Which auth backend the server uses
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum AuthBackendKind {
+    /// Admit anyone who can decrypt the key challenge
+    Challenge,
+    /// Only admit pubkeys listed in the auth file
+    Allowlist,
+    /// Admit pubkeys seen before, new pubkeys must present an invite code
+    Invite,
+    /// Ask an external service, e.g. to check an SSO token maps to the pubkey
+    Webhook,
+}
+
+/// Decides whether a user that has passed the key challenge may use the server. The key challenge
+/// is always done so notes stay E2E encrypted, backends can only further restrict access.
+#[async_trait]
+pub trait AuthBackend: Send + Sync {
+    /// Authorize a pubkey, using the token the client presented if any. This only checks, so a
+    /// login that fails afterwards doesn't use anything up.
+    async fn authorize(&self, pub_key: &str, token: Option<&str>) -> Result<bool>;
+
+    /// Admit an authorized pubkey once it's granted, using up anything single use. Returns false
+    /// if that was used up since it was authorized.
+    async fn admit(&self, _pub_key: &str, _token: Option<&str>) -> Result<bool> {
+        Ok(true)
+    }
+}
+
+/// Admit anyone who passes the key challenge
+pub struct ChallengeOnly;
+
+/// Only admit pubkeys on a fixed list
+pub struct Allowlist {
+    pub_keys: HashSet<String>,
+}
+
+/// Admit pubkeys that have joined before, or that present a valid invite code
+pub struct InviteTokens {
+    storage: Arc<dyn Storage>,
+}
+
+/// Ask an external service whether the pubkey and token should be admitted. Any 2xx response
+/// admits the user.
+pub struct Webhook {
+    url: String,
+    client: reqwest::Client,
+}
+
+/// Body POSTed to the webhook
+#[derive(Serialize)]
+struct WebhookReq<'a> {
+    pub_key: &'a str,
+    token: Option<&'a str>,
+}
+
+/// Build the auth backend selected in the config
+pub async fn build(
+    kind: AuthBackendKind,
+    auth_file: Option<&Path>,
+    webhook_url: Option<&str>,
+    storage: Arc<dyn Storage>,
+) -> Result<Arc<dyn AuthBackend>> {
+    info!("🛂 Using {kind} auth backend");
+    Ok(match kind {
+        AuthBackendKind::Challenge => Arc::new(ChallengeOnly),
+        AuthBackendKind::Allowlist => Arc::new(Allowlist {
+            pub_keys: read_entries(auth_file.context("Allowlist auth requires an auth file")?)?,
+        }),
+        AuthBackendKind::Invite => {
+            // Codes already used are remembered in storage, so they stay used across restarts
+            if let Some(auth_file) = auth_file {
+                for code in read_entries(auth_file)? {
+                    let invite = Invite {
+                        code,
+                        expires_at: None,
+                        reusable: false,
+                    };
+                    storage.add_invite(&invite).await?;
+                }
+            }
+            Arc::new(InviteTokens { storage })
+        }
+        AuthBackendKind::Webhook => Arc::new(Webhook {
+            url: webhook_url
+                .context("Webhook auth requires a webhook url")?
+                .to_string(),
+            client: reqwest::Client::new(),
+        }),
+    })
+}
+
+/// Generate invite codes into the server's database, returning them
+pub async fn create_invites(
+    db: &Path,
+    count: u32,
+    expires_in: Option<u64>,
+    reusable: bool,
+) -> Result<Vec<String>> {
+    if reusable && expires_in.is_none() {
+        bail!("Reusable invites must expire");
+    }
+    let storage = storage::build(Some(db))?;
+    let expires_at = match expires_in {
+        Some(secs) => Some(Utc::now() + TimeDelta::from_std(Duration::from_secs(secs))?),
+        None => None,
+    };
+
+    let mut codes = vec![];
+    for _ in 0..count {
+        let mut code_bytes = [0u8; 8];
+        rand::rng().fill_bytes(&mut code_bytes);
+        let invite = Invite {
+            code: hex::encode(code_bytes),
+            expires_at,
+            reusable,
+        };
+        storage.add_invite(&invite).await?;
+        codes.push(invite.code);
+    }
+    Ok(codes)
+}
+
+/// Read a file with one entry per line, ignoring blank lines and comments
+pub fn read_entries(path: &Path) -> Result<HashSet<String>> {
+    let contents =
+        std::fs::read_to_string(path).context(format!("Error reading {}", path.display()))?;
+    Ok(contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect())
+}
+
+#[async_trait]
+impl AuthBackend for ChallengeOnly {
+    async fn authorize(&self, _pub_key: &str, _token: Option<&str>) -> Result<bool> {
+        Ok(true)
+    }
+}
+
+#[async_trait]
+impl AuthBackend for Allowlist {
+    async fn authorize(&self, pub_key: &str, _token: Option<&str>) -> Result<bool> {
+        Ok(self.pub_keys.contains(pub_key))
+    }
+}
+
+#[async_trait]
+impl AuthBackend for InviteTokens {
+    async fn authorize(&self, pub_key: &str, token: Option<&str>) -> Result<bool> {
+        if self.storage.is_user(pub_key).await? {
+            return Ok(true);
+        }
+        let Some(token) = token else {
+            return Ok(false);
+        };
+        self.storage.is_valid_invite(token).await
+    }
+
+    async fn admit(&self, pub_key: &str, token: Option<&str>) -> Result<bool> {
+        if self.storage.is_user(pub_key).await? {
+            return Ok(true);
+        }
+        let Some(token) = token else {
+            return Ok(false);
+        };
+        let redeemed = self.storage.redeem_invite(token, pub_key).await?;
+        if redeemed {
+            info!("🛂 New user {pub_key} joined with an invite");
+        }
+        Ok(redeemed)
+    }
+}
+
+#[async_trait]
+impl AuthBackend for Webhook {
+    async fn authorize(&self, pub_key: &str, token: Option<&str>) -> Result<bool> {
+        let res = self
+            .client
+            .post(&self.url)
+            .json(&WebhookReq { pub_key, token })
+            .send()
+            .await
+            .context("Error calling auth webhook")?;
+        Ok(res.status().is_success())
+    }
+}
+
+impl FromStr for AuthBackendKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        <Self as ValueEnum>::from_str(s, true)
+    }
+}
+
+impl fmt::Display for AuthBackendKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = self.to_possible_value().ok_or(fmt::Error)?;
+        write!(f, "{}", name.get_name())
+    }
+}
@@ -0,0 +1,148 @@
+use anyhow::{Context, Result};
+use futures_util::StreamExt;
+use rand::RngCore;
+use redis::aio::{MultiplexedConnection, PubSubSink, PubSubStream};
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use tracing::{error, warn};
+
+use crate::common::ServerMsg;
+
+/// Prefix of the channel relaying to each user, followed by their pubkey
+const USER_CHANNEL_PREFIX: &str = "age-chat:user:";
+/// Channel relaying to every user on every instance, e.g. revocations
+const BROADCAST_CHANNEL: &str = "age-chat:broadcast";
+
+/// Relays messages through Redis pub/sub to users connected to other server instances, so
+/// several can run behind a load balancer. Each instance subscribes to the channels of the users
+/// connected to it.
+///
+/// Mailboxes cross instances through the database they share, and a login is a duplicate if any
+/// instance is subscribed to the user. Presence stays local to each instance.
+pub struct Cluster {
+    /// Tells our own broadcasts apart from other instances'
+    instance: String,
+    publisher: MultiplexedConnection,
+    subscriber: PubSubSink,
+}
+
+/// Who a message from another instance is for
+pub enum Target {
+    User(String),
+    Everyone,
+}
+
+/// Messages published by other instances for users connected to this one
+pub struct Inbox {
+    instance: String,
+    stream: PubSubStream,
+}
+
+/// A message as published, tagged with the instance that sent it
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    instance: String,
+    msg: ServerMsg,
+}
+
+impl Cluster {
+    /// Connect to Redis, returning the cluster to publish through and the inbox of messages
+    /// for users connected here
+    pub async fn connect(url: &str) -> Result<(Self, Inbox)> {
+        let client = redis::Client::open(url).context(format!("Invalid Redis url {url}"))?;
+        let publisher = client
+            .get_multiplexed_async_connection()
+            .await
+            .context(format!("Error connecting to Redis at {url}"))?;
+        let (mut subscriber, stream) = client
+            .get_async_pubsub()
+            .await
+            .context(format!("Error connecting to Redis at {url}"))?
+            .split();
+        subscriber.subscribe(BROADCAST_CHANNEL).await?;
+
+        let mut instance_bytes = [0u8; 8];
+        rand::rng().fill_bytes(&mut instance_bytes);
+        let instance = hex::encode(instance_bytes);
+        let inbox = Inbox {
+            instance: instance.clone(),
+            stream,
+        };
+        let cluster = Self {
+            instance,
+            publisher,
+            subscriber,
+        };
+        Ok((cluster, inbox))
+    }
+
+    /// Start receiving messages for a user who connected here
+    pub async fn subscribe(&self, pub_key: &str) -> Result<()> {
+        let mut subscriber = self.subscriber.clone();
+        subscriber.subscribe(user_channel(pub_key)).await?;
+        Ok(())
+    }
+
+    /// Stop receiving messages for a user who disconnected from here
+    pub async fn unsubscribe(&self, pub_key: &str) -> Result<()> {
+        let mut subscriber = self.subscriber.clone();
+        subscriber.unsubscribe(user_channel(pub_key)).await?;
+        Ok(())
+    }
+
+    /// Relay a message to a user connected to another instance, true if any instance has them
+    pub async fn publish(&self, pub_key: &str, msg: &ServerMsg) -> Result<bool> {
+        let receivers = self.publish_to(&user_channel(pub_key), msg).await?;
+        Ok(receivers > 0)
+    }
+
+    /// Relay a message to every user on every other instance
+    pub async fn broadcast(&self, msg: &ServerMsg) -> Result<()> {
+        self.publish_to(BROADCAST_CHANNEL, msg).await?;
+        Ok(())
+    }
+
+    async fn publish_to(&self, channel: &str, msg: &ServerMsg) -> Result<usize> {
+        let envelope = Envelope {
+            instance: self.instance.clone(),
+            msg: msg.clone(),
+        };
+        let payload = serde_json::to_string(&envelope)?;
+        let mut publisher = self.publisher.clone();
+        Ok(publisher.publish(channel, payload).await?)
+    }
+}
+
+impl Inbox {
+    /// Wait for the next message from another instance, None once Redis disconnects
+    pub async fn next(&mut self) -> Option<(Target, ServerMsg)> {
+        loop {
+            let redis_msg = self.stream.next().await?;
+            let channel = redis_msg.get_channel_name();
+            let target = match channel.strip_prefix(USER_CHANNEL_PREFIX) {
+                Some(pub_key) => Target::User(pub_key.to_string()),
+                None if channel == BROADCAST_CHANNEL => Target::Everyone,
+                None => {
+                    warn!("🛰️ Received message on unexpected Redis channel {channel}");
+                    continue;
+                }
+            };
+            let envelope: Envelope = match serde_json::from_slice(redis_msg.get_payload_bytes()) {
+                Ok(envelope) => envelope,
+                Err(e) => {
+                    error!("🛰️ Error deserializing message on Redis channel {channel}: {e}");
+                    continue;
+                }
+            };
+            // We already relayed our own broadcasts locally
+            if matches!(target, Target::Everyone) && envelope.instance == self.instance {
+                continue;
+            }
+            return Some((target, envelope.msg));
+        }
+    }
+}
+
+fn user_channel(pub_key: &str) -> String {
+    format!("{USER_CHANNEL_PREFIX}{pub_key}")
+}
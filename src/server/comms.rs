@@ -1,101 +1,919 @@
-use age::x25519::Recipient;
-use anyhow::{anyhow, Context, Result};
-use futures_util::{future::join_all, SinkExt, StreamExt};
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::Utc;
+use futures_util::{SinkExt, StreamExt};
 use rand::RngCore;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::str::FromStr;
-use std::{net::SocketAddr, sync::Arc};
-use tokio::sync::mpsc::{self, Receiver, Sender};
-use tokio::sync::RwLock;
-use tokio::{
-    net::{TcpListener, TcpStream},
-    signal,
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::OnceLock;
+use std::time::Duration;
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
 };
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::sync::{watch, Mutex, Notify, RwLock, RwLockWriteGuard};
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::{self, Instant, Interval, MissedTickBehavior};
 use tokio_tungstenite::{
-    accept_async,
-    tungstenite::{Message, Utf8Bytes},
+    accept_hdr_async,
+    tungstenite::{
+        protocol::{frame::coding::CloseCode, CloseFrame},
+        Message, Utf8Bytes,
+    },
     WebSocketStream,
 };
-use tracing::{error, info};
+use tracing::{error, info, info_span, warn, Instrument};
 
-use crate::common::{Auth, ClientMsg, Note, ServerMsg, CHANNEL_BUFFER_SIZE};
+use super::access::AccessLists;
+use super::admin::{self, AdminSocket};
+use super::audit::{AuditLog, Event};
+use super::auth::AuthBackend;
+use super::cluster::{Cluster, Inbox, Target};
+use super::compaction;
+use super::handoff;
+use super::mailbox::Mailboxes;
+use super::maintenance;
+use super::rate_limit::RateLimiter;
+use super::recent::RecentNotes;
+use super::relay::{OverflowPolicy, Relay};
+use super::signals::{self, Signal};
+use super::storage::Storage;
+use super::tasks;
+use super::throttle::AuthThrottle;
+use super::upgrade::UpgradeRules;
+use super::user_conns::{self, UserConns};
+use super::{Config, Reload};
+use crate::common::{
+    Auth, AuthChallenge, ClientMsg, Deprecation, DuplicateLogin, ErrorKind, FetchHistory, Hello,
+    HelloAck, HistoryPage, Maintenance, Note, Presence, Resume, Revocation, ServerError, ServerMsg,
+    SessionHandshake, SyncSince, Typing, CHANNEL_BUFFER_SIZE, MAILBOX_PROTOCOL_VERSION,
+    PROTOCOL_VERSION, RESUME_PROTOCOL_VERSION,
+};
+use crate::compression::{self, CompressionStats, MIN_COMPRESS_BYTES};
+
+/// How long a client has to finish the websocket handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Longest reason a websocket close frame can hold
+const MAX_CLOSE_REASON_BYTES: usize = 123;
+/// Percent of a client's relay queue that marks it as slow once filled
+const SLOW_HIGH_WATERMARK_PERCENT: usize = 75;
+/// Percent of a client's relay queue it has to drain down to before it's no longer slow
+const SLOW_LOW_WATERMARK_PERCENT: usize = 25;
+/// Most archived notes sent for one history fetch
+const MAX_HISTORY_NOTES: usize = 100;
+
+/// State shared between all connections
+struct Shared {
+    /// Map of usernames to queues for relaying messages to them
+    user_conns: UserConns,
+    /// Relays to users connected to other server instances, if running as a cluster
+    cluster: Option<Cluster>,
+    /// Presence-only connections watching each user
+    watchers: RwLock<HashMap<String, Vec<Arc<Relay>>>>,
+    /// Every open connection, authenticated or not, for the admin socket
+    conns: RwLock<HashMap<SocketAddr, Arc<ConnInfo>>>,
+    /// Id to give the next connection, so its logs can be told apart from others behind the same
+    /// NAT
+    next_conn_id: AtomicU64,
+    /// Connection tasks still running, including ones yet to finish their handshake
+    active_conns: AtomicUsize,
+    auth_backend: Arc<dyn AuthBackend>,
+    /// Persistent users and bans
+    storage: Arc<dyn Storage>,
+    /// Pubkeys allowed to or banned from authenticating
+    access: RwLock<AccessLists>,
+    /// Notes held for users who are offline
+    mailboxes: Mutex<Mailboxes>,
+    /// Rates, limits and timeouts, which SIGHUP reloads
+    limits: std::sync::RwLock<Limits>,
+    /// Resolves the config again to reload the limits, if it can change
+    reload: Option<Reload>,
+    /// Whether to archive relayed notes for clients to fetch as history
+    archive_notes: bool,
+    /// What to do when a client's relay queue is full
+    relay_overflow: OverflowPolicy,
+    /// Limits how fast each user can send notes
+    rate_limiter: Mutex<RateLimiter>,
+    /// Limits how fast each IP can open connections
+    ip_limiter: Mutex<RateLimiter>,
+    /// Open connections from each IP
+    ip_conns: Mutex<HashMap<IpAddr, u32>>,
+    /// Failed auth attempts per IP and pubkey
+    auth_throttle: Mutex<AuthThrottle>,
+    /// What websocket upgrade requests have to match
+    upgrade_rules: UpgradeRules,
+    /// Set to true to shut the server down, closing every connection
+    shutdown: watch::Sender<bool>,
+    /// Downtime the operator has scheduled, announced to clients
+    maintenance: RwLock<Option<Maintenance>>,
+    /// Client message types that will be removed in a future version
+    deprecations: Vec<Deprecation>,
+    /// Compress large messages for clients that offer it
+    compression: bool,
+    /// How long a session can be resumed for after its connection drops, None to not issue
+    /// resumption tokens
+    resume_ttl: Option<Duration>,
+    /// Sessions that can be resumed, by token
+    resumptions: Mutex<HashMap<String, Resumption>>,
+    /// Notes recently delivered to each user, for reconnecting clients to fetch the ones they
+    /// lost in flight
+    recent_notes: Mutex<RecentNotes>,
+    /// Bytes compressed both ways over every connection since the server started
+    compression_stats: CompressionStats,
+    /// Metadata log for operators to account for the server's use
+    audit_log: Option<AuditLog>,
+}
+
+/// What the admin socket can see of and do to a connection
+struct ConnInfo {
+    id: u64,
+    connected_at: Instant,
+    /// Set once the client authenticates
+    pub_key: OnceLock<String>,
+    notes_sent: AtomicU64,
+    notes_delivered: AtomicU64,
+    /// Messages waiting to be delivered to the client
+    relay: Arc<Relay>,
+    /// Notified to disconnect the client
+    kick: Notify,
+    /// Why the client was kicked, to tell it in the close frame
+    kick_reason: OnceLock<String>,
+    /// Bytes compressed both ways over the connection
+    compression_stats: CompressionStats,
+}
+
+/// Rates, limits and timeouts that can be reloaded while running. Connections pick up new
+/// values the next time they use them, except the send rate, relay buffer, idle timeout and ping
+/// interval, which are fixed once a connection opens.
+#[derive(Clone, Copy)]
+struct Limits {
+    /// Max notes per second delivered to each client, 0 for unlimited
+    send_rate: u32,
+    /// Max messages queued for relaying to each client
+    relay_buffer: usize,
+    /// Max bytes of a note's encrypted content
+    max_note_bytes: usize,
+    /// Rate limited notes a client can send before being disconnected, 0 to never disconnect
+    rate_limit_strikes: u32,
+    /// Max open connections from each IP, 0 for unlimited
+    ip_max_conns: u32,
+    /// How long a client can go without sending anything, even a pong, before it's disconnected
+    idle_timeout: Option<Duration>,
+    /// How long a write to a client can take before it's disconnected
+    write_timeout: Option<Duration>,
+    /// How long a client's relay queue can stay over the high watermark before it's disconnected
+    slow_client_timeout: Option<Duration>,
+    /// How long to wait for connections to close on shutdown before dropping them
+    shutdown_timeout: Option<Duration>,
+    /// How often to ping clients to detect dead connections
+    ping_interval: Option<Duration>,
+    /// Pings in a row a client can leave unanswered before it's disconnected
+    max_missed_pongs: u32,
+}
+
+/// What the program running a server asks of it, e.g. on a signal
+#[derive(Debug, Clone, Copy)]
+pub enum Control {
+    /// Reload the access lists and limits
+    Reload,
+    /// Stop accepting connections and return once the existing ones close, to hand off to a new
+    /// server process
+    Drain,
+    /// Tell every connection the server is shutting down, then close them
+    Shutdown,
+}
+
+/// Run the server, controlled by the process's signals
+pub async fn serve(
+    config: &Config,
+    auth_backend: Arc<dyn AuthBackend>,
+    storage: Arc<dyn Storage>,
+    access: AccessLists,
+) -> Result<()> {
+    let listeners = bind(config).await?;
+    let (control_tx, control_rx) = mpsc::channel(CHANNEL_BUFFER_SIZE);
+    let signals = forward_signals(control_tx)?;
+    let res = serve_on(listeners, config, auth_backend, storage, access, control_rx).await;
+    signals.abort();
+    res
+}
+
+/// Turn SIGHUP, SIGUSR2, SIGTERM and ctrl-c into controls for the server
+fn forward_signals(control_tx: mpsc::Sender<Control>) -> Result<JoinHandle<()>> {
+    let mut reload_signal = Signal::reload()?;
+    let mut drain_signal = Signal::drain()?;
+    Ok(tasks::spawn("signals", async move {
+        loop {
+            let control = tokio::select! {
+                _ = reload_signal.recv() => {
+                    info!("🚧 Received SIGHUP");
+                    Control::Reload
+                }
+                _ = drain_signal.recv() => {
+                    info!("🔁 Received SIGUSR2");
+                    Control::Drain
+                }
+                res = signals::shutdown() => match res {
+                    Ok(signal) => {
+                        info!("⛔ Received {signal}");
+                        Control::Shutdown
+                    }
+                    Err(e) => {
+                        error!("⛔ {e}");
+                        return;
+                    }
+                },
+            };
+            if control_tx.send(control).await.is_err() {
+                return;
+            }
+        }
+    }))
+}
 
-type UserConns = Arc<RwLock<HashMap<String, Sender<Note>>>>;
+/// Bind each address the server listens on
+pub async fn bind(config: &Config) -> Result<Vec<(String, TcpListener)>> {
+    let mut listeners = vec![];
+    for addr in &config.listen {
+        listeners.push((addr.clone(), handoff::bind(addr, config.reuse_port).await?));
+    }
+    Ok(listeners)
+}
 
-/// Run the server
-pub async fn serve(addr: &str) -> Result<()> {
-    let listener = TcpListener::bind(&addr).await?;
-    info!("📡 Server listening on {addr}");
+/// Serve connections accepted on listeners already bound, until told to drain or shut down.
+/// Signals are left to the caller, so a server embedded in another program doesn't take over
+/// its ctrl-c.
+pub async fn serve_on(
+    bound: Vec<(String, TcpListener)>,
+    config: &Config,
+    auth_backend: Arc<dyn AuthBackend>,
+    storage: Arc<dyn Storage>,
+    access: AccessLists,
+    mut control_rx: mpsc::Receiver<Control>,
+) -> Result<()> {
+    // One accept loop per listener, all feeding the same connections
+    let (accepted_tx, mut accepted_rx) = mpsc::channel(CHANNEL_BUFFER_SIZE);
+    let mut listeners = JoinSet::new();
+    for (addr, listener) in bound {
+        info!("📡 Server listening on {addr}");
+        let accepted_tx = accepted_tx.clone();
+        tasks::spawn_in(&mut listeners, &format!("listener {addr}"), async move {
+            loop {
+                let accept_res = listener.accept().await;
+                if accepted_tx.send(accept_res).await.is_err() {
+                    return;
+                }
+            }
+        });
+    }
+    drop(accepted_tx);
+    let mut maintenance_poll = time::interval(maintenance::POLL_INTERVAL);
 
-    // Create map of usernames to channels for sending notes
-    let user_conns: UserConns = Arc::new(RwLock::new(HashMap::new()));
+    let (cluster, inbox) = match &config.redis_url {
+        Some(url) => {
+            let (cluster, inbox) = Cluster::connect(url).await?;
+            info!("🛰️ Relaying to other server instances through Redis");
+            (Some(cluster), Some(inbox))
+        }
+        None => (None, None),
+    };
+    let audit_log = match &config.audit_log {
+        Some(path) => {
+            let audit_log = AuditLog::open(path, config.audit_salt.clone())?;
+            info!(
+                "📒 Writing audit log to {}{}",
+                path.display(),
+                if config.audit_salt.is_some() {
+                    " in privacy mode"
+                } else {
+                    ""
+                }
+            );
+            Some(audit_log)
+        }
+        None => None,
+    };
+    let shared = Arc::new(Shared::new(
+        config,
+        auth_backend,
+        storage,
+        access,
+        cluster,
+        audit_log,
+    ));
 
-    let mut task_handles = vec![];
+    let mut shutdown_rx = shared.shutdown.subscribe();
+    // Keep a sender so the channel stays open without an admin socket
+    let (admin_tx, mut admin_rx) = mpsc::channel(CHANNEL_BUFFER_SIZE);
+    let _admin_socket = match &config.admin_socket {
+        Some(path) => Some(AdminSocket::bind(path, admin_tx.clone()).await?),
+        None => None,
+    };
+    // Aborted on return when dropped
+    let mut background = JoinSet::new();
+    if let Some(inbox) = inbox {
+        tasks::spawn_in(
+            &mut background,
+            "cluster relay",
+            relay_from_cluster(inbox, Arc::clone(&shared)),
+        );
+    }
+    let mut connections = JoinSet::new();
     loop {
         tokio::select! {
             // Serve connections
-            accept_res = listener.accept() => {
-                let (stream, _addr) = accept_res.context("Error accepting tcp connection")?;
-                let user_conns = Arc::clone(&user_conns);
-                let handle = tokio::spawn(async move {
-                    let conn = match Connection::new(stream, user_conns).await {
-                        Ok(conn) => conn,
-                        Err(e) => {
-                            error!("Error creating connection: {e}");
-                            return;
+            accept_res_opt = accepted_rx.recv() => {
+                let accept_res = accept_res_opt.ok_or(anyhow!("All listeners closed"))?;
+                let (stream, addr) = accept_res.context("Error accepting tcp connection")?;
+
+                // Refuse hosts opening too many connections before they can cost us anything.
+                // Dropping the stream closes it.
+                let ip = addr.ip();
+                if let Err(reason) = shared.admit_ip(ip).await {
+                    warn!("🚦 Refusing connection from {addr}, {reason}");
+                    continue;
+                }
+
+                // Every log from the connection's task carries its id
+                let id = shared.next_conn_id.fetch_add(1, Ordering::Relaxed);
+                let span = info_span!("conn", id, peer = %addr);
+                let conn_shared = Arc::clone(&shared);
+                tasks::spawn_in(&mut connections, &format!("connection {id}"), async move {
+                    match Connection::new(stream, id, Arc::clone(&conn_shared)).await {
+                        Ok(conn) => {
+                            let res = conn.serve().await;
+                            if let Err(e) = res {
+                                error!("Error serving connection: {e}");
+                            }
                         }
-                    };
+                        Err(e) => error!("Error creating connection: {e}"),
+                    }
+                    conn_shared.release_ip(ip).await;
+                }.instrument(span));
+                shared.active_conns.store(connections.len(), Ordering::Relaxed);
+            }
 
-                    let res = conn.serve().await;
-                    if let Err(e) = res {
-                        error!("Error serving connection: {e}");
+            // Reap finished connections so their handles don't pile up
+            Some(res) = connections.join_next() => {
+                if let Err(e) = res {
+                    if e.is_panic() {
+                        error!("Connection task panicked: {e}");
                     }
-                });
+                }
+                shared.active_conns.store(connections.len(), Ordering::Relaxed);
+            }
+
+            // Announce maintenance scheduled or cancelled since the last check
+            _ = maintenance_poll.tick() => {
+                if let Err(e) = shared.refresh_maintenance().await {
+                    error!("🚧 Error checking for scheduled maintenance: {e}");
+                }
+            }
+
+            Some(control) = control_rx.recv() => match control {
+                // Reload the access lists and limits
+                Control::Reload => {
+                    info!("🚧 Reloading access lists and limits");
+                    if let Err(e) = shared.reload_access().await {
+                        error!("🚧 {e}");
+                    }
+                    if let Err(e) = shared.reload_limits().await {
+                        error!("🚧 {e:#}");
+                    }
+                }
+                // Hand off to a new server process, which is accepting on the same address
+                Control::Drain => {
+                    listeners.shutdown().await;
+                    info!(
+                        "🔁 No longer accepting connections, draining {} existing",
+                        connections.len()
+                    );
+                    while connections.join_next().await.is_some() {}
+                    info!("🔁 All connections drained");
+                    return Ok(());
+                }
+                // Tell everything to shut down
+                Control::Shutdown => {
+                    info!("⛔ Shutting down");
+                    shared.shutdown.send_replace(true);
+                }
+            },
 
-                task_handles.push(handle);
+            // Carry out commands from the admin socket
+            request_opt = admin_rx.recv() => {
+                let request: admin::Request =
+                    request_opt.ok_or(anyhow!("Admin request channel closed"))?;
+                let reply = shared.handle_admin(request.command).await;
+                _ = request.reply_tx.send(reply);
             }
 
             // Shutdown
-            res = signal::ctrl_c() => {
-                res.context("Error listening for shutdown signal")?;
-                info!("⛔ Received ctrl-c in serve, shutting down");
-                // Wait for connections to close
-                join_all(task_handles).await;
+            _ = shutting_down(&mut shutdown_rx) => {
+                listeners.shutdown().await;
+                // Wait for connections to close, dropping the ones that linger too long
+                let Some(timeout) = shared.limits().shutdown_timeout else {
+                    while connections.join_next().await.is_some() {}
+                    return Ok(());
+                };
+                let drained = async { while connections.join_next().await.is_some() {} };
+                if time::timeout(timeout, drained).await.is_err() {
+                    warn!(
+                        "⏱️ {} connections still open after {}s, force closing",
+                        connections.len(),
+                        timeout.as_secs()
+                    );
+                    connections.shutdown().await;
+                }
                 return Ok(());
             }
         }
     }
 }
 
+impl Limits {
+    fn from_config(config: &Config) -> Self {
+        let secs = |secs: u64| (secs > 0).then(|| Duration::from_secs(secs));
+        Self {
+            send_rate: config.send_rate,
+            relay_buffer: config.relay_buffer,
+            max_note_bytes: config.max_note_bytes,
+            rate_limit_strikes: config.rate_limit_strikes,
+            ip_max_conns: config.ip_max_conns,
+            idle_timeout: secs(config.idle_timeout),
+            write_timeout: secs(config.write_timeout),
+            slow_client_timeout: secs(config.slow_client_timeout),
+            shutdown_timeout: secs(config.shutdown_timeout),
+            ping_interval: secs(config.ping_interval),
+            max_missed_pongs: config.max_missed_pongs.max(1),
+        }
+    }
+}
+
+impl Shared {
+    fn new(
+        config: &Config,
+        auth_backend: Arc<dyn AuthBackend>,
+        storage: Arc<dyn Storage>,
+        access: AccessLists,
+        cluster: Option<Cluster>,
+        audit_log: Option<AuditLog>,
+    ) -> Self {
+        Self {
+            user_conns: UserConns::new(user_conns::DEFAULT_SHARDS),
+            cluster,
+            watchers: RwLock::new(HashMap::new()),
+            conns: RwLock::new(HashMap::new()),
+            next_conn_id: AtomicU64::new(1),
+            active_conns: AtomicUsize::new(0),
+            auth_backend,
+            mailboxes: Mutex::new(Mailboxes::new(
+                config.quota_bytes,
+                config.quota_policy,
+                config.mailbox_bytes,
+                Arc::clone(&storage),
+            )),
+            storage,
+            access: RwLock::new(access),
+            limits: std::sync::RwLock::new(Limits::from_config(config)),
+            reload: config.reload.clone(),
+            archive_notes: config.history_max_age.is_some(),
+            relay_overflow: config.relay_overflow,
+            rate_limiter: Mutex::new(RateLimiter::new(config.note_rate, config.note_burst)),
+            ip_limiter: Mutex::new(RateLimiter::new(config.ip_conn_rate, config.ip_conn_burst)),
+            ip_conns: Mutex::new(HashMap::new()),
+            auth_throttle: Mutex::new(AuthThrottle::new(
+                config.auth_max_failures,
+                Duration::from_secs(config.auth_ban_secs),
+            )),
+            upgrade_rules: config.upgrade_rules.clone(),
+            shutdown: watch::Sender::new(false),
+            maintenance: RwLock::new(None),
+            deprecations: config
+                .deprecated
+                .iter()
+                .map(|feature| Deprecation {
+                    feature: feature.clone(),
+                    message: format!(
+                        "{feature} is deprecated and will be removed in a future version"
+                    ),
+                })
+                .collect(),
+            compression: config.compression,
+            resume_ttl: (config.resume_ttl > 0).then(|| Duration::from_secs(config.resume_ttl)),
+            resumptions: Mutex::new(HashMap::new()),
+            recent_notes: Mutex::new(RecentNotes::new(
+                config.sync_window,
+                Duration::from_secs(config.sync_window_ttl),
+            )),
+            compression_stats: CompressionStats::default(),
+            audit_log,
+        }
+    }
+
+    /// Count a new connection from an IP, or say why it is over its limits
+    async fn admit_ip(&self, ip: IpAddr) -> Result<(), &'static str> {
+        if !self.ip_limiter.lock().await.take(&ip.to_string()) {
+            return Err("connecting too fast");
+        }
+        let mut ip_conns = self.ip_conns.lock().await;
+        let open = ip_conns.entry(ip).or_default();
+        let ip_max_conns = self.limits().ip_max_conns;
+        if ip_max_conns > 0 && *open >= ip_max_conns {
+            return Err("too many open connections");
+        }
+        *open += 1;
+        Ok(())
+    }
+
+    /// Pick up changes to scheduled maintenance, announcing them to everyone connected
+    async fn refresh_maintenance(&self) -> Result<()> {
+        let upcoming = maintenance::upcoming(self.storage.as_ref()).await?;
+        let mut current = self.maintenance.write().await;
+        if *current == upcoming {
+            return Ok(());
+        }
+        let msg = match &upcoming {
+            Some(maintenance) => {
+                info!(
+                    "🚧 Maintenance scheduled from {} for {}s: {}",
+                    maintenance.starts_at, maintenance.duration_secs, maintenance.message
+                );
+                ServerMsg::Maintenance(maintenance.clone())
+            }
+            None => {
+                info!("🚧 Maintenance cancelled or over");
+                ServerMsg::MaintenanceCancelled
+            }
+        };
+        *current = upcoming;
+        // Don't hold up the accept loop on slow clients, they'll get it when they next auth
+        for (_, relay) in self.user_conns.snapshot().await {
+            _ = relay.push(msg.clone());
+        }
+        Ok(())
+    }
+
+    /// Reload the access lists, keeping the old ones if they fail to load
+    async fn reload_access(&self) -> Result<()> {
+        self.access
+            .write()
+            .await
+            .reload()
+            .context("Error reloading access lists, keeping the old ones")
+    }
+
+    /// The current rates, limits and timeouts
+    fn limits(&self) -> Limits {
+        *self.limits.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Resolve the config again and apply its rates, limits and timeouts, keeping the old ones if
+    /// it fails to resolve
+    async fn reload_limits(&self) -> Result<()> {
+        let Some(reload) = &self.reload else {
+            return Ok(());
+        };
+        let config = reload().context("Error reloading config, keeping the old limits")?;
+        *self.limits.write().unwrap_or_else(|e| e.into_inner()) = Limits::from_config(&config);
+        self.rate_limiter
+            .lock()
+            .await
+            .set_rate(config.note_rate, config.note_burst);
+        self.ip_limiter
+            .lock()
+            .await
+            .set_rate(config.ip_conn_rate, config.ip_conn_burst);
+        self.auth_throttle.lock().await.set_limits(
+            config.auth_max_failures,
+            Duration::from_secs(config.auth_ban_secs),
+        );
+        info!("🚧 Reloaded rates, limits and timeouts");
+        Ok(())
+    }
+
+    /// Carry out a command from the admin socket, returning the reply
+    async fn handle_admin(&self, command: admin::Command) -> String {
+        match command {
+            admin::Command::Users => {
+                let mut users: Vec<String> = self
+                    .user_conns
+                    .snapshot()
+                    .await
+                    .into_iter()
+                    .map(|(pub_key, _)| pub_key)
+                    .collect();
+                if users.is_empty() {
+                    return "No users connected".into();
+                }
+                users.sort();
+                users.join("\n")
+            }
+            admin::Command::Stats => {
+                let conns = self.conns.read().await;
+                let mut conns: Vec<_> = conns.iter().collect();
+                if conns.is_empty() {
+                    return "No open connections".into();
+                }
+                conns.sort_by_key(|(_, info)| info.connected_at);
+                let active = self.active_conns.load(Ordering::Relaxed);
+                let lines: Vec<_> = conns
+                    .into_iter()
+                    .map(|(peer_addr, info)| {
+                        format!(
+                            "#{} {peer_addr} {} connected {}s, sent {} notes, delivered {} notes, \
+                             {}/{} relay queued, {} dropped, {}",
+                            info.id,
+                            info.pub_key
+                                .get()
+                                .map_or("(unauthenticated)", String::as_str),
+                            info.connected_at.elapsed().as_secs(),
+                            info.notes_sent.load(Ordering::Relaxed),
+                            info.notes_delivered.load(Ordering::Relaxed),
+                            info.relay.len(),
+                            info.relay.capacity(),
+                            info.relay.dropped(),
+                            format_compression(&info.compression_stats),
+                        )
+                    })
+                    .collect();
+                format!(
+                    "{active} active connections, {}\n{}",
+                    format_compression(&self.compression_stats),
+                    lines.join("\n")
+                )
+            }
+            admin::Command::Kick { pub_key, reason } => {
+                match self.kick(&pub_key, reason, false).await {
+                    Ok(true) => format!("Kicked {pub_key}"),
+                    Ok(false) => format!("Error: {pub_key} is not connected"),
+                    Err(e) => format!("Error: {e:#}"),
+                }
+            }
+            admin::Command::Ban { pub_key, reason } => {
+                match self.kick(&pub_key, reason, true).await {
+                    Ok(true) => format!("Banned and kicked {pub_key}"),
+                    Ok(false) => format!("Banned {pub_key}"),
+                    Err(e) => format!("Error: {e:#}"),
+                }
+            }
+            admin::Command::Reload => {
+                match self.reload_access().await.and(self.reload_limits().await) {
+                    Ok(()) => {
+                        info!("🚧 Reloaded access lists and limits from the admin socket");
+                        "Reloaded access lists and limits".into()
+                    }
+                    Err(e) => format!("Error: {e:#}"),
+                }
+            }
+            admin::Command::Compact => match compaction::compact(self.storage.as_ref()).await {
+                Ok(reclaimed) => format!("Compacted storage, reclaimed {reclaimed} bytes"),
+                Err(e) => format!("Error: {e:#}"),
+            },
+            admin::Command::Announce { message } => {
+                info!("📣 Announcing to all users: {message}");
+                let msg = ServerMsg::Announcement { message };
+                let user_conns = self.user_conns.snapshot().await;
+                for (_, relay) in &user_conns {
+                    _ = relay.push(msg.clone());
+                }
+                self.broadcast_cluster(&msg).await;
+                format!("Announced to {} users", user_conns.len())
+            }
+        }
+    }
+
+    /// Whether a user has a chat session connected, and how many notes wait for them
+    async fn presence(&self, pub_key: &str) -> Result<Presence> {
+        Ok(Presence {
+            online: self.user_conns.read(pub_key).await.contains_key(pub_key),
+            queued_notes: self.storage.queued_count_to(pub_key).await?,
+        })
+    }
+
+    /// Tell a user's presence-only connections about their presence, after it may have changed
+    async fn notify_watchers(&self, pub_key: &str) {
+        if !self.watchers.read().await.contains_key(pub_key) {
+            return;
+        }
+        let presence = match self.presence(pub_key).await {
+            Ok(presence) => presence,
+            Err(e) => {
+                error!("👀 Error checking presence of {pub_key}: {e}");
+                return;
+            }
+        };
+        if let Some(watchers) = self.watchers.read().await.get(pub_key) {
+            for watcher in watchers {
+                _ = watcher.push(ServerMsg::Presence(presence.clone()));
+            }
+        }
+    }
+
+    /// Relay a message to a user connected to another server instance, true if one has them
+    async fn publish_cluster(&self, pub_key: &str, msg: &ServerMsg) -> bool {
+        let Some(cluster) = &self.cluster else {
+            return false;
+        };
+        cluster.publish(pub_key, msg).await.unwrap_or_else(|e| {
+            error!("🛰️ Error relaying to {pub_key} through Redis: {e}");
+            false
+        })
+    }
+
+    /// Stop receiving messages from other server instances for a user no longer connected here
+    async fn unsubscribe_cluster(&self, pub_key: &str) {
+        if let Some(cluster) = &self.cluster {
+            if let Err(e) = cluster.unsubscribe(pub_key).await {
+                error!("🛰️ Error unsubscribing from {pub_key} on Redis: {e}");
+            }
+        }
+    }
+
+    /// Relay a message to every user connected to other server instances
+    async fn broadcast_cluster(&self, msg: &ServerMsg) {
+        if let Some(cluster) = &self.cluster {
+            if let Err(e) = cluster.broadcast(msg).await {
+                error!("🛰️ Error broadcasting through Redis: {e}");
+            }
+        }
+    }
+
+    /// Disconnect a user, optionally banning them first, returning whether they were connected.
+    /// Holds the user's user_conns lock throughout, so they can't authenticate again in between.
+    async fn kick(&self, pub_key: &str, reason: Option<String>, ban: bool) -> Result<bool> {
+        let mut user_conns_write = self.user_conns.write(pub_key).await;
+        if ban {
+            self.storage
+                .ban(pub_key, reason.as_deref())
+                .await
+                .context("Error banning user")?;
+            warn!("🔨 Banned {pub_key} from the admin socket");
+        }
+        let connected = user_conns_write.remove(pub_key).is_some();
+        if connected || ban {
+            self.audit(Event::Kicked {
+                pub_key,
+                banned: ban,
+            });
+        }
+        if !connected {
+            return Ok(false);
+        }
+        // The kicked connection no longer owns the entry, so it won't unsubscribe itself
+        self.unsubscribe_cluster(pub_key).await;
+        let reason = reason.unwrap_or(if ban { "Banned" } else { "Kicked" }.into());
+        self.kick_conn(pub_key, reason).await;
+        Ok(true)
+    }
+
+    /// Disconnect the connection authenticated as a user, once it's been removed from user_conns
+    async fn kick_conn(&self, pub_key: &str, reason: String) {
+        let conns = self.conns.read().await;
+        if let Some(info) = conns
+            .values()
+            .find(|info| info.pub_key.get().map(String::as_str) == Some(pub_key))
+        {
+            _ = info.kick_reason.set(reason);
+            info.kick.notify_one();
+        }
+    }
+
+    /// Record an event in the audit log, if there is one
+    fn audit(&self, event: Event<'_>) {
+        if let Some(audit_log) = &self.audit_log {
+            audit_log.record(event);
+        }
+    }
+
+    /// Stop counting a closed connection from an IP
+    async fn release_ip(&self, ip: IpAddr) {
+        let mut ip_conns = self.ip_conns.lock().await;
+        if let Some(open) = ip_conns.get_mut(&ip) {
+            *open = open.saturating_sub(1);
+            if *open == 0 {
+                ip_conns.remove(&ip);
+            }
+        }
+    }
+}
+
 struct Connection {
     socket: WebSocketStream<TcpStream>,
     peer_addr: SocketAddr,
-    user_conns: UserConns,
-    note_tx: Sender<Note>,
-    note_rx: Receiver<Note>,
+    shared: Arc<Shared>,
+    relay: Arc<Relay>,
+    // Paces delivery of relayed messages to smooth out bursts
+    pacer: Option<Interval>,
+    // Protocol version the client said hello with, clients that don't are assumed to be v1
+    protocol_version: u32,
+    // Whether the client only watches its user's presence rather than chatting
+    presence_only: bool,
+    // Whether large messages are deflated both ways, once negotiated in the hello
+    compress: bool,
     // Track authentication state
     pub_key: Option<String>,
-    auth_secret: Option<String>,
+    signing_key: Option<String>,
+    auth_challenge: Option<PendingAuth>,
+    // Deprecated message types the client has used, so we only warn once for each
+    used_deprecations: HashSet<&'static str>,
+    // Notes the client has sent over its rate limit
+    rate_limit_strikes: u32,
+    // When the client last sent anything, to disconnect idle clients
+    last_activity: Instant,
+    // Pings sent since the client last ponged, to reap half-open connections
+    unanswered_pings: u32,
+    // When the client's relay queue went over the high watermark, if it hasn't drained since
+    slow_since: Option<Instant>,
+    // Token the client can resume its session with, which starts expiring once we disconnect
+    resume_token: Option<String>,
+    // Fires when the server is shutting down
+    shutdown_rx: watch::Receiver<bool>,
+    // Stats and controls shared with the admin socket
+    info: Arc<ConnInfo>,
+}
+
+/// A session that can be resumed with a token instead of a challenge
+struct Resumption {
+    pub_key: String,
+    signing_key: String,
+    /// Relay queue of the connection the token was issued to, so it can be told apart from
+    /// other sessions of the user
+    relay: Arc<Relay>,
+    /// Set once the connection closes
+    expires_at: Option<Instant>,
+}
+
+/// An outstanding auth challenge sent to the client
+struct PendingAuth {
+    pub_key: String,
+    signing_key: String,
+    token: Option<String>,
+    secret: String,
 }
 
 impl Connection {
-    async fn new(tcp_stream: TcpStream, user_conns: UserConns) -> Result<Self> {
+    async fn new(tcp_stream: TcpStream, id: u64, shared: Arc<Shared>) -> Result<Self> {
         // Open WS connection to client
         let peer_addr = tcp_stream.peer_addr()?;
-        let socket = accept_async(tcp_stream).await?;
+        let socket = time::timeout(
+            HANDSHAKE_TIMEOUT,
+            accept_hdr_async(tcp_stream, shared.upgrade_rules.check_for(peer_addr)),
+        )
+        .await
+        .context(format!("Websocket handshake with {peer_addr} timed out"))??;
         info!("🔗 Connected to client: {peer_addr}");
 
-        // Channel to send notes through
-        let (note_tx, note_rx) = mpsc::channel(CHANNEL_BUFFER_SIZE);
+        // Queue for other connections to relay messages through
+        let limits = shared.limits();
+        let relay = Arc::new(Relay::new(limits.relay_buffer, shared.relay_overflow));
+
+        let info = Arc::new(ConnInfo {
+            id,
+            connected_at: Instant::now(),
+            pub_key: OnceLock::new(),
+            notes_sent: AtomicU64::new(0),
+            notes_delivered: AtomicU64::new(0),
+            relay: Arc::clone(&relay),
+            kick: Notify::new(),
+            kick_reason: OnceLock::new(),
+            compression_stats: CompressionStats::default(),
+        });
+        shared
+            .conns
+            .write()
+            .await
+            .insert(peer_addr, Arc::clone(&info));
+
+        // Pace delivery. Delay missed ticks so a stalled burst doesn't get sent all at once.
+        let send_rate = limits.send_rate;
+        let pacer = (send_rate > 0).then(|| {
+            let mut pacer = time::interval(Duration::from_secs(1) / send_rate);
+            pacer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            pacer
+        });
 
         Ok(Self {
             socket,
             peer_addr,
-            user_conns,
-            note_tx,
-            note_rx,
+            relay,
+            pacer,
+            protocol_version: 1,
+            presence_only: false,
+            compress: false,
             pub_key: None,
-            auth_secret: None,
+            signing_key: None,
+            auth_challenge: None,
+            used_deprecations: HashSet::new(),
+            rate_limit_strikes: 0,
+            last_activity: Instant::now(),
+            unanswered_pings: 0,
+            slow_since: None,
+            resume_token: None,
+            shutdown_rx: shared.shutdown.subscribe(),
+            info,
+            shared,
         })
     }
 
@@ -107,10 +925,49 @@ impl Connection {
             error!("Error serving WS connection {}: {e}", self.peer_addr);
         }
 
-        // Clean up user_conns
+        // Clean up user_conns, unless we were kicked and the user has since connected again
         if let Some(username) = self.pub_key {
-            let mut user_conns_write = self.user_conns.write().await;
-            user_conns_write.remove(&username);
+            if self.presence_only {
+                let mut watchers_write = self.shared.watchers.write().await;
+                if let Some(watchers) = watchers_write.get_mut(&username) {
+                    watchers.retain(|watcher| !Arc::ptr_eq(watcher, &self.relay));
+                    if watchers.is_empty() {
+                        watchers_write.remove(&username);
+                    }
+                }
+            } else {
+                let mut user_conns_write = self.shared.user_conns.write(&username).await;
+                if user_conns_write
+                    .get(&username)
+                    .is_some_and(|relay| Arc::ptr_eq(relay, &self.relay))
+                {
+                    user_conns_write.remove(&username);
+                    self.shared.unsubscribe_cluster(&username).await;
+                }
+                drop(user_conns_write);
+                // Notes still queued are lost with the connection unless it's resumed, so keep
+                // them for the client to fetch when it reconnects
+                let undelivered = self.relay.queued_notes();
+                if !undelivered.is_empty() {
+                    let mut recent_notes = self.shared.recent_notes.lock().await;
+                    for note in &undelivered {
+                        recent_notes.record(&username, note);
+                    }
+                }
+                self.shared.notify_watchers(&username).await;
+                self.shared.audit(Event::Disconnected {
+                    pub_key: &username,
+                    peer_addr: self.peer_addr,
+                    notes_sent: self.info.notes_sent.load(Ordering::Relaxed),
+                    notes_delivered: self.info.notes_delivered.load(Ordering::Relaxed),
+                });
+            }
+        }
+        self.shared.conns.write().await.remove(&self.peer_addr);
+        if let (Some(token), Some(ttl)) = (&self.resume_token, self.shared.resume_ttl) {
+            if let Some(resumption) = self.shared.resumptions.lock().await.get_mut(token) {
+                resumption.expires_at = Some(Instant::now() + ttl);
+            }
         }
 
         // Close connection to client. It's fine if it errors out.
@@ -121,12 +978,22 @@ impl Connection {
 
     /// Serve client websocket connection
     async fn serve_client_ws_conn(&mut self) -> Result<()> {
+        // Check for idle clients a few times per idle timeout
+        let limits = self.shared.limits();
+        let idle_timeout = limits.idle_timeout;
+        let mut idle_check = idle_timeout.map(|timeout| time::interval(timeout / 3));
+        // Each ping has until the next one to be answered. The first tick is immediate, so skip
+        // it to give the client a full interval.
+        let mut keepalive = limits
+            .ping_interval
+            .map(|period| time::interval_at(time::Instant::now() + period, period));
         loop {
             tokio::select! {
                 // Handle incoming WS messages from client
                 ws_msg_res_opt = self.socket.next() => {
                     let ws_msg = ws_msg_res_opt.ok_or(anyhow!("Connection to server closed"))??;
                     info!("Received WS message from {}: {ws_msg:?}", self.peer_addr);
+                    self.last_activity = Instant::now();
 
                     match ws_msg {
                         Message::Text(payload) => {
@@ -137,29 +1004,94 @@ impl Connection {
                             info!("👋 Received WS close message from {}, disconnecting", self.peer_addr);
                             return Ok(());
                         },
+                        // Only clients that negotiated compression send binary, deflated messages
+                        Message::Binary(payload) if self.compress => {
+                            let text = compression::inflate(&payload)?;
+                            self.record_compression(text.len(), payload.len());
+                            self.handle_client_ws_text_msg(text.into()).await?
+                        }
                         Message::Binary(_payload) => error!("Server does not support binary messages"),
                         Message::Frame(_frame) => error!("Server does not support frame messages"),
-                        // tokio_tungstenite automatically handles ping/pong
-                        _ => {}
+                        Message::Pong(_payload) => self.unanswered_pings = 0,
+                        // tokio_tungstenite automatically answers pings
+                        Message::Ping(_payload) => {}
                     }
                 }
 
-                // Send notes from channel
-                note_opt = self.note_rx.recv() =>  {
-                    let note = note_opt.ok_or(anyhow!("Note channel for {} closed", self.peer_addr))?;
-                    info!(
-                        "✉️ Client {} receiving note from {} to {}",
-                        self.peer_addr, note.from, note.to
+                // Send relayed messages from the queue, disconnecting the client if it's been
+                // backed up for too long
+                msg = self.relay.pop() => {
+                    self.deliver(msg).await?;
+                    if self.is_too_slow() {
+                        let frame = CloseFrame {
+                            code: CloseCode::Again,
+                            reason: "Too slow".into(),
+                        };
+                        self.socket.close(Some(frame)).await?;
+                        return Ok(());
+                    }
+                }
+
+                // Disconnect the client if it's fallen too far behind to relay to
+                _ = self.relay.overflowed() => {
+                    warn!(
+                        "🐢 Client {} fell {} messages behind, disconnecting",
+                        self.peer_addr,
+                        self.relay.capacity()
+                    );
+                    let frame = CloseFrame {
+                        code: CloseCode::Again,
+                        reason: "Too far behind".into(),
+                    };
+                    self.socket.close(Some(frame)).await?;
+                    return Ok(());
+                }
+
+                // Disconnect idle clients
+                _ = tick(&mut idle_check) => {
+                    let idle = self.last_activity.elapsed();
+                    if idle_timeout.is_some_and(|timeout| idle >= timeout) {
+                        info!(
+                            "💤 Client {} idle for {}s, disconnecting",
+                            self.peer_addr,
+                            idle.as_secs()
+                        );
+                        return Ok(());
+                    }
+                }
+
+                // Ping the client, reaping it if it's stopped answering. A half-open tcp
+                // connection would otherwise leave the user online forever.
+                _ = tick(&mut keepalive) => {
+                    if self.unanswered_pings >= self.shared.limits().max_missed_pongs {
+                        warn!(
+                            "🪦 Client {} missed {} pongs, reaping dead connection",
+                            self.peer_addr, self.unanswered_pings
+                        );
+                        return Ok(());
+                    }
+                    self.unanswered_pings += 1;
+                    self.send_ws(Message::Ping(Default::default())).await?;
+                }
+
+                // Disconnect the client if an operator kicked it
+                _ = self.info.kick.notified() => {
+                    let reason = self.info.kick_reason.get().cloned().unwrap_or_default();
+                    warn!(
+                        "👢 Client {} kicked by an operator, disconnecting: {reason}",
+                        self.peer_addr
                     );
-                    self.socket
-                        .send(ServerMsg::RecNote(note).to_ws_msg())
-                        .await?;
+                    let frame = CloseFrame {
+                        code: CloseCode::Policy,
+                        reason: truncate_close_reason(reason).into(),
+                    };
+                    self.socket.close(Some(frame)).await?;
+                    return Ok(());
                 }
 
                 // Shutdown
-                res = signal::ctrl_c() => {
-                    res.context("Error listening for shutdown signal")?;
-                    info!("⛔ Received ctrl-c in serve_client_ws_conn, shutting down");
+                _ = shutting_down(&mut self.shutdown_rx) => {
+                    info!("⛔ Server shutting down, disconnecting {}", self.peer_addr);
                     return Ok(());
                 }
             }
@@ -171,14 +1103,74 @@ impl Connection {
         let msg = ClientMsg::from_str(&payload)?;
         info!("📥 Received message from {}: {msg}", self.peer_addr);
 
+        // Warn once about clients still using deprecated messages
+        let kind = msg.kind();
+        if self.shared.deprecations.iter().any(|d| d.feature == kind)
+            && self.used_deprecations.insert(kind)
+        {
+            warn!(
+                "⚠️ Client {} is using deprecated message type {kind}",
+                self.peer_addr
+            );
+        }
+
+        // Presence-only connections can authenticate, then just listen
+        if self.presence_only
+            && !matches!(
+                msg,
+                ClientMsg::Hello(_) | ClientMsg::AuthReq(_) | ClientMsg::AuthPlaintext(_)
+            )
+        {
+            warn!(
+                "👀 Client {} sent {kind} on a presence-only connection, dropping",
+                self.peer_addr
+            );
+            return Ok(());
+        }
+
         match msg {
+            ClientMsg::Hello(hello) => self.handle_hello(hello).await?,
             ClientMsg::AuthReq(auth) => self.handle_auth_req(auth).await?,
             ClientMsg::AuthPlaintext(auth) => self.handle_auth_plaintext(auth).await?,
+            ClientMsg::Resume(resume) => self.handle_resume(resume).await?,
             ClientMsg::SendNote(note) => self.handle_send_note(note).await?,
+            ClientMsg::QuotaQuery => self.handle_quota_query().await?,
+            ClientMsg::FetchMailbox => self.handle_fetch_mailbox().await?,
+            ClientMsg::SessionOffer(handshake) => {
+                self.handle_session_handshake(ServerMsg::SessionOffer, handshake)
+                    .await?
+            }
+            ClientMsg::SessionAccept(handshake) => {
+                self.handle_session_handshake(ServerMsg::SessionAccept, handshake)
+                    .await?
+            }
+            ClientMsg::Typing(typing) => self.handle_typing(typing).await?,
+            ClientMsg::Revoke(revocation) => self.handle_revoke(revocation).await?,
+            ClientMsg::SyncSince(sync) => self.handle_sync_since(sync).await?,
+            ClientMsg::FetchHistory(fetch) => self.handle_fetch_history(fetch).await?,
         }
         Ok(())
     }
 
+    /// Handle the client saying hello, acking with the deprecations it should know about and
+    /// whether we'll compress
+    async fn handle_hello(&mut self, hello: Hello) -> Result<()> {
+        info!(
+            "👋 Client {} speaks protocol version {}",
+            self.peer_addr, hello.protocol_version
+        );
+        self.protocol_version = hello.protocol_version;
+        self.presence_only = hello.presence_only;
+        self.compress = hello.compression && self.shared.compression;
+        let ack = HelloAck {
+            protocol_version: PROTOCOL_VERSION,
+            deprecations: self.shared.deprecations.clone(),
+            compression: self.compress,
+        };
+        self.send_ws(ServerMsg::HelloAck(ack).to_ws_msg()).await?;
+        Ok(())
+    }
+
     /// Handle the client requesting to authenticate
     async fn handle_auth_req(&mut self, auth: Auth) -> Result<()> {
         info!(
@@ -186,22 +1178,80 @@ impl Connection {
             self.peer_addr, auth.pub_key
         );
 
-        // Generate random secret and encrypt to client
-        let mut bytes = [0u8; 64];
-        rand::rng().fill_bytes(&mut bytes);
-        let secret = hex::encode(bytes);
-        self.auth_secret = Some(secret.clone());
-        let recipient = Recipient::from_str(&auth.pub_key).map_err(|e| anyhow!(e))?;
-        let ciphertext = age::encrypt_and_armor(&recipient, secret.as_bytes())?;
+        // A connection can only be authenticated as one user
+        if self.pub_key.is_some() {
+            error!(
+                "✍️ Client {} failed authenticating as {}, connection is already authenticated",
+                self.peer_addr, auth.pub_key
+            );
+            self.send_ws(ServerMsg::AuthDenied(auth).to_ws_msg())
+                .await?;
+            return Ok(());
+        }
+
+        // Sources that keep failing have to wait before getting another challenge
+        let throttle_wait = self
+            .shared
+            .auth_throttle
+            .lock()
+            .await
+            .check(self.peer_addr.ip(), &auth.pub_key);
+        if let Some(wait) = throttle_wait {
+            let wait_secs = wait.as_secs().max(1);
+            warn!(
+                "🔨 Client {} throttled authenticating as {}, can retry in {wait_secs}s",
+                self.peer_addr, auth.pub_key
+            );
+            let error = ServerError::new(
+                ErrorKind::AuthThrottled,
+                format!("Too many failed auth attempts, retry in {wait_secs}s"),
+            );
+            self.send_error(error).await?;
+            return Ok(());
+        }
+
+        // Users that aren't allowed don't get a challenge at all
+        let access_res = self.shared.access.read().await.check(&auth.pub_key);
+        if let Err(denial) = access_res {
+            error!(
+                "✍️ Client {} failed authenticating as {}, {denial}",
+                self.peer_addr, auth.pub_key
+            );
+            self.send_ws(ServerMsg::AuthDenied(auth).to_ws_msg())
+                .await?;
+            return Ok(());
+        }
+        if self.shared.storage.is_banned(&auth.pub_key).await? {
+            error!(
+                "✍️ Client {} failed authenticating as {}, user is banned",
+                self.peer_addr, auth.pub_key
+            );
+            self.send_ws(ServerMsg::AuthDenied(auth).to_ws_msg())
+                .await?;
+            return Ok(());
+        }
+
+        // Generate a random challenge bound to the pubkey and encrypt to client
+        let challenge = AuthChallenge::new(auth.pub_key.clone());
+        let ciphertext = challenge.encrypt()?;
+
+        // Remember who the challenge is for, this is the identity that gets granted
+        self.auth_challenge = Some(PendingAuth {
+            pub_key: auth.pub_key.clone(),
+            signing_key: auth.signing_key.clone(),
+            token: auth.token.clone(),
+            secret: challenge.nonce,
+        });
 
         // Send to client for decryption
         let auth_secret = Auth {
             pub_key: auth.pub_key,
+            signing_key: auth.signing_key,
+            token: None,
             ciphertext,
             plaintext: "".to_string(),
         };
-        self.socket
-            .send(ServerMsg::AuthSecret(auth_secret).to_ws_msg())
+        self.send_ws(ServerMsg::AuthSecret(auth_secret).to_ws_msg())
             .await?;
         Ok(())
     }
@@ -213,80 +1263,1133 @@ impl Connection {
             self.peer_addr, auth.pub_key
         );
 
-        // User cannot be authenticated twice at the same time
-        {
-            let user_conns_read = self.user_conns.read().await;
-            if user_conns_read.contains_key(&auth.pub_key) {
-                error!(
-                    "✍️ Client {} failed authenticating as {}, user is already authenticated",
-                    self.peer_addr, auth.pub_key
-                );
-                self.socket
-                    .send(ServerMsg::AuthDenied(auth).to_ws_msg())
-                    .await?;
-                return Ok(());
-            }
+        // Challenges are single use, so answering one again is denied
+        let Some(challenge) = self.auth_challenge.take() else {
+            error!(
+                "✍️ Client {} failed authenticating as {}, no challenge to answer",
+                self.peer_addr, auth.pub_key
+            );
+            self.send_ws(ServerMsg::AuthDenied(auth).to_ws_msg())
+                .await?;
+            return Ok(());
+        };
+
+        // The client must answer the challenge for the pubkey it was issued for
+        if auth.pub_key != challenge.pub_key {
+            error!(
+                "✍️ Client {} failed authenticating as {}, challenge was issued for {}",
+                self.peer_addr, auth.pub_key, challenge.pub_key
+            );
+            self.record_auth_failure(&challenge.pub_key).await;
+            self.send_ws(ServerMsg::AuthDenied(auth).to_ws_msg())
+                .await?;
+            return Ok(());
         }
 
         // Check decryption
-        let auth_secret = self
-            .auth_secret
-            .clone()
-            .ok_or(anyhow!("No auth secret set, cannot check"))?;
-        if auth_secret != auth.plaintext {
+        if challenge.secret != auth.plaintext {
             error!(
                 "✍️ Client {} failed authenticating as {}, incorrect plaintext",
-                self.peer_addr, auth.pub_key
+                self.peer_addr, challenge.pub_key
             );
-            self.socket
-                .send(ServerMsg::AuthDenied(auth).to_ws_msg())
+            self.record_auth_failure(&challenge.pub_key).await;
+            self.send_ws(ServerMsg::AuthDenied(auth).to_ws_msg())
                 .await?;
             return Ok(());
         }
 
-        // Add username and note_tx to user_conns
-        let mut user_conns_write = self.user_conns.write().await;
-        // TODO: this is not secure, client could authenticate as different pub key
-        // than the message was encrypted for
-        user_conns_write.insert(auth.pub_key.clone(), self.note_tx.clone());
-        info!(
-            "✍️ Client {} successfully authenticated as {}",
-            self.peer_addr, auth.pub_key
-        );
-        self.pub_key = Some(auth.pub_key.clone());
-        self.socket
-            .send(ServerMsg::AuthGranted(auth).to_ws_msg())
-            .await?;
-        Ok(())
-    }
+        // Check the user is allowed on this server
+        if !self
+            .shared
+            .auth_backend
+            .authorize(&challenge.pub_key, challenge.token.as_deref())
+            .await?
+        {
+            error!(
+                "✍️ Client {} failed authenticating as {}, denied by auth backend",
+                self.peer_addr, challenge.pub_key
+            );
+            self.record_auth_failure(&challenge.pub_key).await;
+            self.send_ws(ServerMsg::AuthDenied(auth).to_ws_msg())
+                .await?;
+            return Ok(());
+        }
 
-    /// Handle the client sending a note
-    async fn handle_send_note(&mut self, note: Note) -> Result<()> {
+        // Presence-only connections don't make the user online, so don't count towards the limit
+        if self.presence_only {
+            return self.grant_presence(challenge, auth).await;
+        }
+
+        // User cannot be authenticated twice at the same time. Hold the write lock for the check
+        // and insert so two connections can't race each other.
+        let shared = Arc::clone(&self.shared);
+        let user_conns_write = shared.user_conns.write(&challenge.pub_key).await;
+        if let Some(relay) = user_conns_write.get(&challenge.pub_key) {
+            error!(
+                "✍️ Client {} failed authenticating as {}, user is already authenticated",
+                self.peer_addr, challenge.pub_key
+            );
+            // The other device holds the key, so warn the session in case it was stolen
+            let duplicate = DuplicateLogin {
+                ip: self.peer_addr.ip().to_string(),
+                protocol_version: self.protocol_version,
+                at: Utc::now(),
+            };
+            _ = relay.push(ServerMsg::DuplicateLogin(duplicate));
+            drop(user_conns_write);
+            self.send_ws(ServerMsg::AuthDenied(auth).to_ws_msg())
+                .await?;
+            return Ok(());
+        }
+        if self.authenticated_elsewhere(&challenge.pub_key).await {
+            error!(
+                "✍️ Client {} failed authenticating as {}, user is authenticated on another instance",
+                self.peer_addr, challenge.pub_key
+            );
+            drop(user_conns_write);
+            self.send_ws(ServerMsg::AuthDenied(auth).to_ws_msg())
+                .await?;
+            return Ok(());
+        }
+
+        let auth_granted = Auth {
+            pub_key: challenge.pub_key,
+            signing_key: challenge.signing_key,
+            token: None,
+            ciphertext: auth.ciphertext,
+            plaintext: auth.plaintext,
+        };
+        self.grant(
+            user_conns_write,
+            auth_granted,
+            challenge.token.as_deref(),
+            false,
+        )
+        .await
+    }
+
+    /// Make the client's user online, holding their user_conns lock, and tell it it's
+    /// authenticated. Uses the identity the server checked rather than anything the client sent,
+    /// and the token it authorized with, if any.
+    async fn grant(
+        &mut self,
+        mut user_conns_write: RwLockWriteGuard<'_, HashMap<String, Arc<Relay>>>,
+        auth_granted: Auth,
+        token: Option<&str>,
+        resumed: bool,
+    ) -> Result<()> {
+        let pub_key = auth_granted.pub_key.clone();
+
+        // Check bans again under the lock, so one from the admin socket can't slip in between
+        if self.shared.storage.is_banned(&pub_key).await? {
+            error!(
+                "✍️ Client {} failed authenticating as {}, user is banned",
+                self.peer_addr, pub_key
+            );
+            drop(user_conns_write);
+            self.send_ws(ServerMsg::AuthDenied(auth_granted).to_ws_msg())
+                .await?;
+            return Ok(());
+        }
+
+        // Only use up the invite now the login can't fail
+        if !self.shared.auth_backend.admit(&pub_key, token).await? {
+            error!(
+                "✍️ Client {} failed authenticating as {}, invite was used up meanwhile",
+                self.peer_addr, pub_key
+            );
+            drop(user_conns_write);
+            self.send_ws(ServerMsg::AuthDenied(auth_granted).to_ws_msg())
+                .await?;
+            return Ok(());
+        }
+
+        user_conns_write.insert(pub_key.clone(), Arc::clone(&self.relay));
+        if let Some(cluster) = &self.shared.cluster {
+            if let Err(e) = cluster.subscribe(&pub_key).await {
+                error!(
+                    "🛰️ Error subscribing to {} on Redis, other instances can't relay to them: {e}",
+                    pub_key
+                );
+            }
+        }
+        // Clients from before mailboxes have theirs pushed. Take the notes before releasing the
+        // lock, so none can be queued after.
+        let queued_notes = if self.protocol_version < MAILBOX_PROTOCOL_VERSION {
+            self.shared.mailboxes.lock().await.drain(&pub_key).await
+        } else {
+            Ok(vec![])
+        };
+        drop(user_conns_write);
+        let queued_notes = queued_notes?;
+        if self.shared.storage.add_user(&pub_key).await? {
+            info!("✍️ New user {} joined", pub_key);
+        }
+        info!(
+            "✍️ Client {} successfully authenticated as {}",
+            self.peer_addr, pub_key
+        );
+        self.shared.notify_watchers(&pub_key).await;
+        self.shared
+            .auth_throttle
+            .lock()
+            .await
+            .record_success(self.peer_addr.ip(), &pub_key);
+        self.shared.audit(Event::AuthGranted {
+            pub_key: &pub_key,
+            peer_addr: self.peer_addr,
+            resumed,
+        });
+        _ = self.info.pub_key.set(pub_key.clone());
+        self.pub_key = Some(pub_key);
+        self.signing_key = Some(auth_granted.signing_key.clone());
+        self.send_ws(ServerMsg::AuthGranted(auth_granted).to_ws_msg())
+            .await?;
+
+        // Let the client resume the session in one message if the connection drops
+        if let Some(token) = self.issue_resume_token().await {
+            self.send_ws(ServerMsg::ResumeToken { token }.to_ws_msg())
+                .await?;
+        }
+
+        // Let the client know about upcoming downtime
+        let maintenance = self.shared.maintenance.read().await.clone();
+        if let Some(maintenance) = maintenance {
+            self.send_ws(ServerMsg::Maintenance(maintenance).to_ws_msg())
+                .await?;
+        }
+
+        // Let the client know which keys it must no longer trust
+        for revocation in self.shared.storage.revocations().await? {
+            self.deliver(ServerMsg::Revoked(revocation)).await?;
+        }
+
+        // Deliver notes that arrived while the user was offline
+        for note in queued_notes {
+            self.deliver(ServerMsg::RecNote(note)).await?;
+        }
+        Ok(())
+    }
+
+    /// Handle the client resuming an earlier session with a token, in place of a challenge
+    async fn handle_resume(&mut self, resume: Resume) -> Result<()> {
+        info!(
+            "✍️ Client {} attempting to resume session as {}",
+            self.peer_addr, resume.pub_key
+        );
+        let rejection = if self.pub_key.is_some() {
+            Some("connection is already authenticated")
+        } else if self.presence_only {
+            Some("presence-only connections can't resume")
+        } else {
+            None
+        };
+        if let Some(rejection) = rejection {
+            error!(
+                "✍️ Client {} failed resuming as {}, {rejection}",
+                self.peer_addr, resume.pub_key
+            );
+            return self.reject_resume(rejection).await;
+        }
+
+        // Tokens are single use, a new one is issued once resumed
+        let resumption = self
+            .shared
+            .resumptions
+            .lock()
+            .await
+            .remove(&resume.token)
+            .filter(|resumption| {
+                resumption.pub_key == resume.pub_key
+                    && resumption
+                        .expires_at
+                        .is_none_or(|expires_at| Instant::now() < expires_at)
+            });
+        // Not counted as an auth failure, tokens can't be guessed and going stale across server
+        // restarts is routine, so the fallback challenge shouldn't be throttled
+        let Some(resumption) = resumption else {
+            error!(
+                "✍️ Client {} failed resuming as {}, unknown or expired token",
+                self.peer_addr, resume.pub_key
+            );
+            return self.reject_resume("Unknown or expired token").await;
+        };
+
+        // Access can have changed since the token was issued
+        let access_res = self.shared.access.read().await.check(&resume.pub_key);
+        if let Err(denial) = access_res {
+            error!(
+                "✍️ Client {} failed resuming as {}, {denial}",
+                self.peer_addr, resume.pub_key
+            );
+            return self.reject_resume("Not allowed").await;
+        }
+
+        // The session being resumed may not have noticed its connection dropped yet, so it's
+        // replaced. Any other session is a duplicate login as usual.
+        let shared = Arc::clone(&self.shared);
+        let mut user_conns_write = shared.user_conns.write(&resume.pub_key).await;
+        if let Some(relay) = user_conns_write.get(&resume.pub_key) {
+            if !Arc::ptr_eq(relay, &resumption.relay) {
+                error!(
+                    "✍️ Client {} failed resuming as {}, user is already authenticated",
+                    self.peer_addr, resume.pub_key
+                );
+                drop(user_conns_write);
+                return self.reject_resume("Already connected").await;
+            }
+            info!(
+                "✍️ Client {} resuming {}'s session, closing its old connection",
+                self.peer_addr, resume.pub_key
+            );
+            user_conns_write.remove(&resume.pub_key);
+            self.shared
+                .kick_conn(&resume.pub_key, "Session resumed elsewhere".into())
+                .await;
+        } else if self.authenticated_elsewhere(&resume.pub_key).await {
+            error!(
+                "✍️ Client {} failed resuming as {}, user is authenticated on another instance",
+                self.peer_addr, resume.pub_key
+            );
+            drop(user_conns_write);
+            return self.reject_resume("Already connected").await;
+        }
+
+        let auth_granted = Auth {
+            pub_key: resumption.pub_key,
+            signing_key: resumption.signing_key,
+            token: None,
+            ciphertext: String::new(),
+            plaintext: String::new(),
+        };
+        self.grant(user_conns_write, auth_granted, None, true).await
+    }
+
+    /// Whether the user is authenticated on another server instance. Warns that session of the
+    /// duplicate login, which is how we find out if there is one.
+    async fn authenticated_elsewhere(&self, pub_key: &str) -> bool {
+        let duplicate = DuplicateLogin {
+            ip: self.peer_addr.ip().to_string(),
+            protocol_version: self.protocol_version,
+            at: Utc::now(),
+        };
+        self.shared
+            .publish_cluster(pub_key, &ServerMsg::DuplicateLogin(duplicate))
+            .await
+    }
+
+    /// Tell the client its resumption token was refused, so it authenticates in full
+    async fn reject_resume(&mut self, reason: &str) -> Result<()> {
+        let error = ServerError::new(ErrorKind::ResumeRejected, reason.to_string());
+        self.send_error(error).await
+    }
+
+    /// Issue a resumption token for the user the client authenticated as, if enabled and the
+    /// client understands them. It lasts as long as the connection, then for the ttl.
+    async fn issue_resume_token(&mut self) -> Option<String> {
+        self.shared.resume_ttl?;
+        if self.protocol_version < RESUME_PROTOCOL_VERSION {
+            return None;
+        }
+        let mut token_bytes = [0u8; 32];
+        rand::rng().fill_bytes(&mut token_bytes);
+        let token = hex::encode(token_bytes);
+        let resumption = Resumption {
+            pub_key: self.pub_key.clone()?,
+            signing_key: self.signing_key.clone()?,
+            relay: Arc::clone(&self.relay),
+            expires_at: None,
+        };
+        let mut resumptions = self.shared.resumptions.lock().await;
+        resumptions.retain(|_, resumption| {
+            resumption
+                .expires_at
+                .is_none_or(|expires_at| Instant::now() < expires_at)
+        });
+        resumptions.insert(token.clone(), resumption);
+        self.resume_token = Some(token.clone());
+        Some(token)
+    }
+
+    /// Authenticate a presence-only connection, and tell it the user's presence
+    async fn grant_presence(&mut self, challenge: PendingAuth, auth: Auth) -> Result<()> {
+        if self.shared.storage.is_banned(&challenge.pub_key).await? {
+            error!(
+                "✍️ Client {} failed authenticating as {}, user is banned",
+                self.peer_addr, challenge.pub_key
+            );
+            self.send_ws(ServerMsg::AuthDenied(auth).to_ws_msg())
+                .await?;
+            return Ok(());
+        }
+        if !self
+            .shared
+            .auth_backend
+            .admit(&challenge.pub_key, challenge.token.as_deref())
+            .await?
+        {
+            error!(
+                "✍️ Client {} failed authenticating as {}, invite was used up meanwhile",
+                self.peer_addr, challenge.pub_key
+            );
+            self.send_ws(ServerMsg::AuthDenied(auth).to_ws_msg())
+                .await?;
+            return Ok(());
+        }
+        self.shared
+            .watchers
+            .write()
+            .await
+            .entry(challenge.pub_key.clone())
+            .or_default()
+            .push(Arc::clone(&self.relay));
+        info!(
+            "👀 Client {} watching presence of {}",
+            self.peer_addr, challenge.pub_key
+        );
+        self.pub_key = Some(challenge.pub_key.clone());
+        _ = self.info.pub_key.set(challenge.pub_key.clone());
+        let presence = self.shared.presence(&challenge.pub_key).await?;
+        let auth_granted = Auth {
+            pub_key: challenge.pub_key,
+            signing_key: challenge.signing_key,
+            token: None,
+            ciphertext: auth.ciphertext,
+            plaintext: auth.plaintext,
+        };
+        self.send_ws(ServerMsg::AuthGranted(auth_granted).to_ws_msg())
+            .await?;
+        self.deliver(ServerMsg::Presence(presence)).await
+    }
+
+    /// Count a failed auth attempt from this client towards being throttled
+    async fn record_auth_failure(&self, pub_key: &str) {
+        self.shared
+            .auth_throttle
+            .lock()
+            .await
+            .record_failure(self.peer_addr.ip(), pub_key);
+        self.shared.audit(Event::AuthFailed {
+            pub_key,
+            peer_addr: self.peer_addr,
+        });
+    }
+
+    /// Report an error back to the client
+    async fn send_error(&mut self, error: ServerError) -> Result<()> {
+        self.shared.audit(Event::Error {
+            pub_key: self.pub_key.as_deref(),
+            peer_addr: self.peer_addr,
+            kind: error.kind,
+        });
+        self.send_ws(ServerMsg::Error(error).to_ws_msg()).await
+    }
+
+    /// Handle the client sending a note
+    async fn handle_send_note(&mut self, note: Note) -> Result<()> {
         info!(
             "✉️ Client {} sent note from {} to {}",
             self.peer_addr, note.from, note.to
         );
-        // TODO: what if from address does not match?
+
+        // Only relay notes sent as and signed by the authenticated user. Sealed sender notes hide
+        // the sender, so all we can check is that the client is authenticated.
+        let Some(sender) = self.pub_key.clone() else {
+            error!(
+                "✉️ Client {} sent note but is not authenticated, dropping",
+                self.peer_addr
+            );
+            return Ok(());
+        };
+
+        // Drop notes over the user's rate, disconnecting clients that keep flooding
+        if !self.shared.rate_limiter.lock().await.take(&sender) {
+            self.rate_limit_strikes += 1;
+            let max_strikes = self.shared.limits().rate_limit_strikes;
+            if max_strikes > 0 && self.rate_limit_strikes >= max_strikes {
+                bail!(
+                    "Client {} kept sending notes over its rate limit",
+                    self.peer_addr
+                );
+            }
+            warn!(
+                "🚦 Client {} sent note over its rate limit, dropping",
+                self.peer_addr
+            );
+            let error = ServerError::new(
+                ErrorKind::RateLimited,
+                "Sending notes too fast, note dropped".into(),
+            );
+            self.send_error(error).await?;
+            return Ok(());
+        }
+
+        // Refuse notes too large to relay, or that can't be ciphertext, before doing anything
+        // else with them
+        let size = note.encrypted_content.len();
+        let max_note_bytes = self.shared.limits().max_note_bytes;
+        if size > max_note_bytes {
+            warn!(
+                "✉️ Client {} sent note of {size} bytes, over the limit, dropping",
+                self.peer_addr
+            );
+            let error = ServerError::new(
+                ErrorKind::NoteTooLarge,
+                format!("Note of {size} bytes is larger than the limit of {max_note_bytes} bytes"),
+            );
+            return self.send_error(error).await;
+        }
+        if let Err(e) = note.check_ciphertext() {
+            warn!(
+                "✉️ Client {} sent note that isn't ciphertext, dropping: {e}",
+                self.peer_addr
+            );
+            let error = ServerError::new(ErrorKind::InvalidNote, e.to_string());
+            return self.send_error(error).await;
+        }
+
+        if note.is_sealed() {
+            info!("✉️ Client {} sent sealed sender note", self.peer_addr);
+        } else if sender != note.from {
+            error!(
+                "✉️ Client {} sent note from {} but is authenticated as {sender}, dropping",
+                self.peer_addr, note.from
+            );
+            return Ok(());
+        } else if self.signing_key.as_ref() != Some(&note.signing_key) {
+            error!(
+                "✉️ Client {} sent note with a signing key it did not authenticate with, dropping",
+                self.peer_addr
+            );
+            return Ok(());
+        } else if let Err(e) = note.verify_signature() {
+            error!(
+                "✉️ Client {} sent note with an invalid signature, dropping: {e}",
+                self.peer_addr
+            );
+            return Ok(());
+        }
+
+        self.info.notes_sent.fetch_add(1, Ordering::Relaxed);
+
         // Echo back the note so that it will be in the history
-        self.socket
-            .send(ServerMsg::RecNote(note.clone()).to_ws_msg())
+        self.send_ws(ServerMsg::RecNote(note.clone()).to_ws_msg())
             .await?;
 
-        // Relay note to connection of recipient address
-        let user_conns_read = self.user_conns.read().await;
-        match user_conns_read.get(&note.to) {
-            Some(recipient_tx) => {
-                recipient_tx.send(note).await?;
+        // Notes to ourselves are only state for our other devices, not history, and sealed notes
+        // aren't archived since that would keep who sent them
+        if self.shared.archive_notes && note.to != sender && !note.is_sealed() {
+            if let Err(e) = self.shared.storage.archive_note(&sender, &note).await {
+                error!("📜 Error archiving note {}: {e}", note.id);
+            }
+        }
+
+        // Relay note to connection of recipient address. Notes to ourselves sync state to our
+        // other devices, which can't be connected at the same time, so they go to the mailbox.
+        let user_conns_read = self.shared.user_conns.read(&note.to).await;
+        let recipient_conn = if note.to == sender {
+            None
+        } else {
+            user_conns_read.get(&note.to)
+        };
+        match recipient_conn {
+            Some(recipient_relay) => {
+                let recipient = note.to.clone();
+                let relay_res = recipient_relay.push(ServerMsg::RecNote(note));
+                drop(user_conns_read);
+                if relay_res.is_err() {
+                    warn!(
+                        "🐢 Client {} sent note to {recipient}, who is too far behind, dropping",
+                        self.peer_addr
+                    );
+                    let error = ServerError::new(
+                        ErrorKind::PeerBackedUp,
+                        format!("{recipient} is not keeping up, note dropped"),
+                    );
+                    self.send_error(error).await?;
+                }
+            }
+            None if note.to != sender
+                && self
+                    .shared
+                    .publish_cluster(&note.to, &ServerMsg::RecNote(note.clone()))
+                    .await =>
+            {
+                drop(user_conns_read);
+                info!(
+                    "🛰️ Client {} sent note to {}, relayed to another server instance",
+                    self.peer_addr, note.to
+                );
             }
             None => {
-                error!(
-                    "✉️ Client {} sent note from {} to unauthenticated user {}",
+                // Hold the user_conns lock while queueing so the recipient can't come online and
+                // fetch their mailbox in between
+                info!(
+                    "✉️ Client {} sent note from {} to offline user {}, queueing",
                     self.peer_addr, note.from, note.to
                 );
-                // TODO: send back error message?
+                let recipient = note.to.clone();
+                let queue_res = self.shared.mailboxes.lock().await.push(&sender, note).await;
+                drop(user_conns_read);
+                if queue_res.is_ok() {
+                    self.shared.notify_watchers(&recipient).await;
+                }
+                if let Err(e) = queue_res {
+                    error!(
+                        "✉️ Client {} could not queue note for offline user: {e}",
+                        self.peer_addr
+                    );
+                    let error = ServerError::new(ErrorKind::QuotaExceeded, e.to_string());
+                    self.send_error(error).await?;
+                }
             }
         }
 
         Ok(())
     }
+
+    /// Handle the client asking how much of its storage quota it is using
+    async fn handle_quota_query(&mut self) -> Result<()> {
+        let pub_key = self
+            .pub_key
+            .clone()
+            .ok_or(anyhow!("Client {} is not authenticated", self.peer_addr))?;
+        let usage = self.shared.mailboxes.lock().await.usage(&pub_key).await?;
+        self.send_ws(ServerMsg::QuotaUsage(usage).to_ws_msg())
+            .await?;
+        Ok(())
+    }
+
+    /// Handle the client fetching the notes in its mailbox
+    async fn handle_fetch_mailbox(&mut self) -> Result<()> {
+        let pub_key = self
+            .pub_key
+            .clone()
+            .ok_or(anyhow!("Client {} is not authenticated", self.peer_addr))?;
+        let notes = self.shared.mailboxes.lock().await.drain(&pub_key).await?;
+        info!(
+            "📬 Client {} fetched {} notes from mailbox of {pub_key}",
+            self.peer_addr,
+            notes.len()
+        );
+        self.shared.notify_watchers(&pub_key).await;
+        for note in notes {
+            self.deliver(ServerMsg::RecNote(note)).await?;
+        }
+        Ok(())
+    }
+
+    /// Handle a reconnected client fetching the notes delivered after the last one it received,
+    /// which it may have lost when its previous connection dropped
+    async fn handle_sync_since(&mut self, sync: SyncSince) -> Result<()> {
+        let pub_key = self
+            .pub_key
+            .clone()
+            .ok_or(anyhow!("Client {} is not authenticated", self.peer_addr))?;
+        let notes = self
+            .shared
+            .recent_notes
+            .lock()
+            .await
+            .since(&pub_key, sync.after.as_deref());
+        info!(
+            "🔄 Client {} synced {} notes of {pub_key} since {}",
+            self.peer_addr,
+            notes.len(),
+            sync.after.as_deref().unwrap_or("the start")
+        );
+        for note in notes {
+            self.deliver(ServerMsg::RecNote(note)).await?;
+        }
+        Ok(())
+    }
+
+    /// Handle the client fetching archived notes with a peer, e.g. to fill a new device's history
+    async fn handle_fetch_history(&mut self, fetch: FetchHistory) -> Result<()> {
+        let pub_key = self
+            .pub_key
+            .clone()
+            .ok_or(anyhow!("Client {} is not authenticated", self.peer_addr))?;
+        let limit = (fetch.limit as usize).min(MAX_HISTORY_NOTES);
+        let notes = self
+            .shared
+            .storage
+            .archived_notes(&pub_key, &fetch.peer, fetch.before_id.as_deref(), limit)
+            .await?;
+        info!(
+            "📜 Client {} fetched {} archived notes of {pub_key} with {}",
+            self.peer_addr,
+            notes.len(),
+            fetch.peer
+        );
+        let page = HistoryPage {
+            peer: fetch.peer,
+            notes,
+        };
+        self.send_ws(ServerMsg::History(page).to_ws_msg()).await?;
+        Ok(())
+    }
+
+    /// Handle the client offering or accepting a forward secret session with a peer, relaying
+    /// the handshake to the peer
+    async fn handle_session_handshake(
+        &mut self,
+        to_server_msg: fn(SessionHandshake) -> ServerMsg,
+        handshake: SessionHandshake,
+    ) -> Result<()> {
+        info!(
+            "🤝 Client {} sent session handshake from {} to {}",
+            self.peer_addr, handshake.from, handshake.to
+        );
+
+        // Handshakes must come from the authenticated user
+        if self.pub_key.as_ref() != Some(&handshake.from)
+            || self.signing_key.as_ref() != Some(&handshake.signing_key)
+        {
+            error!(
+                "🤝 Client {} sent handshake as a user it is not authenticated as, dropping",
+                self.peer_addr
+            );
+            return Ok(());
+        }
+        if let Err(e) = handshake.verify_signature() {
+            error!(
+                "🤝 Client {} sent handshake with an invalid signature, dropping: {e}",
+                self.peer_addr
+            );
+            return Ok(());
+        }
+
+        // Sessions can only be set up with online peers, the client falls back to plain notes
+        let recipient_relay = self.shared.user_conns.get(&handshake.to).await;
+        let recipient = handshake.to.clone();
+        let msg = to_server_msg(handshake);
+        let error = match recipient_relay {
+            Some(recipient_relay) => match recipient_relay.push(msg) {
+                Ok(()) => return Ok(()),
+                Err(_) => ServerError::new(
+                    ErrorKind::PeerBackedUp,
+                    format!("{recipient} is not keeping up"),
+                ),
+            },
+            None if self.shared.publish_cluster(&recipient, &msg).await => return Ok(()),
+            None => ServerError::new(ErrorKind::PeerOffline, format!("{recipient} is offline")),
+        };
+        self.send_error(error).await?;
+        Ok(())
+    }
+
+    /// Handle the client revoking the key it's authenticated as, telling everyone online. The
+    /// rest are told when they next authenticate.
+    async fn handle_revoke(&mut self, revocation: Revocation) -> Result<()> {
+        if self.pub_key.as_ref() != Some(&revocation.pub_key)
+            || self.signing_key.as_ref() != Some(&revocation.signing_key)
+        {
+            error!(
+                "🪪 Client {} sent revocation for a key it is not authenticated as, dropping",
+                self.peer_addr
+            );
+            return Ok(());
+        }
+        if let Err(e) = revocation.verify_signature() {
+            error!(
+                "🪪 Client {} sent revocation with an invalid signature, dropping: {e}",
+                self.peer_addr
+            );
+            return Ok(());
+        }
+
+        warn!(
+            "🪪 Client {} revoked key {}, banning it",
+            self.peer_addr, revocation.pub_key
+        );
+        self.shared.storage.revoke(&revocation).await?;
+        let msg = ServerMsg::Revoked(revocation);
+        for (pub_key, relay) in self.shared.user_conns.snapshot().await {
+            if Some(&pub_key) != self.pub_key.as_ref() {
+                _ = relay.push(msg.clone());
+            }
+        }
+        self.shared.broadcast_cluster(&msg).await;
+        // Echo it back to confirm
+        self.send_ws(msg.to_ws_msg()).await?;
+        Ok(())
+    }
+
+    /// Relay a typing indicator to the peer if they're online, dropping it otherwise
+    async fn handle_typing(&mut self, typing: Typing) -> Result<()> {
+        if self.pub_key.as_ref() != Some(&typing.from) {
+            error!(
+                "⌨️ Client {} sent typing indicator as a user it is not authenticated as, dropping",
+                self.peer_addr
+            );
+            return Ok(());
+        }
+        // It's only a hint, so it's fine to drop if the peer is backed up
+        match self.shared.user_conns.get(&typing.to).await {
+            Some(recipient_relay) => _ = recipient_relay.push(ServerMsg::Typing(typing)),
+            None => {
+                let recipient = typing.to.clone();
+                self.shared
+                    .publish_cluster(&recipient, &ServerMsg::Typing(typing))
+                    .await;
+            }
+        }
+        Ok(())
+    }
+
+    /// Send a message to the client, deflating it if it's large and compression was negotiated,
+    /// and giving up on the client if the write stalls
+    async fn send_ws(&mut self, ws_msg: Message) -> Result<()> {
+        let ws_msg = match ws_msg {
+            Message::Text(text) if self.compress && text.len() >= MIN_COMPRESS_BYTES => {
+                let data = compression::deflate(&text)?;
+                self.record_compression(text.len(), data.len());
+                Message::binary(data)
+            }
+            ws_msg => ws_msg,
+        };
+        let Some(timeout) = self.shared.limits().write_timeout else {
+            return Ok(self.socket.send(ws_msg).await?);
+        };
+        time::timeout(timeout, self.socket.send(ws_msg))
+            .await
+            .map_err(|_| {
+                warn!(
+                    "🐢 Write to client {} stalled for {}s, disconnecting",
+                    self.peer_addr,
+                    timeout.as_secs()
+                );
+                anyhow!("Write to client {} timed out", self.peer_addr)
+            })??;
+        Ok(())
+    }
+
+    /// Count bytes saved by compressing a message to or from the client
+    fn record_compression(&self, raw_bytes: usize, wire_bytes: usize) {
+        self.info.compression_stats.record(raw_bytes, wire_bytes);
+        self.shared.compression_stats.record(raw_bytes, wire_bytes);
+    }
+
+    /// Classify the client by how backed up its relay queue is, true once it's stayed over the
+    /// high watermark for too long
+    fn is_too_slow(&mut self) -> bool {
+        let Some(timeout) = self.shared.limits().slow_client_timeout else {
+            return false;
+        };
+        let queued = self.relay.len();
+        let capacity = self.relay.capacity();
+        if queued * 100 <= capacity * SLOW_LOW_WATERMARK_PERCENT {
+            if self.slow_since.take().is_some() {
+                info!("🐢 Client {} caught up", self.peer_addr);
+            }
+            return false;
+        }
+        if queued * 100 < capacity * SLOW_HIGH_WATERMARK_PERCENT && self.slow_since.is_none() {
+            return false;
+        }
+        let slow_since = *self.slow_since.get_or_insert_with(|| {
+            info!(
+                "🐢 Client {} is falling behind, {queued}/{capacity} messages queued",
+                self.peer_addr
+            );
+            Instant::now()
+        });
+        if slow_since.elapsed() < timeout {
+            return false;
+        }
+        warn!(
+            "🐢 Client {} backed up for over {}s, disconnecting",
+            self.peer_addr,
+            timeout.as_secs()
+        );
+        true
+    }
+
+    /// Deliver a message relayed from another connection, respecting the send pacing
+    async fn deliver(&mut self, msg: ServerMsg) -> Result<()> {
+        // Older clients would fail to parse newer messages
+        if self.protocol_version < msg.min_protocol_version() {
+            return Ok(());
+        }
+        if let Some(pacer) = &mut self.pacer {
+            pacer.tick().await;
+        }
+        if let ServerMsg::RecNote(note) = &msg {
+            self.info.notes_delivered.fetch_add(1, Ordering::Relaxed);
+            info!(
+                "✉️ Client {} receiving note from {} to {}",
+                self.peer_addr, note.from, note.to
+            );
+            if let Some(pub_key) = &self.pub_key {
+                self.shared.recent_notes.lock().await.record(pub_key, note);
+            }
+        }
+        self.send_ws(msg.to_ws_msg()).await?;
+        Ok(())
+    }
+}
+
+/// Relay messages published by other server instances to the users connected here
+async fn relay_from_cluster(mut inbox: Inbox, shared: Arc<Shared>) {
+    while let Some((target, msg)) = inbox.next().await {
+        match target {
+            Target::User(pub_key) => {
+                let Some(relay) = shared.user_conns.get(&pub_key).await else {
+                    continue;
+                };
+                if relay.push(msg).is_err() {
+                    warn!("🐢 {pub_key} is too far behind, dropping message from another instance");
+                }
+            }
+            Target::Everyone => {
+                for (_, relay) in shared.user_conns.snapshot().await {
+                    _ = relay.push(msg.clone());
+                }
+            }
+        }
+    }
+    error!("🛰️ Lost connection to Redis, only relaying to users connected here");
+}
+
+/// Wait for the next tick of an optional interval, never completing if it's unset
+async fn tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Wait for the server to start shutting down
+async fn shutting_down(shutdown_rx: &mut watch::Receiver<bool>) {
+    // The sender lives in the shared state, so it can't be dropped while anyone is waiting
+    _ = shutdown_rx.wait_for(|shutdown| *shutdown).await;
+}
+
+/// Cut a close frame reason down to the size websockets allow, on a char boundary
+fn truncate_close_reason(mut reason: String) -> String {
+    if reason.len() > MAX_CLOSE_REASON_BYTES {
+        let end = (0..=MAX_CLOSE_REASON_BYTES)
+            .rev()
+            .find(|i| reason.is_char_boundary(*i))
+            .unwrap_or(0);
+        reason.truncate(end);
+    }
+    reason
+}
+
+/// Describe how much compression saved, for admin stats
+fn format_compression(stats: &CompressionStats) -> String {
+    match stats.ratio() {
+        Some(ratio) => format!(
+            "compressed {} to {} bytes ({ratio:.1}x)",
+            stats.raw_bytes(),
+            stats.wire_bytes()
+        ),
+        None => "nothing compressed".into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use age::x25519::{Identity, Recipient};
+    use clap::Parser;
+    use tokio_tungstenite::{connect_async, MaybeTlsStream};
+
+    use super::*;
+    use crate::common::signing_key;
+    use crate::config::Resolver;
+    use crate::server::{auth, storage};
+    use crate::ServerArgs;
+
+    type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+    /// Serve connections with the default config, returning the address and the shared state to
+    /// inspect
+    async fn start() -> Result<(String, Arc<Shared>)> {
+        let args = ServerArgs::try_parse_from(["serve"])?;
+        let config = Config::resolve(args, &mut Resolver::defaults("server"))?;
+        let storage = storage::build(None)?;
+        let auth_backend =
+            auth::build(config.auth_backend, None, None, Arc::clone(&storage)).await?;
+        let access = AccessLists::load(None, None)?;
+        let shared = Arc::new(Shared::new(
+            &config,
+            auth_backend,
+            storage,
+            access,
+            None,
+            None,
+        ));
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?.to_string();
+        let accept_shared = Arc::clone(&shared);
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let id = accept_shared.next_conn_id.fetch_add(1, Ordering::Relaxed);
+                let conn_shared = Arc::clone(&accept_shared);
+                tokio::spawn(async move {
+                    if let Ok(conn) = Connection::new(stream, id, conn_shared).await {
+                        _ = conn.serve().await;
+                    }
+                });
+            }
+        });
+        Ok((address, shared))
+    }
+
+    async fn connect(address: &str) -> Result<Socket> {
+        let (mut socket, _) = connect_async(format!("ws://{address}")).await?;
+        let hello = Hello {
+            protocol_version: PROTOCOL_VERSION,
+            presence_only: false,
+            compression: false,
+        };
+        socket.send(ClientMsg::Hello(hello).to_ws_msg()).await?;
+        Ok(socket)
+    }
+
+    async fn send(socket: &mut Socket, msg: ClientMsg) -> Result<()> {
+        Ok(socket.send(msg.to_ws_msg()).await?)
+    }
+
+    /// Wait for the next message from the server that isn't a control frame
+    async fn recv(socket: &mut Socket) -> Result<ServerMsg> {
+        loop {
+            let ws_msg = socket.next().await.ok_or(anyhow!("Connection closed"))??;
+            if let Message::Text(payload) = ws_msg {
+                return Ok(ServerMsg::from_str(&payload)?);
+            }
+        }
+    }
+
+    /// Wait for the server to grant or deny authentication, true if granted
+    async fn auth_result(socket: &mut Socket) -> Result<bool> {
+        loop {
+            match recv(socket).await? {
+                ServerMsg::AuthGranted(_) => return Ok(true),
+                ServerMsg::AuthDenied(_) => return Ok(false),
+                _ => {}
+            }
+        }
+    }
+
+    /// Ask for a challenge for a key, returning it as issued and the answer to it
+    async fn challenge(socket: &mut Socket, key: &Identity) -> Result<Auth> {
+        let signing_key = hex::encode(signing_key(key).verifying_key().as_bytes());
+        let auth = Auth::new(key.to_public().to_string(), signing_key, None);
+        send(socket, ClientMsg::AuthReq(auth)).await?;
+        loop {
+            if let ServerMsg::AuthSecret(auth) = recv(socket).await? {
+                let challenge = AuthChallenge::decrypt(key, &auth.ciphertext)?;
+                return Ok(Auth {
+                    plaintext: challenge.nonce,
+                    ..auth
+                });
+            }
+        }
+    }
+
+    /// Authenticate as a key, failing if it isn't granted
+    async fn authenticate(socket: &mut Socket, key: &Identity) -> Result<Auth> {
+        let answer = challenge(socket, key).await?;
+        send(socket, ClientMsg::AuthPlaintext(answer.clone())).await?;
+        if !auth_result(socket).await? {
+            bail!("Authentication was denied");
+        }
+        Ok(answer)
+    }
+
+    #[tokio::test]
+    async fn answer_for_another_pubkey_is_denied() -> Result<()> {
+        let (address, shared) = start().await?;
+        let a = Identity::generate();
+        let b_pub_key = Identity::generate().to_public().to_string();
+        let mut socket = connect(&address).await?;
+
+        let answer = challenge(&mut socket, &a).await?;
+        let claim = Auth {
+            pub_key: b_pub_key.clone(),
+            ..answer
+        };
+        send(&mut socket, ClientMsg::AuthPlaintext(claim)).await?;
+
+        assert!(!auth_result(&mut socket).await?);
+        assert!(shared
+            .user_conns
+            .get(&a.to_public().to_string())
+            .await
+            .is_none());
+        assert!(shared.user_conns.get(&b_pub_key).await.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn granted_connection_cannot_claim_another_pubkey() -> Result<()> {
+        let (address, shared) = start().await?;
+        let a = Identity::generate();
+        let b = Identity::generate();
+        let b_pub_key = b.to_public().to_string();
+        let mut socket = connect(&address).await?;
+
+        // Answer the challenge for A, then claim B with the same answer
+        let answer = authenticate(&mut socket, &a).await?;
+        let claim = Auth {
+            pub_key: b_pub_key.clone(),
+            ..answer
+        };
+        send(&mut socket, ClientMsg::AuthPlaintext(claim)).await?;
+        assert!(!auth_result(&mut socket).await?);
+
+        let a_relay = shared.user_conns.get(&a.to_public().to_string()).await;
+        assert!(a_relay.is_some(), "A lost its session");
+        assert!(shared.user_conns.get(&b_pub_key).await.is_none());
+
+        // Notes to B aren't delivered to A's connection
+        let c = Identity::generate();
+        let mut sender = connect(&address).await?;
+        authenticate(&mut sender, &c).await?;
+        let recipient = Recipient::from_str(&b_pub_key).map_err(|e| anyhow!(e))?;
+        let note = Note::encrypt_new(&c, &recipient, "for B only".into())?;
+        send(&mut sender, ClientMsg::SendNote(note.clone())).await?;
+        let delivered = time::timeout(Duration::from_millis(300), async {
+            loop {
+                if let ServerMsg::RecNote(received) = recv(&mut socket).await? {
+                    if received.id == note.id {
+                        return Ok::<_, anyhow::Error>(());
+                    }
+                }
+            }
+        })
+        .await;
+        assert!(delivered.is_err(), "A received a note sent to B");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn replayed_answer_is_denied() -> Result<()> {
+        let (address, shared) = start().await?;
+        let a = Identity::generate();
+        let a_pub_key = a.to_public().to_string();
+        let mut first = connect(&address).await?;
+        let answer = authenticate(&mut first, &a).await?;
+
+        // Answering the same challenge again on the same connection
+        send(&mut first, ClientMsg::AuthPlaintext(answer.clone())).await?;
+        assert!(!auth_result(&mut first).await?);
+        first.close(None).await?;
+        while shared.user_conns.get(&a_pub_key).await.is_some() {
+            time::sleep(Duration::from_millis(10)).await;
+        }
+
+        // Answering a new challenge with the old answer
+        let mut second = connect(&address).await?;
+        challenge(&mut second, &a).await?;
+        send(&mut second, ClientMsg::AuthPlaintext(answer)).await?;
+        assert!(!auth_result(&mut second).await?);
+        assert!(shared.user_conns.get(&a_pub_key).await.is_none());
+        Ok(())
+    }
 }
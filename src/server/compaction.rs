@@ -0,0 +1,39 @@
+use anyhow::Result;
+use chrono::{NaiveTime, TimeDelta, Utc};
+use std::{sync::Arc, time::Duration};
+use tokio::time::{self, Instant};
+use tracing::{error, info};
+
+use super::storage::Storage;
+
+/// Compact storage every day at a time of day, so it can be done off-peak
+pub async fn schedule(at: NaiveTime, storage: Arc<dyn Storage>) {
+    info!("🗜️ Compacting storage every day at {at} UTC");
+    loop {
+        time::sleep(until_next(at)).await;
+        if let Err(e) = compact(storage.as_ref()).await {
+            error!("🗜️ Error compacting storage: {e}");
+        }
+    }
+}
+
+/// Compact storage now, returning the bytes reclaimed
+pub async fn compact(storage: &dyn Storage) -> Result<u64> {
+    let started = Instant::now();
+    let reclaimed = storage.compact().await?;
+    info!(
+        "🗜️ Compacted storage in {}ms, reclaimed {reclaimed} bytes",
+        started.elapsed().as_millis()
+    );
+    Ok(reclaimed)
+}
+
+/// Time until the next time the clock reads a time of day in UTC
+fn until_next(at: NaiveTime) -> Duration {
+    let now = Utc::now();
+    let mut next = now.date_naive().and_time(at).and_utc();
+    if next <= now {
+        next += TimeDelta::days(1);
+    }
+    (next - now).to_std().unwrap_or_default()
+}
@@ -0,0 +1,48 @@
+use anyhow::{Context, Result};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::{lookup_host, TcpListener};
+
+/// Connections the kernel queues for us before they're accepted
+const BACKLOG: i32 = 1024;
+
+/// Bind the listener, allowing another process to bind the same address if `reuse_port`. For a
+/// zero-downtime upgrade the new server binds alongside the old one, then the old one is sent
+/// SIGUSR2 to stop accepting and drain its existing connections.
+///
+/// IPv6 listeners only accept IPv6, so `[::]` and `0.0.0.0` can be listened on side by side for
+/// dual-stack the same way on every OS.
+pub async fn bind(addr: &str, reuse_port: bool) -> Result<TcpListener> {
+    let socket_addr = lookup_host(addr)
+        .await?
+        .next()
+        .context(format!("No address found for {addr}"))?;
+    let socket = Socket::new(
+        Domain::for_address(socket_addr),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
+    if socket_addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    if reuse_port {
+        set_reuse_port(&socket)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket
+        .bind(&socket_addr.into())
+        .context(format!("Error binding {addr}"))?;
+    socket.listen(BACKLOG)?;
+    Ok(TcpListener::from_std(socket.into())?)
+}
+
+#[cfg(unix)]
+fn set_reuse_port(socket: &Socket) -> Result<()> {
+    Ok(socket.set_reuse_port(true)?)
+}
+
+#[cfg(not(unix))]
+fn set_reuse_port(_socket: &Socket) -> Result<()> {
+    anyhow::bail!("--reuse-port is only supported on unix")
+}
@@ -0,0 +1,71 @@
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use std::fs::OpenOptions;
+use std::path::Path;
+use std::str::FromStr;
+use std::{fmt, sync::Mutex};
+use tracing_subscriber::{fmt::writer::BoxMakeWriter, prelude::*, EnvFilter};
+
+/// How the server formats its logs
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum LogFormat {
+    /// Human readable lines
+    Pretty,
+    /// One json object per line, for log pipelines like journald or ELK
+    Json,
+}
+
+/// Set up logging at a level or filter like `info` or `age_chat=debug`, to a file if given and
+/// stdout otherwise. Also serves tokio-console on its default port with the console feature.
+pub fn init(format: LogFormat, level: &str, file: Option<&Path>) -> Result<()> {
+    let filter = EnvFilter::try_new(level).context(format!("Invalid log level {level:?}"))?;
+    let writer = match file {
+        Some(path) => {
+            let file = OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(path)
+                .context(format!("Error opening log file {}", path.display()))?;
+            BoxMakeWriter::new(Mutex::new(file))
+        }
+        None => BoxMakeWriter::new(std::io::stdout),
+    };
+
+    // Only one of the formats is set. The filter only applies to our logs, so the console still
+    // gets the runtime's own instrumentation.
+    let (pretty, json) = match format {
+        LogFormat::Pretty => {
+            let layer = tracing_subscriber::fmt::layer()
+                .with_ansi(file.is_none())
+                .with_writer(writer)
+                .with_filter(filter);
+            (Some(layer), None)
+        }
+        LogFormat::Json => {
+            let layer = tracing_subscriber::fmt::layer()
+                .json()
+                .with_writer(writer)
+                .with_filter(filter);
+            (None, Some(layer))
+        }
+    };
+    let registry = tracing_subscriber::registry().with(pretty).with(json);
+    #[cfg(feature = "console")]
+    let registry = registry.with(console_subscriber::spawn());
+    registry.try_init().context("Error setting up logging")
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        <Self as ValueEnum>::from_str(s, true)
+    }
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = self.to_possible_value().ok_or(fmt::Error)?;
+        write!(f, "{}", name.get_name())
+    }
+}
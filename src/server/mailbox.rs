@@ -0,0 +1,127 @@
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use std::{fmt, str::FromStr, sync::Arc};
+use tracing::info;
+
+use super::storage::{note_size, Storage};
+use crate::common::{Note, QuotaUsage};
+
+/// What to do when a user would go over their storage quota
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum QuotaPolicy {
+    /// Refuse to store the new note
+    Reject,
+    /// Drop the user's oldest stored notes to make room
+    EvictOldest,
+}
+
+/// Each user's mailbox of notes that arrived while they were offline, fetched when they next
+/// connect. Only the encrypted notes are held, the server never sees their content.
+///
+/// Stored bytes are charged to the sender, who can't store more than their quota. Each mailbox
+/// also has a size limit, past which its oldest notes are dropped.
+pub struct Mailboxes {
+    quota_bytes: usize,
+    policy: QuotaPolicy,
+    mailbox_bytes: usize,
+    storage: Arc<dyn Storage>,
+}
+
+impl Mailboxes {
+    pub fn new(
+        quota_bytes: usize,
+        policy: QuotaPolicy,
+        mailbox_bytes: usize,
+        storage: Arc<dyn Storage>,
+    ) -> Self {
+        Self {
+            quota_bytes,
+            policy,
+            mailbox_bytes,
+            storage,
+        }
+    }
+
+    /// Put a note in its recipient's mailbox, applying the sender's quota
+    pub async fn push(&mut self, sender: &str, note: Note) -> Result<()> {
+        let size = note_size(&note);
+        if size > self.quota_bytes {
+            return Err(anyhow!(
+                "Note of {size} bytes is larger than the quota of {} bytes",
+                self.quota_bytes
+            ));
+        }
+        if size > self.mailbox_bytes {
+            return Err(anyhow!(
+                "Note of {size} bytes is larger than the mailbox limit of {} bytes",
+                self.mailbox_bytes
+            ));
+        }
+
+        while self.storage.queued_bytes_from(sender).await? + size > self.quota_bytes {
+            match self.policy {
+                QuotaPolicy::Reject => {
+                    return Err(anyhow!(
+                        "Storing note would exceed quota of {} bytes",
+                        self.quota_bytes
+                    ))
+                }
+                QuotaPolicy::EvictOldest => self.evict_oldest(sender).await?,
+            }
+        }
+
+        let recipient = note.to.clone();
+        self.storage.queue_note(sender, &note).await?;
+
+        // Make room in the recipient's mailbox by dropping its oldest notes
+        while self.storage.queued_bytes_to(&recipient).await? > self.mailbox_bytes {
+            let Some(note) = self.storage.remove_oldest_queued_to(&recipient).await? else {
+                break;
+            };
+            info!(
+                "🗑️ Dropped note {} from mailbox of {recipient}, mailbox is full",
+                note.id
+            );
+        }
+        Ok(())
+    }
+
+    /// Take all notes in a user's mailbox
+    pub async fn drain(&mut self, recipient: &str) -> Result<Vec<Note>> {
+        self.storage.take_queued_notes(recipient).await
+    }
+
+    /// How much of their quota a user is using
+    pub async fn usage(&self, pub_key: &str) -> Result<QuotaUsage> {
+        Ok(QuotaUsage {
+            used_bytes: self.storage.queued_bytes_from(pub_key).await?,
+            quota_bytes: self.quota_bytes,
+        })
+    }
+
+    /// Drop the oldest note stored by a sender
+    async fn evict_oldest(&mut self, sender: &str) -> Result<()> {
+        if let Some(note) = self.storage.remove_oldest_queued_from(sender).await? {
+            info!(
+                "🗑️ Evicted note {} from {sender} to {} over quota",
+                note.id, note.to
+            );
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for QuotaPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        <Self as ValueEnum>::from_str(s, true)
+    }
+}
+
+impl fmt::Display for QuotaPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = self.to_possible_value().ok_or(fmt::Error)?;
+        write!(f, "{}", name.get_name())
+    }
+}
@@ -0,0 +1,39 @@
+use anyhow::Result;
+use chrono::{TimeDelta, Utc};
+use std::{path::Path, time::Duration};
+
+use super::storage::{self, Storage};
+use crate::common::Maintenance;
+
+/// How often the server checks storage for maintenance scheduled with `age-chat maintenance`
+pub const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Schedule downtime in the server's database, replacing any already scheduled
+pub async fn schedule(
+    db: &Path,
+    starts_in: Duration,
+    duration: Duration,
+    message: String,
+) -> Result<()> {
+    let maintenance = Maintenance {
+        starts_at: Utc::now() + TimeDelta::from_std(starts_in)?,
+        duration_secs: duration.as_secs(),
+        message,
+    };
+    storage::build(Some(db))?
+        .set_maintenance(Some(&maintenance))
+        .await
+}
+
+/// Cancel downtime scheduled in the server's database
+pub async fn cancel(db: &Path) -> Result<()> {
+    storage::build(Some(db))?.set_maintenance(None).await
+}
+
+/// Scheduled downtime that hasn't finished yet
+pub async fn upcoming(storage: &dyn Storage) -> Result<Option<Maintenance>> {
+    Ok(storage
+        .maintenance()
+        .await?
+        .filter(|maintenance| maintenance.ends_at() > Utc::now()))
+}
@@ -1,15 +1,511 @@
+mod access;
+pub mod admin;
+mod advertise;
+mod audit;
+mod auth;
+mod cluster;
 mod comms;
+mod compaction;
+mod handoff;
+mod logging;
+mod mailbox;
+pub mod maintenance;
+mod rate_limit;
+mod recent;
+mod relay;
+mod retention;
+mod signals;
+mod storage;
+mod tasks;
+mod throttle;
+mod upgrade;
+mod user_conns;
 
-use anyhow::Result;
+use std::{
+    collections::HashSet, future::Future, net::SocketAddr, path::PathBuf, sync::Arc, time::Duration,
+};
+
+use anyhow::{anyhow, bail, Result};
+use chrono::NaiveTime;
+use clap::Parser;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
 use tracing::info;
 
+use crate::config::{Resolver, DEFAULT_ADDRESS};
 use crate::ServerArgs;
 
+pub use auth::{create_invites, AuthBackendKind};
+pub use logging::LogFormat;
+pub use mailbox::QuotaPolicy;
+pub use relay::{OverflowPolicy, Relay};
+pub use upgrade::UpgradeRules;
+pub use user_conns::{UserConns, DEFAULT_SHARDS};
+
+const DEFAULT_SEND_RATE: u32 = 100;
+const DEFAULT_RELAY_BUFFER: usize = 1000;
+const DEFAULT_MAX_NOTE_BYTES: usize = 1024 * 1024;
+const DEFAULT_NOTE_RATE: u32 = 10;
+const DEFAULT_NOTE_BURST: u32 = 50;
+const DEFAULT_RATE_LIMIT_STRIKES: u32 = 20;
+const DEFAULT_IP_MAX_CONNS: u32 = 32;
+const DEFAULT_IP_CONN_RATE: u32 = 5;
+const DEFAULT_IP_CONN_BURST: u32 = 20;
+const DEFAULT_AUTH_MAX_FAILURES: u32 = 10;
+const DEFAULT_AUTH_BAN_SECS: u64 = 15 * 60;
+const DEFAULT_IDLE_TIMEOUT: u64 = 5 * 60;
+const DEFAULT_WRITE_TIMEOUT: u64 = 10;
+const DEFAULT_SLOW_CLIENT_TIMEOUT: u64 = 30;
+const DEFAULT_SHUTDOWN_TIMEOUT: u64 = 30;
+const DEFAULT_RESUME_TTL: u64 = 5 * 60;
+const DEFAULT_SYNC_WINDOW: usize = 100;
+const DEFAULT_SYNC_WINDOW_TTL: u64 = 5 * 60;
+const DEFAULT_PING_INTERVAL: u64 = 30;
+const DEFAULT_MAX_MISSED_PONGS: u32 = 3;
+const DEFAULT_QUOTA_BYTES: usize = 10 * 1024 * 1024;
+const DEFAULT_MAILBOX_BYTES: usize = 10 * 1024 * 1024;
+const DEFAULT_RETENTION_SWEEP_INTERVAL: u64 = 60;
+const DEFAULT_LOG_LEVEL: &str = "info";
+
+/// Effective server configuration
+pub struct Config {
+    /// Addresses to listen on, each with its own accept loop
+    pub listen: Vec<String>,
+    /// Let a new server process bind the address too, for zero-downtime upgrades
+    pub reuse_port: bool,
+    /// Redis to relay through to users connected to other server instances
+    pub redis_url: Option<String>,
+    /// Max notes per second delivered to each client, 0 to disable pacing
+    pub send_rate: u32,
+    /// Max messages queued for relaying to each client
+    pub relay_buffer: usize,
+    /// What to do when a client's relay queue is full
+    pub relay_overflow: OverflowPolicy,
+    /// Max bytes of a note's encrypted content
+    pub max_note_bytes: usize,
+    /// Notes per second each user can send, 0 to disable rate limiting
+    pub note_rate: u32,
+    /// Max notes each user can send in a burst
+    pub note_burst: u32,
+    /// Rate limited notes before a client is disconnected, 0 to never disconnect
+    pub rate_limit_strikes: u32,
+    /// Max open connections from each IP, 0 for unlimited
+    pub ip_max_conns: u32,
+    /// Connection attempts per second allowed from each IP, 0 for unlimited
+    pub ip_conn_rate: u32,
+    /// Max connection attempts from each IP in a burst
+    pub ip_conn_burst: u32,
+    /// Failed auth attempts before an IP is temporarily banned, 0 to never ban
+    pub auth_max_failures: u32,
+    /// Seconds temporary auth bans last
+    pub auth_ban_secs: u64,
+    /// Seconds a client can be silent before it's disconnected, 0 to never disconnect
+    pub idle_timeout: u64,
+    /// Seconds a write to a client can stall before it's disconnected, 0 to wait forever
+    pub write_timeout: u64,
+    /// Seconds a client's relay queue can stay mostly full before it's disconnected, 0 to never
+    /// disconnect
+    pub slow_client_timeout: u64,
+    /// Unix socket to listen for admin commands on
+    pub admin_socket: Option<PathBuf>,
+    /// Seconds to wait for connections to close on shutdown, 0 to wait forever
+    pub shutdown_timeout: u64,
+    /// Seconds between keepalive pings to each client, 0 to not ping
+    pub ping_interval: u64,
+    /// Pings in a row a client can leave unanswered before it's disconnected
+    pub max_missed_pongs: u32,
+    /// How to authorize users after the key challenge
+    pub auth_backend: AuthBackendKind,
+    /// File of allowed pubkeys or invite codes
+    pub auth_file: Option<PathBuf>,
+    /// Url for the webhook auth backend
+    pub auth_webhook: Option<String>,
+    /// File of the only pubkeys allowed to authenticate
+    pub allow_file: Option<PathBuf>,
+    /// File of pubkeys banned from authenticating
+    pub deny_file: Option<PathBuf>,
+    /// Max bytes of notes each user can have stored on the server
+    pub quota_bytes: usize,
+    /// What to do when a user would go over their quota
+    pub quota_policy: QuotaPolicy,
+    /// Max bytes of notes in each user's mailbox, dropping the oldest when full
+    pub mailbox_bytes: usize,
+    /// Compress large messages for clients that offer it
+    pub compression: bool,
+    /// Seconds a session can be resumed for after its connection drops, 0 to not allow resuming
+    pub resume_ttl: u64,
+    /// Notes delivered to each user kept for reconnecting clients to fetch again, 0 to keep none
+    pub sync_window: usize,
+    /// Seconds delivered notes are kept for reconnecting clients to fetch again
+    pub sync_window_ttl: u64,
+    /// Client message types to warn clients are deprecated
+    pub deprecated: Vec<String>,
+    /// What websocket upgrade requests have to match, for reverse proxy deployments
+    pub upgrade_rules: UpgradeRules,
+    /// SQLite database to persist state in, kept in memory if unset
+    pub db: Option<PathBuf>,
+    /// Seconds to keep notes queued for offline users
+    pub retention_max_age: Option<u64>,
+    /// Max bytes of notes queued for each offline user, dropping the oldest
+    pub retention_max_bytes: Option<usize>,
+    /// Seconds between sweeps for notes past retention
+    pub retention_sweep_interval: u64,
+    /// Seconds to archive relayed notes for clients to fetch as history, none are kept if unset.
+    /// Sealed sender notes are never archived.
+    pub history_max_age: Option<u64>,
+    /// Time of day in UTC to compact storage every day
+    pub compact_at: Option<NaiveTime>,
+    /// File to append an audit log of who authenticated, relay counts and errors to
+    pub audit_log: Option<PathBuf>,
+    /// Salt to hash pubkeys in the audit log with, leaving out addresses, for privacy mode
+    pub audit_salt: Option<String>,
+    /// How to format logs
+    pub log_format: LogFormat,
+    /// Level or filter directives to log at, e.g. `info` or `age_chat=debug`
+    pub log_level: String,
+    /// File to append logs to instead of stdout
+    pub log_file: Option<PathBuf>,
+    /// Name to advertise the server under on the local network over mDNS
+    pub advertise: Option<String>,
+    /// Resolves the config again from the same sources, for SIGHUP to reload the rates, limits
+    /// and timeouts. Every other setting needs a restart to change.
+    pub reload: Option<Reload>,
+}
+
+/// Resolves the server config again, e.g. after the config file changed
+pub type Reload = Arc<dyn Fn() -> Result<Config> + Send + Sync>;
+
+impl Config {
+    /// Resolve the server config from cli args layered over env vars, config file and defaults
+    pub fn resolve(args: ServerArgs, resolver: &mut Resolver) -> Result<Self> {
+        // Listen addresses replace the positional one, which can only be a single address
+        let address = resolver.resolve("address", args.common.address, DEFAULT_ADDRESS.into())?;
+        let listen = (!args.listen.is_empty()).then(|| args.listen.join(","));
+        let listen = match resolver.resolve_optional::<String>("listen", listen)? {
+            Some(listen) => comma_list(&listen),
+            None => vec![address],
+        };
+        let config = Self {
+            listen,
+            reuse_port: resolver.resolve("reuse-port", args.reuse_port.then_some(true), false)?,
+            redis_url: resolver.resolve_optional("redis-url", args.redis_url)?,
+            send_rate: resolver.resolve("send-rate", args.send_rate, DEFAULT_SEND_RATE)?,
+            relay_buffer: resolver.resolve(
+                "relay-buffer",
+                args.relay_buffer,
+                DEFAULT_RELAY_BUFFER,
+            )?,
+            relay_overflow: resolver.resolve(
+                "relay-overflow",
+                args.relay_overflow,
+                OverflowPolicy::DropNew,
+            )?,
+            max_note_bytes: resolver.resolve(
+                "max-note-bytes",
+                args.max_note_bytes,
+                DEFAULT_MAX_NOTE_BYTES,
+            )?,
+            note_rate: resolver.resolve("note-rate", args.note_rate, DEFAULT_NOTE_RATE)?,
+            note_burst: resolver.resolve("note-burst", args.note_burst, DEFAULT_NOTE_BURST)?,
+            rate_limit_strikes: resolver.resolve(
+                "rate-limit-strikes",
+                args.rate_limit_strikes,
+                DEFAULT_RATE_LIMIT_STRIKES,
+            )?,
+            ip_max_conns: resolver.resolve(
+                "ip-max-conns",
+                args.ip_max_conns,
+                DEFAULT_IP_MAX_CONNS,
+            )?,
+            ip_conn_rate: resolver.resolve(
+                "ip-conn-rate",
+                args.ip_conn_rate,
+                DEFAULT_IP_CONN_RATE,
+            )?,
+            ip_conn_burst: resolver.resolve(
+                "ip-conn-burst",
+                args.ip_conn_burst,
+                DEFAULT_IP_CONN_BURST,
+            )?,
+            auth_max_failures: resolver.resolve(
+                "auth-max-failures",
+                args.auth_max_failures,
+                DEFAULT_AUTH_MAX_FAILURES,
+            )?,
+            auth_ban_secs: resolver.resolve(
+                "auth-ban-secs",
+                args.auth_ban_secs,
+                DEFAULT_AUTH_BAN_SECS,
+            )?,
+            idle_timeout: resolver.resolve(
+                "idle-timeout",
+                args.idle_timeout,
+                DEFAULT_IDLE_TIMEOUT,
+            )?,
+            write_timeout: resolver.resolve(
+                "write-timeout",
+                args.write_timeout,
+                DEFAULT_WRITE_TIMEOUT,
+            )?,
+            slow_client_timeout: resolver.resolve(
+                "slow-client-timeout",
+                args.slow_client_timeout,
+                DEFAULT_SLOW_CLIENT_TIMEOUT,
+            )?,
+            admin_socket: resolver
+                .resolve_optional("admin-socket", args.admin_socket)?
+                .map(PathBuf::from),
+            shutdown_timeout: resolver.resolve(
+                "shutdown-timeout",
+                args.shutdown_timeout,
+                DEFAULT_SHUTDOWN_TIMEOUT,
+            )?,
+            ping_interval: resolver.resolve(
+                "ping-interval",
+                args.ping_interval,
+                DEFAULT_PING_INTERVAL,
+            )?,
+            max_missed_pongs: resolver.resolve(
+                "max-missed-pongs",
+                args.max_missed_pongs,
+                DEFAULT_MAX_MISSED_PONGS,
+            )?,
+            auth_backend: resolver.resolve(
+                "auth-backend",
+                args.auth_backend,
+                AuthBackendKind::Challenge,
+            )?,
+            auth_file: resolver
+                .resolve_optional("auth-file", args.auth_file)?
+                .map(PathBuf::from),
+            auth_webhook: resolver.resolve_optional("auth-webhook", args.auth_webhook)?,
+            allow_file: resolver
+                .resolve_optional("allow-file", args.allow_file)?
+                .map(PathBuf::from),
+            deny_file: resolver
+                .resolve_optional("deny-file", args.deny_file)?
+                .map(PathBuf::from),
+            quota_bytes: resolver.resolve("quota-bytes", args.quota_bytes, DEFAULT_QUOTA_BYTES)?,
+            quota_policy: resolver.resolve(
+                "quota-policy",
+                args.quota_policy,
+                QuotaPolicy::Reject,
+            )?,
+            mailbox_bytes: resolver.resolve(
+                "mailbox-bytes",
+                args.mailbox_bytes,
+                DEFAULT_MAILBOX_BYTES,
+            )?,
+            compression: resolver.resolve(
+                "compression",
+                args.no_compression.then_some(false),
+                true,
+            )?,
+            resume_ttl: resolver.resolve("resume-ttl", args.resume_ttl, DEFAULT_RESUME_TTL)?,
+            sync_window: resolver.resolve("sync-window", args.sync_window, DEFAULT_SYNC_WINDOW)?,
+            sync_window_ttl: resolver.resolve(
+                "sync-window-ttl",
+                args.sync_window_ttl,
+                DEFAULT_SYNC_WINDOW_TTL,
+            )?,
+            deprecated: comma_list(&resolver.resolve(
+                "deprecated",
+                args.deprecated,
+                String::new(),
+            )?),
+            upgrade_rules: UpgradeRules {
+                path: resolver.resolve_optional("ws-path", args.ws_path)?,
+                hosts: comma_list(&resolver.resolve(
+                    "allowed-hosts",
+                    args.allowed_hosts,
+                    String::new(),
+                )?),
+                origins: comma_list(&resolver.resolve(
+                    "allowed-origins",
+                    args.allowed_origins,
+                    String::new(),
+                )?),
+            },
+            db: resolver.resolve_optional("db", args.db)?.map(PathBuf::from),
+            retention_max_age: resolver
+                .resolve_optional("retention-max-age", args.retention_max_age)?,
+            retention_max_bytes: resolver
+                .resolve_optional("retention-max-bytes", args.retention_max_bytes)?,
+            retention_sweep_interval: resolver.resolve(
+                "retention-sweep-interval",
+                args.retention_sweep_interval,
+                DEFAULT_RETENTION_SWEEP_INTERVAL,
+            )?,
+            history_max_age: resolver.resolve_optional("history-max-age", args.history_max_age)?,
+            compact_at: resolver.resolve_optional("compact-at", args.compact_at)?,
+            audit_log: resolver
+                .resolve_optional("audit-log", args.audit_log)?
+                .map(PathBuf::from),
+            audit_salt: resolver
+                .resolve_optional::<String>("audit-salt", args.audit_salt)?
+                .filter(|salt| !salt.is_empty()),
+            log_format: resolver.resolve("log-format", args.log_format, LogFormat::Pretty)?,
+            log_level: resolver.resolve("log-level", args.log_level, DEFAULT_LOG_LEVEL.into())?,
+            log_file: resolver
+                .resolve_optional("log-file", args.log_file)?
+                .map(PathBuf::from),
+            advertise: resolver.resolve_optional("advertise", args.advertise)?,
+            reload: None,
+        };
+        // Mailboxes live in storage, so instances only see each other's through a shared database
+        if config.redis_url.is_some() && config.db.is_none() {
+            bail!("--redis-url needs a --db every instance shares, or mailboxes stay per instance");
+        }
+        Ok(config)
+    }
+}
+
+/// Split a comma separated config value, skipping empty entries
+fn comma_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(String::from)
+        .collect()
+}
+
 /// Entrance point to server from cli
-pub async fn run(args: ServerArgs) -> Result<()> {
-    tracing_subscriber::fmt().init();
+pub async fn run(config: Config) -> Result<()> {
+    logging::init(
+        config.log_format,
+        &config.log_level,
+        config.log_file.as_deref(),
+    )?;
     info!("🏁 Server started");
-    comms::serve(&args.common.address).await?;
+    let storage = storage::build(config.db.as_deref())?;
+    let auth_backend = auth::build(
+        config.auth_backend,
+        config.auth_file.as_deref(),
+        config.auth_webhook.as_deref(),
+        Arc::clone(&storage),
+    )
+    .await?;
+    let retention = retention::RetentionPolicy {
+        max_age: config.retention_max_age.map(Duration::from_secs),
+        max_bytes: config.retention_max_bytes,
+        history_max_age: config.history_max_age.map(Duration::from_secs),
+        sweep_interval: Duration::from_secs(config.retention_sweep_interval.max(1)),
+    };
+    let sweeper = retention.is_enabled().then(|| {
+        tasks::spawn(
+            "retention sweep",
+            retention::sweep(retention, Arc::clone(&storage)),
+        )
+    });
+    let compactor = config
+        .compact_at
+        .map(|at| tasks::spawn("compaction", compaction::schedule(at, Arc::clone(&storage))));
+    let access =
+        access::AccessLists::load(config.allow_file.as_deref(), config.deny_file.as_deref())?;
+    let advertiser = config
+        .advertise
+        .as_deref()
+        .map(|name| advertise::Advertiser::start(name, &config.listen, &config.upgrade_rules))
+        .transpose()?;
+    comms::serve(&config, auth_backend, storage, access).await?;
+    if let Some(advertiser) = advertiser {
+        advertiser.stop();
+    }
+    if let Some(sweeper) = sweeper {
+        sweeper.abort();
+    }
+    if let Some(compactor) = compactor {
+        compactor.abort();
+    }
     info!("🛑 Server stopped");
     Ok(())
 }
+
+/// Stops a server running in another program, which keeps its signals to itself
+#[derive(Clone)]
+pub struct Stop(mpsc::Sender<comms::Control>);
+
+impl Stop {
+    /// Tell the server to shut down. Its future returns once the connections are closed.
+    pub async fn shutdown(&self) {
+        // Nothing to stop if the server already returned
+        _ = self.0.send(comms::Control::Shutdown).await;
+    }
+}
+
+/// Start a server embedded in the client, so two people can chat directly with one of them
+/// listening. Only the given pubkeys can authenticate, and nothing is kept on disk. Logs go
+/// wherever the client's do. Returns once listening, with the server to run and its stop handle.
+pub async fn direct(
+    listen: &str,
+    allow: HashSet<String>,
+) -> Result<(Stop, impl Future<Output = Result<()>> + Send + 'static)> {
+    let (_, stop, server) = in_memory(listen, access::AccessLists::only(allow)).await?;
+    info!("🤝 Listening on {listen} for a direct chat");
+    Ok((stop, server))
+}
+
+/// A server running in another program, see [`embedded`]
+pub struct EmbeddedServer {
+    addr: SocketAddr,
+    stop: Stop,
+    server: JoinHandle<Result<()>>,
+}
+
+impl EmbeddedServer {
+    /// Address the server is listening on, with the port picked if the one asked for was 0
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Tell clients the server is shutting down and return once their connections are closed
+    pub async fn shutdown(self) -> Result<()> {
+        self.stop.shutdown().await;
+        self.server.await?
+    }
+}
+
+/// Start a server for embedding in other programs, e.g. to test bots against, with the default
+/// settings and nothing kept on disk. Anyone can authenticate. Returns once listening, with the
+/// server running in a task until it's shut down. Signals are left to the program.
+pub async fn embedded(listen: &str) -> Result<EmbeddedServer> {
+    let (addr, stop, server) = in_memory(listen, access::AccessLists::load(None, None)?).await?;
+    Ok(EmbeddedServer {
+        addr,
+        stop,
+        server: tasks::spawn("embedded server", server),
+    })
+}
+
+/// Bind a server with the default settings that keeps everything in memory
+async fn in_memory(
+    listen: &str,
+    access: access::AccessLists,
+) -> Result<(
+    SocketAddr,
+    Stop,
+    impl Future<Output = Result<()>> + Send + 'static,
+)> {
+    let args = ServerArgs::try_parse_from(["serve", "--listen", listen])?;
+    let config = Config::resolve(args, &mut Resolver::defaults("server"))?;
+    let storage = storage::build(None)?;
+    let auth_backend = auth::build(config.auth_backend, None, None, Arc::clone(&storage)).await?;
+    let listeners = comms::bind(&config).await?;
+    let (_, listener) = listeners
+        .first()
+        .ok_or(anyhow!("No address to listen on"))?;
+    let addr = listener.local_addr()?;
+    let (control_tx, control_rx) = mpsc::channel(1);
+    Ok((addr, Stop(control_tx), async move {
+        comms::serve_on(
+            listeners,
+            &config,
+            auth_backend,
+            storage,
+            access,
+            control_rx,
+        )
+        .await
+    }))
+}
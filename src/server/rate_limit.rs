@@ -0,0 +1,68 @@
+use std::collections::HashMap;
+use tokio::time::Instant;
+
+/// Buckets kept before dropping the ones that have refilled, which act the same as no bucket
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// Token bucket per key, e.g. per pubkey so reconnecting doesn't reset a user's budget
+pub struct RateLimiter {
+    /// Tokens per second each bucket refills by, 0 for unlimited
+    rate: u32,
+    /// Max tokens that can be taken in a burst
+    burst: u32,
+    buckets: HashMap<String, Bucket>,
+    /// Bucket count to next prune at
+    prune_at: usize,
+}
+
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u32, burst: u32) -> Self {
+        Self {
+            rate,
+            burst: burst.max(1),
+            buckets: HashMap::new(),
+            prune_at: PRUNE_THRESHOLD,
+        }
+    }
+
+    /// Change the rate and burst, e.g. on reload. Buckets keep their tokens, capped at the new
+    /// burst when next taken from.
+    pub fn set_rate(&mut self, rate: u32, burst: u32) {
+        self.rate = rate;
+        self.burst = burst.max(1);
+    }
+
+    /// Take a token for the key, returning whether it is within its rate
+    pub fn take(&mut self, key: &str) -> bool {
+        if self.rate == 0 {
+            return true;
+        }
+        let now = Instant::now();
+        let burst = f64::from(self.burst);
+        if self.buckets.len() >= self.prune_at {
+            let rate = f64::from(self.rate);
+            self.buckets.retain(|_, bucket| {
+                bucket.tokens + now.duration_since(bucket.refilled_at).as_secs_f64() * rate < burst
+            });
+            self.prune_at = (self.buckets.len() * 2).max(PRUNE_THRESHOLD);
+        }
+        let bucket = self.buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: burst,
+            refilled_at: now,
+        });
+
+        let elapsed = now.duration_since(bucket.refilled_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * f64::from(self.rate)).min(burst);
+        bucket.refilled_at = now;
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+}
@@ -0,0 +1,72 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+use tokio::time::Instant;
+
+use crate::common::Note;
+
+/// Notes recently handed to each user's connection, kept for a while so a client whose
+/// connection dropped can fetch the ones that were lost in flight. Unlike mailboxes it's only
+/// in memory and bounded, it just has to outlast a reconnect.
+pub struct RecentNotes {
+    /// Notes kept per user, 0 to keep none
+    max_notes: usize,
+    /// How long a note is kept after it's delivered
+    ttl: Duration,
+    notes: HashMap<String, VecDeque<(Instant, Note)>>,
+}
+
+impl RecentNotes {
+    pub fn new(max_notes: usize, ttl: Duration) -> Self {
+        Self {
+            max_notes,
+            ttl,
+            notes: HashMap::new(),
+        }
+    }
+
+    /// Remember a note handed to a user, unless it already is, e.g. when it's synced again
+    pub fn record(&mut self, pub_key: &str, note: &Note) {
+        if self.max_notes == 0 {
+            return;
+        }
+        if !self.notes.contains_key(pub_key) {
+            // Forget users who haven't been sent anything for a while
+            let ttl = self.ttl;
+            self.notes.retain(|_, notes| {
+                notes
+                    .back()
+                    .is_some_and(|(delivered_at, _)| delivered_at.elapsed() < ttl)
+            });
+        }
+        let notes = self.notes.entry(pub_key.to_string()).or_default();
+        if notes.iter().any(|(_, recent)| recent.id == note.id) {
+            return;
+        }
+        notes.push_back((Instant::now(), note.clone()));
+        while notes.len() > self.max_notes {
+            notes.pop_front();
+        }
+    }
+
+    /// Notes handed to a user after the one with an id, oldest first. All that are kept if we
+    /// no longer have it, the client drops the ones it already got.
+    pub fn since(&mut self, pub_key: &str, after: Option<&str>) -> Vec<Note> {
+        let Some(notes) = self.notes.get_mut(pub_key) else {
+            return vec![];
+        };
+        while notes
+            .front()
+            .is_some_and(|(delivered_at, _)| delivered_at.elapsed() >= self.ttl)
+        {
+            notes.pop_front();
+        }
+        let start = after
+            .and_then(|after| notes.iter().position(|(_, note)| note.id == after))
+            .map_or(0, |i| i + 1);
+        notes
+            .iter()
+            .skip(start)
+            .map(|(_, note)| note.clone())
+            .collect()
+    }
+}
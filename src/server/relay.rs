@@ -0,0 +1,130 @@
+use clap::ValueEnum;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::{fmt, str::FromStr};
+use tokio::sync::Notify;
+
+use crate::common::{Note, ServerMsg};
+
+/// What to do when a connection's relay queue is full
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum OverflowPolicy {
+    /// Drop the oldest queued message to make room
+    DropOldest,
+    /// Drop the new message, telling the sender when it's a note
+    DropNew,
+    /// Disconnect the client that isn't keeping up
+    Disconnect,
+}
+
+/// Messages relayed to a connection from the others. It's bounded and never blocks, so a client
+/// that can't keep up doesn't hold up the connections sending to it.
+pub struct Relay {
+    queue: Mutex<VecDeque<ServerMsg>>,
+    capacity: usize,
+    policy: OverflowPolicy,
+    /// Notified when a message is queued
+    ready: Notify,
+    /// Notified when a message is refused under the disconnect policy
+    overflowed: Notify,
+    /// Messages dropped because the queue was full
+    dropped: AtomicU64,
+}
+
+/// The relay queue was full, so the message was dropped
+#[derive(Debug)]
+pub struct Full;
+
+impl Relay {
+    pub fn new(capacity: usize, policy: OverflowPolicy) -> Self {
+        Self {
+            queue: Mutex::new(VecDeque::new()),
+            capacity: capacity.max(1),
+            policy,
+            ready: Notify::new(),
+            overflowed: Notify::new(),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Queue a message without waiting, applying the overflow policy if the queue is full
+    pub fn push(&self, msg: ServerMsg) -> Result<(), Full> {
+        let mut queue = self.queue.lock().expect("Relay queue lock poisoned");
+        if queue.len() >= self.capacity {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            match self.policy {
+                OverflowPolicy::DropOldest => _ = queue.pop_front(),
+                OverflowPolicy::DropNew => return Err(Full),
+                OverflowPolicy::Disconnect => {
+                    self.overflowed.notify_one();
+                    return Err(Full);
+                }
+            }
+        }
+        queue.push_back(msg);
+        drop(queue);
+        self.ready.notify_one();
+        Ok(())
+    }
+
+    /// Wait for the next queued message. Cancel safe, a message is only taken when it's returned.
+    pub async fn pop(&self) -> ServerMsg {
+        loop {
+            if let Some(msg) = self
+                .queue
+                .lock()
+                .expect("Relay queue lock poisoned")
+                .pop_front()
+            {
+                return msg;
+            }
+            self.ready.notified().await;
+        }
+    }
+
+    /// Wait until the queue overflows under the disconnect policy
+    pub async fn overflowed(&self) {
+        self.overflowed.notified().await;
+    }
+
+    /// Notes still waiting to be delivered, e.g. to keep them when the connection drops
+    pub fn queued_notes(&self) -> Vec<Note> {
+        self.queue
+            .lock()
+            .expect("Relay queue lock poisoned")
+            .iter()
+            .filter_map(|msg| match msg {
+                ServerMsg::RecNote(note) => Some(note.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.lock().expect("Relay queue lock poisoned").len()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        <Self as ValueEnum>::from_str(s, true)
+    }
+}
+
+impl fmt::Display for OverflowPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = self.to_possible_value().ok_or(fmt::Error)?;
+        write!(f, "{}", name.get_name())
+    }
+}
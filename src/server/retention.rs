@@ -0,0 +1,82 @@
+use anyhow::Result;
+use chrono::{TimeDelta, Utc};
+use std::{sync::Arc, time::Duration};
+use tokio::time::{self, MissedTickBehavior};
+use tracing::{error, info};
+
+use super::storage::{note_size, Storage};
+
+/// How long queued notes are kept for offline users, and archived notes for history
+pub struct RetentionPolicy {
+    /// Drop notes queued longer ago than this
+    pub max_age: Option<Duration>,
+    /// Drop a recipient's oldest notes while their queued notes are bigger than this
+    pub max_bytes: Option<usize>,
+    /// Drop notes archived longer ago than this
+    pub history_max_age: Option<Duration>,
+    /// How often to sweep
+    pub sweep_interval: Duration,
+}
+
+impl RetentionPolicy {
+    /// Whether the policy ever drops anything
+    pub fn is_enabled(&self) -> bool {
+        self.max_age.is_some() || self.max_bytes.is_some() || self.history_max_age.is_some()
+    }
+}
+
+/// Periodically prune queued notes that are past the retention policy
+pub async fn sweep(policy: RetentionPolicy, storage: Arc<dyn Storage>) {
+    info!(
+        "🧹 Sweeping queued notes every {}s, max age {:?}, max bytes per user {:?}, history max \
+         age {:?}",
+        policy.sweep_interval.as_secs(),
+        policy.max_age,
+        policy.max_bytes,
+        policy.history_max_age
+    );
+    let mut interval = time::interval(policy.sweep_interval);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        if let Err(e) = sweep_once(&policy, storage.as_ref()).await {
+            error!("🧹 Error sweeping queued notes: {e}");
+        }
+    }
+}
+
+async fn sweep_once(policy: &RetentionPolicy, storage: &dyn Storage) -> Result<()> {
+    if let Some(max_age) = policy.max_age {
+        let cutoff = Utc::now() - TimeDelta::from_std(max_age)?;
+        for note in storage.remove_queued_before(cutoff).await? {
+            info!(
+                "🧹 Dropped note {} to {} of {} bytes, queued for longer than {}s",
+                note.id,
+                note.to,
+                note_size(&note),
+                max_age.as_secs()
+            );
+        }
+    }
+    if let Some(max_bytes) = policy.max_bytes {
+        for note in storage.remove_queued_over(max_bytes).await? {
+            info!(
+                "🧹 Dropped note {} to {} of {} bytes, recipient has over {max_bytes} bytes queued",
+                note.id,
+                note.to,
+                note_size(&note)
+            );
+        }
+    }
+    if let Some(history_max_age) = policy.history_max_age {
+        let cutoff = Utc::now() - TimeDelta::from_std(history_max_age)?;
+        let removed = storage.remove_archived_before(cutoff).await?;
+        if removed > 0 {
+            info!(
+                "🧹 Dropped {removed} archived notes, archived for longer than {}s",
+                history_max_age.as_secs()
+            );
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,63 @@
+use anyhow::{Context, Result};
+
+/// A unix signal the server acts on. Never fires on other platforms.
+pub struct Signal {
+    #[cfg(unix)]
+    signal: tokio::signal::unix::Signal,
+}
+
+impl Signal {
+    /// SIGUSR2, telling the server to hand off to a new process
+    pub fn drain() -> Result<Self> {
+        #[cfg(unix)]
+        return Self::new(tokio::signal::unix::SignalKind::user_defined2(), "SIGUSR2");
+        #[cfg(not(unix))]
+        return Ok(Self {});
+    }
+
+    /// SIGTERM, telling the server to shut down, e.g. from a service manager
+    pub fn terminate() -> Result<Self> {
+        #[cfg(unix)]
+        return Self::new(tokio::signal::unix::SignalKind::terminate(), "SIGTERM");
+        #[cfg(not(unix))]
+        return Ok(Self {});
+    }
+
+    /// SIGHUP, telling the server to reload its access lists, rates, limits and timeouts
+    pub fn reload() -> Result<Self> {
+        #[cfg(unix)]
+        return Self::new(tokio::signal::unix::SignalKind::hangup(), "SIGHUP");
+        #[cfg(not(unix))]
+        return Ok(Self {});
+    }
+
+    #[cfg(unix)]
+    fn new(kind: tokio::signal::unix::SignalKind, name: &str) -> Result<Self> {
+        let signal =
+            tokio::signal::unix::signal(kind).context(format!("Error listening for {name}"))?;
+        Ok(Self { signal })
+    }
+
+    /// Wait for the signal
+    #[cfg(unix)]
+    pub async fn recv(&mut self) {
+        self.signal.recv().await;
+    }
+
+    #[cfg(not(unix))]
+    pub async fn recv(&mut self) {
+        std::future::pending().await
+    }
+}
+
+/// Wait for ctrl-c or SIGTERM, returning the name of the one received
+pub async fn shutdown() -> Result<&'static str> {
+    let mut terminate = Signal::terminate()?;
+    tokio::select! {
+        res = tokio::signal::ctrl_c() => {
+            res.context("Error listening for ctrl-c")?;
+            Ok("ctrl-c")
+        }
+        _ = terminate.recv() => Ok("SIGTERM"),
+    }
+}
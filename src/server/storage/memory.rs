@@ -0,0 +1,293 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use std::collections::{HashMap, HashSet, VecDeque};
+use tokio::sync::Mutex;
+
+use super::{note_size, Invite, Storage};
+use crate::common::{Maintenance, Note, Revocation};
+
+/// Storage that only lasts as long as the process
+#[derive(Default)]
+pub struct MemoryStorage {
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    users: HashSet<String>,
+    bans: HashSet<String>,
+    /// Revoked keys, oldest first
+    revocations: Vec<Revocation>,
+    /// Queued notes, oldest first
+    queued: VecDeque<Queued>,
+    /// Archived notes, oldest first
+    archived: VecDeque<Archived>,
+    /// Invite codes and whether they have been used
+    invites: HashMap<String, (Invite, bool)>,
+    maintenance: Option<Maintenance>,
+}
+
+struct Queued {
+    /// Tracked separately since sealed sender notes don't show it
+    sender: String,
+    note: Note,
+    queued_at: DateTime<Utc>,
+}
+
+struct Archived {
+    sender: String,
+    note: Note,
+    archived_at: DateTime<Utc>,
+}
+
+impl Archived {
+    /// Whether the note is between a user and a peer, either way
+    fn is_between(&self, pub_key: &str, peer: &str) -> bool {
+        (self.sender == pub_key && self.note.to == peer)
+            || (self.sender == peer && self.note.to == pub_key)
+    }
+}
+
+impl MemoryStorage {
+    async fn queued_bytes(&self, filter: impl Fn(&Queued) -> bool) -> usize {
+        self.state
+            .lock()
+            .await
+            .queued
+            .iter()
+            .filter(|queued| filter(queued))
+            .map(|queued| note_size(&queued.note))
+            .sum()
+    }
+
+    async fn remove_oldest(&self, filter: impl Fn(&Queued) -> bool) -> Option<Note> {
+        let mut state = self.state.lock().await;
+        let i = state.queued.iter().position(filter)?;
+        state.queued.remove(i).map(|queued| queued.note)
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn add_user(&self, pub_key: &str) -> Result<bool> {
+        Ok(self.state.lock().await.users.insert(pub_key.to_string()))
+    }
+
+    async fn is_user(&self, pub_key: &str) -> Result<bool> {
+        Ok(self.state.lock().await.users.contains(pub_key))
+    }
+
+    async fn is_banned(&self, pub_key: &str) -> Result<bool> {
+        Ok(self.state.lock().await.bans.contains(pub_key))
+    }
+
+    async fn ban(&self, pub_key: &str, _reason: Option<&str>) -> Result<()> {
+        self.state.lock().await.bans.insert(pub_key.to_string());
+        Ok(())
+    }
+
+    async fn revoke(&self, revocation: &Revocation) -> Result<()> {
+        let mut state = self.state.lock().await;
+        if !state
+            .revocations
+            .iter()
+            .any(|r| r.pub_key == revocation.pub_key)
+        {
+            state.revocations.push(revocation.clone());
+        }
+        state.bans.insert(revocation.pub_key.clone());
+        Ok(())
+    }
+
+    async fn revocations(&self) -> Result<Vec<Revocation>> {
+        Ok(self.state.lock().await.revocations.clone())
+    }
+
+    async fn queue_note(&self, sender: &str, note: &Note) -> Result<()> {
+        self.state.lock().await.queued.push_back(Queued {
+            sender: sender.to_string(),
+            note: note.clone(),
+            queued_at: Utc::now(),
+        });
+        Ok(())
+    }
+
+    async fn take_queued_notes(&self, recipient: &str) -> Result<Vec<Note>> {
+        let mut state = self.state.lock().await;
+        let (taken, kept) = state
+            .queued
+            .drain(..)
+            .partition(|queued| queued.note.to == recipient);
+        state.queued = kept;
+        Ok(taken.into_iter().map(|queued| queued.note).collect())
+    }
+
+    async fn queued_bytes_from(&self, sender: &str) -> Result<usize> {
+        Ok(self.queued_bytes(|queued| queued.sender == sender).await)
+    }
+
+    async fn queued_bytes_to(&self, recipient: &str) -> Result<usize> {
+        Ok(self
+            .queued_bytes(|queued| queued.note.to == recipient)
+            .await)
+    }
+
+    async fn queued_count_to(&self, recipient: &str) -> Result<usize> {
+        Ok(self
+            .state
+            .lock()
+            .await
+            .queued
+            .iter()
+            .filter(|queued| queued.note.to == recipient)
+            .count())
+    }
+
+    /// Frees capacity left over from removed notes, which isn't measured
+    async fn compact(&self) -> Result<u64> {
+        let mut state = self.state.lock().await;
+        state.queued.shrink_to_fit();
+        state.invites.shrink_to_fit();
+        Ok(0)
+    }
+
+    async fn remove_oldest_queued_from(&self, sender: &str) -> Result<Option<Note>> {
+        Ok(self.remove_oldest(|queued| queued.sender == sender).await)
+    }
+
+    async fn remove_oldest_queued_to(&self, recipient: &str) -> Result<Option<Note>> {
+        Ok(self
+            .remove_oldest(|queued| queued.note.to == recipient)
+            .await)
+    }
+
+    async fn remove_queued_before(&self, cutoff: DateTime<Utc>) -> Result<Vec<Note>> {
+        let mut state = self.state.lock().await;
+        let (removed, kept) = state
+            .queued
+            .drain(..)
+            .partition(|queued| queued.queued_at < cutoff);
+        state.queued = kept;
+        Ok(removed.into_iter().map(|queued| queued.note).collect())
+    }
+
+    async fn remove_queued_over(&self, max_bytes: usize) -> Result<Vec<Note>> {
+        let mut state = self.state.lock().await;
+        // Walk newest first, keeping notes until each recipient's total would go over
+        let mut kept_bytes: HashMap<String, usize> = HashMap::new();
+        let mut removed = vec![];
+        let mut kept = VecDeque::new();
+        for queued in state.queued.drain(..).rev() {
+            let used = kept_bytes.entry(queued.note.to.clone()).or_default();
+            *used = used.saturating_add(note_size(&queued.note));
+            if *used > max_bytes {
+                // Once over, everything older goes too
+                *used = usize::MAX;
+                removed.push(queued.note);
+            } else {
+                kept.push_front(queued);
+            }
+        }
+        state.queued = kept;
+        removed.reverse();
+        Ok(removed)
+    }
+
+    async fn archive_note(&self, sender: &str, note: &Note) -> Result<()> {
+        self.state.lock().await.archived.push_back(Archived {
+            sender: sender.to_string(),
+            note: note.clone(),
+            archived_at: Utc::now(),
+        });
+        Ok(())
+    }
+
+    async fn archived_notes(
+        &self,
+        pub_key: &str,
+        peer: &str,
+        before_id: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<Note>> {
+        let state = self.state.lock().await;
+        let between = state
+            .archived
+            .iter()
+            .filter(|archived| archived.is_between(pub_key, peer));
+        let end = match before_id {
+            Some(before_id) => {
+                let Some(end) = between
+                    .clone()
+                    .position(|archived| archived.note.id == before_id)
+                else {
+                    return Ok(vec![]);
+                };
+                end
+            }
+            None => between.clone().count(),
+        };
+        Ok(between
+            .take(end)
+            .skip(end.saturating_sub(limit))
+            .map(|archived| archived.note.clone())
+            .collect())
+    }
+
+    async fn remove_archived_before(&self, cutoff: DateTime<Utc>) -> Result<usize> {
+        let mut state = self.state.lock().await;
+        let before = state.archived.len();
+        state
+            .archived
+            .retain(|archived| archived.archived_at >= cutoff);
+        Ok(before - state.archived.len())
+    }
+
+    async fn add_invite(&self, invite: &Invite) -> Result<()> {
+        self.state
+            .lock()
+            .await
+            .invites
+            .entry(invite.code.clone())
+            .or_insert_with(|| (invite.clone(), false));
+        Ok(())
+    }
+
+    async fn is_valid_invite(&self, code: &str) -> Result<bool> {
+        let state = self.state.lock().await;
+        Ok(state
+            .invites
+            .get(code)
+            .is_some_and(|(invite, used)| invite_valid(invite, *used)))
+    }
+
+    async fn redeem_invite(&self, code: &str, pub_key: &str) -> Result<bool> {
+        let mut state = self.state.lock().await;
+        let Some((invite, used)) = state.invites.get_mut(code) else {
+            return Ok(false);
+        };
+        if !invite_valid(invite, *used) {
+            return Ok(false);
+        }
+        *used = true;
+        state.users.insert(pub_key.to_string());
+        Ok(true)
+    }
+
+    async fn set_maintenance(&self, maintenance: Option<&Maintenance>) -> Result<()> {
+        self.state.lock().await.maintenance = maintenance.cloned();
+        Ok(())
+    }
+
+    async fn maintenance(&self) -> Result<Option<Maintenance>> {
+        Ok(self.state.lock().await.maintenance.clone())
+    }
+}
+
+/// Whether an invite can still be used
+fn invite_valid(invite: &Invite, used: bool) -> bool {
+    invite
+        .expires_at
+        .is_none_or(|expires_at| expires_at > Utc::now())
+        && (!used || invite.reusable)
+}
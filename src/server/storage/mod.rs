@@ -0,0 +1,105 @@
+mod memory;
+mod sqlite;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use std::{path::Path, sync::Arc};
+use tracing::{info, warn};
+
+use crate::common::{Maintenance, Note, Revocation};
+
+/// Persistent server state: known users, notes queued for offline users, archived notes, bans,
+/// revoked keys, invite codes and scheduled maintenance
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Record that a user has authenticated, returning whether they are new
+    async fn add_user(&self, pub_key: &str) -> Result<bool>;
+    /// Whether a user has authenticated before
+    async fn is_user(&self, pub_key: &str) -> Result<bool>;
+    /// Whether a user is banned from the server
+    async fn is_banned(&self, pub_key: &str) -> Result<bool>;
+    /// Ban a user from the server
+    async fn ban(&self, pub_key: &str, reason: Option<&str>) -> Result<()>;
+    /// Record a key's revocation and ban it, so it can't be used on the server again
+    async fn revoke(&self, revocation: &Revocation) -> Result<()>;
+    /// Every revocation, oldest first
+    async fn revocations(&self) -> Result<Vec<Revocation>>;
+
+    /// Queue a note for an offline recipient, charged to the sender
+    async fn queue_note(&self, sender: &str, note: &Note) -> Result<()>;
+    /// Remove and return all notes queued for a recipient, oldest first
+    async fn take_queued_notes(&self, recipient: &str) -> Result<Vec<Note>>;
+    /// Bytes of queued notes stored by a sender
+    async fn queued_bytes_from(&self, sender: &str) -> Result<usize>;
+    /// Bytes of queued notes waiting for a recipient
+    async fn queued_bytes_to(&self, recipient: &str) -> Result<usize>;
+    /// Number of queued notes waiting for a recipient
+    async fn queued_count_to(&self, recipient: &str) -> Result<usize>;
+    /// Reclaim the space left by removed data, returning how many bytes were reclaimed
+    async fn compact(&self) -> Result<u64>;
+    /// Remove and return the oldest note queued by a sender
+    async fn remove_oldest_queued_from(&self, sender: &str) -> Result<Option<Note>>;
+    /// Remove and return the oldest note queued for a recipient
+    async fn remove_oldest_queued_to(&self, recipient: &str) -> Result<Option<Note>>;
+    /// Remove and return notes queued before a time
+    async fn remove_queued_before(&self, cutoff: DateTime<Utc>) -> Result<Vec<Note>>;
+    /// Remove and return each recipient's oldest notes until they have at most `max_bytes` queued
+    async fn remove_queued_over(&self, max_bytes: usize) -> Result<Vec<Note>>;
+
+    /// Keep a relayed note so its sender and recipient can fetch it again as history
+    async fn archive_note(&self, sender: &str, note: &Note) -> Result<()>;
+    /// Up to `limit` archived notes between a user and a peer, oldest first. The newest ones, or
+    /// the ones before the note with `before_id`, none if that note isn't archived.
+    async fn archived_notes(
+        &self,
+        pub_key: &str,
+        peer: &str,
+        before_id: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<Note>>;
+    /// Remove notes archived before a time, returning how many were removed
+    async fn remove_archived_before(&self, cutoff: DateTime<Utc>) -> Result<usize>;
+
+    /// Add an invite code, codes that were already used stay used
+    async fn add_invite(&self, invite: &Invite) -> Result<()>;
+    /// Whether an invite code is valid and unexpired, without using it up
+    async fn is_valid_invite(&self, code: &str) -> Result<bool>;
+    /// Use up an invite code and add the user it admits in one step, returning whether the code
+    /// was still valid and unexpired
+    async fn redeem_invite(&self, code: &str, pub_key: &str) -> Result<bool>;
+
+    /// Schedule downtime, replacing any already scheduled, or cancel it with None
+    async fn set_maintenance(&self, maintenance: Option<&Maintenance>) -> Result<()>;
+    /// Scheduled downtime, if any
+    async fn maintenance(&self) -> Result<Option<Maintenance>>;
+}
+
+/// A code that lets an unknown pubkey join the server
+#[derive(Clone)]
+pub struct Invite {
+    pub code: String,
+    /// When the code stops working, if ever
+    pub expires_at: Option<DateTime<Utc>>,
+    /// Whether the code can be used any number of times until it expires
+    pub reusable: bool,
+}
+
+/// Open SQLite storage if a database path is set, otherwise keep everything in memory
+pub fn build(db: Option<&Path>) -> Result<Arc<dyn Storage>> {
+    Ok(match db {
+        Some(path) => {
+            info!("💾 Using SQLite storage at {}", path.display());
+            Arc::new(sqlite::SqliteStorage::open(path)?)
+        }
+        None => {
+            warn!("💾 Using in-memory storage, everything will be lost on restart");
+            Arc::new(memory::MemoryStorage::default())
+        }
+    })
+}
+
+/// Bytes a note takes up in storage
+pub fn note_size(note: &Note) -> usize {
+    note.encrypted_content.len()
+}
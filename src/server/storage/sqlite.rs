@@ -0,0 +1,565 @@
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::task;
+use tracing::info;
+
+use super::{note_size, Invite, Storage};
+use crate::common::{Maintenance, Note, Revocation};
+
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Schema migrations, applied in order at startup. The database's `user_version` records how
+/// many have been applied. Only ever append to this list, never edit an existing migration.
+const MIGRATIONS: &[&str] = &[
+    // 1: initial schema
+    "CREATE TABLE users (
+        pub_key TEXT PRIMARY KEY,
+        first_seen TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+    );
+    CREATE TABLE bans (
+        pub_key TEXT PRIMARY KEY,
+        reason TEXT,
+        created TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+    );
+    CREATE TABLE queued_notes (
+        seq INTEGER PRIMARY KEY AUTOINCREMENT,
+        sender TEXT NOT NULL,
+        recipient TEXT NOT NULL,
+        size INTEGER NOT NULL,
+        note TEXT NOT NULL
+    );
+    CREATE INDEX queued_notes_recipient ON queued_notes (recipient);
+    CREATE INDEX queued_notes_sender ON queued_notes (sender);
+    CREATE TABLE invites (
+        code TEXT PRIMARY KEY,
+        used INTEGER NOT NULL DEFAULT 0
+    );",
+    // 2: track when notes were queued for retention
+    "ALTER TABLE queued_notes ADD COLUMN queued_at INTEGER NOT NULL DEFAULT 0;
+    UPDATE queued_notes SET queued_at = CAST(strftime('%s', 'now') AS INTEGER);",
+    // 3: expiring and reusable invites
+    "ALTER TABLE invites ADD COLUMN expires_at INTEGER;
+    ALTER TABLE invites ADD COLUMN reusable INTEGER NOT NULL DEFAULT 0;",
+    // 4: scheduled maintenance, at most one row
+    "CREATE TABLE maintenance (
+        id INTEGER PRIMARY KEY CHECK (id = 1),
+        starts_at INTEGER NOT NULL,
+        duration_secs INTEGER NOT NULL,
+        message TEXT NOT NULL
+    );",
+    // 5: key revocations, relayed to clients
+    "CREATE TABLE revocations (
+        seq INTEGER PRIMARY KEY AUTOINCREMENT,
+        pub_key TEXT NOT NULL UNIQUE,
+        revocation TEXT NOT NULL
+    );",
+    // 6: relayed notes kept for clients to fetch as history
+    "CREATE TABLE archived_notes (
+        seq INTEGER PRIMARY KEY AUTOINCREMENT,
+        note_id TEXT NOT NULL,
+        sender TEXT NOT NULL,
+        recipient TEXT NOT NULL,
+        note TEXT NOT NULL,
+        archived_at INTEGER NOT NULL
+    );
+    CREATE INDEX archived_notes_sender ON archived_notes (sender, recipient);
+    CREATE INDEX archived_notes_recipient ON archived_notes (recipient, sender);
+    CREATE INDEX archived_notes_archived_at ON archived_notes (archived_at);",
+];
+
+/// Storage in a SQLite database file, so it survives restarts. Queries block, so they run on
+/// tokio's blocking thread pool rather than stalling the runtime.
+pub struct SqliteStorage {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStorage {
+    /// Open or create the database and bring its schema up to date
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut conn =
+            Connection::open(path).context(format!("Error opening database {}", path.display()))?;
+        // Two servers share the database while handing off during an upgrade
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        migrate(&mut conn)?;
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    /// Run queries with the connection on the blocking thread pool
+    async fn with_conn<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut Connection) -> Result<T> + Send + 'static,
+    ) -> Result<T> {
+        let conn = Arc::clone(&self.conn);
+        task::spawn_blocking(move || f(&mut conn.lock().unwrap_or_else(|e| e.into_inner()))).await?
+    }
+}
+
+/// Bytes of queued notes where `column` is `pub_key`
+fn queued_bytes(conn: &Connection, column: &'static str, pub_key: &str) -> Result<usize> {
+    Ok(conn.query_row(
+        &format!("SELECT COALESCE(SUM(size), 0) FROM queued_notes WHERE {column} = ?1"),
+        params![pub_key],
+        |row| row.get(0),
+    )?)
+}
+
+/// Remove the oldest queued note where `column` is `pub_key`
+fn remove_oldest(
+    conn: &mut Connection,
+    column: &'static str,
+    pub_key: &str,
+) -> Result<Option<Note>> {
+    let tx = conn.transaction()?;
+    let oldest: Option<(i64, String)> = tx
+        .query_row(
+            &format!("SELECT seq, note FROM queued_notes WHERE {column} = ?1 ORDER BY seq LIMIT 1"),
+            params![pub_key],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()?;
+    let Some((seq, json)) = oldest else {
+        return Ok(None);
+    };
+    tx.execute("DELETE FROM queued_notes WHERE seq = ?1", params![seq])?;
+    tx.commit()?;
+    Ok(Some(serde_json::from_str(&json)?))
+}
+
+/// Apply any migrations the database hasn't had yet, refusing to touch a database written by a
+/// newer server
+fn migrate(conn: &mut Connection) -> Result<()> {
+    let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    if version > MIGRATIONS.len() {
+        bail!(
+            "Database schema version {version} is newer than this server supports ({}), \
+             refusing to downgrade",
+            MIGRATIONS.len()
+        );
+    }
+
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)
+            .context(format!("Error applying database migration {}", i + 1))?;
+        tx.pragma_update(None, "user_version", i + 1)?;
+        tx.commit()?;
+        info!("💾 Applied database migration {}", i + 1);
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl Storage for SqliteStorage {
+    async fn add_user(&self, pub_key: &str) -> Result<bool> {
+        let pub_key = pub_key.to_string();
+        self.with_conn(move |conn| {
+            let inserted = conn.execute(
+                "INSERT OR IGNORE INTO users (pub_key) VALUES (?1)",
+                params![pub_key],
+            )?;
+            Ok(inserted > 0)
+        })
+        .await
+    }
+
+    async fn is_user(&self, pub_key: &str) -> Result<bool> {
+        let pub_key = pub_key.to_string();
+        self.with_conn(move |conn| {
+            let user = conn
+                .query_row(
+                    "SELECT 1 FROM users WHERE pub_key = ?1",
+                    params![pub_key],
+                    |_| Ok(()),
+                )
+                .optional()?;
+            Ok(user.is_some())
+        })
+        .await
+    }
+
+    async fn is_banned(&self, pub_key: &str) -> Result<bool> {
+        let pub_key = pub_key.to_string();
+        self.with_conn(move |conn| {
+            let banned = conn
+                .query_row(
+                    "SELECT 1 FROM bans WHERE pub_key = ?1",
+                    params![pub_key],
+                    |_| Ok(()),
+                )
+                .optional()?;
+            Ok(banned.is_some())
+        })
+        .await
+    }
+
+    async fn ban(&self, pub_key: &str, reason: Option<&str>) -> Result<()> {
+        let pub_key = pub_key.to_string();
+        let reason = reason.map(String::from);
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO bans (pub_key, reason) VALUES (?1, ?2)",
+                params![pub_key, reason],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn revoke(&self, revocation: &Revocation) -> Result<()> {
+        let pub_key = revocation.pub_key.clone();
+        let json = serde_json::to_string(revocation)?;
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute(
+                "INSERT OR IGNORE INTO revocations (pub_key, revocation) VALUES (?1, ?2)",
+                params![pub_key, json],
+            )?;
+            tx.execute(
+                "INSERT OR REPLACE INTO bans (pub_key, reason) VALUES (?1, 'key revoked')",
+                params![pub_key],
+            )?;
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn revocations(&self) -> Result<Vec<Revocation>> {
+        self.with_conn(|conn| {
+            let mut stmt = conn.prepare("SELECT revocation FROM revocations ORDER BY seq")?;
+            let jsons = stmt
+                .query_map([], |row| row.get::<_, String>(0))?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            jsons
+                .iter()
+                .map(|json| Ok(serde_json::from_str(json)?))
+                .collect()
+        })
+        .await
+    }
+
+    async fn queue_note(&self, sender: &str, note: &Note) -> Result<()> {
+        let sender = sender.to_string();
+        let recipient = note.to.clone();
+        let size = note_size(note);
+        let json = serde_json::to_string(note)?;
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT INTO queued_notes (sender, recipient, size, note, queued_at)
+                    VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![sender, recipient, size, json, Utc::now().timestamp()],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn take_queued_notes(&self, recipient: &str) -> Result<Vec<Note>> {
+        let recipient = recipient.to_string();
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            let notes = tx
+                .prepare("SELECT note FROM queued_notes WHERE recipient = ?1 ORDER BY seq")?
+                .query_map(params![recipient], |row| row.get::<_, String>(0))?
+                .map(|json| Ok(serde_json::from_str(&json?)?))
+                .collect::<Result<Vec<Note>>>()?;
+            tx.execute(
+                "DELETE FROM queued_notes WHERE recipient = ?1",
+                params![recipient],
+            )?;
+            tx.commit()?;
+            Ok(notes)
+        })
+        .await
+    }
+
+    async fn queued_bytes_from(&self, sender: &str) -> Result<usize> {
+        let sender = sender.to_string();
+        self.with_conn(move |conn| queued_bytes(conn, "sender", &sender))
+            .await
+    }
+
+    async fn queued_bytes_to(&self, recipient: &str) -> Result<usize> {
+        let recipient = recipient.to_string();
+        self.with_conn(move |conn| queued_bytes(conn, "recipient", &recipient))
+            .await
+    }
+
+    async fn queued_count_to(&self, recipient: &str) -> Result<usize> {
+        let recipient = recipient.to_string();
+        self.with_conn(move |conn| {
+            Ok(conn.query_row(
+                "SELECT COUNT(*) FROM queued_notes WHERE recipient = ?1",
+                params![recipient],
+                |row| row.get(0),
+            )?)
+        })
+        .await
+    }
+
+    async fn compact(&self) -> Result<u64> {
+        self.with_conn(|conn| {
+            let before = db_size(conn)?;
+            conn.execute_batch("VACUUM")?;
+            let after = db_size(conn)?;
+            Ok(before.saturating_sub(after))
+        })
+        .await
+    }
+
+    async fn remove_oldest_queued_from(&self, sender: &str) -> Result<Option<Note>> {
+        let sender = sender.to_string();
+        self.with_conn(move |conn| remove_oldest(conn, "sender", &sender))
+            .await
+    }
+
+    async fn remove_oldest_queued_to(&self, recipient: &str) -> Result<Option<Note>> {
+        let recipient = recipient.to_string();
+        self.with_conn(move |conn| remove_oldest(conn, "recipient", &recipient))
+            .await
+    }
+
+    async fn remove_queued_before(&self, cutoff: DateTime<Utc>) -> Result<Vec<Note>> {
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            let notes = tx
+                .prepare("SELECT note FROM queued_notes WHERE queued_at < ?1 ORDER BY seq")?
+                .query_map(params![cutoff.timestamp()], |row| row.get::<_, String>(0))?
+                .map(|json| Ok(serde_json::from_str(&json?)?))
+                .collect::<Result<Vec<Note>>>()?;
+            tx.execute(
+                "DELETE FROM queued_notes WHERE queued_at < ?1",
+                params![cutoff.timestamp()],
+            )?;
+            tx.commit()?;
+            Ok(notes)
+        })
+        .await
+    }
+
+    async fn remove_queued_over(&self, max_bytes: usize) -> Result<Vec<Note>> {
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            // Walk newest first, keeping notes until each recipient's total would go over
+            let mut kept_bytes: HashMap<String, usize> = HashMap::new();
+            let mut removed = vec![];
+            {
+                let mut stmt = tx.prepare(
+                    "SELECT seq, recipient, size, note FROM queued_notes ORDER BY seq DESC",
+                )?;
+                let mut rows = stmt.query([])?;
+                while let Some(row) = rows.next()? {
+                    let used = kept_bytes.entry(row.get(1)?).or_default();
+                    *used = used.saturating_add(row.get(2)?);
+                    if *used > max_bytes {
+                        // Once over, everything older goes too
+                        *used = usize::MAX;
+                        removed.push((row.get::<_, i64>(0)?, row.get::<_, String>(3)?));
+                    }
+                }
+            }
+            let mut notes = vec![];
+            for (seq, json) in removed.into_iter().rev() {
+                tx.execute("DELETE FROM queued_notes WHERE seq = ?1", params![seq])?;
+                notes.push(serde_json::from_str(&json)?);
+            }
+            tx.commit()?;
+            Ok(notes)
+        })
+        .await
+    }
+
+    async fn archive_note(&self, sender: &str, note: &Note) -> Result<()> {
+        let sender = sender.to_string();
+        let id = note.id.clone();
+        let recipient = note.to.clone();
+        let json = serde_json::to_string(note)?;
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT INTO archived_notes (note_id, sender, recipient, note, archived_at)
+                    VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![id, sender, recipient, json, Utc::now().timestamp()],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn archived_notes(
+        &self,
+        pub_key: &str,
+        peer: &str,
+        before_id: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<Note>> {
+        const BETWEEN: &str =
+            "((sender = ?1 AND recipient = ?2) OR (sender = ?2 AND recipient = ?1))";
+        let pub_key = pub_key.to_string();
+        let peer = peer.to_string();
+        let before_id = before_id.map(String::from);
+        self.with_conn(move |conn| {
+            let before_seq = match before_id {
+                Some(before_id) => {
+                    let seq: Option<i64> = conn
+                        .query_row(
+                            &format!(
+                                "SELECT seq FROM archived_notes WHERE {BETWEEN} AND note_id = ?3"
+                            ),
+                            params![pub_key, peer, before_id],
+                            |row| row.get(0),
+                        )
+                        .optional()?;
+                    let Some(seq) = seq else {
+                        return Ok(vec![]);
+                    };
+                    seq
+                }
+                None => i64::MAX,
+            };
+            let mut notes = conn
+                .prepare(&format!(
+                    "SELECT note FROM archived_notes WHERE {BETWEEN} AND seq < ?3
+                        ORDER BY seq DESC LIMIT ?4"
+                ))?
+                .query_map(params![pub_key, peer, before_seq, limit], |row| {
+                    row.get::<_, String>(0)
+                })?
+                .map(|json| Ok(serde_json::from_str(&json?)?))
+                .collect::<Result<Vec<Note>>>()?;
+            notes.reverse();
+            Ok(notes)
+        })
+        .await
+    }
+
+    async fn remove_archived_before(&self, cutoff: DateTime<Utc>) -> Result<usize> {
+        self.with_conn(move |conn| {
+            Ok(conn.execute(
+                "DELETE FROM archived_notes WHERE archived_at < ?1",
+                params![cutoff.timestamp()],
+            )?)
+        })
+        .await
+    }
+
+    async fn add_invite(&self, invite: &Invite) -> Result<()> {
+        let invite = invite.clone();
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT OR IGNORE INTO invites (code, expires_at, reusable) VALUES (?1, ?2, ?3)",
+                params![
+                    invite.code,
+                    invite.expires_at.map(|expires_at| expires_at.timestamp()),
+                    invite.reusable
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn is_valid_invite(&self, code: &str) -> Result<bool> {
+        let code = code.to_string();
+        self.with_conn(move |conn| {
+            let valid = conn
+                .query_row(
+                    "SELECT 1 FROM invites
+                        WHERE code = ?1
+                        AND (used = 0 OR reusable = 1)
+                        AND (expires_at IS NULL OR expires_at > ?2)",
+                    params![code, Utc::now().timestamp()],
+                    |_| Ok(()),
+                )
+                .optional()?;
+            Ok(valid.is_some())
+        })
+        .await
+    }
+
+    async fn redeem_invite(&self, code: &str, pub_key: &str) -> Result<bool> {
+        let code = code.to_string();
+        let pub_key = pub_key.to_string();
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            let updated = tx.execute(
+                "UPDATE invites SET used = 1
+                    WHERE code = ?1
+                    AND (used = 0 OR reusable = 1)
+                    AND (expires_at IS NULL OR expires_at > ?2)",
+                params![code, Utc::now().timestamp()],
+            )?;
+            if updated == 0 {
+                return Ok(false);
+            }
+            tx.execute(
+                "INSERT OR IGNORE INTO users (pub_key) VALUES (?1)",
+                params![pub_key],
+            )?;
+            tx.commit()?;
+            Ok(true)
+        })
+        .await
+    }
+
+    async fn set_maintenance(&self, maintenance: Option<&Maintenance>) -> Result<()> {
+        let maintenance = maintenance.cloned();
+        self.with_conn(move |conn| {
+            match maintenance {
+                Some(maintenance) => conn.execute(
+                    "INSERT OR REPLACE INTO maintenance (id, starts_at, duration_secs, message)
+                        VALUES (1, ?1, ?2, ?3)",
+                    params![
+                        maintenance.starts_at.timestamp(),
+                        maintenance.duration_secs,
+                        maintenance.message
+                    ],
+                )?,
+                None => conn.execute("DELETE FROM maintenance", [])?,
+            };
+            Ok(())
+        })
+        .await
+    }
+
+    async fn maintenance(&self) -> Result<Option<Maintenance>> {
+        let row: Option<(i64, u64, String)> = self
+            .with_conn(|conn| {
+                Ok(conn
+                    .query_row(
+                        "SELECT starts_at, duration_secs, message FROM maintenance WHERE id = 1",
+                        [],
+                        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+                    )
+                    .optional()?)
+            })
+            .await?;
+        let Some((starts_at, duration_secs, message)) = row else {
+            return Ok(None);
+        };
+        Ok(Some(Maintenance {
+            starts_at: DateTime::from_timestamp(starts_at, 0)
+                .ok_or(anyhow!("Invalid maintenance start time {starts_at}"))?,
+            duration_secs,
+            message,
+        }))
+    }
+}
+
+/// Bytes the database file takes up
+fn db_size(conn: &Connection) -> Result<u64> {
+    Ok(conn.query_row(
+        "SELECT page_count * page_size FROM pragma_page_count(), pragma_page_size()",
+        [],
+        |row| row.get(0),
+    )?)
+}
@@ -0,0 +1,42 @@
+use std::future::Future;
+use tokio::task::{AbortHandle, JoinHandle, JoinSet};
+
+#[cfg(all(feature = "console", not(tokio_unstable)))]
+compile_error!("The console feature needs RUSTFLAGS=\"--cfg tokio_unstable\"");
+
+/// Spawn a task, named so it can be told apart in tokio-console with the console feature
+pub fn spawn<F>(name: &str, future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    #[cfg(all(feature = "console", tokio_unstable))]
+    return tokio::task::Builder::new()
+        .name(name)
+        .spawn(future)
+        .expect("Error spawning task");
+    #[cfg(not(all(feature = "console", tokio_unstable)))]
+    {
+        _ = name;
+        tokio::spawn(future)
+    }
+}
+
+/// Spawn a task into a set, named like [`spawn`]
+pub fn spawn_in<F>(set: &mut JoinSet<F::Output>, name: &str, future: F) -> AbortHandle
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    #[cfg(all(feature = "console", tokio_unstable))]
+    return set
+        .build_task()
+        .name(name)
+        .spawn(future)
+        .expect("Error spawning task");
+    #[cfg(not(all(feature = "console", tokio_unstable)))]
+    {
+        _ = name;
+        set.spawn(future)
+    }
+}
@@ -0,0 +1,120 @@
+use std::{collections::HashMap, hash::Hash, net::IpAddr, time::Duration};
+use tokio::time::Instant;
+use tracing::warn;
+
+/// Wait after the first failure, doubling with each one after
+const BASE_BACKOFF: Duration = Duration::from_secs(1);
+/// Longest a pubkey is made to wait from an IP. Anyone can fail as any pubkey, so pubkeys only back
+/// off per IP and are never banned, otherwise a stranger could lock a user out.
+const MAX_PUB_KEY_BACKOFF: Duration = Duration::from_secs(60);
+/// Entries kept before forgetting the ones that have gone quiet
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// Tracks failed auth attempts per IP and per pubkey from each IP, making them back off
+/// exponentially and temporarily banning IPs that keep failing
+pub struct AuthThrottle {
+    /// Failures an IP can have before it is banned, 0 to never ban
+    max_failures: u32,
+    /// How long bans last, and how long without failures until they are forgotten
+    ban_duration: Duration,
+    ips: HashMap<IpAddr, Failures>,
+    pub_keys: HashMap<(IpAddr, String), Failures>,
+}
+
+#[derive(Default)]
+struct Failures {
+    count: u32,
+    /// When the next attempt is allowed
+    retry_at: Option<Instant>,
+}
+
+impl AuthThrottle {
+    pub fn new(max_failures: u32, ban_duration: Duration) -> Self {
+        Self {
+            max_failures,
+            ban_duration,
+            ips: HashMap::new(),
+            pub_keys: HashMap::new(),
+        }
+    }
+
+    /// Change when IPs are banned and for how long, e.g. on reload
+    pub fn set_limits(&mut self, max_failures: u32, ban_duration: Duration) {
+        self.max_failures = max_failures;
+        self.ban_duration = ban_duration;
+    }
+
+    /// How long the IP or pubkey has to wait before trying again, if at all
+    pub fn check(&self, ip: IpAddr, pub_key: &str) -> Option<Duration> {
+        let now = Instant::now();
+        let ip_wait = self.ips.get(&ip).and_then(|f| f.wait(now));
+        let pub_key_wait = self
+            .pub_keys
+            .get(&(ip, pub_key.to_string()))
+            .and_then(|f| f.wait(now));
+        ip_wait.max(pub_key_wait)
+    }
+
+    /// Record a failed attempt, banning the IP if it has failed too many times
+    pub fn record_failure(&mut self, ip: IpAddr, pub_key: &str) {
+        let now = Instant::now();
+        let ban_duration = self.ban_duration;
+        prune(&mut self.ips, now, ban_duration);
+        prune(&mut self.pub_keys, now, ban_duration);
+
+        let ip_failures = self.ips.entry(ip).or_default();
+        ip_failures.fail(now, ban_duration, ban_duration);
+        if self.max_failures > 0 && ip_failures.count >= self.max_failures {
+            warn!(
+                "🔨 Temporarily banning {ip} for {}s after {} failed auth attempts",
+                ban_duration.as_secs(),
+                ip_failures.count
+            );
+            ip_failures.count = 0;
+            ip_failures.retry_at = Some(now + ban_duration);
+        }
+
+        self.pub_keys
+            .entry((ip, pub_key.to_string()))
+            .or_default()
+            .fail(now, MAX_PUB_KEY_BACKOFF, ban_duration);
+    }
+
+    /// Forget the failures of an IP and pubkey that authenticated successfully
+    pub fn record_success(&mut self, ip: IpAddr, pub_key: &str) {
+        self.ips.remove(&ip);
+        self.pub_keys.remove(&(ip, pub_key.to_string()));
+    }
+}
+
+impl Failures {
+    fn wait(&self, now: Instant) -> Option<Duration> {
+        self.retry_at?
+            .checked_duration_since(now)
+            .filter(|wait| !wait.is_zero())
+    }
+
+    /// Whether it's been long enough since the last failure to forget them
+    fn is_stale(&self, now: Instant, forget_after: Duration) -> bool {
+        self.retry_at
+            .is_none_or(|retry_at| now.saturating_duration_since(retry_at) >= forget_after)
+    }
+
+    fn fail(&mut self, now: Instant, max_backoff: Duration, forget_after: Duration) {
+        if self.is_stale(now, forget_after) {
+            self.count = 0;
+        }
+        self.count += 1;
+        let backoff = BASE_BACKOFF
+            .saturating_mul(2u32.saturating_pow(self.count - 1))
+            .min(max_backoff);
+        self.retry_at = Some(now + backoff);
+    }
+}
+
+/// Forget entries that have gone quiet once there are a lot of them
+fn prune<K: Eq + Hash>(entries: &mut HashMap<K, Failures>, now: Instant, forget_after: Duration) {
+    if entries.len() >= PRUNE_THRESHOLD {
+        entries.retain(|_, failures| !failures.is_stale(now, forget_after));
+    }
+}
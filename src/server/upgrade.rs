@@ -0,0 +1,100 @@
+use std::net::SocketAddr;
+use tokio_tungstenite::tungstenite::{
+    handshake::server::{Callback, ErrorResponse, Request, Response},
+    http::{header, StatusCode},
+};
+use tracing::warn;
+
+/// What a websocket upgrade request has to match, e.g. for deployments behind a reverse proxy
+/// sharing a host with other sites
+#[derive(Clone, Debug, Default)]
+pub struct UpgradeRules {
+    /// Path clients have to connect to, any path if unset
+    pub path: Option<String>,
+    /// Host headers to accept, with or without the port, any if empty
+    pub hosts: Vec<String>,
+    /// Origin headers to accept, any if empty. Our client doesn't send one, so only browsers
+    /// sending a different origin are refused.
+    pub origins: Vec<String>,
+}
+
+/// Why an upgrade request was refused, and the http status to refuse it with
+struct Refusal {
+    status: StatusCode,
+    reason: String,
+}
+
+impl UpgradeRules {
+    /// Callback checking a connection's upgrade request against the rules during the handshake
+    pub fn check_for(&self, peer_addr: SocketAddr) -> UpgradeCheck<'_> {
+        UpgradeCheck {
+            rules: self,
+            peer_addr,
+        }
+    }
+
+    fn check(&self, request: &Request) -> Result<(), Refusal> {
+        if let Some(path) = &self.path {
+            if request.uri().path() != path {
+                return Err(Refusal {
+                    status: StatusCode::NOT_FOUND,
+                    reason: format!("Unknown path {}", request.uri().path()),
+                });
+            }
+        }
+
+        let header = |name| {
+            request
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+        };
+        if !self.hosts.is_empty() {
+            let host = header(header::HOST).unwrap_or_default();
+            let hostname = host.rsplit_once(':').map_or(host, |(hostname, _)| hostname);
+            if !self.hosts.iter().any(|allowed| {
+                allowed.eq_ignore_ascii_case(host) || allowed.eq_ignore_ascii_case(hostname)
+            }) {
+                return Err(Refusal {
+                    status: StatusCode::FORBIDDEN,
+                    reason: format!("Unknown host {host}"),
+                });
+            }
+        }
+        if let Some(origin) = header(header::ORIGIN) {
+            if !self.origins.is_empty()
+                && !self
+                    .origins
+                    .iter()
+                    .any(|allowed| allowed.eq_ignore_ascii_case(origin))
+            {
+                return Err(Refusal {
+                    status: StatusCode::FORBIDDEN,
+                    reason: format!("Origin {origin} not allowed"),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Checks an upgrade request, refusing it with an http error if it doesn't match the rules
+pub struct UpgradeCheck<'a> {
+    rules: &'a UpgradeRules,
+    peer_addr: SocketAddr,
+}
+
+impl Callback for UpgradeCheck<'_> {
+    fn on_request(self, request: &Request, response: Response) -> Result<Response, ErrorResponse> {
+        let Err(refusal) = self.rules.check(request) else {
+            return Ok(response);
+        };
+        warn!(
+            "🚪 Refused websocket upgrade from {}: {}",
+            self.peer_addr, refusal.reason
+        );
+        let mut response = ErrorResponse::new(Some(refusal.reason));
+        *response.status_mut() = refusal.status;
+        Err(response)
+    }
+}
@@ -0,0 +1,66 @@
+use std::collections::HashMap;
+use std::hash::{BuildHasher, RandomState};
+use std::sync::Arc;
+use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use super::relay::Relay;
+
+/// Shards the map is split into, enough that thousands of users rarely wait on each other
+pub const DEFAULT_SHARDS: usize = 64;
+
+type Shard = HashMap<String, Arc<Relay>>;
+
+/// Relay queues of the users with a chat session, by pubkey. Split into shards with their own
+/// lock, so relaying a note or authenticating only contends with users in the same shard.
+///
+/// Locking a user's shard is how the server makes checks and changes to their entry atomic, the
+/// guard can be held across awaits like a single lock would be.
+pub struct UserConns {
+    shards: Vec<RwLock<Shard>>,
+    hasher: RandomState,
+}
+
+impl UserConns {
+    pub fn new(shards: usize) -> Self {
+        Self {
+            shards: (0..shards.max(1))
+                .map(|_| RwLock::new(HashMap::new()))
+                .collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    fn shard(&self, pub_key: &str) -> &RwLock<Shard> {
+        let i = self.hasher.hash_one(pub_key) as usize % self.shards.len();
+        &self.shards[i]
+    }
+
+    /// Lock the shard holding a user for reading
+    pub async fn read(&self, pub_key: &str) -> RwLockReadGuard<'_, Shard> {
+        self.shard(pub_key).read().await
+    }
+
+    /// Lock the shard holding a user for writing
+    pub async fn write(&self, pub_key: &str) -> RwLockWriteGuard<'_, Shard> {
+        self.shard(pub_key).write().await
+    }
+
+    /// A user's relay queue, if they're connected
+    pub async fn get(&self, pub_key: &str) -> Option<Arc<Relay>> {
+        self.read(pub_key).await.get(pub_key).cloned()
+    }
+
+    /// Every connected user and their relay queue, locking one shard at a time
+    pub async fn snapshot(&self) -> Vec<(String, Arc<Relay>)> {
+        let mut users = vec![];
+        for shard in &self.shards {
+            let shard = shard.read().await;
+            users.extend(
+                shard
+                    .iter()
+                    .map(|(pub_key, relay)| (pub_key.clone(), Arc::clone(relay))),
+            );
+        }
+        users
+    }
+}